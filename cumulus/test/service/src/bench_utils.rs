@@ -197,6 +197,10 @@ pub fn get_wasm_module() -> Box<dyn sc_executor_common::wasm_runtime::WasmModule
 			wasm_bulk_memory: false,
 			wasm_reference_types: false,
 			wasm_simd: false,
+			wasm_relaxed_simd: false,
+			signals_based_traps: true,
+			optimization_level: sc_executor_wasmtime::OptimizationLevel::SpeedAndSize,
+			hugepage_aligned_code: false,
 		},
 	};
 	Box::new(
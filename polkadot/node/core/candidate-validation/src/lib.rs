@@ -481,8 +481,6 @@ where
 		);
 		return None
 	};
-	let timeout = pvf_prep_timeout(&executor_params, PvfPrepKind::Prepare);
-
 	let mut active_pvfs = vec![];
 	let mut processed_code_hashes = vec![];
 	for code_hash in code_hashes {
@@ -500,6 +498,7 @@ where
 			continue;
 		};
 
+		let timeout = pvf_prep_timeout(&executor_params, PvfPrepKind::Prepare, &validation_code.0);
 		let pvf = PvfPrepData::from_code(
 			validation_code.0,
 			executor_params.clone(),
@@ -636,7 +635,7 @@ where
 		return PreCheckOutcome::Invalid
 	};
 
-	let timeout = pvf_prep_timeout(&executor_params, PvfPrepKind::Precheck);
+	let timeout = pvf_prep_timeout(&executor_params, PvfPrepKind::Precheck, &validation_code.0);
 
 	let pvf = PvfPrepData::from_code(
 		validation_code.0,
@@ -856,7 +855,8 @@ async fn validate_candidate_exhaustive(
 		// Retry is disabled to reduce the chance of nondeterministic blocks getting backed and
 		// honest backers getting slashed.
 		PvfExecKind::Backing => {
-			let prep_timeout = pvf_prep_timeout(&executor_params, PvfPrepKind::Prepare);
+			let prep_timeout =
+				pvf_prep_timeout(&executor_params, PvfPrepKind::Prepare, &validation_code.0);
 			let exec_timeout = pvf_exec_timeout(&executor_params, exec_kind);
 			let pvf = PvfPrepData::from_code(
 				validation_code.0,
@@ -993,7 +993,7 @@ trait ValidationBackend {
 		// The priority for the preparation job.
 		prepare_priority: polkadot_node_core_pvf::Priority,
 	) -> Result<WasmValidationResult, ValidationError> {
-		let prep_timeout = pvf_prep_timeout(&executor_params, PvfPrepKind::Prepare);
+		let prep_timeout = pvf_prep_timeout(&executor_params, PvfPrepKind::Prepare, &code);
 		// Construct the PVF a single time, since it is an expensive operation. Cloning it is cheap.
 		let pvf = PvfPrepData::from_code(
 			code,
@@ -1191,7 +1191,12 @@ fn perform_basic_checks(
 /// Prepare
 ///The time period after which the preparation worker is considered
 /// unresponsive and will be killed.
-fn pvf_prep_timeout(executor_params: &ExecutorParams, kind: PvfPrepKind) -> Duration {
+///
+/// A timeout that scales with the size of the (maybe compressed) code takes precedence.
+fn pvf_prep_timeout(executor_params: &ExecutorParams, kind: PvfPrepKind, code: &[u8]) -> Duration {
+	if let Some(timeout) = executor_params.pvf_prep_timeout_for_code_size(kind, code.len() as u64) {
+		return timeout
+	}
 	if let Some(timeout) = executor_params.pvf_prep_timeout(kind) {
 		return timeout
 	}
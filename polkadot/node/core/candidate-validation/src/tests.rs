@@ -19,7 +19,7 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use super::*;
 use assert_matches::assert_matches;
 use futures::executor;
-use polkadot_node_core_pvf::PrepareError;
+use polkadot_node_core_pvf::{PrepareError, PrevalidationError};
 use polkadot_node_primitives::{BlockData, VALIDATION_CODE_BOMB_LIMIT};
 use polkadot_node_subsystem::messages::AllMessages;
 use polkadot_node_subsystem_util::reexports::SubsystemContext;
@@ -1103,7 +1103,10 @@ fn precheck_properly_classifies_outcomes() {
 		executor::block_on(test_fut);
 	};
 
-	inner(Err(PrepareError::Prevalidation("foo".to_owned())), PreCheckOutcome::Invalid);
+	inner(
+		Err(PrepareError::Prevalidation(PrevalidationError::Invalid("foo".to_owned()))),
+		PreCheckOutcome::Invalid,
+	);
 	inner(Err(PrepareError::Preparation("bar".to_owned())), PreCheckOutcome::Invalid);
 	inner(Err(PrepareError::JobError("baz".to_owned())), PreCheckOutcome::Invalid);
 
@@ -1736,3 +1739,39 @@ fn maybe_prepare_validation_does_not_prepare_already_prepared_pvfs() {
 	assert!(state.is_next_session_authority);
 	assert_eq!(state.already_prepared_code_hashes.len(), 3);
 }
+
+#[test]
+fn pvf_prep_timeout_scales_with_the_code_size() {
+	use polkadot_primitives::ExecutorParam;
+
+	let executor_params = ExecutorParams::from(
+		&[
+			ExecutorParam::PvfPrepTimeout(PvfPrepKind::Prepare, 5_000),
+			ExecutorParam::PvfPrepTimeoutScaling {
+				kind: PvfPrepKind::Prepare,
+				per_mib_ms: 10_000,
+				min_ms: 2_000,
+				max_ms: 60_000,
+			},
+		][..],
+	);
+	let code = vec![0; 3 * 1024 * 1024];
+
+	assert_eq!(
+		pvf_prep_timeout(&executor_params, PvfPrepKind::Prepare, &code),
+		Duration::from_secs(30)
+	);
+	// Only the timeout of the given kind scales.
+	assert_eq!(
+		pvf_prep_timeout(&executor_params, PvfPrepKind::Precheck, &code),
+		DEFAULT_PRECHECK_PREPARATION_TIMEOUT
+	);
+	// The scaled timeout is the one the host applies to the preparation job.
+	let pvf = PvfPrepData::from_code(
+		code.clone(),
+		executor_params.clone(),
+		pvf_prep_timeout(&executor_params, PvfPrepKind::Prepare, &code),
+		PrepareJobKind::Compilation,
+	);
+	assert_eq!(pvf.cpu_timeout(), Duration::from_secs(30));
+}
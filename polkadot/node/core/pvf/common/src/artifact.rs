@@ -0,0 +1,648 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Handling of compiled artifacts as they are stored on disk.
+//!
+//! A [`CompiledArtifact`] starts with a header containing magic bytes, the format version, the
+//! [`ArtifactFlags`], the version of the compiler that produced it, the hash of the code it was
+//! compiled from and a checksum of the payload, which allows detecting truncated, corrupted, stale
+//! or misplaced artifacts.
+//!
+//! The payload is compressed with zstd by the prepare worker and decompressed by the execute
+//! worker. A compressed payload starts with an 8-byte magic identifier, followed by a single byte
+//! holding the compression level. Payloads without the magic identifier are loaded as they are.
+
+use codec::{Decode, Encode};
+use polkadot_primitives::ValidationCodeHash;
+use sp_crypto_hashing::blake2_256;
+use std::{
+	borrow::Cow,
+	io::{Read, Write},
+};
+
+/// Magic bytes at the start of every compiled artifact.
+const ARTIFACT_MAGIC: [u8; 4] = *b"pvfa";
+
+/// The current version of the artifact format. Must be bumped whenever the layout changes.
+pub const ARTIFACT_FORMAT_VERSION: u8 = 4;
+
+/// The version of the compiler producing the artifacts. Artifacts compiled by a different version
+/// can't be loaded, so they are rejected up front. Must be updated along with the `wasmtime`
+/// dependency of `sc-executor-wasmtime`.
+pub const COMPILER_VERSION: &str = "wasmtime 8.0.1";
+
+/// The length of the compiler version in the artifact header. Shorter versions are padded with
+/// zeroes.
+const ARTIFACT_COMPILER_VERSION_LEN: usize = 32;
+
+/// The length of the code hash in the artifact header.
+const ARTIFACT_CODE_HASH_LEN: usize = 32;
+
+/// The length of the blake2-256 checksum in the artifact header.
+const ARTIFACT_CHECKSUM_LEN: usize = 32;
+
+/// The length of the artifact header: the magic bytes, the format version, the flags, the compiler
+/// version, the code hash and the checksum.
+pub const ARTIFACT_HEADER_LEN: usize = ARTIFACT_MAGIC.len() +
+	1 + 1 +
+	ARTIFACT_COMPILER_VERSION_LEN +
+	ARTIFACT_CODE_HASH_LEN +
+	ARTIFACT_CHECKSUM_LEN;
+
+/// The bit of the flags byte set if the payload uses the hugepage layout.
+const ARTIFACT_FLAG_HUGEPAGE_LAYOUT: u8 = 1 << 0;
+
+/// How the payload of an artifact was compiled, as recorded in its header.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArtifactFlags {
+	/// The compiled code is padded to whole hugepages, see
+	/// [`ExecutorParam::HugepageAlignedCode`](polkadot_primitives::ExecutorParam::HugepageAlignedCode).
+	pub hugepage_layout: bool,
+}
+
+impl ArtifactFlags {
+	fn to_byte(self) -> u8 {
+		if self.hugepage_layout {
+			ARTIFACT_FLAG_HUGEPAGE_LAYOUT
+		} else {
+			0
+		}
+	}
+
+	fn from_byte(byte: u8) -> Self {
+		Self { hugepage_layout: byte & ARTIFACT_FLAG_HUGEPAGE_LAYOUT != 0 }
+	}
+}
+
+/// The header of an artifact is invalid.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ArtifactHeaderError {
+	/// The artifact is too short to even contain the header.
+	#[error("artifact is too short to contain a header: {0} bytes")]
+	TooShort(usize),
+	/// The artifact does not start with the expected magic bytes.
+	#[error("artifact does not start with the expected magic bytes")]
+	BadMagic,
+	/// The artifact was written in a different format version.
+	#[error("artifact format version {found} does not match the expected version {expected}")]
+	VersionMismatch { found: u8, expected: u8 },
+	/// The artifact has flags set that this version doesn't know about.
+	#[error("artifact has unknown flags set: {0:#04x}")]
+	UnknownFlags(u8),
+	/// The artifact was produced by a different compiler version.
+	#[error("artifact compiler version {found:?} does not match the running version {expected:?}")]
+	CompilerVersionMismatch { found: String, expected: String },
+	/// The checksum in the header does not match the payload.
+	#[error("artifact checksum does not match its payload")]
+	ChecksumMismatch,
+}
+
+/// The maximum size of a compiled artifact, including the header. The payload can't be larger than
+/// [`ARTIFACT_DECOMPRESSION_LIMIT`] without failing to load, whether or not it is compressed.
+pub const MAX_COMPILED_ARTIFACT_SIZE: usize = ARTIFACT_HEADER_LEN + ARTIFACT_DECOMPRESSION_LIMIT;
+
+/// Contains the bytes for a successfully compiled artifact, prefixed with the header.
+#[derive(Encode)]
+pub struct CompiledArtifact(Vec<u8>);
+
+// Decodes like the derived implementation would, but rejects artifacts larger than
+// `MAX_COMPILED_ARTIFACT_SIZE` before allocating for them.
+impl Decode for CompiledArtifact {
+	fn decode<I: codec::Input>(input: &mut I) -> Result<Self, codec::Error> {
+		let len = <codec::Compact<u32>>::decode(input)?.0 as usize;
+		if len > MAX_COMPILED_ARTIFACT_SIZE {
+			return Err("compiled artifact exceeds the maximum size".into())
+		}
+		codec::decode_vec_with_len(input, len).map(Self)
+	}
+}
+
+impl CompiledArtifact {
+	/// Creates a `CompiledArtifact` of the code with the given hash, prepending the header to the
+	/// given payload.
+	pub fn new(payload: Vec<u8>, code_hash: ValidationCodeHash) -> Self {
+		Self::with_flags(payload, code_hash, ArtifactFlags::default())
+	}
+
+	/// Like [`Self::new`], but records the given flags in the header.
+	pub fn with_flags(
+		payload: Vec<u8>,
+		code_hash: ValidationCodeHash,
+		flags: ArtifactFlags,
+	) -> Self {
+		let mut bytes = Vec::with_capacity(ARTIFACT_HEADER_LEN + payload.len());
+		write_header(&mut bytes, code_hash, COMPILER_VERSION, flags, &blake2_256(&payload));
+		bytes.extend(payload);
+		Self(bytes)
+	}
+
+	/// Like [`Self::new`], but claims to be produced by the given compiler version, which is
+	/// truncated to fit the header.
+	fn with_compiler_version(
+		payload: Vec<u8>,
+		code_hash: ValidationCodeHash,
+		compiler_version: &str,
+	) -> Self {
+		let mut bytes = Vec::with_capacity(ARTIFACT_HEADER_LEN + payload.len());
+		write_header(
+			&mut bytes,
+			code_hash,
+			compiler_version,
+			ArtifactFlags::default(),
+			&blake2_256(&payload),
+		);
+		bytes.extend(payload);
+		Self(bytes)
+	}
+
+	/// Validates the header of the given artifact bytes and returns the payload.
+	pub fn parse(bytes: &[u8]) -> Result<&[u8], ArtifactHeaderError> {
+		let mut verifier = ArtifactVerifier::new();
+		verifier.update(bytes);
+		verifier.finish()?;
+		Ok(&bytes[ARTIFACT_HEADER_LEN..])
+	}
+
+	/// Like [`Self::parse`], but also returns the hash of the code the artifact was compiled from.
+	pub fn parse_with_code_hash(
+		bytes: &[u8],
+	) -> Result<(ValidationCodeHash, &[u8]), ArtifactHeaderError> {
+		let payload = Self::parse(bytes)?;
+		let code_hash_start = ARTIFACT_HEADER_LEN - ARTIFACT_CHECKSUM_LEN - ARTIFACT_CODE_HASH_LEN;
+		let mut code_hash = [0; ARTIFACT_CODE_HASH_LEN];
+		code_hash.copy_from_slice(&bytes[code_hash_start..][..ARTIFACT_CODE_HASH_LEN]);
+		Ok((ValidationCodeHash::from(code_hash), payload))
+	}
+
+	/// Creates a `CompiledArtifact` from bytes that already start with the header, validating it.
+	pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ArtifactHeaderError> {
+		Self::parse(&bytes)?;
+		Ok(Self(bytes))
+	}
+
+	/// Returns the payload of the artifact, without the header.
+	pub fn payload(&self) -> &[u8] {
+		&self.0[ARTIFACT_HEADER_LEN..]
+	}
+
+	/// Returns the flags recorded in the header.
+	pub fn flags(&self) -> ArtifactFlags {
+		ArtifactFlags::from_byte(self.0[ARTIFACT_MAGIC.len() + 1])
+	}
+}
+
+impl AsRef<[u8]> for CompiledArtifact {
+	fn as_ref(&self) -> &[u8] {
+		self.0.as_slice()
+	}
+}
+
+/// Appends the header of an artifact with the given payload checksum to `bytes`.
+fn write_header(
+	bytes: &mut Vec<u8>,
+	code_hash: ValidationCodeHash,
+	compiler_version: &str,
+	flags: ArtifactFlags,
+	checksum: &[u8],
+) {
+	bytes.extend_from_slice(&ARTIFACT_MAGIC);
+	bytes.push(ARTIFACT_FORMAT_VERSION);
+	bytes.push(flags.to_byte());
+	let mut version = [0; ARTIFACT_COMPILER_VERSION_LEN];
+	let version_len = compiler_version.len().min(ARTIFACT_COMPILER_VERSION_LEN);
+	version[..version_len].copy_from_slice(&compiler_version.as_bytes()[..version_len]);
+	bytes.extend_from_slice(&version);
+	bytes.extend_from_slice(code_hash.as_ref());
+	bytes.extend_from_slice(checksum);
+}
+
+/// Builds the header of an artifact whose payload is produced in chunks, e.g. while it is streamed,
+/// so that the header can only be written once the payload is complete. See
+/// [`CompiledArtifact::new`].
+pub struct ArtifactHeaderBuilder {
+	payload_hasher: blake2b_simd::State,
+}
+
+impl ArtifactHeaderBuilder {
+	/// Creates a builder that hasn't seen any of the payload yet.
+	pub fn new() -> Self {
+		Self {
+			payload_hasher: blake2b_simd::Params::new()
+				.hash_length(ARTIFACT_CHECKSUM_LEN)
+				.to_state(),
+		}
+	}
+
+	/// Feeds the next bytes of the payload to the builder.
+	pub fn update(&mut self, payload: &[u8]) {
+		self.payload_hasher.update(payload);
+	}
+
+	/// Returns the header of the artifact of the code with the given hash and the given flags, for
+	/// the payload fed so far.
+	pub fn finish(self, code_hash: ValidationCodeHash, flags: ArtifactFlags) -> Vec<u8> {
+		let mut header = Vec::with_capacity(ARTIFACT_HEADER_LEN);
+		write_header(
+			&mut header,
+			code_hash,
+			COMPILER_VERSION,
+			flags,
+			self.payload_hasher.finalize().as_bytes(),
+		);
+		header
+	}
+}
+
+impl Default for ArtifactHeaderBuilder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Validates the header of an artifact whose bytes arrive in chunks, e.g. from a pipe, without
+/// holding the whole artifact in memory. See [`CompiledArtifact::parse`].
+pub struct ArtifactVerifier {
+	header: Vec<u8>,
+	payload_hasher: blake2b_simd::State,
+}
+
+impl ArtifactVerifier {
+	/// Creates a verifier that hasn't seen any bytes yet.
+	pub fn new() -> Self {
+		Self {
+			header: Vec::with_capacity(ARTIFACT_HEADER_LEN),
+			payload_hasher: blake2b_simd::Params::new()
+				.hash_length(ARTIFACT_CHECKSUM_LEN)
+				.to_state(),
+		}
+	}
+
+	/// Feeds the next bytes of the artifact to the verifier.
+	pub fn update(&mut self, mut bytes: &[u8]) {
+		let missing_header_len = ARTIFACT_HEADER_LEN - self.header.len();
+		if missing_header_len > 0 {
+			let (header, rest) = bytes.split_at(missing_header_len.min(bytes.len()));
+			self.header.extend_from_slice(header);
+			bytes = rest;
+		}
+		self.payload_hasher.update(bytes);
+	}
+
+	/// Validates the header against the bytes fed so far.
+	pub fn finish(self) -> Result<(), ArtifactHeaderError> {
+		if self.header.len() < ARTIFACT_HEADER_LEN {
+			return Err(ArtifactHeaderError::TooShort(self.header.len()))
+		}
+
+		let (magic, rest) = self.header.split_at(ARTIFACT_MAGIC.len());
+		if magic != ARTIFACT_MAGIC {
+			return Err(ArtifactHeaderError::BadMagic)
+		}
+
+		let (version, rest) = rest.split_at(1);
+		if version[0] != ARTIFACT_FORMAT_VERSION {
+			return Err(ArtifactHeaderError::VersionMismatch {
+				found: version[0],
+				expected: ARTIFACT_FORMAT_VERSION,
+			})
+		}
+
+		let (flags, rest) = rest.split_at(1);
+		if flags[0] & !ARTIFACT_FLAG_HUGEPAGE_LAYOUT != 0 {
+			return Err(ArtifactHeaderError::UnknownFlags(flags[0]))
+		}
+
+		let (compiler_version, rest) = rest.split_at(ARTIFACT_COMPILER_VERSION_LEN);
+		let compiler_version = compiler_version_from_header(compiler_version);
+		if compiler_version != compiler_version_from_header(COMPILER_VERSION.as_bytes()) {
+			return Err(ArtifactHeaderError::CompilerVersionMismatch {
+				found: compiler_version,
+				expected: COMPILER_VERSION.into(),
+			})
+		}
+
+		// The code hash is only checked by those who know which code to expect, see
+		// [`CompiledArtifact::parse_with_code_hash`].
+		let (_code_hash, checksum) = rest.split_at(ARTIFACT_CODE_HASH_LEN);
+		if checksum != self.payload_hasher.finalize().as_bytes() {
+			return Err(ArtifactHeaderError::ChecksumMismatch)
+		}
+
+		Ok(())
+	}
+}
+
+/// Decodes the zero-padded compiler version of an artifact header, truncated like
+/// [`CompiledArtifact::new`] does.
+fn compiler_version_from_header(version: &[u8]) -> String {
+	let version = &version[..version.len().min(ARTIFACT_COMPILER_VERSION_LEN)];
+	let len = version.iter().position(|&byte| byte == 0).unwrap_or(version.len());
+	String::from_utf8_lossy(&version[..len]).into_owned()
+}
+
+impl Default for ArtifactVerifier {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+// An arbitrary prefix that indicates a zstd-compressed artifact.
+//
+// This differs from the ELF magic bytes, so uncompressed artifacts will not have this prefix.
+const COMPRESSED_ARTIFACT_PREFIX: [u8; 8] = [0x70, 0x76, 0x66, 0x7a, 0x73, 0x74, 0x64, 0x01];
+
+/// The zstd compression level used for compiled artifacts.
+pub const ARTIFACT_COMPRESSION_LEVEL: u8 = 3;
+
+/// The maximum size of a decompressed artifact. Artifacts are expected to be in the order of tens
+/// of MiB, so this is set well above that.
+pub const ARTIFACT_DECOMPRESSION_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// An error occurred while compressing or decompressing an artifact.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum Error {
+	/// Decoded size was too large, and the artifact may be a bomb.
+	#[error("Possible compression bomb encountered")]
+	PossibleBomb,
+	/// The compressed artifact had an invalid format.
+	#[error("Compressed artifact had invalid format")]
+	Invalid,
+	/// The artifact could not be compressed.
+	#[error("Could not compress artifact: {0}")]
+	Compression(String),
+}
+
+/// Compresses the given artifact bytes with the given zstd compression level.
+pub fn compress(artifact: &[u8], level: u8) -> Result<Vec<u8>, Error> {
+	let mut buf = Vec::new();
+	compress_to(artifact, level, &mut buf)?;
+	Ok(buf)
+}
+
+/// Like [`compress`], but writes the compressed bytes to `writer` as they are produced, instead of
+/// collecting them.
+pub fn compress_to(artifact: &[u8], level: u8, mut writer: impl Write) -> Result<(), Error> {
+	let to_error = |err: std::io::Error| Error::Compression(err.to_string());
+	writer.write_all(&COMPRESSED_ARTIFACT_PREFIX).map_err(to_error)?;
+	writer.write_all(&[level]).map_err(to_error)?;
+
+	let mut encoder = zstd::Encoder::new(writer, level as i32).map_err(to_error)?;
+	encoder.write_all(artifact).map_err(to_error)?;
+	encoder.finish().map_err(to_error)?;
+
+	Ok(())
+}
+
+/// Decompresses the given artifact bytes if they are compressed, otherwise returns them as they
+/// are.
+pub fn decompress(artifact: &[u8]) -> Result<Cow<[u8]>, Error> {
+	let Some(rest) = artifact.strip_prefix(&COMPRESSED_ARTIFACT_PREFIX[..]) else {
+		return Ok(artifact.into())
+	};
+	// The compression level is not needed for decompression.
+	let Some((_level, payload)) = rest.split_first() else { return Err(Error::Invalid) };
+
+	let mut decoder = zstd::Decoder::with_buffer(payload)
+		.map_err(|_| Error::Invalid)?
+		.take((ARTIFACT_DECOMPRESSION_LIMIT + 1) as u64);
+
+	let mut buf = Vec::with_capacity(payload.len());
+	decoder.read_to_end(&mut buf).map_err(|_| Error::Invalid)?;
+
+	if buf.len() <= ARTIFACT_DECOMPRESSION_LIMIT {
+		Ok(buf.into())
+	} else {
+		Err(Error::PossibleBomb)
+	}
+}
+
+/// Returns the compression level of the given artifact, or `None` if it is not compressed.
+pub fn compression_level(artifact: &[u8]) -> Option<u8> {
+	artifact.strip_prefix(&COMPRESSED_ARTIFACT_PREFIX[..])?.first().copied()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn code_hash() -> ValidationCodeHash {
+		ValidationCodeHash::from([1; 32])
+	}
+
+	#[test]
+	fn valid_artifact_parses() {
+		let artifact = CompiledArtifact::new(b"some compiled artifact".to_vec(), code_hash());
+
+		assert_eq!(artifact.payload(), b"some compiled artifact");
+		assert_eq!(CompiledArtifact::parse(artifact.as_ref()), Ok(&b"some compiled artifact"[..]));
+	}
+
+	#[test]
+	fn artifact_names_its_code_hash() {
+		let artifact = CompiledArtifact::new(b"some compiled artifact".to_vec(), code_hash());
+
+		assert_eq!(
+			CompiledArtifact::parse_with_code_hash(artifact.as_ref()),
+			Ok((code_hash(), &b"some compiled artifact"[..]))
+		);
+	}
+
+	#[test]
+	fn truncated_artifact_fails() {
+		let artifact = CompiledArtifact::new(b"some compiled artifact".to_vec(), code_hash());
+
+		assert_eq!(
+			CompiledArtifact::parse(&artifact.as_ref()[..ARTIFACT_HEADER_LEN - 1]),
+			Err(ArtifactHeaderError::TooShort(ARTIFACT_HEADER_LEN - 1))
+		);
+		assert_eq!(
+			CompiledArtifact::parse(&artifact.as_ref()[..ARTIFACT_HEADER_LEN + 4]),
+			Err(ArtifactHeaderError::ChecksumMismatch)
+		);
+	}
+
+	#[test]
+	fn bad_magic_fails() {
+		let mut bytes = CompiledArtifact::new(b"some compiled artifact".to_vec(), code_hash())
+			.as_ref()
+			.to_vec();
+		bytes[0] ^= 0xff;
+
+		assert_eq!(CompiledArtifact::parse(&bytes), Err(ArtifactHeaderError::BadMagic));
+	}
+
+	#[test]
+	fn version_mismatch_fails() {
+		let mut bytes = CompiledArtifact::new(b"some compiled artifact".to_vec(), code_hash())
+			.as_ref()
+			.to_vec();
+		bytes[ARTIFACT_MAGIC.len()] = ARTIFACT_FORMAT_VERSION + 1;
+
+		assert_eq!(
+			CompiledArtifact::parse(&bytes),
+			Err(ArtifactHeaderError::VersionMismatch {
+				found: ARTIFACT_FORMAT_VERSION + 1,
+				expected: ARTIFACT_FORMAT_VERSION,
+			})
+		);
+	}
+
+	#[test]
+	fn matching_compiler_version_parses() {
+		let artifact = CompiledArtifact::with_compiler_version(
+			b"some compiled artifact".to_vec(),
+			code_hash(),
+			COMPILER_VERSION,
+		);
+
+		assert_eq!(CompiledArtifact::parse(artifact.as_ref()), Ok(&b"some compiled artifact"[..]));
+	}
+
+	#[test]
+	fn compiler_version_mismatch_fails() {
+		let artifact = CompiledArtifact::with_compiler_version(
+			b"some compiled artifact".to_vec(),
+			code_hash(),
+			"wasmtime 0.0.1",
+		);
+
+		assert_eq!(
+			CompiledArtifact::parse(artifact.as_ref()),
+			Err(ArtifactHeaderError::CompilerVersionMismatch {
+				found: "wasmtime 0.0.1".into(),
+				expected: COMPILER_VERSION.into(),
+			})
+		);
+	}
+
+	#[test]
+	fn corrupted_checksum_fails() {
+		let mut bytes = CompiledArtifact::new(b"some compiled artifact".to_vec(), code_hash())
+			.as_ref()
+			.to_vec();
+		*bytes.last_mut().unwrap() ^= 0xff;
+
+		assert_eq!(CompiledArtifact::parse(&bytes), Err(ArtifactHeaderError::ChecksumMismatch));
+	}
+
+	#[test]
+	fn artifact_verifies_in_chunks() {
+		let artifact = CompiledArtifact::new(b"some compiled artifact".repeat(16), code_hash());
+
+		for chunk_size in [1, ARTIFACT_HEADER_LEN - 1, ARTIFACT_HEADER_LEN, 100] {
+			let mut verifier = ArtifactVerifier::new();
+			artifact.as_ref().chunks(chunk_size).for_each(|chunk| verifier.update(chunk));
+			assert_eq!(verifier.finish(), Ok(()), "chunk size {}", chunk_size);
+		}
+
+		let mut verifier = ArtifactVerifier::new();
+		artifact.as_ref()[..artifact.as_ref().len() - 1]
+			.chunks(7)
+			.for_each(|chunk| verifier.update(chunk));
+		assert_eq!(verifier.finish(), Err(ArtifactHeaderError::ChecksumMismatch));
+	}
+
+	#[test]
+	fn header_built_in_chunks_matches_the_artifact() {
+		let payload = b"some compiled artifact".repeat(16);
+		let artifact = CompiledArtifact::new(payload.clone(), code_hash());
+
+		let mut builder = ArtifactHeaderBuilder::new();
+		payload.chunks(7).for_each(|chunk| builder.update(chunk));
+
+		assert_eq!(
+			builder.finish(code_hash(), ArtifactFlags::default()),
+			artifact.as_ref()[..ARTIFACT_HEADER_LEN]
+		);
+	}
+
+	#[test]
+	fn flags_round_trip() {
+		for hugepage_layout in [false, true] {
+			let flags = ArtifactFlags { hugepage_layout };
+			let artifact = CompiledArtifact::with_flags(
+				b"some compiled artifact".to_vec(),
+				code_hash(),
+				flags,
+			);
+
+			let parsed = CompiledArtifact::from_bytes(artifact.as_ref().to_vec()).unwrap();
+
+			assert_eq!(parsed.flags(), flags);
+			assert_eq!(parsed.payload(), b"some compiled artifact");
+		}
+	}
+
+	#[test]
+	fn unknown_flags_fail() {
+		let mut bytes = CompiledArtifact::new(b"some compiled artifact".to_vec(), code_hash())
+			.as_ref()
+			.to_vec();
+		bytes[ARTIFACT_MAGIC.len() + 1] = 0x80;
+
+		assert_eq!(CompiledArtifact::parse(&bytes), Err(ArtifactHeaderError::UnknownFlags(0x80)));
+	}
+
+	#[test]
+	fn compress_and_decompress() {
+		let artifact = b"\x7fELF some compiled artifact".repeat(1024);
+
+		let compressed = compress(&artifact, ARTIFACT_COMPRESSION_LEVEL).unwrap();
+
+		assert!(compressed.len() < artifact.len());
+		assert_eq!(compression_level(&compressed), Some(ARTIFACT_COMPRESSION_LEVEL));
+		assert_eq!(&decompress(&compressed).unwrap()[..], &artifact[..]);
+	}
+
+	#[test]
+	fn uncompressed_artifacts_load_as_they_are() {
+		let artifact = b"\x7fELF some compiled artifact".to_vec();
+
+		assert_eq!(compression_level(&artifact), None);
+		assert!(matches!(decompress(&artifact).unwrap(), Cow::Borrowed(_)));
+		assert_eq!(&decompress(&artifact).unwrap()[..], &artifact[..]);
+	}
+
+	#[test]
+	fn corrupted_compressed_artifact_fails() {
+		let mut compressed =
+			compress(b"some compiled artifact", ARTIFACT_COMPRESSION_LEVEL).unwrap();
+		compressed.truncate(COMPRESSED_ARTIFACT_PREFIX.len() + 4);
+
+		assert_eq!(decompress(&compressed).err(), Some(Error::Invalid));
+		assert_eq!(decompress(&COMPRESSED_ARTIFACT_PREFIX).err(), Some(Error::Invalid));
+	}
+
+	#[test]
+	fn artifact_decodes_from_its_encoding() {
+		let artifact = CompiledArtifact::new(b"some compiled artifact".to_vec(), code_hash());
+
+		let decoded = CompiledArtifact::decode(&mut &artifact.encode()[..]).unwrap();
+
+		assert_eq!(decoded.as_ref(), artifact.as_ref());
+	}
+
+	#[test]
+	fn oversized_artifact_is_rejected_before_decoding_its_bytes() {
+		// Only the length prefix, so decoding would fail on the missing bytes if the length were
+		// not checked first.
+		let encoded = codec::Compact((MAX_COMPILED_ARTIFACT_SIZE + 1) as u32).encode();
+
+		let err = CompiledArtifact::decode(&mut &encoded[..]).err().unwrap();
+
+		assert!(err.to_string().contains("exceeds the maximum size"), "{}", err);
+	}
+}
@@ -14,9 +14,14 @@
 // You should have received a copy of the GNU General Public License
 // along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::prepare::{PrepareSuccess, PrepareWorkerSuccess};
+use crate::{
+	executor_interface::PrepareStageError,
+	prepare::{MemoryStats, PrepareStage, PrepareSuccess, PrepareWorkerSuccess},
+};
 use codec::{Decode, Encode};
+use polkadot_parachain_primitives::primitives::ValidationCodeHash;
 pub use sc_executor_common::error::Error as ExecuteError;
+use std::time::Duration;
 
 /// Result of PVF preparation from a worker, with checksum of the compiled PVF and stats of the
 /// preparation if successful.
@@ -31,14 +36,18 @@ pub type PrepareResult = Result<PrepareSuccess, PrepareError>;
 pub type PrecheckResult = Result<(), PrepareError>;
 
 /// An error that occurred during the prepare part of the PVF pipeline.
+///
+/// The `Display` messages and the labels of [`Self::kind_str`] are stable, so tools can key off
+/// them instead of the `Debug` output.
 // Codec indexes are intended to stabilize pre-encoded payloads (see `OOM_PAYLOAD`)
 #[derive(thiserror::Error, Debug, Clone, Encode, Decode)]
 pub enum PrepareError {
 	/// During the prevalidation stage of preparation an issue was found with the PVF.
 	#[codec(index = 0)]
 	#[error("prepare: prevalidation error: {0}")]
-	Prevalidation(String),
-	/// Compilation failed for the given PVF.
+	Prevalidation(PrevalidationError),
+	/// Legacy catch-all for preparation failures; new code reports [`Self::Translation`] or
+	/// [`Self::Compilation`].
 	#[codec(index = 1)]
 	#[error("prepare: preparation error: {0}")]
 	Preparation(String),
@@ -88,8 +97,8 @@ pub enum PrepareError {
 	ClearWorkerDir(String),
 	/// The preparation job process died, due to OOM, a seccomp violation, or some other factor.
 	#[codec(index = 10)]
-	#[error("prepare: prepare job with pid {job_pid} died: {err}")]
-	JobDied { err: String, job_pid: i32 },
+	#[error("prepare: prepare job with pid {job_pid}{} died: {err}", for_code(.code_hash))]
+	JobDied { err: String, job_pid: i32, code_hash: Option<ValidationCodeHash> },
 	/// Some error occurred when interfacing with the kernel.
 	#[codec(index = 11)]
 	#[error("prepare: error interfacing with the kernel: {0}")]
@@ -98,9 +107,255 @@ pub enum PrepareError {
 	#[codec(index = 12)]
 	#[error("prepare: could not decompress code blob: {0}")]
 	CouldNotDecompressCodeBlob(String),
+	/// The PVF could not be transformed into the form expected by the compiler.
+	#[codec(index = 13)]
+	#[error("prepare: translation error: {0}")]
+	Translation(String),
+	/// Wasmtime failed to compile the given PVF.
+	#[codec(index = 14)]
+	#[error("prepare: compilation error: {0}")]
+	Compilation(String),
+	/// The preparation job process was killed with `SIGKILL`, most likely by the OOM killer.
+	#[codec(index = 15)]
+	#[error(
+		"prepare: prepare job with pid {job_pid}{} killed by signal {signal}, likely OOM",
+		for_code(.code_hash)
+	)]
+	JobKilled { signal: i32, job_pid: i32, code_hash: Option<ValidationCodeHash> },
+	/// The preparation job process was killed with `SIGSEGV`.
+	#[codec(index = 16)]
+	#[error(
+		"prepare: prepare job with pid {job_pid}{} segfaulted (signal {signal})",
+		for_code(.code_hash)
+	)]
+	JobSegfault { signal: i32, job_pid: i32, code_hash: Option<ValidationCodeHash> },
+	/// The preparation job process was killed with `SIGABRT`, e.g. due to a failed allocation.
+	#[codec(index = 17)]
+	#[error(
+		"prepare: prepare job with pid {job_pid}{} aborted (signal {signal})",
+		for_code(.code_hash)
+	)]
+	JobAborted { signal: i32, job_pid: i32, code_hash: Option<ValidationCodeHash> },
+	/// The preparation job process was killed with `SIGSYS`, most likely for making a syscall
+	/// that is not allowed by seccomp.
+	#[codec(index = 18)]
+	#[error(
+		"prepare: prepare job with pid {job_pid}{} made a forbidden syscall (signal {signal})",
+		for_code(.code_hash)
+	)]
+	SeccompViolation { signal: i32, job_pid: i32, code_hash: Option<ValidationCodeHash> },
+	/// The preparation job was denied filesystem access by landlock.
+	#[codec(index = 19)]
+	#[error("prepare: prepare job was denied filesystem access: {0}")]
+	LandlockViolation(String),
+	/// The host and the prepare worker speak different versions of the protocol.
+	#[codec(index = 20)]
+	#[error("prepare: host speaks protocol version {host} but the worker speaks {worker}")]
+	ProtocolMismatch { host: u32, worker: u32 },
+	/// The prepare job process could not be forked, e.g. because of process or memory limits.
+	#[codec(index = 21)]
+	#[error(
+		"prepare: could not fork the prepare job: {}",
+		std::io::Error::from_raw_os_error(*errno)
+	)]
+	ForkFailed { errno: i32 },
+	/// The request from the host was larger than any valid request can be. It was rejected before
+	/// being read.
+	#[codec(index = 22)]
+	#[error("prepare: request of {len} bytes exceeds the maximum of {max} bytes")]
+	MessageTooLarge { len: u64, max: u64 },
+	/// The decompressed Wasm code is larger than the maximum accepted by prevalidation.
+	#[codec(index = 23)]
+	#[error(
+		"prepare: prevalidation: wasm code of {size} bytes exceeds the maximum of {max} bytes"
+	)]
+	PrevalidationWasmTooLarge { size: u64, max: u64 },
+	/// The preparation job failed with the inner error, and reports the memory stats it observed
+	/// until it failed. Only sent by the worker: the host records the stats and then carries on
+	/// with the inner error.
+	#[codec(index = 24)]
+	#[error("{inner}")]
+	PreparationWithStats { stats: MemoryStats, inner: Box<PrepareError> },
+	/// The worker could not write to its worker dir, e.g. because it is read-only or full. Checked
+	/// by the worker on startup.
+	#[codec(index = 25)]
+	#[error("prepare: worker dir {path} is not writable: {err}")]
+	WorkerDirUnwritable { path: String, err: String },
+	/// Compiling the code twice produced different artifacts. Only checked by
+	/// [`PrepareJobKind::VerifyDeterminism`](crate::prepare::PrepareJobKind) jobs.
+	#[codec(index = 26)]
+	#[error(
+		"prepare: compiling the code twice produced artifacts of {len} and {other_len} bytes, differing from byte {offset}"
+	)]
+	NonDeterministicCompilation { len: u64, other_len: u64, offset: u64 },
+	/// The worker was told to only prepare in a sandbox, but the named security features are
+	/// missing. No job was started.
+	#[codec(index = 27)]
+	#[error("prepare: refusing to prepare without {0}")]
+	InsecureEnvironment(String),
+	/// The code was sent compressed, and decompresses to more than the given limit.
+	#[codec(index = 28)]
+	#[error("prepare: code decompresses to more than {limit} bytes")]
+	DecompressionLimit { limit: u64 },
+	/// The worker was already running as many jobs as it can run at once. No job was started.
+	#[codec(index = 29)]
+	#[error("prepare: worker is overloaded, already running {max_jobs} jobs")]
+	Overloaded { max_jobs: u32 },
+	/// The job process died while writing its response to the worker, which only received the
+	/// given number of bytes of it.
+	#[codec(index = 30)]
+	#[error(
+		"prepare: job died while writing its response, received {received} of {expected} bytes"
+	)]
+	ChildDiedDuringWrite { expected: u64, received: u64 },
+	/// A benchmarking job timed out in the given stage, after using the given CPU time. Other jobs
+	/// time out with [`Self::TimedOut`].
+	#[codec(index = 31)]
+	#[error("prepare: timed out at stage {stage:?} after {cpu_used:?} of CPU time")]
+	TimedOutAtStage { stage: PrepareStage, cpu_used: Duration },
+	/// The artifact was produced by a different compiler version than the one running, see
+	/// [`crate::artifact::COMPILER_VERSION`].
+	#[codec(index = 32)]
+	#[error("prepare: artifact compiler version {found:?} does not match {expected:?}")]
+	CompilerVersionMismatch { found: String, expected: String },
+	/// The job was killed by a signal with the inner error, and dumped its core to the given path,
+	/// relative to the worker dir. Only reported for jobs requested with
+	/// [`PvfPrepData::with_core_dumps`](crate::pvf::PvfPrepData::with_core_dumps).
+	#[codec(index = 33)]
+	#[error("{inner} (core dumped to {core_path} in the worker dir)")]
+	CoreDumped { core_path: String, inner: Box<PrepareError> },
+	/// The artifact was compiled from other code than the PVF it was checked against.
+	#[codec(index = 34)]
+	#[error("prepare: artifact was compiled from code {found:?}, not {expected:?}")]
+	CodeHashMismatch { found: ValidationCodeHash, expected: ValidationCodeHash },
+	/// The host cancelled the job, see
+	/// [`PrepareWorkerRequest::Cancel`](crate::prepare::PrepareWorkerRequest::Cancel).
+	#[codec(index = 35)]
+	#[error("prepare: cancelled by the host")]
+	Cancelled,
+	/// The linear memory of the code declares more pages than allowed by
+	/// [`ExecutorParam::MaxDeclaredMemoryPages`](polkadot_primitives::ExecutorParam::MaxDeclaredMemoryPages).
+	#[codec(index = 36)]
+	#[error(
+		"prepare: prevalidation: memory declares {declared} pages, more than the limit of {limit}"
+	)]
+	MemoryPagesExceeded { declared: u64, limit: u32 },
+	/// The compiled artifact is larger than allowed by
+	/// [`ExecutorParam::MaxArtifactSize`](polkadot_primitives::ExecutorParam::MaxArtifactSize).
+	#[codec(index = 37)]
+	#[error("prepare: compiled artifact of {size} bytes exceeds the limit of {limit} bytes")]
+	ArtifactTooLarge { size: u64, limit: u64 },
+	/// The worker was built from another version than the node, e.g. because the node was upgraded
+	/// in place without being restarted. Checked by the worker on startup, after which it tears
+	/// down the node.
+	#[codec(index = 38)]
+	#[error(
+		"prepare: node version {node} does not match worker version {worker}, the node needs restarting"
+	)]
+	VersionMismatch { node: String, worker: String },
+}
+
+/// The maximum length in bytes of the messages that prepare errors take over from the compiler and
+/// the other libraries used for preparation, see [`truncate_message`]. A pathological PVF could
+/// otherwise cause an error message of megabytes, bloating the logs and the response to the host.
+pub const MAX_PREPARE_ERROR_MESSAGE_LEN: usize = 4 * 1024;
+
+/// Truncates `message` to at most `max_len` bytes, ending it with an ellipsis if anything was cut.
+pub fn truncate_message(mut message: String, max_len: usize) -> String {
+	const ELLIPSIS: &str = "...";
+	if message.len() <= max_len {
+		return message
+	}
+	// There is no room for the ellipsis in tiny limits.
+	let ellipsis = if max_len >= ELLIPSIS.len() { ELLIPSIS } else { "" };
+	let mut end = max_len - ellipsis.len();
+	while !message.is_char_boundary(end) {
+		end -= 1;
+	}
+	message.truncate(end);
+	message.push_str(ellipsis);
+	message
+}
+
+/// The reason why prevalidation rejected the code of a PVF.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum PrevalidationError {
+	/// The code does not start with the Wasm magic number.
+	#[codec(index = 0)]
+	#[error("not a Wasm module: bad magic number")]
+	BadMagic,
+	/// The code is a Wasm binary of another version than 1, e.g. a component.
+	#[codec(index = 1)]
+	#[error("unsupported Wasm version {0:#x}")]
+	UnsupportedVersion(u32),
+	/// The code contains a section that is not allowed in a PVF.
+	#[codec(index = 2)]
+	#[error("disallowed section: {0}")]
+	DisallowedSection(String),
+	/// The code exceeds one of the limits of the parser, e.g. on the number of exports.
+	#[codec(index = 3)]
+	#[error("size limit exceeded: {0}")]
+	SizeLimit(String),
+	/// The code is invalid for any other reason, e.g. it is malformed or uses a disabled feature.
+	#[codec(index = 4)]
+	#[error("{0}")]
+	Invalid(String),
+}
+
+impl PrevalidationError {
+	/// Returns the label of the cause of the rejection, for metrics.
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Self::BadMagic => "prevalidation_bad_magic",
+			Self::UnsupportedVersion(_) => "prevalidation_unsupported_version",
+			Self::DisallowedSection(_) => "prevalidation_disallowed_section",
+			Self::SizeLimit(_) => "prevalidation_size_limit",
+			Self::Invalid(_) => "prevalidation",
+		}
+	}
+
+	/// Truncates the message taken over from the parser, if any, see [`truncate_message`].
+	fn with_truncated_message(self, max_len: usize) -> Self {
+		match self {
+			Self::SizeLimit(message) => Self::SizeLimit(truncate_message(message, max_len)),
+			Self::Invalid(message) => Self::Invalid(truncate_message(message, max_len)),
+			err => err,
+		}
+	}
+}
+
+/// Formats the hash of the code a job was preparing, if known, for the error messages of the job
+/// errors. The hash lets a failure be correlated with a runtime across nodes.
+fn for_code(code_hash: &Option<ValidationCodeHash>) -> String {
+	code_hash
+		.map(|code_hash| format!(" (code hash {:?})", code_hash))
+		.unwrap_or_default()
 }
 
 impl PrepareError {
+	/// Truncates the message of the errors that take it over from a library to `max_len` bytes,
+	/// see [`truncate_message`]. Other errors are returned as they are.
+	pub fn with_truncated_message(self, max_len: usize) -> Self {
+		use PrepareError::*;
+		let truncate = |message| truncate_message(message, max_len);
+		match self {
+			Prevalidation(err) => Prevalidation(err.with_truncated_message(max_len)),
+			Preparation(message) => Preparation(truncate(message)),
+			RuntimeConstruction(message) => RuntimeConstruction(truncate(message)),
+			Translation(message) => Translation(truncate(message)),
+			Compilation(message) => Compilation(truncate(message)),
+			JobError(message) => JobError(truncate(message)),
+			CouldNotDecompressCodeBlob(message) => CouldNotDecompressCodeBlob(truncate(message)),
+			PreparationWithStats { stats, inner } => PreparationWithStats {
+				stats,
+				inner: Box::new(inner.with_truncated_message(max_len)),
+			},
+			CoreDumped { core_path, inner } =>
+				CoreDumped { core_path, inner: Box::new(inner.with_truncated_message(max_len)) },
+			err => err,
+		}
+	}
+
 	/// Returns whether this is a deterministic error, i.e. one that should trigger reliably. Those
 	/// errors depend on the PVF itself and the sc-executor/wasmtime logic.
 	///
@@ -112,19 +367,109 @@ impl PrepareError {
 		match self {
 			Prevalidation(_) |
 			Preparation(_) |
+			Translation(_) |
+			Compilation(_) |
 			JobError(_) |
 			OutOfMemory |
-			CouldNotDecompressCodeBlob(_) => true,
+			CouldNotDecompressCodeBlob(_) |
+			DecompressionLimit { .. } |
+			PrevalidationWasmTooLarge { .. } |
+			MemoryPagesExceeded { .. } |
+			ArtifactTooLarge { .. } => true,
 			IoErr(_) |
 			JobDied { .. } |
+			JobKilled { .. } |
+			JobSegfault { .. } |
+			JobAborted { .. } |
+			// A job may hit a syscall missing from the allowlist on some machines only.
+			SeccompViolation { .. } |
+			LandlockViolation(_) |
+			ProtocolMismatch { .. } |
+			VersionMismatch { .. } |
+			ForkFailed { .. } |
+			MessageTooLarge { .. } |
+			WorkerDirUnwritable { .. } |
+			// Points at the compiler or the machine rather than the PVF.
+			NonDeterministicCompilation { .. } |
+			InsecureEnvironment(_) |
+			Overloaded { .. } |
+			Cancelled |
+			ChildDiedDuringWrite { .. } |
+			// Points at a stale build rather than the PVF.
+			CompilerVersionMismatch { .. } |
+			// Points at a misplaced artifact rather than the PVF.
+			CodeHashMismatch { .. } |
 			CreateTmpFile(_) |
 			RenameTmpFile { .. } |
 			ClearWorkerDir(_) |
 			Kernel(_) => false,
 			// Can occur due to issues with the PVF, but also due to factors like local load.
-			TimedOut => false,
+			TimedOut | TimedOutAtStage { .. } => false,
 			// Can occur due to issues with the PVF, but also due to local errors.
 			RuntimeConstruction(_) => false,
+			PreparationWithStats { inner, .. } | CoreDumped { inner, .. } => inner.is_deterministic(),
+		}
+	}
+
+	/// Returns a stable, machine-readable label of the kind of the error, e.g. for metrics. Errors
+	/// wrapping another one are labelled like the inner error.
+	pub fn kind_str(&self) -> &'static str {
+		use PrepareError::*;
+		match self {
+			Prevalidation(err) => err.kind(),
+			Preparation(_) => "preparation",
+			RuntimeConstruction(_) => "runtime_construction",
+			JobError(_) => "job_error",
+			TimedOut => "timed_out",
+			IoErr(_) => "io_err",
+			CreateTmpFile(_) => "create_tmp_file",
+			RenameTmpFile { .. } => "rename_tmp_file",
+			OutOfMemory => "out_of_memory",
+			ClearWorkerDir(_) => "clear_worker_dir",
+			JobDied { .. } => "job_died",
+			Kernel(_) => "kernel",
+			CouldNotDecompressCodeBlob(_) => "could_not_decompress_code_blob",
+			Translation(_) => "translation",
+			Compilation(_) => "compilation",
+			JobKilled { .. } => "job_killed",
+			JobSegfault { .. } => "job_segfault",
+			JobAborted { .. } => "job_aborted",
+			SeccompViolation { .. } => "seccomp_violation",
+			LandlockViolation(_) => "landlock_violation",
+			ProtocolMismatch { .. } => "protocol_mismatch",
+			ForkFailed { .. } => "fork_failed",
+			MessageTooLarge { .. } => "message_too_large",
+			PrevalidationWasmTooLarge { .. } => "prevalidation_wasm_too_large",
+			PreparationWithStats { inner, .. } | CoreDumped { inner, .. } => inner.kind_str(),
+			WorkerDirUnwritable { .. } => "worker_dir_unwritable",
+			NonDeterministicCompilation { .. } => "non_deterministic_compilation",
+			InsecureEnvironment(_) => "insecure_environment",
+			DecompressionLimit { .. } => "decompression_limit",
+			Overloaded { .. } => "overloaded",
+			ChildDiedDuringWrite { .. } => "child_died_during_write",
+			TimedOutAtStage { .. } => "timed_out_at_stage",
+			CompilerVersionMismatch { .. } => "compiler_version_mismatch",
+			CodeHashMismatch { .. } => "code_hash_mismatch",
+			Cancelled => "cancelled",
+			MemoryPagesExceeded { .. } => "memory_pages_exceeded",
+			ArtifactTooLarge { .. } => "artifact_too_large",
+			VersionMismatch { .. } => "version_mismatch",
+		}
+	}
+}
+
+impl From<PrepareStageError> for PrepareError {
+	fn from(err: PrepareStageError) -> Self {
+		// The inner errors of the later stages are formatted the same way as they were before the
+		// stages were told apart, so that anything parsing them keeps working.
+		match err {
+			PrepareStageError::Validation(err) => Self::Prevalidation(err),
+			PrepareStageError::Translation(err) => Self::Translation(format!("{:?}", err)),
+			PrepareStageError::Compilation(err) => Self::Compilation(format!("{:?}", err)),
+			PrepareStageError::MemoryPagesExceeded { declared, limit } =>
+				Self::MemoryPagesExceeded { declared, limit },
+			PrepareStageError::NonDeterministicParams(reason) =>
+				Self::Compilation(format!("non-deterministic executor params: {}", reason)),
 		}
 	}
 }
@@ -162,3 +507,383 @@ pub enum InternalValidationError {
 	#[error("validation: prepare: {0}")]
 	NonDeterministicPrepareError(PrepareError),
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use assert_matches::assert_matches;
+	use sc_executor_common::error::WasmError;
+
+	#[test]
+	fn prepare_stage_errors_map_to_prepare_errors() {
+		assert_matches!(
+			PrepareError::from(PrepareStageError::Validation(PrevalidationError::BadMagic)),
+			PrepareError::Prevalidation(PrevalidationError::BadMagic)
+		);
+		assert_matches!(
+			PrepareError::from(PrepareStageError::Translation(WasmError::Other("metering".into()))),
+			PrepareError::Translation(err) if err == "Other(\"metering\")"
+		);
+		assert_matches!(
+			PrepareError::from(PrepareStageError::Compilation(WasmError::Other("engine".into()))),
+			PrepareError::Compilation(err) if err == "Other(\"engine\")"
+		);
+		assert_matches!(
+			PrepareError::from(PrepareStageError::MemoryPagesExceeded { declared: 2, limit: 1 }),
+			PrepareError::MemoryPagesExceeded { declared: 2, limit: 1 }
+		);
+		assert_matches!(
+			PrepareError::from(PrepareStageError::NonDeterministicParams("reason")),
+			PrepareError::Compilation(err) if err == "non-deterministic executor params: reason"
+		);
+	}
+
+	#[test]
+	fn prepare_stage_errors_are_deterministic() {
+		for err in [
+			PrepareStageError::Validation(PrevalidationError::Invalid("InvalidModule".into())),
+			PrepareStageError::Translation(WasmError::InvalidModule),
+			PrepareStageError::Compilation(WasmError::InvalidModule),
+			PrepareStageError::NonDeterministicParams("reason"),
+		] {
+			assert!(PrepareError::from(err).is_deterministic());
+		}
+	}
+
+	#[test]
+	fn errors_caused_by_the_pvf_are_deterministic() {
+		for err in [
+			PrepareError::Prevalidation(PrevalidationError::Invalid(String::new())),
+			PrepareError::Preparation(String::new()),
+			PrepareError::Translation(String::new()),
+			PrepareError::Compilation(String::new()),
+			PrepareError::JobError(String::new()),
+			// The memory limit comes from the executor params, so the same PVF exceeds it on every
+			// machine.
+			PrepareError::OutOfMemory,
+			PrepareError::CouldNotDecompressCodeBlob(String::new()),
+			PrepareError::DecompressionLimit { limit: 1 },
+			PrepareError::PrevalidationWasmTooLarge { size: 2, max: 1 },
+			// The limit comes from the executor params as well.
+			PrepareError::MemoryPagesExceeded { declared: 2, limit: 1 },
+			PrepareError::ArtifactTooLarge { size: 2, limit: 1 },
+		] {
+			assert!(err.is_deterministic(), "{:?} should be deterministic", err);
+		}
+	}
+
+	#[test]
+	fn local_and_transient_errors_are_not_deterministic() {
+		for err in [
+			PrepareError::RuntimeConstruction(String::new()),
+			PrepareError::TimedOut,
+			PrepareError::IoErr(String::new()),
+			PrepareError::CreateTmpFile(String::new()),
+			PrepareError::RenameTmpFile { err: String::new(), src: None, dest: None },
+			PrepareError::ClearWorkerDir(String::new()),
+			PrepareError::JobDied { err: String::new(), job_pid: 1, code_hash: None },
+			PrepareError::Kernel(String::new()),
+			PrepareError::JobKilled { signal: 9, job_pid: 1, code_hash: None },
+			PrepareError::JobSegfault { signal: 11, job_pid: 1, code_hash: None },
+			PrepareError::JobAborted { signal: 6, job_pid: 1, code_hash: None },
+			PrepareError::SeccompViolation { signal: 31, job_pid: 1, code_hash: None },
+			PrepareError::LandlockViolation(String::new()),
+			PrepareError::ProtocolMismatch { host: 1, worker: 2 },
+			PrepareError::VersionMismatch { node: String::new(), worker: String::new() },
+			PrepareError::ForkFailed { errno: 11 },
+			PrepareError::MessageTooLarge { len: 2, max: 1 },
+			PrepareError::WorkerDirUnwritable { path: String::new(), err: String::new() },
+			PrepareError::NonDeterministicCompilation { len: 2, other_len: 2, offset: 1 },
+			PrepareError::InsecureEnvironment(String::new()),
+			PrepareError::Overloaded { max_jobs: 1 },
+			PrepareError::Cancelled,
+			PrepareError::ChildDiedDuringWrite { expected: 2, received: 1 },
+			PrepareError::TimedOutAtStage {
+				stage: PrepareStage::Preparation,
+				cpu_used: Duration::from_secs(1),
+			},
+			PrepareError::CompilerVersionMismatch { found: String::new(), expected: String::new() },
+			PrepareError::CodeHashMismatch {
+				found: ValidationCodeHash::from([1; 32]),
+				expected: ValidationCodeHash::from([2; 32]),
+			},
+		] {
+			assert!(!err.is_deterministic(), "{:?} should not be deterministic", err);
+		}
+	}
+
+	#[test]
+	fn job_errors_name_the_code_hash() {
+		let code_hash = ValidationCodeHash::from([1; 32]);
+
+		let err = PrepareError::JobKilled { signal: 9, job_pid: 1, code_hash: Some(code_hash) };
+		assert!(err.to_string().contains(&format!("{:?}", code_hash)));
+
+		let err = PrepareError::JobKilled { signal: 9, job_pid: 1, code_hash: None };
+		assert_eq!(
+			err.to_string(),
+			"prepare: prepare job with pid 1 killed by signal 9, likely OOM"
+		);
+	}
+
+	#[test]
+	fn errors_with_stats_behave_like_the_inner_error() {
+		let with_stats = |inner| PrepareError::PreparationWithStats {
+			stats: MemoryStats::default(),
+			inner: Box::new(inner),
+		};
+
+		assert!(with_stats(PrepareError::Compilation(String::new())).is_deterministic());
+		assert!(!with_stats(PrepareError::RuntimeConstruction(String::new())).is_deterministic());
+		assert_eq!(
+			with_stats(PrepareError::Compilation("engine".into())).to_string(),
+			PrepareError::Compilation("engine".into()).to_string()
+		);
+	}
+
+	#[test]
+	fn core_dumps_behave_like_the_inner_error() {
+		let err = PrepareError::CoreDumped {
+			core_path: "core.1".into(),
+			inner: Box::new(PrepareError::JobSegfault { signal: 11, job_pid: 1, code_hash: None }),
+		};
+
+		assert!(!err.is_deterministic());
+		assert_eq!(
+			err.to_string(),
+			"prepare: prepare job with pid 1 segfaulted (signal 11) (core dumped to core.1 in the worker dir)"
+		);
+	}
+
+	#[test]
+	fn every_error_has_a_stable_message_and_kind() {
+		let found = ValidationCodeHash::from([1; 32]);
+		let expected = ValidationCodeHash::from([2; 32]);
+		let cases = [
+			(
+				PrepareError::Prevalidation(PrevalidationError::BadMagic),
+				"prepare: prevalidation error: not a Wasm module: bad magic number".to_string(),
+				"prevalidation_bad_magic",
+			),
+			(
+				PrepareError::Preparation("x".into()),
+				"prepare: preparation error: x".into(),
+				"preparation",
+			),
+			(
+				PrepareError::RuntimeConstruction("x".into()),
+				"prepare: runtime construction: x".into(),
+				"runtime_construction",
+			),
+			(PrepareError::JobError("x".into()), "prepare: job error: x".into(), "job_error"),
+			(PrepareError::TimedOut, "prepare: timeout".into(), "timed_out"),
+			(
+				PrepareError::IoErr("x".into()),
+				"prepare: io error while receiving response: x".into(),
+				"io_err",
+			),
+			(
+				PrepareError::CreateTmpFile("x".into()),
+				"prepare: error creating tmp file: x".into(),
+				"create_tmp_file",
+			),
+			(
+				PrepareError::RenameTmpFile { err: "x".into(), src: Some("a".into()), dest: None },
+				"prepare: error renaming tmp file (Some(\"a\") -> None): x".into(),
+				"rename_tmp_file",
+			),
+			(PrepareError::OutOfMemory, "prepare: out of memory".into(), "out_of_memory"),
+			(
+				PrepareError::ClearWorkerDir("x".into()),
+				"prepare: error clearing worker cache: x".into(),
+				"clear_worker_dir",
+			),
+			(
+				PrepareError::JobDied { err: "x".into(), job_pid: 1, code_hash: None },
+				"prepare: prepare job with pid 1 died: x".into(),
+				"job_died",
+			),
+			(
+				PrepareError::Kernel("x".into()),
+				"prepare: error interfacing with the kernel: x".into(),
+				"kernel",
+			),
+			(
+				PrepareError::CouldNotDecompressCodeBlob("x".into()),
+				"prepare: could not decompress code blob: x".into(),
+				"could_not_decompress_code_blob",
+			),
+			(
+				PrepareError::Translation("x".into()),
+				"prepare: translation error: x".into(),
+				"translation",
+			),
+			(
+				PrepareError::Compilation("x".into()),
+				"prepare: compilation error: x".into(),
+				"compilation",
+			),
+			(
+				PrepareError::JobKilled { signal: 9, job_pid: 1, code_hash: None },
+				"prepare: prepare job with pid 1 killed by signal 9, likely OOM".into(),
+				"job_killed",
+			),
+			(
+				PrepareError::JobSegfault { signal: 11, job_pid: 1, code_hash: None },
+				"prepare: prepare job with pid 1 segfaulted (signal 11)".into(),
+				"job_segfault",
+			),
+			(
+				PrepareError::JobAborted { signal: 6, job_pid: 1, code_hash: None },
+				"prepare: prepare job with pid 1 aborted (signal 6)".into(),
+				"job_aborted",
+			),
+			(
+				PrepareError::SeccompViolation { signal: 31, job_pid: 1, code_hash: None },
+				"prepare: prepare job with pid 1 made a forbidden syscall (signal 31)".into(),
+				"seccomp_violation",
+			),
+			(
+				PrepareError::LandlockViolation("x".into()),
+				"prepare: prepare job was denied filesystem access: x".into(),
+				"landlock_violation",
+			),
+			(
+				PrepareError::ProtocolMismatch { host: 1, worker: 2 },
+				"prepare: host speaks protocol version 1 but the worker speaks 2".into(),
+				"protocol_mismatch",
+			),
+			(
+				PrepareError::ForkFailed { errno: 11 },
+				format!(
+					"prepare: could not fork the prepare job: {}",
+					std::io::Error::from_raw_os_error(11)
+				),
+				"fork_failed",
+			),
+			(
+				PrepareError::MessageTooLarge { len: 2, max: 1 },
+				"prepare: request of 2 bytes exceeds the maximum of 1 bytes".into(),
+				"message_too_large",
+			),
+			(
+				PrepareError::PrevalidationWasmTooLarge { size: 2, max: 1 },
+				"prepare: prevalidation: wasm code of 2 bytes exceeds the maximum of 1 bytes".into(),
+				"prevalidation_wasm_too_large",
+			),
+			(
+				PrepareError::PreparationWithStats {
+					stats: MemoryStats::default(),
+					inner: Box::new(PrepareError::TimedOut),
+				},
+				"prepare: timeout".into(),
+				"timed_out",
+			),
+			(
+				PrepareError::WorkerDirUnwritable { path: "/worker".into(), err: "x".into() },
+				"prepare: worker dir /worker is not writable: x".into(),
+				"worker_dir_unwritable",
+			),
+			(
+				PrepareError::NonDeterministicCompilation { len: 2, other_len: 3, offset: 1 },
+				"prepare: compiling the code twice produced artifacts of 2 and 3 bytes, differing from byte 1".into(),
+				"non_deterministic_compilation",
+			),
+			(
+				PrepareError::InsecureEnvironment("landlock".into()),
+				"prepare: refusing to prepare without landlock".into(),
+				"insecure_environment",
+			),
+			(
+				PrepareError::DecompressionLimit { limit: 1 },
+				"prepare: code decompresses to more than 1 bytes".into(),
+				"decompression_limit",
+			),
+			(
+				PrepareError::Overloaded { max_jobs: 1 },
+				"prepare: worker is overloaded, already running 1 jobs".into(),
+				"overloaded",
+			),
+			(
+				PrepareError::ChildDiedDuringWrite { expected: 2, received: 1 },
+				"prepare: job died while writing its response, received 1 of 2 bytes".into(),
+				"child_died_during_write",
+			),
+			(
+				PrepareError::TimedOutAtStage {
+					stage: PrepareStage::Preparation,
+					cpu_used: Duration::from_secs(1),
+				},
+				"prepare: timed out at stage Preparation after 1s of CPU time".into(),
+				"timed_out_at_stage",
+			),
+			(
+				PrepareError::CompilerVersionMismatch { found: "a".into(), expected: "b".into() },
+				"prepare: artifact compiler version \"a\" does not match \"b\"".into(),
+				"compiler_version_mismatch",
+			),
+			(
+				PrepareError::CoreDumped {
+					core_path: "core.1".into(),
+					inner: Box::new(PrepareError::TimedOut),
+				},
+				"prepare: timeout (core dumped to core.1 in the worker dir)".into(),
+				"timed_out",
+			),
+			(
+				PrepareError::CodeHashMismatch { found, expected },
+				format!(
+					"prepare: artifact was compiled from code {:?}, not {:?}",
+					found, expected
+				),
+				"code_hash_mismatch",
+			),
+			(PrepareError::Cancelled, "prepare: cancelled by the host".into(), "cancelled"),
+			(
+				PrepareError::MemoryPagesExceeded { declared: 2, limit: 1 },
+				"prepare: prevalidation: memory declares 2 pages, more than the limit of 1".into(),
+				"memory_pages_exceeded",
+			),
+			(
+				PrepareError::ArtifactTooLarge { size: 2, limit: 1 },
+				"prepare: compiled artifact of 2 bytes exceeds the limit of 1 bytes".into(),
+				"artifact_too_large",
+			),
+			(
+				PrepareError::VersionMismatch { node: "1.0.0".into(), worker: "1.0.1".into() },
+				"prepare: node version 1.0.0 does not match worker version 1.0.1, the node needs restarting".into(),
+				"version_mismatch",
+			),
+		];
+
+		for (err, message, kind) in cases {
+			assert_eq!(err.to_string(), message);
+			assert_eq!(err.kind_str(), kind, "{:?}", err);
+		}
+	}
+
+	#[test]
+	fn long_messages_are_truncated() {
+		assert_eq!(truncate_message("short".into(), 5), "short");
+		assert_eq!(truncate_message("a bit longer".into(), 8), "a bit...");
+		// The cut never splits a character.
+		assert_eq!(truncate_message("ääää".into(), 6), "ä...");
+		assert_eq!(truncate_message("long".into(), 2), "lo");
+	}
+
+	#[test]
+	fn truncation_keeps_the_variant() {
+		let err = PrepareError::PreparationWithStats {
+			stats: Default::default(),
+			inner: Box::new(PrepareError::Compilation("x".repeat(100))),
+		}
+		.with_truncated_message(10);
+
+		assert_matches!(
+			err,
+			PrepareError::PreparationWithStats { inner, .. }
+				if matches!(*inner, PrepareError::Compilation(ref message) if message == "xxxxxxx...")
+		);
+		assert_matches!(PrepareError::TimedOut.with_truncated_message(10), PrepareError::TimedOut);
+	}
+}
@@ -16,20 +16,46 @@
 
 //! Interface to the Substrate Executor
 
-use crate::error::ExecuteError;
+use crate::{
+	artifact::ArtifactFlags,
+	error::{ExecuteError, PrevalidationError},
+	prepare::ModuleShape,
+};
+use cpu_time::{ProcessTime, ThreadTime};
 use polkadot_primitives::{
 	executor_params::{DEFAULT_LOGICAL_STACK_MAX, DEFAULT_NATIVE_STACK_MAX},
-	ExecutorParam, ExecutorParams,
+	ExecutorParam, ExecutorParams, PvfOptimizationLevel, RelaxedSimdPolicy,
 };
 use sc_executor_common::{
 	error::WasmError,
-	runtime_blob::RuntimeBlob,
+	runtime_blob,
 	wasm_runtime::{HeapAllocStrategy, WasmModule as _},
 };
-use sc_executor_wasmtime::{Config, DeterministicStackLimit, Semantics, WasmtimeRuntime};
+use sc_executor_wasmtime::{
+	Config, DeterministicStackLimit, OptimizationLevel, Semantics, WasmtimeRuntime,
+};
 use sp_core::storage::{ChildInfo, TrackedStorageKey};
 use sp_externalities::MultiRemovalResults;
-use std::any::{Any, TypeId};
+use sp_tracing::{
+	tracing::{
+		field::{Field, Visit},
+		Dispatch, Event, Level, Subscriber,
+	},
+	tracing_subscriber::{
+		layer::{Context, SubscriberExt},
+		Layer,
+	},
+};
+use std::{
+	any::{Any, TypeId},
+	fmt,
+	sync::{Arc, Mutex, PoisonError},
+	time::{Duration, Instant},
+};
+use wasmparser::{
+	BinaryReaderError, ExternalKind, FuncValidatorAllocations, MemoryType, Parser, Payload,
+	TypeRef, ValidPayload, Validator, WasmFeatures,
+};
 
 // Memory configuration
 //
@@ -91,8 +117,12 @@ pub const DEFAULT_CONFIG: Config = Config {
 		// extensions (e.g., sign extension ops) are enabled by Wasmtime and cannot be disabled.
 		wasm_reference_types: false,
 		wasm_simd: false,
+		wasm_relaxed_simd: false,
+		signals_based_traps: true,
 		wasm_bulk_memory: false,
 		wasm_multi_value: false,
+		optimization_level: OptimizationLevel::SpeedAndSize,
+		hugepage_aligned_code: false,
 	},
 };
 
@@ -167,8 +197,27 @@ pub fn params_to_wasmtime_semantics(par: &ExecutorParams) -> (Semantics, Determi
 			ExecutorParam::StackLogicalMax(slm) => stack_limit.logical_max = *slm,
 			ExecutorParam::StackNativeMax(snm) => stack_limit.native_stack_max = *snm,
 			ExecutorParam::WasmExtBulkMemory => sem.wasm_bulk_memory = true,
+			ExecutorParam::OptimizationLevel(level) =>
+				sem.optimization_level = match level {
+					PvfOptimizationLevel::None => OptimizationLevel::None,
+					PvfOptimizationLevel::Speed => OptimizationLevel::Speed,
+					PvfOptimizationLevel::SpeedAndSize => OptimizationLevel::SpeedAndSize,
+				},
+			// Wasmtime compiles in parallel on the thread pool it is run in, see `prepare`.
+			ExecutorParam::CompilationThreads(threads) => sem.parallel_compilation = *threads > 1,
+			ExecutorParam::HugepageAlignedCode => sem.hugepage_aligned_code = true,
+			ExecutorParam::NanCanonicalization(enabled) => sem.canonicalize_nans = *enabled,
+			ExecutorParam::RelaxedSimd(RelaxedSimdPolicy::Reject) => (),
+			ExecutorParam::RelaxedSimd(RelaxedSimdPolicy::Deterministic) => {
+				sem.wasm_simd = true;
+				sem.wasm_relaxed_simd = true;
+			},
+			ExecutorParam::SignalsBasedTraps(enabled) => sem.signals_based_traps = *enabled,
 			ExecutorParam::PrecheckingMaxMemory(_) |
+			ExecutorParam::MaxDeclaredMemoryPages(_) |
+			ExecutorParam::MaxArtifactSize(_) |
 			ExecutorParam::PvfPrepTimeout(_, _) |
+			ExecutorParam::PvfPrepTimeoutScaling { .. } |
 			ExecutorParam::PvfExecTimeout(_, _) => (), /* Not used here */
 		}
 	}
@@ -176,22 +225,531 @@ pub fn params_to_wasmtime_semantics(par: &ExecutorParams) -> (Semantics, Determi
 	(sem, stack_limit)
 }
 
+/// An error that occurred at a particular stage of preparing a PVF.
+#[derive(thiserror::Error, Debug)]
+pub enum PrepareStageError {
+	/// The code failed the basic consistency checks of prevalidation.
+	#[error("validation: {0}")]
+	Validation(PrevalidationError),
+	/// The runtime blob could not be transformed into the form expected by the compiler, e.g.
+	/// because the stack depth metering could not be injected.
+	#[error("translation: {0}")]
+	Translation(WasmError),
+	/// Wasmtime failed to compile the transformed runtime blob.
+	#[error("compilation: {0}")]
+	Compilation(WasmError),
+	/// The linear memory declares more pages than allowed by the executor params, see
+	/// [`ExecutorParam::MaxDeclaredMemoryPages`].
+	#[error("validation: memory declares {declared} pages, more than the limit of {limit}")]
+	MemoryPagesExceeded { declared: u64, limit: u32 },
+	/// The executor params combine into semantics under which the compiled code would not
+	/// execute the same way on every validator, see [`check_determinism`].
+	#[error("compilation: non-deterministic executor params: {0}")]
+	NonDeterministicParams(&'static str),
+}
+
+/// The kind of an item imported or exported by a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemKind {
+	Function,
+	Table,
+	Memory,
+	Global,
+	Tag,
+}
+
+/// An item imported by a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleImport {
+	/// The name of the module the item is imported from, e.g. `env` for host functions.
+	pub module: String,
+	pub name: String,
+	pub kind: ItemKind,
+}
+
+/// An item exported by a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleExport {
+	pub name: String,
+	pub kind: ItemKind,
+}
+
+/// What prevalidation learned about a module, see [`inspect_module`].
+#[derive(Debug, Clone, Default)]
+struct ModuleInfo {
+	shape: ModuleShape,
+	/// The number of pages the linear memory may grow to as declared by the module, see
+	/// [`declared_memory_pages`]. Zero if there is no memory.
+	declared_memory_pages: u64,
+	imports: Vec<ModuleImport>,
+	exports: Vec<ModuleExport>,
+}
+
+/// Prevalidated code, ready to be compiled by [`prepare`]. It can be inspected beforehand, e.g. by
+/// tools that look at the interface of a PVF. For code that is not Wasm, the module has no imports,
+/// exports or memory as far as the accessors are concerned.
+///
+/// Cloning it is cheaper than prevalidating the code again, so code compiled more than once can be
+/// prevalidated just once.
+#[derive(Clone)]
+pub struct RuntimeBlob {
+	blob: runtime_blob::RuntimeBlob,
+	info: ModuleInfo,
+}
+
+impl fmt::Debug for RuntimeBlob {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("RuntimeBlob").field("info", &self.info).finish_non_exhaustive()
+	}
+}
+
+impl RuntimeBlob {
+	/// Returns the items imported by the module, in the order of the import section.
+	pub fn imports(&self) -> &[ModuleImport] {
+		&self.info.imports
+	}
+
+	/// Returns the items exported by the module, in the order of the export section.
+	pub fn exports(&self) -> &[ModuleExport] {
+		&self.info.exports
+	}
+
+	/// Returns the initial size of the linear memory in Wasm pages, whether it is imported or
+	/// defined.
+	pub fn memory_pages(&self) -> u64 {
+		self.info.shape.memory_pages
+	}
+
+	/// Returns the shape of the module.
+	pub fn shape(&self) -> ModuleShape {
+		self.info.shape
+	}
+}
+
 /// Runs the prevalidation on the given code. Returns a [`RuntimeBlob`] if it succeeds.
-pub fn prevalidate(code: &[u8]) -> Result<RuntimeBlob, sc_executor_common::error::WasmError> {
+///
+/// Wasm code is rejected if it uses any feature that is not enabled by the executor params, see
+/// [`check_wasm_features`], or if its linear memory declares more pages than allowed by
+/// [`ExecutorParam::MaxDeclaredMemoryPages`].
+pub fn prevalidate(
+	code: &[u8],
+	executor_params: &ExecutorParams,
+) -> Result<RuntimeBlob, PrepareStageError> {
+	// PolkaVM blobs are not Wasm, `RuntimeBlob` decides whether they are accepted at all.
+	let info = if code.starts_with(POLKAVM_MAGIC) {
+		ModuleInfo::default()
+	} else {
+		let (semantics, _) = params_to_wasmtime_semantics(executor_params);
+		inspect_module(code, &semantics)?
+	};
+	if let Some(limit) = executor_params.max_declared_memory_pages() {
+		if info.declared_memory_pages > u64::from(limit) {
+			return Err(PrepareStageError::MemoryPagesExceeded {
+				declared: info.declared_memory_pages,
+				limit,
+			})
+		}
+	}
 	// Construct the runtime blob and do some basic checks for consistency.
-	let blob = RuntimeBlob::new(code)?;
-	// In the future this function should take care of any further prevalidation logic.
-	Ok(blob)
+	let blob = runtime_blob::RuntimeBlob::new(code).map_err(|err| {
+		PrepareStageError::Validation(PrevalidationError::Invalid(format!("{:?}", err)))
+	})?;
+	Ok(RuntimeBlob { blob, info })
+}
+
+/// Constructs a [`RuntimeBlob`] out of code that already passed [`prevalidate`] with the same
+/// executor params, without checking it again. The code is still parsed, but its features and
+/// memory declaration are not inspected, so the [`ModuleShape`] of the blob is left empty.
+///
+/// Only to be used for code vouched for by a trusted party, as nothing keeps the code from using
+/// features that are not enabled.
+pub fn trust_prevalidated(code: &[u8]) -> Result<RuntimeBlob, PrepareStageError> {
+	let blob = runtime_blob::RuntimeBlob::new(code).map_err(|err| {
+		PrepareStageError::Validation(PrevalidationError::Invalid(format!("{:?}", err)))
+	})?;
+	Ok(RuntimeBlob { blob, info: ModuleInfo::default() })
+}
+
+/// The magic bytes that start a PolkaVM program blob.
+const POLKAVM_MAGIC: &[u8] = b"PVM\0";
+
+/// The magic bytes that start a Wasm binary.
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+/// The only supported version of the Wasm binary format, as it follows the magic bytes.
+const WASM_VERSION: u32 = 1;
+
+/// Checks the preamble of the given Wasm code: the magic bytes and the version. Code too short to
+/// hold the version is left to the parser to reject.
+fn check_wasm_preamble(code: &[u8]) -> Result<(), PrevalidationError> {
+	if !code.starts_with(WASM_MAGIC) {
+		return Err(PrevalidationError::BadMagic)
+	}
+	if let Some(version) = code.get(WASM_MAGIC.len()..WASM_MAGIC.len() + 4) {
+		let version = u32::from_le_bytes(version.try_into().expect("the slice has 4 bytes; qed"));
+		if version != WASM_VERSION {
+			return Err(PrevalidationError::UnsupportedVersion(version))
+		}
+	}
+	Ok(())
+}
+
+/// Returns the name of the given section if it is not allowed in a PVF. Such sections belong to
+/// proposals that are never enabled, or are unknown altogether.
+fn disallowed_section(payload: &Payload) -> Option<String> {
+	match payload {
+		Payload::TagSection(_) => Some("tag".into()),
+		Payload::UnknownSection { id, .. } => Some(format!("unknown section {}", id)),
+		_ => None,
+	}
+}
+
+/// Converts an error of the parser, telling violated limits apart from other issues.
+fn parser_error(err: BinaryReaderError) -> PrevalidationError {
+	let message = err.to_string();
+	// The parser reports its limits, e.g. on the number of exports, as "<items> count exceeds
+	// limit of <max>", but has no error kind for them.
+	if message.contains("exceeds limit") {
+		PrevalidationError::SizeLimit(message)
+	} else {
+		PrevalidationError::Invalid(message)
+	}
+}
+
+/// Validates the given Wasm code against the features enabled by the given semantics, and returns
+/// the shape of the module, which is collected in the same pass.
+///
+/// Wasmtime would reject disabled features as well, but only once compilation is under way, and
+/// the parser of [`RuntimeBlob`] rejects some of them with a generic error. The error returned here
+/// names the offending feature instead, e.g. "SIMD support is not enabled".
+pub fn check_wasm_features(
+	code: &[u8],
+	semantics: &Semantics,
+) -> Result<ModuleShape, PrepareStageError> {
+	inspect_module(code, semantics).map(|info| info.shape)
+}
+
+/// Does the work of [`check_wasm_features`], collecting the imports and exports of the module as
+/// well.
+fn inspect_module(code: &[u8], semantics: &Semantics) -> Result<ModuleInfo, PrepareStageError> {
+	let to_error = |err: BinaryReaderError| PrepareStageError::Validation(parser_error(err));
+
+	check_wasm_preamble(code).map_err(PrepareStageError::Validation)?;
+
+	// This is `Validator::validate_all`, with the shape recorded along the way.
+	let mut validator = Validator::new_with_features(wasm_features(semantics));
+	let mut info = ModuleInfo::default();
+	let mut functions = Vec::new();
+	for payload in Parser::new(0).parse_all(code) {
+		let payload = payload.map_err(to_error)?;
+		if let Some(section) = disallowed_section(&payload) {
+			return Err(PrepareStageError::Validation(PrevalidationError::DisallowedSection(
+				section,
+			)))
+		}
+		if let ValidPayload::Func(func, body) = validator.payload(&payload).map_err(to_error)? {
+			functions.push((func, body));
+		}
+		record_module_shape(&mut info.shape, &payload).map_err(to_error)?;
+		record_module_items(&mut info, &payload).map_err(to_error)?;
+		if let Some(pages) = declared_memory_pages(&payload).map_err(to_error)? {
+			info.declared_memory_pages = pages;
+		}
+	}
+
+	let mut allocations = FuncValidatorAllocations::default();
+	for (func, body) in functions {
+		let mut func_validator = func.into_validator(allocations);
+		func_validator.validate(&body).map_err(to_error)?;
+		allocations = func_validator.into_allocations();
+	}
+	Ok(info)
+}
+
+/// Records what the given payload tells about the shape of the module. The payload must have
+/// passed validation.
+fn record_module_shape(
+	shape: &mut ModuleShape,
+	payload: &Payload,
+) -> Result<(), BinaryReaderError> {
+	match payload {
+		Payload::ImportSection(imports) => {
+			shape.import_count = imports.count();
+			for import in imports.clone() {
+				if let TypeRef::Memory(memory) = import?.ty {
+					shape.memory_pages = memory.initial;
+				}
+			}
+		},
+		Payload::FunctionSection(functions) => shape.function_count = functions.count(),
+		Payload::MemorySection(memories) =>
+			for memory in memories.clone() {
+				shape.memory_pages = memory?.initial;
+			},
+		Payload::CodeSectionStart { size, .. } => shape.code_section_bytes = *size,
+		_ => {},
+	}
+	Ok(())
+}
+
+/// Returns the number of pages the linear memory in the given payload may grow to, i.e. its
+/// declared maximum, or its initial size if it declares no maximum. `None` if the payload holds no
+/// memory. The payload must have passed validation.
+fn declared_memory_pages(payload: &Payload) -> Result<Option<u64>, BinaryReaderError> {
+	let declared = |memory: MemoryType| memory.maximum.unwrap_or(memory.initial);
+	match payload {
+		Payload::ImportSection(imports) =>
+			for import in imports.clone() {
+				if let TypeRef::Memory(memory) = import?.ty {
+					return Ok(Some(declared(memory)))
+				}
+			},
+		Payload::MemorySection(memories) =>
+			if let Some(memory) = memories.clone().into_iter().next() {
+				return Ok(Some(declared(memory?)))
+			},
+		_ => {},
+	}
+	Ok(None)
+}
+
+/// Records the imports and exports in the given payload. The payload must have passed validation.
+fn record_module_items(info: &mut ModuleInfo, payload: &Payload) -> Result<(), BinaryReaderError> {
+	match payload {
+		Payload::ImportSection(imports) =>
+			for import in imports.clone() {
+				let import = import?;
+				let kind = match import.ty {
+					TypeRef::Func(_) => ItemKind::Function,
+					TypeRef::Table(_) => ItemKind::Table,
+					TypeRef::Memory(_) => ItemKind::Memory,
+					TypeRef::Global(_) => ItemKind::Global,
+					TypeRef::Tag(_) => ItemKind::Tag,
+				};
+				info.imports.push(ModuleImport {
+					module: import.module.to_owned(),
+					name: import.name.to_owned(),
+					kind,
+				});
+			},
+		Payload::ExportSection(exports) =>
+			for export in exports.clone() {
+				let export = export?;
+				let kind = match export.kind {
+					ExternalKind::Func => ItemKind::Function,
+					ExternalKind::Table => ItemKind::Table,
+					ExternalKind::Memory => ItemKind::Memory,
+					ExternalKind::Global => ItemKind::Global,
+					ExternalKind::Tag => ItemKind::Tag,
+				};
+				info.exports.push(ModuleExport { name: export.name.to_owned(), kind });
+			},
+		_ => {},
+	}
+	Ok(())
+}
+
+/// Returns the Wasm features enabled by the given semantics. This mirrors the features that
+/// `sc_executor_wasmtime` configures wasmtime with.
+fn wasm_features(semantics: &Semantics) -> WasmFeatures {
+	WasmFeatures {
+		reference_types: semantics.wasm_reference_types,
+		simd: semantics.wasm_simd,
+		// The relaxed SIMD instructions are lowered deterministically, see `sc_executor_wasmtime`.
+		relaxed_simd: semantics.wasm_relaxed_simd,
+		// The bit patterns of the NaNs produced by float operations differ between CPUs, unless
+		// they are canonicalized.
+		floats: semantics.canonicalize_nans,
+		bulk_memory: semantics.wasm_bulk_memory,
+		multi_value: semantics.wasm_multi_value,
+		// Shared memories and atomics make the execution depend on the scheduling of threads.
+		threads: false,
+		multi_memory: false,
+		memory64: false,
+		tail_call: false,
+		exceptions: false,
+		component_model: false,
+		..WasmFeatures::default()
+	}
+}
+
+/// The output of [`prepare`].
+#[derive(Debug)]
+pub struct PreparedArtifact {
+	/// The serialized compiled artifact.
+	pub artifact: Vec<u8>,
+	/// The warnings the compiler emitted, see [`collect_compiler_warnings`].
+	pub warnings: Vec<String>,
+	/// The wall clock time the compiler took.
+	pub compile_wall_time: Duration,
+	/// The CPU time the compiler took. Only the calling thread is measured, as parallel
+	/// compilation is disabled.
+	pub compile_cpu_time: Duration,
 }
 
 /// Runs preparation on the given runtime blob. If successful, it returns a serialized compiled
-/// artifact which can then be used to pass into `Executor::execute` after writing it to the disk.
+/// artifact which can then be used to pass into `Executor::execute` after writing it to the disk,
+/// along with the warnings the compiler emitted and the time it took.
 pub fn prepare(
 	blob: RuntimeBlob,
 	executor_params: &ExecutorParams,
-) -> Result<Vec<u8>, sc_executor_common::error::WasmError> {
+) -> Result<PreparedArtifact, PrepareStageError> {
+	let (semantics, _) = params_to_wasmtime_semantics(executor_params);
+	check_determinism(&semantics)?;
+	let blob = sc_executor_wasmtime::prepare_blob_for_compilation(blob.blob, &semantics)
+		.map_err(PrepareStageError::Translation)?;
+	let threads = executor_params.compilation_threads().unwrap_or(1);
+	let wall_time_start = Instant::now();
+	// Parallel compilation runs on other threads, so the CPU time of the whole process is measured
+	// then. It includes the little CPU time used by other threads of the job meanwhile.
+	let thread_time_start = ThreadTime::now();
+	let process_time_start = ProcessTime::now();
+	let (artifact, warnings) = collect_compiler_warnings(threads, || {
+		sc_executor_wasmtime::precompile_runtime_blob(blob, &semantics)
+	})?;
+	let compile_cpu_time =
+		if threads > 1 { process_time_start.elapsed() } else { thread_time_start.elapsed() };
+	let compile_wall_time = wall_time_start.elapsed();
+	artifact
+		.map(|artifact| PreparedArtifact {
+			artifact,
+			warnings,
+			compile_wall_time,
+			compile_cpu_time,
+		})
+		.map_err(PrepareStageError::Compilation)
+}
+
+/// Checks that code compiled under the given semantics executes the same way on every validator.
+///
+/// Disabling the NaN canonicalization alone is fine, as [`prevalidate`] then rejects any code
+/// using floats.
+fn check_determinism(semantics: &Semantics) -> Result<(), PrepareStageError> {
+	if semantics.wasm_relaxed_simd && !semantics.wasm_simd {
+		return Err(PrepareStageError::NonDeterministicParams("relaxed SIMD requires SIMD"))
+	}
+	if semantics.wasm_relaxed_simd && !semantics.canonicalize_nans {
+		return Err(PrepareStageError::NonDeterministicParams(
+			"relaxed SIMD requires NaN canonicalization",
+		))
+	}
+	Ok(())
+}
+
+/// Returns the flags to record in the header of an artifact [`prepare`]d with the given executor
+/// parameters.
+pub fn artifact_flags(executor_params: &ExecutorParams) -> ArtifactFlags {
 	let (semantics, _) = params_to_wasmtime_semantics(executor_params);
-	sc_executor_wasmtime::prepare_runtime_artifact(blob, &semantics)
+	ArtifactFlags { hugepage_layout: semantics.hugepage_aligned_code }
+}
+
+/// The log targets of Wasmtime and Cranelift, under which they report diagnostics.
+const COMPILER_LOG_TARGETS: &[&str] = &["wasmtime", "cranelift"];
+
+/// Runs `f`, collecting the warnings and errors the compiler logs meanwhile.
+///
+/// With a single thread, `f` runs on the calling thread. Otherwise it runs on a dedicated pool of
+/// the given number of threads, on which Wasmtime compiles in parallel. The pool is dropped before
+/// returning, upon which its threads exit.
+///
+/// The compiler logs through the `log` crate, which the workers forward to `tracing`, so the
+/// messages are picked up by a subscriber that is set on the compiling threads. Nothing else is
+/// logged by these threads in the meantime.
+fn collect_compiler_warnings<R: Send>(
+	threads: u32,
+	f: impl FnOnce() -> R + Send,
+) -> Result<(R, Vec<String>), PrepareStageError> {
+	// Records above the max level of `log` never make it to `tracing`. The level is process-wide,
+	// so it is only raised while compiling.
+	let _max_level = RaisedLogMaxLevel::at_least(log::LevelFilter::Warn);
+
+	let warnings = Arc::new(Mutex::new(Vec::new()));
+	let dispatch = Dispatch::new(
+		sp_tracing::tracing_subscriber::registry()
+			.with(CompilerWarnings { warnings: Arc::clone(&warnings) }),
+	);
+	let result = if threads > 1 {
+		let thread_dispatch = dispatch.clone();
+		let pool = rayon::ThreadPoolBuilder::new()
+			.num_threads(threads as usize)
+			.thread_name(|index| format!("compiler thread {}", index))
+			// The threads exit along with the pool, so the subscriber is never unset.
+			.start_handler(move |_| {
+				std::mem::forget(sp_tracing::tracing::dispatcher::set_default(&thread_dispatch))
+			})
+			.build()
+			.map_err(|err| {
+				PrepareStageError::Compilation(WasmError::Other(format!(
+					"could not spawn the compiler threads: {}",
+					err
+				)))
+			})?;
+		pool.install(|| sp_tracing::tracing::dispatcher::with_default(&dispatch, f))
+	} else {
+		sp_tracing::tracing::dispatcher::with_default(&dispatch, f)
+	};
+	let warnings = std::mem::take(&mut *warnings.lock().unwrap_or_else(PoisonError::into_inner));
+	Ok((result, warnings))
+}
+
+/// Raises the max level of `log` and restores the previous one when dropped.
+struct RaisedLogMaxLevel {
+	previous: Option<log::LevelFilter>,
+}
+
+impl RaisedLogMaxLevel {
+	fn at_least(level: log::LevelFilter) -> Self {
+		let previous = log::max_level();
+		if previous >= level {
+			return Self { previous: None }
+		}
+		log::set_max_level(level);
+		Self { previous: Some(previous) }
+	}
+}
+
+impl Drop for RaisedLogMaxLevel {
+	fn drop(&mut self) {
+		if let Some(previous) = self.previous {
+			log::set_max_level(previous);
+		}
+	}
+}
+
+/// A [`Layer`] keeping the warnings and errors logged under [`COMPILER_LOG_TARGETS`].
+struct CompilerWarnings {
+	warnings: Arc<Mutex<Vec<String>>>,
+}
+
+impl<S: Subscriber> Layer<S> for CompilerWarnings {
+	fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+		let metadata = event.metadata();
+		// More verbose levels compare greater.
+		if *metadata.level() > Level::WARN ||
+			!COMPILER_LOG_TARGETS.iter().any(|target| metadata.target().starts_with(target))
+		{
+			return
+		}
+
+		let mut message = MessageVisitor(String::new());
+		event.record(&mut message);
+		self.warnings.lock().unwrap_or_else(PoisonError::into_inner).push(format!(
+			"{}: {}",
+			metadata.target(),
+			message.0
+		));
+	}
+}
+
+/// Extracts the message of an event.
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		if field.name() == "message" {
+			self.0 = format!("{:?}", value);
+		}
+	}
 }
 
 /// Available host functions. We leave out:
@@ -365,7 +923,7 @@ impl sp_core::traits::ReadRuntimeVersion for ReadRuntimeVersion {
 		wasm_code: &[u8],
 		_ext: &mut dyn sp_externalities::Externalities,
 	) -> Result<Vec<u8>, String> {
-		let blob = RuntimeBlob::uncompress_if_needed(wasm_code)
+		let blob = runtime_blob::RuntimeBlob::uncompress_if_needed(wasm_code)
 			.map_err(|e| format!("Failed to read the PVF runtime blob: {:?}", e))?;
 
 		match sc_executor::read_embedded_version(&blob)
@@ -379,3 +937,443 @@ impl sp_core::traits::ReadRuntimeVersion for ReadRuntimeVersion {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn prevalidate_wat(wat: &str, executor_params: &ExecutorParams) -> Result<(), String> {
+		let code = wat::parse_str(wat).unwrap();
+		prevalidate(&code, executor_params).map(|_blob| ()).map_err(|err| match err {
+			PrepareStageError::Validation(PrevalidationError::Invalid(msg)) => msg,
+			err => panic!("unexpected error: {:?}", err),
+		})
+	}
+
+	#[test]
+	fn prevalidate_reports_the_module_shape() {
+		let code = wat::parse_str(
+			r#"(module
+				(import "env" "memory" (memory 17))
+				(import "env" "ext_print" (func (param i32)))
+				(func)
+				(func)
+				(func)
+			)"#,
+		)
+		.unwrap();
+
+		let blob = prevalidate(&code, &ExecutorParams::default()).unwrap();
+
+		assert_eq!(
+			blob.shape(),
+			ModuleShape {
+				function_count: 3,
+				import_count: 2,
+				// The function count, then 3 bytes per empty body: its size, the count of local
+				// declarations and `end`.
+				code_section_bytes: 10,
+				memory_pages: 17,
+			}
+		);
+	}
+
+	#[test]
+	fn prevalidated_blob_lists_imports_and_exports() {
+		let _lock = LOG_MAX_LEVEL.lock().unwrap_or_else(PoisonError::into_inner);
+		let code = wat::parse_str(
+			r#"(module
+				(import "env" "memory" (memory 17))
+				(import "env" "ext_print" (func (param i32)))
+				(global (export "__heap_base") i32 (i32.const 0))
+				(func (export "validate_block") (param i32 i32) (result i64)
+					i64.const 0)
+			)"#,
+		)
+		.unwrap();
+
+		let blob = prevalidate(&code, &ExecutorParams::default()).unwrap();
+
+		assert_eq!(
+			blob.imports(),
+			[
+				ModuleImport {
+					module: "env".into(),
+					name: "memory".into(),
+					kind: ItemKind::Memory
+				},
+				ModuleImport {
+					module: "env".into(),
+					name: "ext_print".into(),
+					kind: ItemKind::Function
+				},
+			]
+		);
+		assert_eq!(
+			blob.exports(),
+			[
+				ModuleExport { name: "__heap_base".into(), kind: ItemKind::Global },
+				ModuleExport { name: "validate_block".into(), kind: ItemKind::Function },
+			]
+		);
+		assert_eq!(blob.memory_pages(), 17);
+
+		// The blob is consumed by the compiler once inspected.
+		assert!(prepare(blob, &ExecutorParams::default()).is_ok());
+	}
+
+	fn prevalidation_error(code: &[u8]) -> PrevalidationError {
+		match prevalidate(code, &ExecutorParams::default()) {
+			Err(PrepareStageError::Validation(err)) => err,
+			Err(err) => panic!("unexpected error: {:?}", err),
+			Ok(_) => panic!("the code was accepted"),
+		}
+	}
+
+	#[test]
+	fn prevalidate_rejects_bad_magic() {
+		assert_eq!(prevalidation_error(b"not wasm"), PrevalidationError::BadMagic);
+		assert_eq!(prevalidation_error(&[]), PrevalidationError::BadMagic);
+	}
+
+	#[test]
+	fn prevalidate_rejects_unsupported_versions() {
+		assert_eq!(
+			prevalidation_error(b"\0asm\x02\0\0\0"),
+			PrevalidationError::UnsupportedVersion(2)
+		);
+		// The preamble of a component.
+		assert_eq!(
+			prevalidation_error(b"\0asm\x0d\0\x01\0"),
+			PrevalidationError::UnsupportedVersion(0x1000d)
+		);
+	}
+
+	#[test]
+	fn prevalidate_rejects_disallowed_sections() {
+		// A module with an empty section of an unknown id.
+		assert_eq!(
+			prevalidation_error(b"\0asm\x01\0\0\0\x20\0"),
+			PrevalidationError::DisallowedSection("unknown section 32".into())
+		);
+	}
+
+	#[test]
+	fn prevalidate_limits_the_declared_memory_pages() {
+		let executor_params =
+			ExecutorParams::from(&[ExecutorParam::MaxDeclaredMemoryPages(16)][..]);
+		let check = |wat: &str, executor_params: &ExecutorParams| {
+			prevalidate(&wat::parse_str(wat).unwrap(), executor_params).map(|_blob| ())
+		};
+
+		assert!(check("(module (memory 1 15))", &executor_params).is_ok());
+		assert!(check("(module (memory 1 16))", &executor_params).is_ok());
+		assert!(matches!(
+			check("(module (memory 1 17))", &executor_params),
+			Err(PrepareStageError::MemoryPagesExceeded { declared: 17, limit: 16 })
+		));
+		// Without a declared maximum, the initial size is what counts.
+		assert!(check("(module (memory 16))", &executor_params).is_ok());
+		assert!(matches!(
+			check("(module (memory 17))", &executor_params),
+			Err(PrepareStageError::MemoryPagesExceeded { declared: 17, limit: 16 })
+		));
+		assert!(matches!(
+			check(r#"(module (import "env" "memory" (memory 1 17)))"#, &executor_params),
+			Err(PrepareStageError::MemoryPagesExceeded { declared: 17, limit: 16 })
+		));
+		// The declaration is not limited without the param.
+		assert!(check("(module (memory 1 17))", &ExecutorParams::default()).is_ok());
+	}
+
+	#[test]
+	fn prevalidate_rejects_modules_over_the_limits() {
+		let exports: String =
+			(0..=100_000).map(|i| format!(r#"(export "f{}" (func $f))"#, i)).collect();
+		let code = wat::parse_str(format!("(module (func $f) {})", exports)).unwrap();
+
+		assert!(matches!(
+			prevalidation_error(&code),
+			PrevalidationError::SizeLimit(msg) if msg.contains("exports")
+		));
+	}
+
+	#[test]
+	fn prevalidate_rejects_malformed_modules() {
+		// A section id without the size of the section.
+		assert!(matches!(
+			prevalidation_error(b"\0asm\x01\0\0\0\x01"),
+			PrevalidationError::Invalid(_)
+		));
+	}
+
+	#[test]
+	fn prevalidate_accepts_mvp_code() {
+		let wat = r#"(module
+			(memory (export "memory") 1)
+			(func (export "validate_block") (param i32 i32) (result i64)
+				f64.const 1.5
+				f64.sqrt
+				drop
+				i64.const 0)
+		)"#;
+		assert_eq!(prevalidate_wat(wat, &ExecutorParams::default()), Ok(()));
+	}
+
+	#[test]
+	fn prevalidate_rejects_threads() {
+		let wat = r#"(module
+			(memory 1 1 shared)
+			(func (result i32)
+				i32.const 0
+				i32.atomic.load)
+		)"#;
+		let err = prevalidate_wat(wat, &ExecutorParams::default()).unwrap_err();
+		assert!(err.contains("threads"), "{}", err);
+	}
+
+	#[test]
+	fn prevalidate_rejects_simd() {
+		let wat = r#"(module
+			(func (result i32)
+				v128.const i64x2 0 0
+				i32x4.extract_lane 0)
+		)"#;
+		let err = prevalidate_wat(wat, &ExecutorParams::default()).unwrap_err();
+		assert!(err.contains("SIMD"), "{}", err);
+	}
+
+	#[test]
+	fn relaxed_simd_is_only_accepted_under_the_deterministic_policy() {
+		let code = wat::parse_str(
+			r#"(module
+				(func (result v128)
+					v128.const i64x2 0 0
+					v128.const i64x2 0 0
+					i8x16.relaxed_swizzle)
+			)"#,
+		)
+		.unwrap();
+		let mut semantics = DEFAULT_CONFIG.semantics.clone();
+		semantics.wasm_simd = true;
+
+		assert!(matches!(
+			check_wasm_features(&code, &semantics),
+			Err(PrepareStageError::Validation(PrevalidationError::Invalid(msg)))
+				if msg.contains("relaxed SIMD")
+		));
+
+		let executor_params = ExecutorParams::from(
+			&[ExecutorParam::RelaxedSimd(RelaxedSimdPolicy::Deterministic)][..],
+		);
+		let (semantics, _) = params_to_wasmtime_semantics(&executor_params);
+		assert!(check_wasm_features(&code, &semantics).is_ok());
+	}
+
+	/// Serializes the tests that compile, or depend on the process-wide max level of `log`, which
+	/// compiling changes.
+	static LOG_MAX_LEVEL: Mutex<()> = Mutex::new(());
+
+	#[test]
+	fn compiler_warnings_are_collected() {
+		use sp_tracing::tracing::{info, warn};
+		let _lock = LOG_MAX_LEVEL.lock().unwrap_or_else(PoisonError::into_inner);
+
+		let ((), warnings) = collect_compiler_warnings(1, || {
+			warn!(target: "cranelift_codegen::isa", "unsupported feature");
+			info!(target: "wasmtime::compile", "compiling");
+			warn!(target: "parachain::pvf", "unrelated");
+		})
+		.unwrap();
+
+		assert_eq!(warnings, vec!["cranelift_codegen::isa: unsupported feature".to_string()]);
+	}
+
+	#[test]
+	fn compiler_warnings_are_collected_from_compiler_threads() {
+		use sp_tracing::tracing::warn;
+		let _lock = LOG_MAX_LEVEL.lock().unwrap_or_else(PoisonError::into_inner);
+
+		let (thread_name, warnings) = collect_compiler_warnings(4, || {
+			warn!(target: "cranelift_codegen::isa", "unsupported feature");
+			std::thread::current().name().map(str::to_owned)
+		})
+		.unwrap();
+
+		assert!(
+			matches!(thread_name.as_deref(), Some(name) if name.starts_with("compiler thread"))
+		);
+		assert_eq!(warnings, vec!["cranelift_codegen::isa: unsupported feature".to_string()]);
+	}
+
+	#[test]
+	fn log_max_level_is_only_raised_while_collecting_compiler_warnings() {
+		let _lock = LOG_MAX_LEVEL.lock().unwrap_or_else(PoisonError::into_inner);
+		let previous = log::max_level();
+		log::set_max_level(log::LevelFilter::Error);
+
+		let (level, _) = collect_compiler_warnings(1, log::max_level).unwrap();
+
+		assert_eq!(level, log::LevelFilter::Warn);
+		assert_eq!(log::max_level(), log::LevelFilter::Error);
+		log::set_max_level(previous);
+	}
+
+	#[test]
+	fn compilation_threads_dont_change_the_artifact() {
+		let _lock = LOG_MAX_LEVEL.lock().unwrap_or_else(PoisonError::into_inner);
+		let code = wat::parse_str(
+			r#"(module
+				(memory (export "memory") 1)
+				(func (export "validate_block") (param i32 i32) (result i64)
+					i64.const 0)
+				(func (export "other") (result i32)
+					i32.const 1)
+			)"#,
+		)
+		.unwrap();
+		let prepare_with_threads = |threads| {
+			let executor_params =
+				ExecutorParams::from(&[ExecutorParam::CompilationThreads(threads)][..]);
+			let blob = prevalidate(&code, &executor_params).unwrap();
+			prepare(blob, &executor_params).unwrap()
+		};
+
+		let sequential = prepare_with_threads(1);
+		let parallel = prepare_with_threads(4);
+
+		assert_eq!(sequential.artifact, parallel.artifact);
+		assert!(parallel.compile_cpu_time > Duration::ZERO);
+	}
+
+	#[test]
+	fn prepare_succeeds_without_warnings() {
+		let _lock = LOG_MAX_LEVEL.lock().unwrap_or_else(PoisonError::into_inner);
+		let code = wat::parse_str(
+			r#"(module
+				(memory (export "memory") 1)
+				(func (export "validate_block") (param i32 i32) (result i64)
+					i64.const 0)
+			)"#,
+		)
+		.unwrap();
+		let blob = prevalidate(&code, &ExecutorParams::default()).unwrap();
+
+		let prepared = prepare(blob, &ExecutorParams::default()).unwrap();
+		assert!(!prepared.artifact.is_empty());
+		assert!(prepared.warnings.is_empty(), "{:?}", prepared.warnings);
+	}
+
+	#[test]
+	fn bulk_memory_is_only_accepted_if_enabled() {
+		let wat = r#"(module
+			(memory 1)
+			(func
+				i32.const 0
+				i32.const 0
+				i32.const 0
+				memory.fill)
+		)"#;
+
+		let err = prevalidate_wat(wat, &ExecutorParams::default()).unwrap_err();
+		assert!(err.contains("bulk memory"), "{}", err);
+
+		let executor_params = ExecutorParams::from(&[ExecutorParam::WasmExtBulkMemory][..]);
+		let (semantics, _) = params_to_wasmtime_semantics(&executor_params);
+		assert!(check_wasm_features(&wat::parse_str(wat).unwrap(), &semantics).is_ok());
+	}
+
+	/// The params that explicitly select the semantics used when they are absent.
+	const DEFAULT_DETERMINISM_PARAMS: [ExecutorParam; 3] = [
+		ExecutorParam::NanCanonicalization(true),
+		ExecutorParam::RelaxedSimd(RelaxedSimdPolicy::Reject),
+		ExecutorParam::SignalsBasedTraps(true),
+	];
+
+	#[test]
+	fn determinism_params_default_to_the_previous_semantics() {
+		for params in [&[][..], &DEFAULT_DETERMINISM_PARAMS[..]] {
+			let (semantics, _) = params_to_wasmtime_semantics(&ExecutorParams::from(params));
+			assert!(semantics.canonicalize_nans);
+			assert!(!semantics.wasm_simd);
+			assert!(!semantics.wasm_relaxed_simd);
+			assert!(semantics.signals_based_traps);
+		}
+	}
+
+	#[test]
+	fn determinism_params_override_the_semantics() {
+		let executor_params = ExecutorParams::from(
+			&[
+				ExecutorParam::RelaxedSimd(RelaxedSimdPolicy::Deterministic),
+				ExecutorParam::SignalsBasedTraps(false),
+			][..],
+		);
+		let (semantics, _) = params_to_wasmtime_semantics(&executor_params);
+		assert!(semantics.canonicalize_nans);
+		assert!(semantics.wasm_simd);
+		assert!(semantics.wasm_relaxed_simd);
+		assert!(!semantics.signals_based_traps);
+
+		let executor_params =
+			ExecutorParams::from(&[ExecutorParam::NanCanonicalization(false)][..]);
+		let (semantics, _) = params_to_wasmtime_semantics(&executor_params);
+		assert!(!semantics.canonicalize_nans);
+	}
+
+	#[test]
+	fn floats_are_rejected_without_nan_canonicalization() {
+		let wat = r#"(module
+			(func (result i64)
+				f64.const 1.5
+				f64.sqrt
+				i64.reinterpret_f64)
+		)"#;
+		assert_eq!(prevalidate_wat(wat, &ExecutorParams::default()), Ok(()));
+
+		let executor_params =
+			ExecutorParams::from(&[ExecutorParam::NanCanonicalization(false)][..]);
+		let err = prevalidate_wat(wat, &executor_params).unwrap_err();
+		assert!(err.contains("floating-point"), "{}", err);
+	}
+
+	#[test]
+	fn prepare_rejects_relaxed_simd_without_nan_canonicalization() {
+		let code = wat::parse_str("(module)").unwrap();
+		let executor_params = ExecutorParams::from(
+			&[
+				ExecutorParam::NanCanonicalization(false),
+				ExecutorParam::RelaxedSimd(RelaxedSimdPolicy::Deterministic),
+			][..],
+		);
+		let blob = prevalidate(&code, &executor_params).unwrap();
+
+		assert!(matches!(
+			prepare(blob, &executor_params),
+			Err(PrepareStageError::NonDeterministicParams(_))
+		));
+	}
+
+	#[test]
+	fn determinism_params_only_change_the_artifact_if_not_default() {
+		let _lock = LOG_MAX_LEVEL.lock().unwrap_or_else(PoisonError::into_inner);
+		let code = wat::parse_str(
+			r#"(module
+				(memory (export "memory") 1)
+				(func (export "validate_block") (param i32 i32) (result i64)
+					local.get 0
+					i64.load)
+			)"#,
+		)
+		.unwrap();
+		let prepare_with_params = |params: &[ExecutorParam]| {
+			let executor_params = ExecutorParams::from(params);
+			let blob = prevalidate(&code, &executor_params).unwrap();
+			prepare(blob, &executor_params).unwrap().artifact
+		};
+
+		let default = prepare_with_params(&[]);
+		assert_eq!(default, prepare_with_params(&DEFAULT_DETERMINISM_PARAMS));
+		assert_ne!(default, prepare_with_params(&[ExecutorParam::SignalsBasedTraps(false)]));
+	}
+}
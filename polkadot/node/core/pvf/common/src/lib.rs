@@ -17,6 +17,7 @@
 //! Contains functionality related to PVFs that is shared by the PVF host and the PVF workers.
 #![deny(unused_crate_dependencies)]
 
+pub mod artifact;
 pub mod error;
 pub mod execute;
 pub mod executor_interface;
@@ -60,6 +61,8 @@ pub struct SecurityStatus {
 	pub can_unshare_user_namespace_and_change_root: bool,
 	/// Whether we are able to call `clone` with all sandboxing flags.
 	pub can_do_secure_clone: bool,
+	/// Whether prepare jobs are able to unshare the network and mount namespaces.
+	pub can_unshare_network_and_mount_namespaces: bool,
 }
 
 /// A handshake with information for the worker.
@@ -70,6 +73,9 @@ pub struct WorkerHandshake {
 
 /// Write some data prefixed by its length into `w`. Sync version of `framed_send` to avoid
 /// dependency on tokio.
+///
+/// Writes interrupted by a signal are retried, and short writes are continued, so that a benign
+/// signal doesn't leave a partial frame in the stream.
 pub fn framed_send_blocking(w: &mut (impl Write + Unpin), buf: &[u8]) -> io::Result<()> {
 	let len_buf = buf.len().to_le_bytes();
 	w.write_all(&len_buf)?;
@@ -79,6 +85,8 @@ pub fn framed_send_blocking(w: &mut (impl Write + Unpin), buf: &[u8]) -> io::Res
 
 /// Read some data prefixed by its length from `r`. Sync version of `framed_recv` to avoid
 /// dependency on tokio.
+///
+/// Like [`framed_send_blocking`], this retries interrupted reads and continues short ones.
 pub fn framed_recv_blocking(r: &mut (impl Read + Unpin)) -> io::Result<Vec<u8>> {
 	let mut len_buf = [0u8; mem::size_of::<usize>()];
 	r.read_exact(&mut len_buf)?;
@@ -88,6 +96,34 @@ pub fn framed_recv_blocking(r: &mut (impl Read + Unpin)) -> io::Result<Vec<u8>>
 	Ok(buf)
 }
 
+/// The error returned by [`framed_recv_blocking_bounded`], wrapped in an [`io::Error`] of kind
+/// [`io::ErrorKind::InvalidData`], if the data is longer than allowed.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("framed data of {len} bytes exceeds the maximum of {max_len} bytes")]
+pub struct FrameTooLarge {
+	pub len: usize,
+	pub max_len: usize,
+}
+
+/// Like [`framed_recv_blocking`], but fails without reading the data if it is longer than
+/// `max_len`, so that a bogus length prefix can't make us allocate arbitrary amounts of memory.
+///
+/// Note that the data is left unread in that case, so nothing else can be read from `r`.
+pub fn framed_recv_blocking_bounded(
+	r: &mut (impl Read + Unpin),
+	max_len: usize,
+) -> io::Result<Vec<u8>> {
+	let mut len_buf = [0u8; mem::size_of::<usize>()];
+	r.read_exact(&mut len_buf)?;
+	let len = usize::from_le_bytes(len_buf);
+	if len > max_len {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, FrameTooLarge { len, max_len }))
+	}
+	let mut buf = vec![0; len];
+	r.read_exact(&mut buf)?;
+	Ok(buf)
+}
+
 #[cfg(all(test, not(feature = "test-utils")))]
 mod tests {
 	use super::*;
@@ -115,5 +151,91 @@ mod tests {
 			!status.can_do_secure_clone,
 			"can_do_secure_clone is false for default security status"
 		);
+		assert!(
+			!status.can_unshare_network_and_mount_namespaces,
+			"can_unshare_network_and_mount_namespaces is false for default security status"
+		);
+	}
+
+	#[test]
+	fn bounded_recv_rejects_oversized_frames_before_reading_them() {
+		let mut data = usize::MAX.to_le_bytes().to_vec();
+		data.extend_from_slice(&[0; 16]);
+		let mut r = io::Cursor::new(data);
+
+		let err = framed_recv_blocking_bounded(&mut r, 16).unwrap_err();
+
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		assert_eq!(
+			err.get_ref().and_then(|err| err.downcast_ref::<FrameTooLarge>()),
+			Some(&FrameTooLarge { len: usize::MAX, max_len: 16 })
+		);
+		assert_eq!(r.position(), mem::size_of::<usize>() as u64);
+	}
+
+	/// Fails every other call with [`io::ErrorKind::Interrupted`], like a blocking call interrupted
+	/// by a signal, and transfers at most a few bytes in the others.
+	struct Interrupting<T> {
+		inner: T,
+		interrupt: bool,
+	}
+
+	impl<T> Interrupting<T> {
+		const MAX_TRANSFER: usize = 3;
+
+		fn new(inner: T) -> Self {
+			Self { inner, interrupt: true }
+		}
+
+		fn interrupt(&mut self) -> io::Result<()> {
+			self.interrupt = !self.interrupt;
+			if self.interrupt {
+				Ok(())
+			} else {
+				Err(io::ErrorKind::Interrupted.into())
+			}
+		}
+	}
+
+	impl<T: Write> Write for Interrupting<T> {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.interrupt()?;
+			self.inner.write(&buf[..buf.len().min(Self::MAX_TRANSFER)])
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			self.inner.flush()
+		}
+	}
+
+	impl<T: Read> Read for Interrupting<T> {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			self.interrupt()?;
+			let len = buf.len().min(Self::MAX_TRANSFER);
+			self.inner.read(&mut buf[..len])
+		}
+	}
+
+	#[test]
+	fn interrupted_transfer_still_delivers_the_whole_frame() {
+		let frame: Vec<u8> = (0..=255).collect();
+
+		let mut w = Interrupting::new(Vec::new());
+		framed_send_blocking(&mut w, &frame).unwrap();
+		assert_eq!(w.inner.len(), mem::size_of::<usize>() + frame.len());
+
+		let mut r = Interrupting::new(io::Cursor::new(w.inner));
+		assert_eq!(framed_recv_blocking(&mut r).unwrap(), frame);
+		assert_eq!(r.inner.position() as usize, r.inner.get_ref().len());
+	}
+
+	#[test]
+	fn bounded_recv_accepts_frames_within_the_bound() {
+		let mut data = Vec::new();
+		framed_send_blocking(&mut data, &[1, 2, 3]).unwrap();
+
+		let buf = framed_recv_blocking_bounded(&mut io::Cursor::new(data), 3).unwrap();
+
+		assert_eq!(buf, vec![1, 2, 3]);
 	}
 }
@@ -14,18 +14,162 @@
 // You should have received a copy of the GNU General Public License
 // along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
 
+use crate::{error::PrepareWorkerResult, pvf::PvfPrepData, SecurityStatus};
 use codec::{Decode, Encode};
-use std::path::PathBuf;
+use polkadot_primitives::ExecutorParams;
+use std::{path::PathBuf, time::Duration};
+
+/// Version of the protocol spoken between the host and the prepare worker. The host sends it to the
+/// worker right after spawning it, and the worker refuses to serve requests if it doesn't match.
+///
+/// Bump this whenever the encoding of the requests or responses changes.
+pub const PROTOCOL_VERSION: u32 = 45;
+
+/// The default multiple of the preparation timeout (in CPU time) after which the prepare worker
+/// kills a job that is still running (in wall clock time). It is below the factor the host waits
+/// for, so that a stalled job is reaped by the worker before the host gives up on the whole worker.
+pub const DEFAULT_PREPARE_WALL_CLOCK_TIMEOUT_FACTOR: u32 = 3;
 
 /// Result from prepare worker if successful.
 #[derive(Debug, Clone, Default, Encode, Decode)]
 pub struct PrepareWorkerSuccess {
 	/// Checksum of the compiled PVF.
 	pub checksum: String,
+	/// The absolute path the artifact was written to, as seen by the host. Only set if the job
+	/// produced an artifact, and if the path is valid UTF-8. The host falls back to the path of
+	/// the temporary artifact it created otherwise.
+	pub artifact_path: Option<String>,
 	/// Stats of the current preparation run.
 	pub stats: PrepareStats,
 }
 
+/// A request sent from the host to the prepare worker.
+#[derive(Debug, Encode, Decode)]
+pub enum PrepareWorkerRequest {
+	/// Prepare a single PVF. Answered with [`PrepareWorkerMessage::Result`].
+	#[codec(index = 0)]
+	Single(PvfPrepData),
+	/// Prepare the given PVFs one after another, each in its own job. Answered with
+	/// [`PrepareWorkerMessage::BatchResult`]. The artifact of the PVF at a given index is written
+	/// to [`crate::worker_dir::prepare_tmp_batch_artifact`].
+	#[codec(index = 1)]
+	Batch(Vec<PvfPrepData>),
+	/// Drop the state the worker keeps between jobs, i.e. its cache of recently prepared
+	/// artifacts and its default executor params, leaving it as if it was freshly spawned.
+	/// Answered with [`PrepareWorkerMessage::ResetDone`].
+	#[codec(index = 2)]
+	Reset,
+	/// Prepare a single PVF, sent without its code, see
+	/// [`PvfPrepData::without_code`](crate::pvf::PvfPrepData::without_code). If the worker has the
+	/// artifact cached, it serves it right away, sparing the host sending the code. Otherwise it
+	/// asks for the code with [`PrepareWorkerMessage::CodeRequested`], which the host answers with
+	/// a [`Self::Single`] request for the same PVF. Answered with
+	/// [`PrepareWorkerMessage::Result`].
+	#[codec(index = 3)]
+	Probe(PvfPrepData),
+	/// Cancel the job in flight, which is then answered with
+	/// [`PrepareError::Cancelled`](crate::error::PrepareError::Cancelled) instead of its result.
+	/// The worker only notices the cancellation if the host sent no other request after the one
+	/// being cancelled. If the job concluded before the cancellation arrived, its result is sent
+	/// as usual and the cancellation is ignored. Cancelling a batch cancels its remaining PVFs
+	/// too.
+	#[codec(index = 4)]
+	Cancel,
+	/// Ask for the [`SecurityStatus`] the worker was started with, so that the host can check
+	/// which security features are in effect. Doesn't change any state of the worker. Answered
+	/// with [`PrepareWorkerMessage::SecurityStatus`].
+	#[codec(index = 5)]
+	QuerySecurityStatus,
+	/// Set the executor params used for the PVFs of subsequent requests that leave theirs out, see
+	/// [`PvfPrepData::without_executor_params`](crate::pvf::PvfPrepData::without_executor_params).
+	/// Replaces the default set before, if any. A request leaving out the executor params before
+	/// any default is set is rejected. Not answered.
+	#[codec(index = 6)]
+	SetDefaultExecutorParams(ExecutorParams),
+	/// Like [`Self::Single`], but the artifact is written to a file of the host instead of the
+	/// worker dir, e.g. to seal it or to pass it on. The file descriptor is sent right after the
+	/// request, see [`send_fd`](crate::worker::send_fd), and closed by the worker once the job
+	/// concluded. The host must keep reading from a pipe while the job runs, as the worker blocks
+	/// writing to a full one. The artifact is still staged in the temporary artifact file, which
+	/// the host creates like for [`Self::Single`] and the worker truncates afterwards. Answered
+	/// with [`PrepareWorkerMessage::Result`], which has no
+	/// [`artifact_path`](PrepareWorkerSuccess::artifact_path).
+	#[codec(index = 7)]
+	SingleToFd(PvfPrepData),
+}
+
+/// A message sent from the prepare worker to the host.
+#[derive(Debug, Encode, Decode)]
+pub enum PrepareWorkerMessage {
+	/// The worker is still busy with the job. Sent periodically if the job was requested with a
+	/// heartbeat interval.
+	#[codec(index = 0)]
+	Heartbeat,
+	/// The job has concluded with the given result. This is the last message for the job.
+	#[codec(index = 1)]
+	Result {
+		/// The id the job was requested with, see
+		/// [`PvfPrepData::with_request_id`](crate::pvf::PvfPrepData::with_request_id). `None` if
+		/// the request had no id, or could not be read.
+		request_id: Option<u64>,
+		/// The last stage the job reached, whether it succeeded or failed. A job that wrote its
+		/// artifact, also from the cache, reached [`PrepareStage::ArtifactWrite`]. `None` if the
+		/// job failed before entering any stage, e.g. because the request was rejected.
+		reached_stage: Option<PrepareStage>,
+		result: PrepareWorkerResult,
+	},
+	/// Metrics of the job, sent right before its result if the job was requested with worker
+	/// metrics.
+	#[codec(index = 2)]
+	Metrics(WorkerMetrics),
+	/// The results of a batch request, in the order of its PVFs. This is the last message for the
+	/// batch.
+	#[codec(index = 3)]
+	BatchResult(Vec<PrepareWorkerResult>),
+	/// The worker handled a [`PrepareWorkerRequest::Reset`].
+	#[codec(index = 4)]
+	ResetDone,
+	/// How busy the worker is, sent after the metrics of a job, if any, and right before its
+	/// result if the job was requested with a busy hint.
+	#[codec(index = 5)]
+	BusyHint(BusyHint),
+	/// The PVF of a [`PrepareWorkerRequest::Probe`] is not cached, so the worker waits for the
+	/// host to send it in full.
+	#[codec(index = 6)]
+	CodeRequested,
+	/// The security status the worker was started with, in answer to a
+	/// [`PrepareWorkerRequest::QuerySecurityStatus`].
+	#[codec(index = 7)]
+	SecurityStatus(SecurityStatus),
+}
+
+/// How busy a prepare worker is, so that the host can back off instead of piling up requests on
+/// its socket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub struct BusyHint {
+	/// The number of jobs the worker is running, not counting the one that just concluded.
+	pub running_jobs: u32,
+	/// The maximum number of jobs the worker runs at once. Further requests fail with
+	/// [`PrepareError::Overloaded`](crate::error::PrepareError::Overloaded).
+	pub max_jobs: u32,
+	/// The number of bytes of further requests that are waiting to be read from the socket. This
+	/// is a rough estimate of the queue depth, as requests differ in size.
+	pub pending_request_bytes: u64,
+}
+
+/// Metrics of a single preparation job, collected by the worker for the host to aggregate in its
+/// metrics registry.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct WorkerMetrics {
+	/// The wall clock time the worker spent on the job, from receiving the request until sending
+	/// the result.
+	pub preparation_duration: Duration,
+	/// The size of the compiled artifact in bytes, if one was produced.
+	pub artifact_size: Option<u64>,
+	/// `ru_maxrss` of the job, if it could be measured.
+	pub max_rss: Option<i64>,
+}
+
 /// Result of PVF preparation if successful.
 #[derive(Debug, Clone, Default)]
 pub struct PrepareSuccess {
@@ -41,25 +185,193 @@ pub struct PrepareSuccess {
 #[derive(Debug, Clone, Default, Encode, Decode)]
 pub struct PrepareStats {
 	/// The CPU time that elapsed for the preparation job.
-	pub cpu_time_elapsed: std::time::Duration,
+	pub cpu_time_elapsed: Duration,
 	/// The observed memory statistics for the preparation job.
 	pub memory_stats: MemoryStats,
 	/// The decompressed Wasm code length observed during the preparation.
 	pub observed_wasm_code_len: u32,
+	/// The size of the code as received, i.e. possibly compressed, in bytes.
+	pub code_size: u64,
+	/// The size of the compiled artifact as written to disk, including its header, in bytes. Zero
+	/// if no artifact was produced.
+	pub compiled_artifact_size: u64,
+	/// Timings of the individual preparation stages. Only collected for
+	/// [`PrepareJobKind::Benchmark`] jobs.
+	pub stage_timings: Option<PrepareStageTimings>,
+	/// The security features that were actually applied to the job. Jobs fall back to the
+	/// namespaces of the worker if they can't be moved into their own.
+	pub applied_security: AppliedSecurity,
+	/// Whether the job produced an artifact. This is not the case for
+	/// [`PrepareJobKind::ValidateOnly`] jobs.
+	pub artifact_produced: bool,
+	/// Whether the artifact was taken from the worker's cache of recently prepared artifacts,
+	/// without running a job. The other stats are not collected in that case.
+	pub cache_hit: bool,
+	/// The warnings the compiler emitted while compiling the PVF, if any. These don't affect the
+	/// outcome, but may help debugging borderline PVFs.
+	pub compiler_warnings: Vec<String>,
+	/// The CPU time the compiler itself took, without prevalidation and serialization. Part of
+	/// `cpu_time_elapsed`. Zero if nothing was compiled.
+	pub compile_cpu_time: Duration,
+	/// The structure of the Wasm module, as seen by the prevalidation.
+	pub module_shape: ModuleShape,
+	/// The wall clock time from the worker receiving the request until the job process started,
+	/// i.e. the time spent waiting for a job slot and forking. Zero if no job process was spawned,
+	/// e.g. on a cache hit.
+	pub fork_latency: Duration,
+	/// How the job was scheduled. `None` if no job process concluded successfully, e.g. on a cache
+	/// hit.
+	#[cfg(target_os = "linux")]
+	pub sched_stats: Option<SchedStats>,
+}
+
+/// Scheduling statistics of a preparation job. Many preemptions or a long run queue delay often
+/// explain why the same PVF takes longer to compile on a busy host.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub struct SchedStats {
+	/// `ru_nivcsw` from `getrusage`, the number of times the threads of the job were preempted,
+	/// e.g. because their time slice was used up.
+	pub involuntary_context_switches: i64,
+	/// `ru_nvcsw` from `getrusage`, the number of times the threads of the job gave up the CPU
+	/// themselves, e.g. to wait for each other.
+	pub voluntary_context_switches: i64,
+	/// The time the thread preparing the PVF was runnable but waiting for a CPU, from
+	/// `/proc/thread-self/schedstat`. `None` if the job is sandboxed with landlock or seccomp,
+	/// which don't let it read the file, or if the kernel doesn't provide it.
+	pub run_queue_delay: Option<Duration>,
+}
+
+/// A set of the security features applied to a prepare job, e.g. for auditing the hardening of a
+/// validator. Combine the flags with `|`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub struct AppliedSecurity(u8);
+
+impl AppliedSecurity {
+	/// The job was spawned with `clone` with all sandboxing flags.
+	pub const SECURE_CLONE: Self = Self(1 << 0);
+	/// The job runs in its own network and mount namespaces.
+	pub const NETWORK_AND_MOUNT_NAMESPACES: Self = Self(1 << 1);
+	/// Landlock restricts the filesystem access of the job to the worker dir.
+	pub const LANDLOCK: Self = Self(1 << 2);
+	/// Seccomp restricts the job to the syscalls needed for compilation.
+	pub const SECCOMP: Self = Self(1 << 3);
+
+	/// Returns the empty set.
+	pub const fn empty() -> Self {
+		Self(0)
+	}
+
+	/// Returns whether all the features in `other` are in the set.
+	pub const fn contains(self, other: Self) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	/// Adds the features in `other` to the set.
+	pub fn insert(&mut self, other: Self) {
+		self.0 |= other.0;
+	}
+}
+
+impl std::ops::BitOr for AppliedSecurity {
+	type Output = Self;
+
+	fn bitor(self, other: Self) -> Self {
+		Self(self.0 | other.0)
+	}
+}
+
+/// Wall clock timings of the individual stages of a preparation job, measured with a monotonic
+/// clock.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct PrepareStageTimings {
+	/// Time spent in `prevalidate`.
+	pub prevalidation: Duration,
+	/// Time spent in `prepare`, i.e. compiling the code into a serialized artifact.
+	pub preparation: Duration,
+	/// Time spent writing the serialized artifact into the temporary artifact file.
+	pub artifact_write: Duration,
+}
+
+/// A stage of a preparation job, as reported by
+/// [`PrepareError::TimedOutAtStage`](crate::error::PrepareError::TimedOutAtStage), and with the
+/// result of every job, see [`PrepareWorkerMessage::Result`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum PrepareStage {
+	/// Decompressing the code.
+	Decompression = 0,
+	/// Validating the code before compiling it, see `prevalidate`.
+	Prevalidation = 1,
+	/// Compiling the code into an artifact, see `prepare`.
+	Preparation = 2,
+	/// Constructing the runtime from the artifact, for jobs that check it.
+	RuntimeConstruction = 3,
+	/// Compressing the artifact before sending it to the worker.
+	ArtifactCompression = 4,
+	/// Writing the artifact into the temporary artifact file, done by the worker.
+	ArtifactWrite = 5,
+}
+
+/// Cheap structural stats of a Wasm module, collected while prevalidating it, e.g. to spot unusual
+/// PVFs. All zero for code that is not Wasm.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub struct ModuleShape {
+	/// The number of functions defined by the module, not counting imported ones.
+	pub function_count: u32,
+	/// The number of imports of any kind.
+	pub import_count: u32,
+	/// The size of the code section in bytes, without its section header.
+	pub code_section_bytes: u32,
+	/// The initial size of the linear memory in Wasm pages, whether it is imported or defined.
+	pub memory_pages: u64,
 }
 
 /// Helper struct to contain all the memory stats, including `MemoryAllocationStats` and, if
 /// supported by the OS, `ru_maxrss`.
 #[derive(Clone, Debug, Default, Encode, Decode)]
 pub struct MemoryStats {
-	/// Memory stats from `tikv_jemalloc_ctl`, polling-based and not very precise.
+	/// Memory stats from `tikv_jemalloc_ctl`, polling-based and not very precise. `None` if the
+	/// memory tracker failed, including if it panicked, which doesn't fail the job.
 	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
 	pub memory_tracker_stats: Option<MemoryAllocationStats>,
-	/// `ru_maxrss` from `getrusage`. `None` if an error occurred.
+	/// The peak resident set size in kilobytes. This is the peak `Rss` sampled from
+	/// `/proc/self/smaps_rollup` if the job was requested with
+	/// [`PvfPrepData::with_smaps_rollup_sampling`](crate::pvf::PvfPrepData::with_smaps_rollup_sampling)
+	/// and the file could be read, and `ru_maxrss` from `getrusage` otherwise. `None` if an error
+	/// occurred.
 	#[cfg(target_os = "linux")]
 	pub max_rss: Option<i64>,
+	/// `ru_minflt` from `getrusage`, the number of page faults serviced without any I/O activity
+	/// during the preparation job. `None` if it could not be measured.
+	#[cfg(target_os = "linux")]
+	pub minor_page_faults: Option<i64>,
+	/// `ru_majflt` from `getrusage`, the number of page faults serviced that required I/O activity
+	/// during the preparation job. `None` if it could not be measured.
+	#[cfg(target_os = "linux")]
+	pub major_page_faults: Option<i64>,
+	/// The peak amount of memory of the job that was swapped out, in bytes, as sampled by the
+	/// memory tracker. Any swapping slows the preparation down considerably. `None` if it could
+	/// not be sampled.
+	#[cfg(target_os = "linux")]
+	pub peak_swap: Option<u64>,
 	/// Peak allocation in bytes measured by tracking allocator
 	pub peak_tracked_alloc: u64,
+	/// The allocator of the job, which `memory_tracker_stats` and `peak_tracked_alloc` depend on.
+	/// This tells apart the stats of workers built with different allocators, e.g. when comparing
+	/// them on the same host. `None` if no job ran, e.g. on a cache hit.
+	pub allocator: Option<MemoryAllocator>,
+}
+
+/// The global allocator of a prepare worker. It is chosen when building the worker: jemalloc is
+/// always used on Linux, and elsewhere only with the `jemalloc-allocator` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode)]
+pub enum MemoryAllocator {
+	/// The allocator of the platform, without a memory tracker.
+	#[codec(index = 0)]
+	System,
+	/// jemalloc, whose stats the memory tracker reads.
+	#[codec(index = 1)]
+	Jemalloc,
 }
 
 /// Statistics of collected memory metrics.
@@ -70,6 +382,19 @@ pub struct MemoryAllocationStats {
 	pub resident: u64,
 	/// Total allocated memory, in bytes.
 	pub allocated: u64,
+	/// The interval at which the memory tracker polled the stats.
+	pub poll_interval: Duration,
+	/// The number of snapshots the memory tracker took.
+	pub samples: u32,
+	/// The peak `Rss` sampled from `/proc/self/smaps_rollup`, in bytes. `None` unless the sampling
+	/// was requested and the file could be read.
+	pub peak_rss: Option<u64>,
+	/// The peak `Pss` sampled from `/proc/self/smaps_rollup`, in bytes. Unlike `Rss`, this only
+	/// counts a share of the pages the job shares with other processes, e.g. the worker.
+	pub peak_pss: Option<u64>,
+	/// The peak `VmSwap` sampled from `/proc/self/status`, in bytes. `None` unless the file could
+	/// be read, which is only the case on Linux.
+	pub peak_swap: Option<u64>,
 }
 
 /// The kind of prepare job.
@@ -79,4 +404,54 @@ pub enum PrepareJobKind {
 	Compilation,
 	/// A prechecking job.
 	Prechecking,
+	/// A benchmarking job. Same as `Compilation`, but additionally collects the timings of the
+	/// individual preparation stages.
+	Benchmark,
+	/// Only prevalidates the code, without compiling it. No artifact is produced, which makes this
+	/// a cheap way to reject malformed code, e.g. in tooling.
+	ValidateOnly,
+	/// Same as `Compilation`, but compiles the code twice and fails with
+	/// [`PrepareError::NonDeterministicCompilation`](crate::error::PrepareError) if the artifacts
+	/// differ. Meant for catching nondeterminism of the compiler or toolchain.
+	VerifyDeterminism,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn memory_stats_page_faults_roundtrip() {
+		let memory_stats = MemoryStats {
+			memory_tracker_stats: None,
+			max_rss: Some(1024),
+			minor_page_faults: Some(42),
+			major_page_faults: Some(7),
+			peak_swap: Some(0),
+			peak_tracked_alloc: 2048,
+			allocator: Some(MemoryAllocator::Jemalloc),
+		};
+
+		let decoded = MemoryStats::decode(&mut &memory_stats.encode()[..]).unwrap();
+		assert_eq!(decoded.minor_page_faults, Some(42));
+		assert_eq!(decoded.major_page_faults, Some(7));
+		assert_eq!(decoded.max_rss, Some(1024));
+		assert_eq!(decoded.peak_tracked_alloc, 2048);
+		assert_eq!(decoded.allocator, Some(MemoryAllocator::Jemalloc));
+	}
+
+	#[test]
+	fn applied_security_is_a_set() {
+		let mut applied = AppliedSecurity::empty();
+		assert!(!applied.contains(AppliedSecurity::LANDLOCK));
+		assert!(applied.contains(AppliedSecurity::empty()));
+
+		applied.insert(AppliedSecurity::LANDLOCK);
+		applied.insert(AppliedSecurity::SECCOMP);
+
+		assert!(applied.contains(AppliedSecurity::LANDLOCK | AppliedSecurity::SECCOMP));
+		assert!(!applied.contains(AppliedSecurity::SECURE_CLONE));
+		assert_eq!(applied, AppliedSecurity::SECCOMP | AppliedSecurity::LANDLOCK);
+	}
 }
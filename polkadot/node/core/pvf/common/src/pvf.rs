@@ -14,12 +14,34 @@
 // You should have received a copy of the GNU General Public License
 // along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::prepare::PrepareJobKind;
+use crate::prepare::{PrepareJobKind, DEFAULT_PREPARE_WALL_CLOCK_TIMEOUT_FACTOR};
 use codec::{Decode, Encode};
 use polkadot_parachain_primitives::primitives::ValidationCodeHash;
-use polkadot_primitives::ExecutorParams;
+use polkadot_primitives::{ExecutorParams, PvfPrepKind};
 use std::{fmt, sync::Arc, time::Duration};
 
+/// How the code of a PVF is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum CodeCompression {
+	/// The code is compressed if it starts with the prefix of `sp_maybe_compressed_blob`, and is
+	/// decompressed up to the bomb limit of validation code.
+	Detect,
+	/// The code must be compressed with zstd, in the format of `sp_maybe_compressed_blob`. Code
+	/// that decompresses to more than `max_size` bytes is rejected.
+	Zstd { max_size: u64 },
+}
+
+/// How the job pipes an artifact that is sent as a whole to the worker. The response of the job
+/// is SCALE-encoded either way, and streamed artifacts have a framing of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum ArtifactFormat {
+	/// The bytes of the artifact follow the response as they are, so that they are never copied
+	/// into an encoding buffer.
+	Raw,
+	/// The artifact is SCALE-encoded, and follows the response in a frame of its own.
+	Scale,
+}
+
 /// A struct that carries the exhaustive set of data to prepare an artifact out of plain
 /// Wasm binary
 ///
@@ -36,6 +58,38 @@ pub struct PvfPrepData {
 	prep_timeout: Duration,
 	/// The kind of preparation job.
 	prep_kind: PrepareJobKind,
+	/// The limit of the address space of the preparation job, in bytes.
+	address_space_limit: Option<u64>,
+	/// The multiple of the preparation timeout after which the job is killed in wall clock time.
+	wall_clock_timeout_factor: u32,
+	/// The interval at which the worker sends heartbeats to the host while preparing.
+	heartbeat_interval: Option<Duration>,
+	/// Whether the worker sends the metrics of the job to the host along with the result.
+	worker_metrics: bool,
+	/// Whether the worker tells the host how busy it is along with the result.
+	busy_hint: bool,
+	/// Whether the code was left out and is in the code file of the worker dir instead.
+	code_in_file: bool,
+	/// How the code is compressed.
+	code_compression: CodeCompression,
+	/// Whether the job constructs the runtime from the artifact, even if it is not pre-checking.
+	runtime_construction_check: bool,
+	/// The id the worker echoes back with the result, if any.
+	request_id: Option<u64>,
+	/// Whether the memory tracker samples `/proc/self/smaps_rollup` of the job.
+	smaps_rollup_sampling: bool,
+	/// Whether the job dumps its core into the worker dir if it is killed by a signal.
+	core_dumps: bool,
+	/// Whether the job streams the artifact to the worker while compressing it.
+	artifact_streaming: bool,
+	/// How the job pipes an artifact that is not streamed.
+	artifact_format: ArtifactFormat,
+	/// Whether a panic of the job reports its backtrace.
+	panic_backtraces: bool,
+	/// Whether the executor params were left out, for the worker to use its default ones instead.
+	executor_params_omitted: bool,
+	/// Whether the host vouches for the code having passed prevalidation already.
+	code_prevalidated: bool,
 }
 
 impl PvfPrepData {
@@ -49,7 +103,219 @@ impl PvfPrepData {
 		let maybe_compressed_code = Arc::new(code);
 		let code_hash = sp_crypto_hashing::blake2_256(&maybe_compressed_code).into();
 		let executor_params = Arc::new(executor_params);
-		Self { maybe_compressed_code, code_hash, executor_params, prep_timeout, prep_kind }
+		Self {
+			maybe_compressed_code,
+			code_hash,
+			executor_params,
+			prep_timeout,
+			prep_kind,
+			address_space_limit: None,
+			wall_clock_timeout_factor: DEFAULT_PREPARE_WALL_CLOCK_TIMEOUT_FACTOR,
+			heartbeat_interval: None,
+			worker_metrics: false,
+			busy_hint: false,
+			code_in_file: false,
+			code_compression: CodeCompression::Detect,
+			runtime_construction_check: false,
+			request_id: None,
+			smaps_rollup_sampling: false,
+			core_dumps: false,
+			artifact_streaming: false,
+			artifact_format: ArtifactFormat::Raw,
+			panic_backtraces: false,
+			executor_params_omitted: false,
+			code_prevalidated: false,
+		}
+	}
+
+	/// Limits the address space of the preparation job to the given number of bytes. Allocations
+	/// beyond the limit fail the job with an out-of-memory error.
+	///
+	/// Note that the limit applies to the virtual memory of the whole job process, not only to the
+	/// memory allocated while compiling.
+	pub fn with_address_space_limit(mut self, limit: u64) -> Self {
+		self.address_space_limit = Some(limit);
+		self
+	}
+
+	/// Sets the multiple of the preparation timeout after which the job is killed, even if it did
+	/// not use up its CPU time, e.g. because it is blocked on I/O. Defaults to
+	/// [`DEFAULT_PREPARE_WALL_CLOCK_TIMEOUT_FACTOR`].
+	pub fn with_wall_clock_timeout_factor(mut self, factor: u32) -> Self {
+		self.wall_clock_timeout_factor = factor;
+		self
+	}
+
+	/// Makes the worker send a heartbeat to the host at the given interval while preparing, so
+	/// that the host can tell a busy worker from a hung one.
+	pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+		self.heartbeat_interval = Some(interval);
+		self
+	}
+
+	/// Makes the worker send a [`BusyHint`] to the host right before the result, so that the host
+	/// can back off if the worker has more requests queued than it can handle.
+	///
+	/// [`BusyHint`]: crate::prepare::BusyHint
+	pub fn with_busy_hint(mut self) -> Self {
+		self.busy_hint = true;
+		self
+	}
+
+	/// Makes the worker send the [`WorkerMetrics`] of the job to the host right before its result.
+	///
+	/// [`WorkerMetrics`]: crate::prepare::WorkerMetrics
+	pub fn with_worker_metrics(mut self) -> Self {
+		self.worker_metrics = true;
+		self
+	}
+
+	/// Tells the worker that the code is compressed with zstd and must not decompress to more than
+	/// `max_size` bytes. The worker then rejects uncompressed code, and fails with
+	/// [`PrepareError::DecompressionLimit`] if the limit is exceeded. The limit is capped at the
+	/// bomb limit of validation code.
+	///
+	/// [`PrepareError::DecompressionLimit`]: crate::error::PrepareError::DecompressionLimit
+	pub fn with_zstd_code(mut self, max_size: u64) -> Self {
+		self.code_compression = CodeCompression::Zstd { max_size };
+		self
+	}
+
+	/// Makes the job construct the runtime from the compiled artifact, as pre-checking jobs always
+	/// do, and fail with [`PrepareError::RuntimeConstruction`] if that fails. This catches
+	/// artifacts that compile but can't be instantiated before they are executed.
+	///
+	/// [`PrepareError::RuntimeConstruction`]: crate::error::PrepareError::RuntimeConstruction
+	pub fn with_runtime_construction_check(mut self) -> Self {
+		self.runtime_construction_check = true;
+		self
+	}
+
+	/// Makes the worker echo the given id back with the result, so that the host can match results
+	/// to requests without relying on their order. The id is up to the host, and is not checked
+	/// for uniqueness by the worker.
+	pub fn with_request_id(mut self, request_id: u64) -> Self {
+		self.request_id = Some(request_id);
+		self
+	}
+
+	/// Makes the memory tracker of the job sample the `Rss` and `Pss` from
+	/// `/proc/self/smaps_rollup`, which unlike `getrusage` account for shared mappings. The peak
+	/// `Rss` is then reported as the max RSS of the job. If the file can't be read, e.g. on
+	/// platforms other than Linux, the job falls back to `getrusage`.
+	pub fn with_smaps_rollup_sampling(mut self) -> Self {
+		self.smaps_rollup_sampling = true;
+		self
+	}
+
+	/// Makes the job dump its core into the worker dir if it is killed by a signal, for debugging
+	/// crashes of the compiler. The path of the core is then reported with the error, see
+	/// [`PrepareError::CoreDumped`](crate::error::PrepareError::CoreDumped).
+	///
+	/// Only supported on Linux. Whether a core is written at all is up to the kernel: a
+	/// `core_pattern` piping cores to a program, such as `systemd-coredump`, leaves nothing in the
+	/// worker dir. This also allows the job to create files in the worker dir despite landlock, so
+	/// it should only be used for debugging. Workers that don't allow core dumps ignore this.
+	pub fn with_core_dumps(mut self) -> Self {
+		self.core_dumps = true;
+		self
+	}
+
+	/// Makes the job stream the artifact to the worker in chunks as it is compressed, instead of
+	/// compressing it as a whole before sending it. The job never holds the compressed artifact
+	/// then, which pays off for very large artifacts, while the worker verifies the artifact once
+	/// it is written.
+	pub fn with_artifact_streaming(mut self) -> Self {
+		self.artifact_streaming = true;
+		self
+	}
+
+	/// Sets how the job pipes the artifact to the worker, unless it is streamed. Defaults to
+	/// [`ArtifactFormat::Raw`].
+	pub fn with_artifact_format(mut self, format: ArtifactFormat) -> Self {
+		self.artifact_format = format;
+		self
+	}
+
+	/// Makes a panic while preparing report its backtrace along with its message, in the
+	/// [`PrepareError::JobError`](crate::error::PrepareError::JobError) of the job, for debugging
+	/// crashes of the compiler. Backtraces reveal details of the worker binary, so they are not
+	/// reported by default.
+	///
+	/// Jobs running under seccomp can't symbolize backtraces, so they ignore this and only report
+	/// the message. Backtraces are thus only reported by workers that can't enable seccomp.
+	pub fn with_panic_backtraces(mut self) -> Self {
+		self.panic_backtraces = true;
+		self
+	}
+
+	/// Tells the worker that the code already passed prevalidation with the same executor params,
+	/// e.g. in an earlier job, so that the job compiles it right away. The size of the code is
+	/// still checked.
+	///
+	/// Workers only skip prevalidation for hosts they were told to trust, and prevalidate the code
+	/// as usual otherwise, see [`Self::with_prevalidation_enforced`].
+	pub fn with_prevalidated_code(mut self) -> Self {
+		self.code_prevalidated = true;
+		self
+	}
+
+	/// Undoes [`Self::with_core_dumps`], for a worker that doesn't allow its jobs to dump their
+	/// core.
+	pub fn without_core_dumps(self) -> Self {
+		Self { core_dumps: false, ..self }
+	}
+
+	/// Undoes [`Self::with_prevalidated_code`], for a worker that doesn't trust the host to vouch
+	/// for the code.
+	pub fn with_prevalidation_enforced(self) -> Self {
+		Self { code_prevalidated: false, ..self }
+	}
+
+	/// Returns the PVF without its code, for sending large code to the worker in the code file of
+	/// the worker dir instead of the request. The code hash is kept, so that the worker can check
+	/// the code it reads from the file. See [`crate::worker_dir::prepare_code`].
+	pub fn without_code(&self) -> Self {
+		Self { maybe_compressed_code: Arc::new(Vec::new()), code_in_file: true, ..self.clone() }
+	}
+
+	/// Puts back the code left out by [`Self::without_code`]. Returns `None` if the code doesn't
+	/// match the code hash.
+	pub fn with_code(self, code: Vec<u8>) -> Option<Self> {
+		let code_hash: ValidationCodeHash = sp_crypto_hashing::blake2_256(&code).into();
+		(code_hash == self.code_hash).then(|| Self {
+			maybe_compressed_code: Arc::new(code),
+			code_in_file: false,
+			..self
+		})
+	}
+
+	/// Returns whether the code was left out and is in the code file of the worker dir instead.
+	pub fn code_in_file(&self) -> bool {
+		self.code_in_file
+	}
+
+	/// Returns the PVF without its executor params, for a worker that was given them as its
+	/// default with
+	/// [`PrepareWorkerRequest::SetDefaultExecutorParams`](crate::prepare::PrepareWorkerRequest::SetDefaultExecutorParams).
+	/// This spares sending the same params with every request.
+	pub fn without_executor_params(&self) -> Self {
+		Self {
+			executor_params: Arc::new(ExecutorParams::default()),
+			executor_params_omitted: true,
+			..self.clone()
+		}
+	}
+
+	/// Puts in the executor params left out by [`Self::without_executor_params`].
+	pub fn with_executor_params(self, executor_params: Arc<ExecutorParams>) -> Self {
+		Self { executor_params, executor_params_omitted: false, ..self }
+	}
+
+	/// Returns whether the executor params were left out, for the worker to use its default ones
+	/// instead.
+	pub fn executor_params_omitted(&self) -> bool {
+		self.executor_params_omitted
 	}
 
 	/// Returns validation code hash
@@ -62,6 +328,17 @@ impl PvfPrepData {
 		self.maybe_compressed_code.clone()
 	}
 
+	/// Returns whether the job should construct the runtime from the artifact, regardless of its
+	/// kind.
+	pub fn runtime_construction_check(&self) -> bool {
+		self.runtime_construction_check
+	}
+
+	/// Returns how the code is compressed.
+	pub fn code_compression(&self) -> CodeCompression {
+		self.code_compression
+	}
+
 	/// Returns executor params
 	pub fn executor_params(&self) -> Arc<ExecutorParams> {
 		self.executor_params.clone()
@@ -72,11 +349,92 @@ impl PvfPrepData {
 		self.prep_timeout
 	}
 
+	/// Returns the CPU time limit of the job. The executor params may set separate timeouts for
+	/// pre-checking and compilation, as a compilation may legitimately take much longer than a
+	/// pre-check should, and may scale them with the size of the code, see
+	/// [`ExecutorParam::PvfPrepTimeoutScaling`](polkadot_primitives::ExecutorParam::PvfPrepTimeoutScaling).
+	/// Falls back to [`Self::prep_timeout`] if there is none for the job kind.
+	///
+	/// Both the host and the worker enforce this limit, so they must not derive it separately.
+	pub fn cpu_timeout(&self) -> Duration {
+		let prep_kind = match self.prep_kind {
+			PrepareJobKind::Prechecking | PrepareJobKind::ValidateOnly => PvfPrepKind::Precheck,
+			PrepareJobKind::Compilation |
+			PrepareJobKind::Benchmark |
+			PrepareJobKind::VerifyDeterminism => PvfPrepKind::Prepare,
+		};
+		let code_size = self.maybe_compressed_code.len() as u64;
+		self.executor_params
+			.pvf_prep_timeout_for_code_size(prep_kind, code_size)
+			.or_else(|| self.executor_params.pvf_prep_timeout(prep_kind))
+			.unwrap_or(self.prep_timeout)
+	}
+
+	/// Returns the preparation timeout in wall clock time.
+	pub fn prep_wall_clock_timeout(&self) -> Duration {
+		self.cpu_timeout().saturating_mul(self.wall_clock_timeout_factor)
+	}
+
+	/// Returns the heartbeat interval, if heartbeats were requested.
+	pub fn heartbeat_interval(&self) -> Option<Duration> {
+		self.heartbeat_interval
+	}
+
+	/// Returns whether the worker should send the metrics of the job to the host.
+	pub fn worker_metrics(&self) -> bool {
+		self.worker_metrics
+	}
+
+	/// Returns whether the worker should tell the host how busy it is.
+	pub fn busy_hint(&self) -> bool {
+		self.busy_hint
+	}
+
+	/// Returns the id to echo back with the result, if any.
+	pub fn request_id(&self) -> Option<u64> {
+		self.request_id
+	}
+
+	/// Returns whether the memory tracker should sample `/proc/self/smaps_rollup`.
+	pub fn smaps_rollup_sampling(&self) -> bool {
+		self.smaps_rollup_sampling
+	}
+
+	/// Returns whether the job should dump its core if it is killed by a signal.
+	pub fn core_dumps(&self) -> bool {
+		self.core_dumps
+	}
+
+	/// Returns whether the job should stream the artifact while compressing it.
+	pub fn artifact_streaming(&self) -> bool {
+		self.artifact_streaming
+	}
+
+	/// Returns how the job should pipe an artifact that is not streamed.
+	pub fn artifact_format(&self) -> ArtifactFormat {
+		self.artifact_format
+	}
+
+	/// Returns whether a panic of the job should report its backtrace.
+	pub fn panic_backtraces(&self) -> bool {
+		self.panic_backtraces
+	}
+
+	/// Returns whether the host vouches for the code having passed prevalidation already.
+	pub fn code_prevalidated(&self) -> bool {
+		self.code_prevalidated
+	}
+
 	/// Returns preparation kind.
 	pub fn prep_kind(&self) -> PrepareJobKind {
 		self.prep_kind
 	}
 
+	/// Returns the address space limit of the preparation job, if any.
+	pub fn address_space_limit(&self) -> Option<u64> {
+		self.address_space_limit
+	}
+
 	/// Creates a structure for tests.
 	#[cfg(feature = "test-utils")]
 	pub fn from_discriminator_and_timeout(num: u32, timeout: Duration) -> Self {
@@ -24,26 +24,45 @@ use crate::{
 use codec::{Decode, Encode};
 use cpu_time::ProcessTime;
 use futures::never::Never;
-use nix::{errno::Errno, sys::resource::Usage};
+use nix::{
+	errno::Errno,
+	sys::{
+		resource::Usage,
+		signal::{sigaction, signal, SaFlags, SigAction, SigHandler, SigSet, Signal},
+		time::TimeVal,
+	},
+};
 use std::{
 	any::Any,
+	ffi::{OsStr, OsString},
 	fmt::{self},
 	fs::File,
 	io::{self, Read, Write},
+	mem,
 	os::{
-		fd::{AsRawFd, FromRawFd, RawFd},
-		unix::net::UnixStream,
+		fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+		unix::{ffi::OsStrExt, net::UnixStream},
 	},
-	path::PathBuf,
-	sync::mpsc::{Receiver, RecvTimeoutError},
-	time::Duration,
+	path::{Path, PathBuf},
+	ptr,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		mpsc::{Receiver, RecvTimeoutError},
+	},
+	time::{Duration, Instant},
 };
 
 /// Use this macro to declare a `fn main() {}` that will create an executable that can be used for
 /// spawning the desired worker.
+///
+/// The entrypoint is called with the socket path, the worker dir path, the node and worker versions
+/// and the idle timeout. A worker with options of its own passes their type as `args = <type>`,
+/// see [`WorkerArgs`](crate::worker::WorkerArgs), and its entrypoint gets them as an additional
+/// last argument.
 #[macro_export]
 macro_rules! decl_worker_main {
-	($expected_command:expr, $entrypoint:expr, $worker_version:expr, $worker_version_hash:expr $(,)*) => {
+	($expected_command:expr, $entrypoint:expr, args = $worker_args:ty, $worker_version:expr, $worker_version_hash:expr $(,)*) => {
 		fn get_full_version() -> String {
 			format!("{}-{}", $worker_version, $worker_version_hash)
 		}
@@ -141,6 +160,20 @@ macro_rules! decl_worker_main {
 					std::process::exit(status)
 				},
 
+				"--check-can-unshare-network-and-mount-namespaces" => {
+					#[cfg(target_os = "linux")]
+					let status = if let Err(err) = security::unshare::check_can_fully_enable() {
+						// Write the error to stderr, log it on the host-side.
+						eprintln!("{}", err);
+						-1
+					} else {
+						0
+					};
+					#[cfg(not(target_os = "linux"))]
+					let status = -1;
+					std::process::exit(status)
+				},
+
 				"test-sleep" => {
 					std::thread::sleep(std::time::Duration::from_secs(5));
 					return
@@ -160,36 +193,283 @@ macro_rules! decl_worker_main {
 			let mut socket_path = None;
 			let mut worker_dir_path = None;
 			let mut node_version = None;
-
-			let mut i = 2;
-			while i < args.len() {
-				match args[i].as_ref() {
-					"--socket-path" => {
-						socket_path = Some(args[i + 1].as_str());
-						i += 1
-					},
-					"--worker-dir-path" => {
-						worker_dir_path = Some(args[i + 1].as_str());
-						i += 1
+			let mut idle_timeout = None;
+			let mut worker_args = <$worker_args as Default>::default();
+
+			let mut args = args[2..].iter().map(String::as_str);
+			while let Some(arg) = args.next() {
+				match arg {
+					"--socket-path" => socket_path = args.next(),
+					"--worker-dir-path" => worker_dir_path = args.next(),
+					"--node-impl-version" => node_version = args.next(),
+					"--idle-timeout-secs" => {
+						let secs: u64 = args
+							.next()
+							.and_then(|secs| secs.parse().ok())
+							.expect("the --idle-timeout-secs argument must be a number of seconds");
+						idle_timeout = Some(std::time::Duration::from_secs(secs));
 					},
-					"--node-impl-version" => {
-						node_version = Some(args[i + 1].as_str());
-						i += 1
-					},
-					arg => panic!("Unexpected argument found: {}", arg),
+					arg =>
+						if !$crate::worker::WorkerArgs::parse_arg(&mut worker_args, arg, &mut args)
+						{
+							panic!("Unexpected argument found: {}", arg)
+						},
 				}
-				i += 1;
 			}
 			let socket_path = socket_path.expect("the --socket-path argument is required");
 			let worker_dir_path =
 				worker_dir_path.expect("the --worker-dir-path argument is required");
 
-			let socket_path = std::path::Path::new(socket_path).to_owned();
+			let socket_path = $crate::worker::socket_path_from_arg(socket_path);
 			let worker_dir_path = std::path::Path::new(worker_dir_path).to_owned();
 
-			$entrypoint(socket_path, worker_dir_path, node_version, Some($worker_version));
+			$entrypoint(
+				socket_path,
+				worker_dir_path,
+				node_version,
+				Some($worker_version),
+				idle_timeout,
+				worker_args,
+			);
+		}
+	};
+	($expected_command:expr, $entrypoint:expr, $worker_version:expr, $worker_version_hash:expr $(,)*) => {
+		$crate::decl_worker_main!(
+			$expected_command,
+			|socket_path, worker_dir_path, node_version, worker_version, idle_timeout, ()| {
+				$entrypoint(
+					socket_path,
+					worker_dir_path,
+					node_version,
+					worker_version,
+					idle_timeout,
+				)
+			},
+			args = (),
+			$worker_version,
+			$worker_version_hash,
+		);
+	};
+}
+
+/// The command-line options of one kind of worker, which [`decl_worker_main!`] parses in addition
+/// to the ones all workers take.
+pub trait WorkerArgs: Default {
+	/// Parses the argument `arg`, taking its value from `values` if it has one. Returns `false` if
+	/// the worker has no such option.
+	fn parse_arg<'a>(&mut self, arg: &str, values: &mut impl Iterator<Item = &'a str>) -> bool;
+}
+
+/// For workers without options of their own.
+impl WorkerArgs for () {
+	fn parse_arg<'a>(&mut self, _arg: &str, _values: &mut impl Iterator<Item = &'a str>) -> bool {
+		false
+	}
+}
+
+/// Marks an address in the abstract socket namespace on the command line, where the leading NUL
+/// byte of such an address can't be passed.
+pub const ABSTRACT_SOCKET_ARG_PREFIX: &str = "@";
+
+/// Converts the `--socket-path` argument into the path of the socket the worker connects to. An
+/// argument starting with [`ABSTRACT_SOCKET_ARG_PREFIX`] is an abstract address, which gets its
+/// leading NUL byte back.
+///
+/// Note that this also applies to a relative path whose first component starts with `@`: it is
+/// reinterpreted as an abstract address instead of a file in the current directory. Pass such a
+/// socket file as `./@name` instead.
+pub fn socket_path_from_arg(arg: &str) -> PathBuf {
+	match arg.strip_prefix(ABSTRACT_SOCKET_ARG_PREFIX) {
+		Some(name) => {
+			let mut path = OsString::from("\0");
+			path.push(name);
+			path.into()
+		},
+		None => PathBuf::from(arg),
+	}
+}
+
+/// Converts the path of a socket into the `--socket-path` argument, see [`socket_path_from_arg`].
+pub fn socket_path_to_arg(socket_path: &Path) -> OsString {
+	match socket_path.as_os_str().as_bytes().strip_prefix(b"\0") {
+		Some(name) => {
+			let mut arg = OsString::from(ABSTRACT_SOCKET_ARG_PREFIX);
+			arg.push(OsStr::from_bytes(name));
+			arg
+		},
+		None => socket_path.as_os_str().to_owned(),
+	}
+}
+
+/// Connects to the host over the socket at the given path.
+///
+/// On Linux, a path starting with a NUL byte is an address in the abstract socket namespace, see
+/// `unix(7)`. Such a socket has no file, so a crashed worker or host can't leave a stale one
+/// behind. Otherwise the socket file is removed once connected, as it is not needed anymore.
+///
+/// An abstract address is not protected by filesystem permissions, nor by landlock: any process in
+/// the same network namespace may bind or connect to it. The host must therefore pick a name
+/// nothing else can predict, and only a host that trusts all the processes of its network
+/// namespace should use one. As the address belongs to the network namespace, it also can't be
+/// reached from a process cloned into a new one, so it is rejected under secure clone, see
+/// [`check_socket_path`].
+pub fn connect_to_host(socket_path: &Path) -> io::Result<UnixStream> {
+	#[cfg(target_os = "linux")]
+	if let Some(name) = socket_path.as_os_str().as_bytes().strip_prefix(b"\0") {
+		use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+		return UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)
+	}
+
+	let stream = UnixStream::connect(socket_path)?;
+	let _ = std::fs::remove_file(socket_path);
+	Ok(stream)
+}
+
+/// Returns whether the path of a socket is an address in the abstract socket namespace, see
+/// [`connect_to_host`].
+pub fn is_abstract_socket_path(socket_path: &Path) -> bool {
+	socket_path.as_os_str().as_bytes().starts_with(b"\0")
+}
+
+/// Checks that the socket of the host can be used with the given security status. An abstract
+/// address lives in the network namespace of the host, which the processes of a worker in secure
+/// clone mode don't share, and bypasses the filesystem sandbox, so the two can't be combined.
+pub fn check_socket_path(
+	socket_path: &Path,
+	security_status: &SecurityStatus,
+) -> Result<(), String> {
+	if is_abstract_socket_path(socket_path) && security_status.can_do_secure_clone {
+		return Err(format!(
+			"the abstract socket address {:?} can't be used with secure clone, use a socket file \
+			 instead",
+			socket_path
+		))
+	}
+	Ok(())
+}
+
+/// Sends a file descriptor over a Unix socket, as `SCM_RIGHTS` ancillary data of a single byte.
+/// The receiving end gets its own descriptor of the same file with [`recv_fd`], while `fd` stays
+/// open here.
+///
+/// Works for non-blocking sockets too, by waiting for the socket to become writable.
+pub fn send_fd(socket: &impl AsFd, fd: BorrowedFd) -> io::Result<()> {
+	let socket = socket.as_fd().as_raw_fd();
+	let mut byte = [0u8];
+	let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: byte.len() };
+	let mut control = FdControlBuffer::new();
+	let msg = control.msghdr(&mut iov);
+	// SAFETY: the control buffer is aligned and large enough for a header with a single fd.
+	unsafe {
+		let cmsg = libc::CMSG_FIRSTHDR(&msg);
+		(*cmsg).cmsg_level = libc::SOL_SOCKET;
+		(*cmsg).cmsg_type = libc::SCM_RIGHTS;
+		(*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+		ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), fd.as_raw_fd());
+	}
+
+	loop {
+		// SAFETY: `msg` and the buffers it points to are valid for the duration of the call.
+		if unsafe { libc::sendmsg(socket, &msg, 0) } >= 0 {
+			return Ok(())
+		}
+		let err = io::Error::last_os_error();
+		match err.kind() {
+			io::ErrorKind::Interrupted => {},
+			io::ErrorKind::WouldBlock => {
+				let mut pollfd = libc::pollfd { fd: socket, events: libc::POLLOUT, revents: 0 };
+				// SAFETY: a single valid `pollfd` is passed.
+				if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+					let err = io::Error::last_os_error();
+					if err.kind() != io::ErrorKind::Interrupted {
+						return Err(err)
+					}
+				}
+			},
+			_ => return Err(err),
 		}
+	}
+}
+
+/// Receives a file descriptor sent with [`send_fd`]. Fails if the byte it was sent with carries
+/// anything but a single file descriptor. On Linux, the descriptor is received close-on-exec.
+pub fn recv_fd(socket: &impl AsFd) -> io::Result<OwnedFd> {
+	let socket = socket.as_fd().as_raw_fd();
+	let mut byte = [0u8];
+	let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: byte.len() };
+	let mut control = FdControlBuffer::new();
+	let mut msg = control.msghdr(&mut iov);
+	#[cfg(target_os = "linux")]
+	let flags = libc::MSG_CMSG_CLOEXEC;
+	#[cfg(not(target_os = "linux"))]
+	let flags = 0;
+
+	let received = loop {
+		// SAFETY: `msg` and the buffers it points to are valid for the duration of the call.
+		let received = unsafe { libc::recvmsg(socket, &mut msg, flags) };
+		if received >= 0 {
+			break received
+		}
+		let err = io::Error::last_os_error();
+		if err.kind() != io::ErrorKind::Interrupted {
+			return Err(err)
+		}
+	};
+	if received == 0 {
+		return Err(io::Error::new(
+			io::ErrorKind::UnexpectedEof,
+			"the socket was closed before a file descriptor was received",
+		))
+	}
+
+	// SAFETY: the kernel filled in the control buffer up to `msg_controllen`. A header of the
+	// expected length carries a single fd, which is owned by this process from now on.
+	let fd = unsafe {
+		let cmsg = libc::CMSG_FIRSTHDR(&msg);
+		let single_fd = !cmsg.is_null() &&
+			(*cmsg).cmsg_level == libc::SOL_SOCKET &&
+			(*cmsg).cmsg_type == libc::SCM_RIGHTS &&
+			(*cmsg).cmsg_len as usize == libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as usize;
+		single_fd.then(|| {
+			OwnedFd::from_raw_fd(ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>()))
+		})
 	};
+	// Fds that didn't fit into the buffer were closed by the kernel, and the one received is closed
+	// on drop.
+	match fd {
+		Some(fd) if msg.msg_flags & libc::MSG_CTRUNC == 0 => Ok(fd),
+		_ => Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			"expected a single file descriptor along with the byte received",
+		)),
+	}
+}
+
+/// Room for a control message carrying a single file descriptor, aligned for `cmsghdr`.
+struct FdControlBuffer {
+	buf: [u64; 4],
+	len: usize,
+}
+
+impl FdControlBuffer {
+	fn new() -> Self {
+		// SAFETY: `CMSG_SPACE` only computes a size.
+		let len = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+		assert!(len <= mem::size_of::<[u64; 4]>(), "a control message with a single fd is small");
+		Self { buf: [0; 4], len }
+	}
+
+	/// A message of the given single byte, with this buffer for its ancillary data.
+	fn msghdr(&mut self, iov: &mut libc::iovec) -> libc::msghdr {
+		// SAFETY: an all-zero `msghdr` is valid, it describes an empty message.
+		let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+		msg.msg_iov = iov;
+		msg.msg_iovlen = 1;
+		msg.msg_control = self.buf.as_mut_ptr().cast();
+		msg.msg_controllen = self.len as _;
+		msg
+	}
 }
 
 //taken from the os_pipe crate. Copied here to reduce one dependency and
@@ -274,6 +554,49 @@ impl Write for PipeFd {
 /// child process.
 pub const JOB_TIMEOUT_OVERHEAD: Duration = Duration::from_millis(50);
 
+/// The scheduling priority of the jobs of a worker, so that preparing in the background doesn't
+/// compete with the validator for CPU and disk. Unset parts are inherited from the worker, as
+/// before.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JobPriority {
+	/// The nice value of the jobs, from -20 to 19. Only privileged workers may go below their own
+	/// nice value.
+	pub nice: Option<i32>,
+	/// The IO scheduling class of the jobs.
+	pub io_priority: Option<IoPriority>,
+}
+
+/// An IO scheduling class and the level within it, see `ioprio_set(2)`. The realtime class is left
+/// out, as it needs privileges and would starve the rest of the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+	/// The default class, with levels from 0 (highest) to 7 (lowest).
+	BestEffort(u8),
+	/// Only gets disk time when no other process needs it.
+	Idle,
+}
+
+impl IoPriority {
+	/// The lowest level of the best-effort class.
+	pub const LOWEST_BEST_EFFORT_LEVEL: u8 = 7;
+}
+
+impl FromStr for IoPriority {
+	type Err = String;
+
+	/// Parses `idle`, or `best-effort:<level>`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.split_once(':') {
+			None if s == "idle" => Ok(Self::Idle),
+			Some(("best-effort", level)) => match level.parse() {
+				Ok(level) if level <= Self::LOWEST_BEST_EFFORT_LEVEL => Ok(Self::BestEffort(level)),
+				_ => Err(format!("invalid best-effort level: {}", level)),
+			},
+			_ => Err(format!("invalid IO priority: {}", s)),
+		}
+	}
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum WorkerKind {
 	Prepare,
@@ -313,6 +636,38 @@ pub fn run_worker<F>(
 	worker_dir_path: PathBuf,
 	node_version: Option<&str>,
 	worker_version: Option<&str>,
+	event_loop: F,
+) where
+	F: FnMut(UnixStream, &WorkerInfo, SecurityStatus) -> io::Result<Never>,
+{
+	run_worker_with_options(
+		worker_kind,
+		socket_path,
+		worker_dir_path,
+		node_version,
+		worker_version,
+		RunWorkerOptions::default(),
+		event_loop,
+	)
+}
+
+/// Loosens the sandbox [`run_worker`] sets up, for workers that need more than the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RunWorkerOptions {
+	/// Whether the jobs of the worker may dump their core into the worker dir. With landlock, this
+	/// lets the worker create files in the worker dir, see
+	/// [`security::landlock::enable_for_worker`].
+	pub allow_core_dumps: bool,
+}
+
+/// Like [`run_worker`], but with the given options.
+pub fn run_worker_with_options<F>(
+	worker_kind: WorkerKind,
+	socket_path: PathBuf,
+	worker_dir_path: PathBuf,
+	node_version: Option<&str>,
+	worker_version: Option<&str>,
+	#[cfg_attr(not(target_os = "linux"), allow(unused_variables))] options: RunWorkerOptions,
 	mut event_loop: F,
 ) where
 	F: FnMut(UnixStream, &WorkerInfo, SecurityStatus) -> io::Result<Never>,
@@ -335,14 +690,7 @@ pub fn run_worker<F>(
 	// Check for a mismatch between the node and worker versions.
 	if let (Some(node_version), Some(worker_version)) = (node_version, &worker_info.version) {
 		if node_version != worker_version {
-			gum::error!(
-				target: LOG_TARGET,
-				?worker_info,
-				%node_version,
-				"Node and worker version mismatch, node needs restarting, forcing shutdown",
-			);
-			kill_parent_node_in_emergency();
-			worker_shutdown(worker_info, "Version mismatch");
+			worker_shutdown_version_mismatch(&worker_info, node_version);
 		}
 	}
 
@@ -359,12 +707,7 @@ pub fn run_worker<F>(
 	}
 
 	// Connect to the socket.
-	let stream = || -> io::Result<UnixStream> {
-		let stream = UnixStream::connect(&socket_path)?;
-		let _ = std::fs::remove_file(&socket_path);
-		Ok(stream)
-	}();
-	let mut stream = match stream {
+	let mut stream = match connect_to_host(&socket_path) {
 		Ok(ok) => ok,
 		Err(err) => worker_shutdown_error(worker_info, &err.to_string()),
 	};
@@ -373,6 +716,9 @@ pub fn run_worker<F>(
 		Ok(ok) => ok,
 		Err(err) => worker_shutdown_error(worker_info, &err.to_string()),
 	};
+	if let Err(err) = check_socket_path(&socket_path, &security_status) {
+		worker_shutdown_error(worker_info, &err);
+	}
 
 	// Enable some security features.
 	{
@@ -412,7 +758,9 @@ pub fn run_worker<F>(
 
 		#[cfg(target_os = "linux")]
 		if security_status.can_enable_landlock {
-			if let Err(err) = security::landlock::enable_for_worker(&worker_info) {
+			if let Err(err) =
+				security::landlock::enable_for_worker(&worker_info, options.allow_core_dumps)
+			{
 				// We previously were able to enable, so this should never happen. Shutdown if
 				// running in secure mode.
 				let err = format!("could not fully enable landlock: {:?}", err);
@@ -457,6 +805,115 @@ pub fn run_worker<F>(
 	worker_shutdown(worker_info, &err.to_string());
 }
 
+/// Set by the `SIGTERM` handler installed by [`enable_graceful_shutdown`].
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often [`wait_for_request`] checks whether a shutdown was requested, in milliseconds. This
+/// bounds the delay if the signal arrives right before polling.
+const SHUTDOWN_POLL_INTERVAL_MS: libc::c_int = 500;
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+	SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a `SIGTERM` handler which only records that a shutdown was requested, so that the
+/// worker can finish its current job and exit cleanly in between jobs.
+pub fn enable_graceful_shutdown() -> nix::Result<()> {
+	// Restart interrupted syscalls, so that the current job is not affected by the signal.
+	let action =
+		SigAction::new(SigHandler::Handler(request_shutdown), SaFlags::SA_RESTART, SigSet::empty());
+	// SAFETY: The handler only does an atomic store, which is async-signal-safe.
+	unsafe { sigaction(Signal::SIGTERM, &action) }.map(|_| ())
+}
+
+/// Restores the default `SIGTERM` disposition. Job processes inherit the handler installed by
+/// [`enable_graceful_shutdown`], but should be terminated by the signal like any other process.
+pub fn disable_graceful_shutdown() -> nix::Result<()> {
+	// SAFETY: Restoring the default disposition does not install any handler.
+	unsafe { signal(Signal::SIGTERM, SigHandler::SigDfl) }.map(|_| ())
+}
+
+/// Returns whether the worker received `SIGTERM` since [`enable_graceful_shutdown`] was called.
+pub fn shutdown_requested() -> bool {
+	SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// What [`wait_for_request`] stopped waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestWait {
+	/// The host sent something on the stream.
+	Request,
+	/// A shutdown was requested, see [`enable_graceful_shutdown`].
+	Shutdown,
+	/// Nothing arrived within the idle timeout.
+	Idle,
+}
+
+/// Blocks until the host sent something on the stream, a shutdown was requested, or `idle_timeout`
+/// passed without either.
+pub fn wait_for_request(
+	stream: &UnixStream,
+	idle_timeout: Option<Duration>,
+) -> io::Result<RequestWait> {
+	let idle_deadline = idle_timeout.map(|timeout| Instant::now() + timeout);
+	loop {
+		if shutdown_requested() {
+			return Ok(RequestWait::Shutdown)
+		}
+
+		let poll_timeout = match idle_deadline {
+			Some(deadline) => {
+				let remaining = deadline.saturating_duration_since(Instant::now());
+				if remaining.is_zero() {
+					return Ok(RequestWait::Idle)
+				}
+				remaining.as_millis().clamp(1, SHUTDOWN_POLL_INTERVAL_MS as u128) as libc::c_int
+			},
+			None => SHUTDOWN_POLL_INTERVAL_MS,
+		};
+
+		let mut poll_fd = libc::pollfd { fd: stream.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+		// SAFETY: `poll_fd` is a single valid `pollfd` structure, matching the count of 1.
+		match unsafe { libc::poll(&mut poll_fd, 1, poll_timeout) } {
+			-1 => {
+				let err = io::Error::last_os_error();
+				// `poll` is never restarted, so this is how we learn about the signal.
+				if err.kind() != io::ErrorKind::Interrupted {
+					return Err(err)
+				}
+			},
+			0 => {},
+			_ => return Ok(RequestWait::Request),
+		}
+	}
+}
+
+/// Exits the worker after a requested shutdown.
+pub fn worker_shutdown_gracefully(worker_info: &WorkerInfo) -> ! {
+	gum::debug!(target: LOG_TARGET, ?worker_info, "quitting pvf worker ({}) on request", worker_info.kind);
+	std::process::exit(0);
+}
+
+/// Exits the worker after it received no request within its idle timeout, so that the host can
+/// reclaim its slot.
+pub fn worker_shutdown_idle(worker_info: &WorkerInfo) -> ! {
+	gum::debug!(target: LOG_TARGET, ?worker_info, "quitting idle pvf worker ({})", worker_info.kind);
+	std::process::exit(0);
+}
+
+/// Tears down the node and exits the worker, after finding that the node runs another version than
+/// the worker, see [`kill_parent_node_in_emergency`].
+pub fn worker_shutdown_version_mismatch(worker_info: &WorkerInfo, node_version: &str) -> ! {
+	gum::error!(
+		target: LOG_TARGET,
+		?worker_info,
+		%node_version,
+		"Node and worker version mismatch, node needs restarting, forcing shutdown",
+	);
+	kill_parent_node_in_emergency();
+	std::process::exit(1);
+}
+
 /// Provide a consistent message on unexpected worker shutdown.
 fn worker_shutdown(worker_info: WorkerInfo, err: &str) -> ! {
 	gum::warn!(target: LOG_TARGET, ?worker_info, "quitting pvf worker ({}): {}", worker_info.kind, err);
@@ -561,10 +1018,30 @@ fn recv_worker_handshake(stream: &mut UnixStream) -> io::Result<WorkerHandshake>
 ///
 /// Returns a `Duration` representing the total CPU time.
 pub fn get_total_cpu_usage(rusage: Usage) -> Duration {
-	let micros = (((rusage.user_time().tv_sec() + rusage.system_time().tv_sec()) * 1_000_000) +
-		(rusage.system_time().tv_usec() + rusage.user_time().tv_usec()) as i64) as u64;
+	// Accumulate everything in microseconds, so that `tv_usec` sums exceeding one second are
+	// carried over into the total instead of being truncated.
+	fn timeval_micros(tv: TimeVal) -> i64 {
+		i64::from(tv.tv_sec())
+			.saturating_mul(1_000_000)
+			.saturating_add(i64::from(tv.tv_usec()))
+	}
+
+	let micros =
+		timeval_micros(rusage.user_time()).saturating_add(timeval_micros(rusage.system_time()));
+
+	// The kernel never reports negative times, but make sure we don't wrap around if it does.
+	Duration::from_micros(micros.max(0) as u64)
+}
 
-	return Duration::from_micros(micros)
+/// Calculate the CPU time spent by a job process, given the resource usage of all terminated child
+/// processes before and after the job.
+///
+/// As `getrusage` with `RUSAGE_CHILDREN` returns the accumulated usage of all terminated child
+/// processes, the usage from before the job has to be subtracted to isolate the job's CPU time.
+/// The result keeps microsecond resolution, so that jobs finishing in under a second are not
+/// reported as having taken no time at all.
+pub fn get_cpu_time_elapsed(usage_before: Usage, usage_after: Usage) -> Duration {
+	get_total_cpu_usage(usage_after).saturating_sub(get_total_cpu_usage(usage_before))
 }
 
 /// Get a job response.
@@ -593,6 +1070,21 @@ where
 	T: std::fmt::Debug,
 	E: std::fmt::Debug + std::fmt::Display,
 	Result<T, E>: Encode,
+{
+	send_result_with(stream, result, worker_info, |result| result.encode())
+}
+
+/// Like [`send_result`], but encodes the result with the given function, e.g. to wrap it in a
+/// message type.
+pub fn send_result_with<T, E>(
+	stream: &mut UnixStream,
+	result: Result<T, E>,
+	worker_info: &WorkerInfo,
+	encode: impl FnOnce(Result<T, E>) -> Vec<u8>,
+) -> io::Result<()>
+where
+	T: std::fmt::Debug,
+	E: std::fmt::Debug + std::fmt::Display,
 {
 	if let Err(ref err) = result {
 		gum::warn!(
@@ -609,7 +1101,7 @@ where
 		result
 	);
 
-	framed_send_blocking(stream, &result.encode()).map_err(|err| {
+	framed_send_blocking(stream, &encode(result)).map_err(|err| {
 		gum::warn!(
 			target: LOG_TARGET,
 			?worker_info,
@@ -815,8 +1307,151 @@ pub mod thread {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use nix::sys::resource::{getrusage, UsageWho};
 	use std::sync::mpsc::channel;
 
+	/// Builds a `Usage` with the given `(tv_sec, tv_usec)` pairs for user and system time.
+	fn usage_with_times(user: (i64, i64), system: (i64, i64)) -> Usage {
+		let mut usage = getrusage(UsageWho::RUSAGE_SELF).unwrap();
+		let rusage: &mut libc::rusage = usage.as_mut();
+		rusage.ru_utime = libc::timeval { tv_sec: user.0 as _, tv_usec: user.1 as _ };
+		rusage.ru_stime = libc::timeval { tv_sec: system.0 as _, tv_usec: system.1 as _ };
+		usage
+	}
+
+	#[test]
+	fn cpu_time_elapsed_keeps_sub_second_precision() {
+		let usage_before = usage_with_times((0, 100), (0, 200));
+		let usage_after = usage_with_times((0, 450), (0, 350));
+
+		let elapsed = get_cpu_time_elapsed(usage_before, usage_after);
+		assert!(!elapsed.is_zero());
+		assert_eq!(elapsed, Duration::from_micros(500));
+	}
+
+	#[test]
+	fn total_cpu_usage_carries_microseconds() {
+		// `tv_usec` sums that exceed a second must be carried over into the total.
+		let cases = [
+			((0, 600_000), (0, 600_000), Duration::from_micros(1_200_000)),
+			((1, 999_999), (2, 500_000), Duration::from_micros(4_499_999)),
+			((3, 999_999), (0, 1), Duration::from_secs(4)),
+			((0, 0), (0, 999_999), Duration::from_micros(999_999)),
+		];
+
+		for (user, system, expected) in cases {
+			assert_eq!(get_total_cpu_usage(usage_with_times(user, system)), expected);
+		}
+	}
+
+	#[test]
+	fn cpu_time_elapsed_does_not_underflow() {
+		let usage_before = usage_with_times((1, 0), (0, 0));
+		let usage_after = usage_with_times((0, 0), (0, 0));
+
+		assert_eq!(get_cpu_time_elapsed(usage_before, usage_after), Duration::ZERO);
+	}
+
+	#[test]
+	fn wait_for_request_times_out_when_idle() {
+		let (stream, _host) = UnixStream::pair().unwrap();
+
+		let wait = wait_for_request(&stream, Some(Duration::from_millis(50))).unwrap();
+		assert_eq!(wait, RequestWait::Idle);
+	}
+
+	#[test]
+	fn wait_for_request_returns_pending_requests_before_the_idle_timeout() {
+		let (stream, mut host) = UnixStream::pair().unwrap();
+		host.write_all(&[0]).unwrap();
+
+		let wait = wait_for_request(&stream, Some(Duration::from_secs(10))).unwrap();
+		assert_eq!(wait, RequestWait::Request);
+	}
+
+	#[test]
+	fn io_priority_parses_the_supported_classes() {
+		assert_eq!("idle".parse(), Ok(IoPriority::Idle));
+		assert_eq!("best-effort:0".parse(), Ok(IoPriority::BestEffort(0)));
+		assert_eq!("best-effort:7".parse(), Ok(IoPriority::BestEffort(7)));
+
+		for invalid in ["best-effort:8", "best-effort", "realtime:0", "idle:0", ""] {
+			assert!(invalid.parse::<IoPriority>().is_err(), "{invalid} should not parse");
+		}
+	}
+
+	#[test]
+	fn socket_path_survives_the_command_line() {
+		for socket_path in [PathBuf::from("/tmp/pvf-host-prepare-abc"), PathBuf::from("\0pvf-abc")]
+		{
+			let arg = socket_path_to_arg(&socket_path);
+			assert_eq!(socket_path_from_arg(arg.to_str().unwrap()), socket_path);
+		}
+		assert_eq!(socket_path_to_arg(Path::new("\0pvf-abc")), "@pvf-abc");
+	}
+
+	#[test]
+	fn abstract_socket_is_rejected_under_secure_clone() {
+		let abstract_path = socket_path_from_arg("@pvf-abc");
+		let file_path = socket_path_from_arg("./@pvf-abc");
+		let secure_clone = SecurityStatus { can_do_secure_clone: true, ..Default::default() };
+
+		assert!(is_abstract_socket_path(&abstract_path));
+		assert!(!is_abstract_socket_path(&file_path));
+		assert!(check_socket_path(&abstract_path, &secure_clone).is_err());
+		assert!(check_socket_path(&abstract_path, &SecurityStatus::default()).is_ok());
+		assert!(check_socket_path(&file_path, &secure_clone).is_ok());
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn worker_connects_over_an_abstract_address() {
+		use std::os::{
+			linux::net::SocketAddrExt,
+			unix::net::{SocketAddr, UnixListener},
+		};
+
+		let name = format!("pvf-test-{}", std::process::id());
+		let listener =
+			UnixListener::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+
+		let mut worker = connect_to_host(&socket_path_from_arg(&format!("@{}", name))).unwrap();
+		let (mut host, _) = listener.accept().unwrap();
+		worker.write_all(b"ping").unwrap();
+		let mut buf = [0; 4];
+		host.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf, b"ping");
+	}
+
+	#[test]
+	fn fd_is_passed_over_the_socket() {
+		let (host, worker) = UnixStream::pair().unwrap();
+		let (pipe_read_fd, pipe_write_fd) = pipe2_cloexec().unwrap();
+		// SAFETY: both ends are open and owned from here on.
+		let (mut pipe_read, pipe_write) =
+			unsafe { (PipeFd::from_raw_fd(pipe_read_fd), OwnedFd::from_raw_fd(pipe_write_fd)) };
+
+		send_fd(&host, pipe_write.as_fd()).unwrap();
+		drop(pipe_write);
+		let mut received = File::from(recv_fd(&worker).unwrap());
+		received.write_all(b"artifact").unwrap();
+		drop(received);
+
+		let mut buf = Vec::new();
+		pipe_read.read_to_end(&mut buf).unwrap();
+		assert_eq!(buf, b"artifact");
+	}
+
+	#[test]
+	fn byte_without_an_fd_is_rejected() {
+		let (mut host, worker) = UnixStream::pair().unwrap();
+		host.write_all(&[0]).unwrap();
+		assert_eq!(recv_fd(&worker).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+		drop(host);
+		assert_eq!(recv_fd(&worker).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+	}
+
 	#[test]
 	fn cpu_time_monitor_loop_should_return_time_elapsed() {
 		let cpu_time_start = ProcessTime::now();
@@ -87,10 +87,31 @@ pub enum Error {
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Try to enable landlock for the given kind of worker.
-pub fn enable_for_worker(worker_info: &WorkerInfo) -> Result<()> {
+///
+/// The prepare worker gets these rights on its worker dir:
+///
+/// - `ReadFile`, to read the code file, see [`crate::worker_dir::prepare_code`].
+///
+/// - `WriteFile`, to write the artifact into the temporary artifact file, which the host creates
+///   beforehand, and to post-process it there.
+///
+/// - `MakeReg`, only if `allow_core_dumps` is set. Rulesets stack, so the jobs of the worker can
+///   only create their core dump in the worker dir if the worker may create files there too.
+///
+/// It never gets `RemoveFile`, so it can neither remove nor rename files. The host removes the
+/// worker dir and moves artifacts into the cache itself. Anything else, e.g. persisting artifacts
+/// in the worker dir, needs landlock to be disabled.
+///
+/// The execute worker only gets `ReadFile`, to read the artifact.
+pub fn enable_for_worker(worker_info: &WorkerInfo, allow_core_dumps: bool) -> Result<()> {
 	let exceptions: Vec<(PathBuf, BitFlags<AccessFs>)> = match worker_info.kind {
 		WorkerKind::Prepare => {
-			vec![(worker_info.worker_dir_path.to_owned(), AccessFs::WriteFile.into())]
+			let access = if allow_core_dumps {
+				AccessFs::ReadFile | AccessFs::WriteFile | AccessFs::MakeReg
+			} else {
+				AccessFs::ReadFile | AccessFs::WriteFile
+			};
+			vec![(worker_info.worker_dir_path.to_owned(), access)]
 		},
 		WorkerKind::Execute => {
 			vec![(worker_info.worker_dir_path.to_owned(), AccessFs::ReadFile.into())]
@@ -109,6 +130,31 @@ pub fn enable_for_worker(worker_info: &WorkerInfo) -> Result<()> {
 	try_restrict(exceptions)
 }
 
+/// Try to enable landlock for a prepare job, on top of the ruleset of its worker.
+///
+/// The job only communicates over a pipe, so it gets no access to the filesystem at all. In
+/// particular it can't tamper with files the worker keeps in the worker dir, e.g. its persisted
+/// artifacts. Since rulesets stack, this keeps the job restricted even if the worker's ruleset
+/// changes.
+///
+/// If `allow_core_dump` is set, the job may create and write files in the worker dir, so that the
+/// kernel can write its core there.
+pub fn enable_for_prepare_job(worker_dir_path: &Path, allow_core_dump: bool) -> Result<()> {
+	gum::trace!(
+		target: LOG_TARGET,
+		?worker_dir_path,
+		allow_core_dump,
+		"enabling landlock for prepare job",
+	);
+
+	let exceptions = if allow_core_dump {
+		vec![(worker_dir_path, AccessFs::WriteFile | AccessFs::MakeReg)]
+	} else {
+		Vec::new()
+	};
+	try_restrict(exceptions)
+}
+
 // TODO: <https://github.com/landlock-lsm/rust-landlock/issues/36>
 /// Runs a check for landlock in its own thread, and returns an error indicating whether the given
 /// landlock ABI is fully enabled on the current Linux environment.
@@ -285,6 +331,100 @@ mod tests {
 		assert!(handle.join().is_ok());
 	}
 
+	#[test]
+	fn prepare_job_cannot_access_the_filesystem() {
+		// TODO: This would be nice: <https://github.com/rust-lang/rust/issues/68007>.
+		if check_can_fully_enable().is_err() {
+			return
+		}
+
+		let handle = thread::spawn(|| {
+			let worker_dir = tempfile::tempdir().unwrap();
+			let artifact_path = worker_dir.path().join("artifact");
+			// Like the host, create the file the artifact is written to.
+			fs::write(&artifact_path, "").unwrap();
+
+			// Reading other files should succeed before any landlock restrictions are applied.
+			assert!(fs::read("/etc/passwd").is_ok());
+
+			let status = enable_for_prepare_job(worker_dir.path(), false);
+			if !matches!(status, Ok(())) {
+				panic!(
+					"Ruleset should be enforced since we checked if landlock is enabled: {:?}",
+					status
+				);
+			}
+
+			let result = fs::read("/etc/passwd");
+			assert!(matches!(
+				result,
+				Err(err) if matches!(err.kind(), ErrorKind::PermissionDenied)
+			));
+
+			// Neither is writing to the worker dir, as the job only communicates over a pipe.
+			let result = fs::write(&artifact_path, "foo");
+			assert!(matches!(
+				result,
+				Err(err) if matches!(err.kind(), ErrorKind::PermissionDenied)
+			));
+		});
+
+		assert!(handle.join().is_ok());
+	}
+
+	#[test]
+	fn prepare_worker_can_only_create_files_if_core_dumps_are_allowed() {
+		// TODO: This would be nice: <https://github.com/rust-lang/rust/issues/68007>.
+		if check_can_fully_enable().is_err() {
+			return
+		}
+
+		for allow_core_dumps in [false, true] {
+			let handle = thread::spawn(move || {
+				let worker_dir = tempfile::tempdir().unwrap();
+				let artifact_path = worker_dir.path().join("artifact");
+				fs::write(&artifact_path, "").unwrap();
+				let worker_info = WorkerInfo {
+					pid: std::process::id(),
+					kind: WorkerKind::Prepare,
+					version: None,
+					worker_dir_path: worker_dir.path().to_owned(),
+				};
+
+				let status = enable_for_worker(&worker_info, allow_core_dumps);
+				if !matches!(status, Ok(())) {
+					panic!(
+						"Ruleset should be enforced since we checked if landlock is enabled: {:?}",
+						status
+					);
+				}
+
+				// The artifact is written in place and read back.
+				assert!(fs::write(&artifact_path, "foo").is_ok());
+				assert!(fs::read(&artifact_path).is_ok());
+
+				let result = fs::write(worker_dir.path().join("core"), "foo");
+				if allow_core_dumps {
+					assert!(result.is_ok());
+				} else {
+					assert!(matches!(
+						result,
+						Err(err) if matches!(err.kind(), ErrorKind::PermissionDenied)
+					));
+				}
+
+				// Nothing can be removed.
+				let result = fs::remove_file(&artifact_path);
+				assert!(matches!(
+					result,
+					Err(err) if matches!(err.kind(), ErrorKind::PermissionDenied)
+				));
+			});
+
+			assert!(handle.join().is_ok());
+		}
+	}
+
 	// Test that checks whether landlock under our ABI version is able to truncate files.
 	#[test]
 	fn restricted_thread_can_truncate_file() {
@@ -25,6 +25,7 @@
 //!   - Unshare the user and mount namespaces.
 //!   - Change the root directory to a worker-specific temporary directory.
 //! - Restrict networking by blocking socket creation and io_uring.
+//! - Move prepare jobs into new network and mount namespaces.
 //! - Remove env vars
 
 #[cfg(target_os = "linux")]
@@ -35,6 +36,8 @@ pub mod clone;
 pub mod landlock;
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
 pub mod seccomp;
+#[cfg(target_os = "linux")]
+pub mod unshare;
 
 use crate::{worker::WorkerInfo, LOG_TARGET};
 
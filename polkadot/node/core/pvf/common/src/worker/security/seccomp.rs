@@ -65,6 +65,11 @@
 //! Considering how many things would have to go wrong here, we believe it's safe to block
 //! `io_uring`.
 //!
+//! # Prepare jobs
+//!
+//! Prepare jobs are additionally restricted to an allowlist of syscalls, see
+//! [`PREPARE_JOB_SYSCALLS`]. Compilation is pure computation on memory, so the list is short.
+//!
 //! # Action on syscall violations
 //!
 //! When a forbidden syscall is attempted we immediately kill the process in order to prevent the
@@ -97,6 +102,61 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The syscalls prepare jobs are allowed to make. Any other syscall kills the job.
+///
+/// NOTE: Every entry must be justified. When updating `wasmtime` or changing the prepare job,
+/// check that the job does not need any new syscalls, otherwise preparation will fail.
+pub const PREPARE_JOB_SYSCALLS: &[i64] = &[
+	// Communicating the result to the worker and logging.
+	libc::SYS_read,
+	libc::SYS_write,
+	libc::SYS_close,
+	// Sampling the memory stats from the `/proc` files the job opened before enabling seccomp.
+	// Reading at an offset spares seeking back to the start of the file for every sample, which
+	// would need `lseek`.
+	libc::SYS_pread64,
+	// Memory management, for the allocator and for wasmtime's code and memories.
+	libc::SYS_brk,
+	libc::SYS_mmap,
+	libc::SYS_munmap,
+	libc::SYS_mremap,
+	libc::SYS_mprotect,
+	libc::SYS_madvise,
+	// Memory images created by wasmtime during the runtime construction check.
+	libc::SYS_memfd_create,
+	libc::SYS_ftruncate,
+	libc::SYS_fcntl,
+	libc::SYS_pwrite64,
+	// Threads spawned by the job and synchronization between them.
+	libc::SYS_clone,
+	libc::SYS_clone3,
+	libc::SYS_set_robust_list,
+	libc::SYS_rseq,
+	libc::SYS_futex,
+	libc::SYS_sched_yield,
+	libc::SYS_sched_getaffinity,
+	libc::SYS_prctl,
+	libc::SYS_gettid,
+	libc::SYS_getpid,
+	// Signal handling, including stack overflow detection and aborting.
+	libc::SYS_rt_sigaction,
+	libc::SYS_rt_sigprocmask,
+	libc::SYS_rt_sigreturn,
+	libc::SYS_sigaltstack,
+	libc::SYS_tgkill,
+	// Time keeping for the CPU time monitor and stats.
+	libc::SYS_clock_gettime,
+	libc::SYS_clock_nanosleep,
+	libc::SYS_nanosleep,
+	libc::SYS_getrusage,
+	// Setting the address space limit of the job.
+	libc::SYS_prlimit64,
+	// Seeding hash maps.
+	libc::SYS_getrandom,
+	libc::SYS_exit,
+	libc::SYS_exit_group,
+];
+
 /// Try to enable seccomp for the given kind of worker.
 pub fn enable_for_worker(worker_info: &WorkerInfo) -> Result<()> {
 	gum::trace!(
@@ -118,6 +178,28 @@ pub fn check_can_fully_enable() -> Result<()> {
 	}
 }
 
+/// Applies a `seccomp` filter only allowing [`PREPARE_JOB_SYSCALLS`] to the calling thread. Threads
+/// spawned afterwards inherit the filter, so this must be called before the job spawns any.
+pub fn enable_for_prepare_job() -> Result<()> {
+	let allowlisted_rules = PREPARE_JOB_SYSCALLS.iter().map(|syscall| (*syscall, vec![])).collect();
+
+	let filter = SeccompFilter::new(
+		allowlisted_rules,
+		// Mismatch action: what to do if not in rule list.
+		CAUGHT_ACTION,
+		// Match action: what to do if in rule list.
+		SeccompAction::Allow,
+		TargetArch::x86_64,
+	)?;
+
+	let bpf_prog: BpfProgram = filter.try_into()?;
+
+	// Applies filter (runs seccomp) to the calling thread.
+	seccompiler::apply_filter(&bpf_prog)?;
+
+	Ok(())
+}
+
 /// Applies a `seccomp` filter to disable networking for the PVF threads.
 fn try_restrict() -> Result<()> {
 	// Build a `seccomp` filter which by default allows all syscalls except those blocked in the
@@ -188,4 +270,29 @@ mod tests {
 
 		assert!(handle.join().is_ok());
 	}
+
+	#[test]
+	fn prepare_job_cannot_use_syscalls_outside_the_allowlist() {
+		if check_can_fully_enable().is_err() {
+			return
+		}
+
+		let handle = thread::spawn(|| {
+			if !matches!(enable_for_prepare_job(), Ok(())) {
+				panic!("Filter should be enforced since we checked if seccomp is enabled");
+			}
+
+			// `socket` is not in the allowlist.
+			assert_eq!(unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) }, -1);
+			assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::EACCES));
+
+			// Neither is `getppid`, although it is harmless.
+			assert_eq!(unsafe { libc::getppid() }, -1);
+
+			// Allowlisted syscalls should still work.
+			let _ = vec![0u8; 64 * 1024 * 1024];
+		});
+
+		assert!(handle.join().is_ok());
+	}
 }
@@ -0,0 +1,103 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Moves prepare jobs into their own network and mount namespaces.
+//!
+//! A job in a new network namespace only sees a loopback interface, which is down, so it has no
+//! network access at all. A new mount namespace gives it a private view of the mounts, so any
+//! mounts it makes are not visible to the worker.
+//!
+//! Jobs spawned with `clone` already get these namespaces from the clone flags. This is for jobs
+//! spawned with `fork`, which is used when secure clone is not available.
+
+use crate::LOG_TARGET;
+use nix::{errno::Errno, sched::CloneFlags};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+	#[error("could not unshare user namespace, errno: {0}")]
+	UnshareUserNamespace(Errno),
+	#[error("could not unshare network and mount namespaces, errno: {0}")]
+	Unshare(Errno),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The namespaces a prepare job is moved into.
+const FLAGS: CloneFlags = CloneFlags::CLONE_NEWNET.union(CloneFlags::CLONE_NEWNS);
+
+/// Try to move the calling process into new network and mount namespaces.
+///
+/// This needs `CAP_SYS_ADMIN`, which the worker has if it unshared the user namespace, and must be
+/// called while the process is single-threaded.
+pub fn unshare_for_prepare_job() -> Result<()> {
+	gum::trace!(
+		target: LOG_TARGET,
+		"unsharing network and mount namespaces with flags: {:?}",
+		FLAGS
+	);
+
+	nix::sched::unshare(FLAGS).map_err(Error::Unshare)
+}
+
+/// Runs a check for unsharing the network and mount namespaces, from a new user namespace like
+/// the one of the worker. Returns an error indicating whether it can be fully done on the current
+/// Linux environment.
+///
+/// NOTE: This should not be called in a process with more than one thread, and it changes the
+/// namespaces of the calling process.
+pub fn check_can_fully_enable() -> Result<()> {
+	nix::sched::unshare(CloneFlags::CLONE_NEWUSER).map_err(Error::UnshareUserNamespace)?;
+	nix::sched::unshare(FLAGS).map_err(Error::Unshare)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::{TcpListener, TcpStream};
+
+	#[test]
+	fn unshared_process_has_no_network_access() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		// Run the check in a new process, as unsharing requires being single-threaded.
+		//
+		// SAFETY: the child only runs the check and exits.
+		match unsafe { libc::fork() } {
+			-1 => panic!("fork failed: {}", std::io::Error::last_os_error()),
+			0 => {
+				let status = if check_can_fully_enable().is_err() {
+					// Not supported in this environment, skip the test.
+					0
+				} else if TcpStream::connect(addr).is_ok() {
+					1
+				} else {
+					0
+				};
+				// SAFETY: `_exit` is always safe to call.
+				unsafe { libc::_exit(status) }
+			},
+			child => {
+				let mut status = 0;
+				// SAFETY: `child` is our child process.
+				assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+				assert!(libc::WIFEXITED(status));
+				assert_eq!(libc::WEXITSTATUS(status), 0, "child could connect to the host");
+			},
+		}
+	}
+}
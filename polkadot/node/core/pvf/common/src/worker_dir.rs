@@ -20,6 +20,7 @@ use std::path::{Path, PathBuf};
 
 const WORKER_EXECUTE_ARTIFACT_NAME: &str = "artifact";
 const WORKER_PREPARE_TMP_ARTIFACT_NAME: &str = "tmp-artifact";
+const WORKER_PREPARE_CODE_NAME: &str = "code";
 
 pub fn execute_artifact(worker_dir_path: &Path) -> PathBuf {
 	worker_dir_path.join(WORKER_EXECUTE_ARTIFACT_NAME)
@@ -28,3 +29,33 @@ pub fn execute_artifact(worker_dir_path: &Path) -> PathBuf {
 pub fn prepare_tmp_artifact(worker_dir_path: &Path) -> PathBuf {
 	worker_dir_path.join(WORKER_PREPARE_TMP_ARTIFACT_NAME)
 }
+
+/// The temporary artifact of the job at `index` of a batch request.
+pub fn prepare_tmp_batch_artifact(worker_dir_path: &Path, index: usize) -> PathBuf {
+	worker_dir_path.join(format!("{}-{}", WORKER_PREPARE_TMP_ARTIFACT_NAME, index))
+}
+
+/// The temporary artifact of the worker with the given worker dir, when written to `artifact_dir`
+/// instead. Several workers may share `artifact_dir`, so the file is named after the worker dir.
+pub fn prepare_shared_tmp_artifact(artifact_dir: &Path, worker_dir_path: &Path) -> PathBuf {
+	artifact_dir.join(shared_tmp_artifact_name(worker_dir_path))
+}
+
+/// Like [`prepare_shared_tmp_artifact`], for the job at `index` of a batch request.
+pub fn prepare_shared_tmp_batch_artifact(
+	artifact_dir: &Path,
+	worker_dir_path: &Path,
+	index: usize,
+) -> PathBuf {
+	artifact_dir.join(format!("{}-{}", shared_tmp_artifact_name(worker_dir_path), index))
+}
+
+fn shared_tmp_artifact_name(worker_dir_path: &Path) -> String {
+	let worker_dir_name = worker_dir_path.file_name().unwrap_or_default().to_string_lossy();
+	format!("{}-{}", worker_dir_name, WORKER_PREPARE_TMP_ARTIFACT_NAME)
+}
+
+/// The file the host writes the code of a PVF into, if it sends the code outside of the request.
+pub fn prepare_code(worker_dir_path: &Path) -> PathBuf {
+	worker_dir_path.join(WORKER_PREPARE_CODE_NAME)
+}
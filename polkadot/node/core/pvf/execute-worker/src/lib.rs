@@ -39,15 +39,16 @@ use nix::{
 	unistd::{ForkResult, Pid},
 };
 use polkadot_node_core_pvf_common::{
+	artifact::{self, CompiledArtifact},
 	error::InternalValidationError,
 	execute::{Handshake, JobError, JobResponse, JobResult, WorkerError, WorkerResponse},
 	executor_interface::params_to_wasmtime_semantics,
 	framed_recv_blocking, framed_send_blocking,
 	worker::{
-		cpu_time_monitor_loop, get_total_cpu_usage, pipe2_cloexec, recv_child_response, run_worker,
-		send_result, stringify_errno, stringify_panic_payload,
+		cpu_time_monitor_loop, get_cpu_time_elapsed, pipe2_cloexec, recv_child_response,
+		run_worker, send_result, stringify_errno, stringify_panic_payload,
 		thread::{self, WaitOutcome},
-		PipeFd, WorkerInfo, WorkerKind,
+		wait_for_request, worker_shutdown_idle, PipeFd, RequestWait, WorkerInfo, WorkerKind,
 	},
 	worker_dir,
 };
@@ -128,7 +129,9 @@ macro_rules! map_and_send_err {
 ///
 /// # Parameters
 ///
-/// - `socket_path`: specifies the path to the socket used to communicate with the host.
+/// - `socket_path`: specifies the path to the socket used to communicate with the host. A path
+///   starting with a NUL byte is an abstract address, see
+///   [`connect_to_host`](polkadot_node_core_pvf_common::worker::connect_to_host).
 ///
 /// - `worker_dir_path`: specifies the path to the worker-specific temporary directory.
 ///
@@ -137,11 +140,14 @@ macro_rules! map_and_send_err {
 ///   check is not necessary.
 ///
 /// - `worker_version`: see above
+///
+/// - `idle_timeout`: if `Some`, the worker exits cleanly once it received no request for this long.
 pub fn worker_entrypoint(
 	socket_path: PathBuf,
 	worker_dir_path: PathBuf,
 	node_version: Option<&str>,
 	worker_version: Option<&str>,
+	idle_timeout: Option<Duration>,
 ) {
 	run_worker(
 		WorkerKind::Execute,
@@ -166,6 +172,12 @@ pub fn worker_entrypoint(
 			let execute_thread_stack_size = max_stack_size(&executor_params);
 
 			loop {
+				if idle_timeout.is_some() &&
+					wait_for_request(&stream, idle_timeout)? == RequestWait::Idle
+				{
+					worker_shutdown_idle(worker_info);
+				}
+
 				let (pvd, pov, execution_timeout) = recv_request(&mut stream).map_err(|e| {
 					map_and_send_err!(
 						e,
@@ -192,6 +204,31 @@ pub fn worker_entrypoint(
 					)
 				})?;
 
+				// Check the artifact header and decompress the payload. A corrupted artifact is
+				// treated like any other runtime construction failure, so that it gets
+				// re-prepared.
+				let payload = CompiledArtifact::parse(&compiled_artifact_blob)
+					.map_err(|err| ("artifact header", err.to_string()))
+					.and_then(|payload| {
+						artifact::decompress(payload)
+							.map_err(|err| ("artifact decompression", err.to_string()))
+					});
+				let compiled_artifact_blob = match payload {
+					Ok(blob) => blob.into_owned(),
+					Err((ctx, err)) => {
+						send_result::<WorkerResponse, WorkerError>(
+							&mut stream,
+							Ok(WorkerResponse {
+								job_response: JobResponse::runtime_construction(ctx, &err),
+								duration: Duration::ZERO,
+								pov_size: 0,
+							}),
+							worker_info,
+						)?;
+						continue;
+					},
+				};
+
 				let (pipe_read_fd, pipe_write_fd) = pipe2_cloexec().map_err(|e| {
 					map_and_send_err!(
 						e,
@@ -602,7 +639,7 @@ fn handle_parent_process(
 	// As `getrusage` returns resource usage from all terminated child processes,
 	// it is necessary to subtract the usage before the current child process to isolate its cpu
 	// time
-	let cpu_tv = get_total_cpu_usage(usage_after) - get_total_cpu_usage(usage_before);
+	let cpu_tv = get_cpu_time_elapsed(usage_before, usage_after);
 	if cpu_tv >= timeout {
 		gum::warn!(
 			target: LOG_TARGET,
@@ -16,42 +16,57 @@
 
 use criterion::{criterion_group, criterion_main, Criterion, SamplingMode};
 use polkadot_node_core_pvf_common::{
+	artifact::{self, ARTIFACT_COMPRESSION_LEVEL},
 	executor_interface::{prepare, prevalidate},
 	prepare::PrepareJobKind,
 	pvf::PvfPrepData,
 };
-use polkadot_primitives::ExecutorParams;
+use polkadot_primitives::{ExecutorParam, ExecutorParams};
 use std::time::Duration;
 
-fn do_prepare_runtime(pvf: PvfPrepData) {
+fn do_prepare_runtime(pvf: PvfPrepData) -> Vec<u8> {
+	do_prepare_runtime_with_times(pvf).0
+}
+
+/// Prepares the runtime and returns the artifact along with the compilation's CPU and wall clock
+/// time.
+fn do_prepare_runtime_with_times(pvf: PvfPrepData) -> (Vec<u8>, Duration, Duration) {
 	let maybe_compressed_code = pvf.maybe_compressed_code();
 	let raw_validation_code =
 		sp_maybe_compressed_blob::decompress(&maybe_compressed_code, usize::MAX).unwrap();
 
-	let blob = match prevalidate(&raw_validation_code) {
+	let blob = match prevalidate(&raw_validation_code, &pvf.executor_params()) {
 		Err(err) => panic!("{:?}", err),
-		Ok(b) => b,
+		Ok(blob) => blob,
 	};
 
 	match prepare(blob, &pvf.executor_params()) {
-		Ok(_) => (),
+		Ok(prepared) => (prepared.artifact, prepared.compile_cpu_time, prepared.compile_wall_time),
 		Err(err) => panic!("{:?}", err),
 	}
 }
 
-fn prepare_rococo_runtime(c: &mut Criterion) {
+fn rococo_pvf() -> PvfPrepData {
+	rococo_pvf_with_params(ExecutorParams::default())
+}
+
+fn rococo_pvf_with_params(executor_params: ExecutorParams) -> PvfPrepData {
 	let blob = rococo_runtime::WASM_BINARY.unwrap();
-	let pvf = match sp_maybe_compressed_blob::decompress(&blob, 64 * 1024 * 1024) {
+	match sp_maybe_compressed_blob::decompress(&blob, 64 * 1024 * 1024) {
 		Ok(code) => PvfPrepData::from_code(
 			code.into_owned(),
-			ExecutorParams::default(),
+			executor_params,
 			Duration::from_secs(360),
 			PrepareJobKind::Compilation,
 		),
 		Err(e) => {
 			panic!("Cannot decompress blob: {:?}", e);
 		},
-	};
+	}
+}
+
+fn prepare_rococo_runtime(c: &mut Criterion) {
+	let pvf = rococo_pvf();
 
 	let mut group = c.benchmark_group("rococo");
 	group.sampling_mode(SamplingMode::Flat);
@@ -65,5 +80,78 @@ fn prepare_rococo_runtime(c: &mut Criterion) {
 	group.finish();
 }
 
-criterion_group!(preparation, prepare_rococo_runtime);
+fn prepare_rococo_runtime_in_parallel(c: &mut Criterion) {
+	let mut group = c.benchmark_group("rococo");
+	group.sampling_mode(SamplingMode::Flat);
+	group.sample_size(10);
+	group.measurement_time(Duration::from_secs(120));
+	for threads in [1, 2, 4] {
+		let pvf = rococo_pvf_with_params(ExecutorParams::from(
+			&[ExecutorParam::CompilationThreads(threads)][..],
+		));
+
+		// The wall clock time goes down with more threads, while the CPU time, which the
+		// preparation timeout is measured in, stays about the same.
+		let (_, cpu_time, wall_time) = do_prepare_runtime_with_times(pvf.clone());
+		println!(
+			"Rococo runtime compiled on {} threads in {:?} of CPU time, {:?} of wall clock time",
+			threads, cpu_time, wall_time,
+		);
+
+		group.bench_function(format!("prepare Rococo runtime on {} threads", threads), |b| {
+			b.iter(|| do_prepare_runtime(pvf.clone()))
+		});
+	}
+	group.finish();
+}
+
+fn prevalidate_rococo_runtime(c: &mut Criterion) {
+	let pvf = rococo_pvf();
+	let raw_validation_code =
+		sp_maybe_compressed_blob::decompress(&pvf.maybe_compressed_code(), usize::MAX)
+			.unwrap()
+			.into_owned();
+	let executor_params = pvf.executor_params();
+	let blob = prevalidate(&raw_validation_code, &executor_params).unwrap();
+
+	// Jobs that compile the code twice, e.g. to verify determinism, reuse the prevalidated blob
+	// instead of prevalidating the code again.
+	let mut group = c.benchmark_group("rococo");
+	group.sample_size(20);
+	group.bench_function("prevalidate Rococo runtime", |b| {
+		b.iter(|| prevalidate(&raw_validation_code, &executor_params).unwrap())
+	});
+	group.bench_function("reuse prevalidated Rococo runtime", |b| b.iter(|| blob.clone()));
+	group.finish();
+}
+
+fn compress_rococo_artifact(c: &mut Criterion) {
+	let artifact = do_prepare_runtime(rococo_pvf());
+	let compressed = artifact::compress(&artifact, ARTIFACT_COMPRESSION_LEVEL).unwrap();
+	println!(
+		"Rococo artifact size: {} bytes uncompressed, {} bytes compressed with zstd level {} ({:.1}%)",
+		artifact.len(),
+		compressed.len(),
+		ARTIFACT_COMPRESSION_LEVEL,
+		compressed.len() as f64 * 100.0 / artifact.len() as f64,
+	);
+
+	let mut group = c.benchmark_group("rococo");
+	group.sample_size(20);
+	group.bench_function("compress Rococo artifact", |b| {
+		b.iter(|| artifact::compress(&artifact, ARTIFACT_COMPRESSION_LEVEL).unwrap())
+	});
+	group.bench_function("decompress Rococo artifact", |b| {
+		b.iter(|| artifact::decompress(&compressed).unwrap().len())
+	});
+	group.finish();
+}
+
+criterion_group!(
+	preparation,
+	prepare_rococo_runtime,
+	prepare_rococo_runtime_in_parallel,
+	prevalidate_rococo_runtime,
+	compress_rococo_artifact
+);
 criterion_main!(preparation);
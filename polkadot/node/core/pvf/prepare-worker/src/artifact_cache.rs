@@ -0,0 +1,216 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A cache of the artifacts recently prepared by a worker, so that repeated requests for the same
+//! code and executor params don't have to compile it again.
+//!
+//! The host moves every artifact out of the worker dir once it receives the result, so the cache
+//! keeps the artifacts in memory. It is bounded both in the number of artifacts and in their total
+//! size, and evicts the least recently used artifact first.
+
+use codec::{Decode, Encode};
+use polkadot_node_core_pvf_common::{artifact::CompiledArtifact, pvf::PvfPrepData};
+use polkadot_primitives::{ExecutorParamsHash, ValidationCodeHash};
+use std::collections::VecDeque;
+
+/// The maximum number of artifacts kept in the cache.
+const MAX_ENTRIES: usize = 4;
+
+/// The maximum total size of the artifacts kept in the cache, in bytes.
+const MAX_TOTAL_SIZE: usize = 64 * 1024 * 1024;
+
+/// Identifies the code and executor params an artifact was prepared from, like [`PvfPrepData`]'s
+/// notion of equality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct CacheKey {
+	code_hash: ValidationCodeHash,
+	executor_params_hash: ExecutorParamsHash,
+}
+
+impl CacheKey {
+	pub fn new(pvf: &PvfPrepData) -> Self {
+		Self { code_hash: pvf.code_hash(), executor_params_hash: pvf.executor_params().hash() }
+	}
+
+	/// Returns the hash of the code.
+	pub fn code_hash(&self) -> ValidationCodeHash {
+		self.code_hash
+	}
+}
+
+/// A cached artifact, along with the stats that are about the code rather than the job. The code
+/// size is kept as well, as a probing request comes without the code.
+pub struct CachedArtifact {
+	pub artifact: CompiledArtifact,
+	pub observed_wasm_code_len: u32,
+	pub code_size: u64,
+}
+
+/// A bounded LRU cache of artifacts. The most recently used artifact is at the front.
+#[derive(Default)]
+pub struct ArtifactCache {
+	entries: VecDeque<(CacheKey, CachedArtifact)>,
+	total_size: usize,
+}
+
+impl ArtifactCache {
+	/// Returns the artifact for the given key, if cached, and marks it as the most recently used.
+	pub fn get(&mut self, key: &CacheKey) -> Option<&CachedArtifact> {
+		let index = self.entries.iter().position(|(k, _)| k == key)?;
+		let entry = self.entries.remove(index)?;
+		self.entries.push_front(entry);
+		self.entries.front().map(|(_, cached)| cached)
+	}
+
+	/// Returns whether the artifact for the given key is cached, without marking it as used.
+	pub fn contains(&self, key: &CacheKey) -> bool {
+		self.entries.iter().any(|(k, _)| k == key)
+	}
+
+	/// Returns whether an artifact of the given size is small enough to ever be cached.
+	pub fn can_hold(size: u64) -> bool {
+		size <= MAX_TOTAL_SIZE as u64
+	}
+
+	/// Caches the artifact under the given key, evicting the least recently used artifacts to stay
+	/// within the bounds. Artifacts which are too big to ever fit are not cached.
+	pub fn insert(&mut self, key: CacheKey, cached: CachedArtifact) {
+		let size = cached.artifact.as_ref().len();
+		if !Self::can_hold(size as u64) {
+			return
+		}
+
+		self.remove(&key);
+		while self.entries.len() >= MAX_ENTRIES || self.total_size + size > MAX_TOTAL_SIZE {
+			let Some((_, evicted)) = self.entries.pop_back() else { break };
+			self.total_size -= evicted.artifact.as_ref().len();
+		}
+
+		self.total_size += size;
+		self.entries.push_front((key, cached));
+	}
+
+	/// Drops all cached artifacts, e.g. to free memory when the system is under memory pressure.
+	pub fn clear(&mut self) {
+		self.entries.clear();
+		self.total_size = 0;
+	}
+
+	fn remove(&mut self, key: &CacheKey) {
+		if let Some(index) = self.entries.iter().position(|(k, _)| k == key) {
+			if let Some((_, removed)) = self.entries.remove(index) {
+				self.total_size -= removed.artifact.as_ref().len();
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use polkadot_node_core_pvf_common::prepare::PrepareJobKind;
+	use polkadot_primitives::ExecutorParams;
+	use std::time::Duration;
+
+	fn key(discriminator: u8) -> CacheKey {
+		CacheKey::new(&PvfPrepData::from_code(
+			vec![discriminator],
+			ExecutorParams::default(),
+			Duration::from_secs(1),
+			PrepareJobKind::Compilation,
+		))
+	}
+
+	fn cached(size: usize) -> CachedArtifact {
+		CachedArtifact {
+			artifact: CompiledArtifact::new(vec![0; size], ValidationCodeHash::from([0; 32])),
+			observed_wasm_code_len: 1,
+			code_size: 1,
+		}
+	}
+
+	#[test]
+	fn returns_cached_artifacts() {
+		let mut cache = ArtifactCache::default();
+		assert!(cache.get(&key(0)).is_none());
+
+		cache.insert(key(0), cached(10));
+
+		assert!(cache.get(&key(0)).is_some());
+		assert!(cache.get(&key(1)).is_none());
+		assert!(cache.contains(&key(0)));
+		assert!(!cache.contains(&key(1)));
+	}
+
+	#[test]
+	fn key_depends_on_executor_params() {
+		let pvf = |executor_params| {
+			PvfPrepData::from_code(
+				vec![0],
+				executor_params,
+				Duration::from_secs(1),
+				PrepareJobKind::Compilation,
+			)
+		};
+		let other_params =
+			ExecutorParams::from(&[polkadot_primitives::ExecutorParam::MaxMemoryPages(1)][..]);
+
+		assert_eq!(CacheKey::new(&pvf(ExecutorParams::default())), key(0));
+		assert_ne!(CacheKey::new(&pvf(other_params)), key(0));
+	}
+
+	#[test]
+	fn evicts_least_recently_used_artifacts() {
+		let mut cache = ArtifactCache::default();
+		for i in 0..MAX_ENTRIES as u8 {
+			cache.insert(key(i), cached(10));
+		}
+		// Use the oldest artifact, so that the second oldest is evicted next.
+		assert!(cache.get(&key(0)).is_some());
+
+		cache.insert(key(MAX_ENTRIES as u8), cached(10));
+
+		assert!(cache.get(&key(0)).is_some());
+		assert!(cache.get(&key(1)).is_none());
+		assert!(cache.get(&key(MAX_ENTRIES as u8)).is_some());
+	}
+
+	#[test]
+	fn stays_within_total_size() {
+		let mut cache = ArtifactCache::default();
+		cache.insert(key(0), cached(MAX_TOTAL_SIZE / 2));
+		cache.insert(key(1), cached(MAX_TOTAL_SIZE / 2));
+
+		assert!(cache.get(&key(0)).is_none());
+		assert!(cache.get(&key(1)).is_some());
+
+		// Too big to be cached at all.
+		cache.insert(key(2), cached(MAX_TOTAL_SIZE));
+		assert!(cache.get(&key(2)).is_none());
+		assert!(cache.get(&key(1)).is_some());
+	}
+
+	#[test]
+	fn clear_drops_all_artifacts() {
+		let mut cache = ArtifactCache::default();
+		cache.insert(key(0), cached(10));
+
+		cache.clear();
+
+		assert!(cache.get(&key(0)).is_none());
+		assert_eq!(cache.total_size, 0);
+	}
+}
@@ -0,0 +1,440 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The log records of a job, piped to the worker.
+//!
+//! The job exits abruptly once it sent its response, or dies, so what it logs through the
+//! subscriber it inherited from the worker may never make it out. Instead, the job writes its log
+//! records into a pipe of their own, and the worker logs them once the job ended.
+//!
+//! Logging never blocks the job: a record that doesn't fit into the pipe anymore is dropped. Every
+//! record is written at once and fits into `PIPE_BUF` bytes, so the records of the threads of the
+//! job never interleave.
+
+use crate::LOG_TARGET;
+use codec::{Decode, Encode};
+use nix::unistd::Pid;
+use polkadot_node_core_pvf_common::worker::{pipe2_cloexec, PipeFd};
+use sp_tracing::{
+	tracing::{
+		field::{Field, Visit},
+		level_filters::LevelFilter,
+		Dispatch, Event, Level, Subscriber,
+	},
+	tracing_subscriber::{
+		layer::{Context, SubscriberExt},
+		Layer,
+	},
+};
+use std::{
+	fmt,
+	io::{self, Read},
+	os::fd::{AsRawFd, FromRawFd, RawFd},
+	sync::Arc,
+};
+
+/// The maximum size of a record on the pipe, including its length prefix. Writes to a pipe of up to
+/// this size are atomic.
+const MAX_FRAME_SIZE: usize = libc::PIPE_BUF;
+
+/// The size of the length prefix of a record on the pipe.
+const LEN_PREFIX_SIZE: usize = 2;
+
+/// The level of a [`JobLogRecord`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum JobLogLevel {
+	#[codec(index = 0)]
+	Error,
+	#[codec(index = 1)]
+	Warn,
+	#[codec(index = 2)]
+	Info,
+	#[codec(index = 3)]
+	Debug,
+	#[codec(index = 4)]
+	Trace,
+}
+
+impl From<&Level> for JobLogLevel {
+	fn from(level: &Level) -> Self {
+		match *level {
+			Level::ERROR => Self::Error,
+			Level::WARN => Self::Warn,
+			Level::INFO => Self::Info,
+			Level::DEBUG => Self::Debug,
+			_ => Self::Trace,
+		}
+	}
+}
+
+/// A record logged by the job.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct JobLogRecord {
+	pub level: JobLogLevel,
+	/// The target the record was logged under by the job.
+	pub target: String,
+	pub message: String,
+	/// The other fields of the record, formatted.
+	pub fields: Vec<(String, String)>,
+}
+
+impl fmt::Display for JobLogRecord {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.message)?;
+		for (name, value) in &self.fields {
+			write!(f, " {}={}", name, value)?;
+		}
+		Ok(())
+	}
+}
+
+/// Encodes the record along with its length prefix. Records too large for a single atomic write
+/// lose their fields first, then the end of their message, then the end of their target.
+fn frame(mut record: JobLogRecord) -> Vec<u8> {
+	loop {
+		let encoded = record.encode();
+		let excess = (LEN_PREFIX_SIZE + encoded.len()).saturating_sub(MAX_FRAME_SIZE);
+		if excess == 0 {
+			let mut frame = (encoded.len() as u16).to_le_bytes().to_vec();
+			frame.extend(encoded);
+			return frame
+		}
+		if !record.fields.is_empty() {
+			record.fields.clear();
+		} else if !record.message.is_empty() {
+			truncate_by(&mut record.message, excess);
+		} else {
+			// A record without a target always fits, so this terminates.
+			truncate_by(&mut record.target, excess);
+		}
+	}
+}
+
+/// Shortens the string by at least `excess` bytes, or empties it, keeping it valid UTF-8.
+fn truncate_by(s: &mut String, excess: usize) {
+	let mut len = s.len().saturating_sub(excess);
+	while !s.is_char_boundary(len) {
+		len -= 1;
+	}
+	s.truncate(len);
+}
+
+/// The file descriptors of the pipe carrying the log records of a job. Both ends are non-blocking.
+///
+/// It is created before spawning the job, which takes the writing end with [`Self::into_writer`].
+/// The worker takes the reading end with [`Self::into_reader`].
+#[derive(Debug, Clone, Copy)]
+pub struct LogPipe {
+	read_fd: RawFd,
+	write_fd: RawFd,
+}
+
+impl LogPipe {
+	pub fn new() -> io::Result<Self> {
+		let (read_fd, write_fd) = pipe2_cloexec()?;
+		let pipe = Self { read_fd, write_fd };
+		if let Err(err) = set_nonblocking(read_fd).and_then(|()| set_nonblocking(write_fd)) {
+			let _ = nix::unistd::close(read_fd);
+			let _ = nix::unistd::close(write_fd);
+			return Err(err)
+		}
+		Ok(pipe)
+	}
+
+	/// Closes the reading end and returns the writing end, for the job.
+	///
+	/// # Safety
+	///
+	/// Both file descriptors must be open and owned by the calling process, and not be used
+	/// otherwise.
+	pub unsafe fn into_writer(self) -> JobLogWriter {
+		let _ = nix::unistd::close(self.read_fd);
+		JobLogWriter { pipe: Arc::new(PipeFd::from_raw_fd(self.write_fd)) }
+	}
+
+	/// Closes the writing end and returns the reading end, for the worker.
+	///
+	/// # Safety
+	///
+	/// Both file descriptors must be open and owned by the calling process, and not be used
+	/// otherwise.
+	pub unsafe fn into_reader(self) -> JobLogReader {
+		let _ = nix::unistd::close(self.write_fd);
+		JobLogReader { pipe: PipeFd::from_raw_fd(self.read_fd), buf: Vec::new() }
+	}
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+	// SAFETY: only the status flags of the given file descriptor are changed.
+	let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+	if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+		return Err(io::Error::last_os_error())
+	}
+	Ok(())
+}
+
+/// The writing end of the [`LogPipe`], in the job.
+pub struct JobLogWriter {
+	pipe: Arc<PipeFd>,
+}
+
+impl JobLogWriter {
+	/// Returns a dispatch writing the records up to the given level into the pipe. A thread of the
+	/// job logs into the pipe once it sets the dispatch as its default.
+	///
+	/// The level is usually that of the subscriber of the worker, so that the job doesn't pipe
+	/// records the worker would discard anyway. It must be taken before any thread sets the
+	/// dispatch.
+	pub fn dispatch(&self, max_level: LevelFilter) -> Dispatch {
+		Dispatch::new(
+			sp_tracing::tracing_subscriber::registry()
+				.with(JobLogLayer { pipe: Arc::clone(&self.pipe), max_level }),
+		)
+	}
+}
+
+/// A [`Layer`] writing every record into the pipe.
+struct JobLogLayer {
+	pipe: Arc<PipeFd>,
+	max_level: LevelFilter,
+}
+
+impl<S: Subscriber> Layer<S> for JobLogLayer {
+	fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+		let metadata = event.metadata();
+		if *metadata.level() > self.max_level {
+			return
+		}
+
+		let mut visitor = RecordVisitor::default();
+		event.record(&mut visitor);
+		let frame = frame(JobLogRecord {
+			level: metadata.level().into(),
+			target: metadata.target().to_owned(),
+			message: visitor.message,
+			fields: visitor.fields,
+		});
+		// SAFETY: the pipe is open as long as `self` holds it. The record is dropped if it doesn't
+		// fit into the pipe.
+		let _ = unsafe { libc::write(self.pipe.as_raw_fd(), frame.as_ptr().cast(), frame.len()) };
+	}
+}
+
+/// Collects the message and the other fields of an event.
+#[derive(Default)]
+struct RecordVisitor {
+	message: String,
+	fields: Vec<(String, String)>,
+}
+
+impl Visit for RecordVisitor {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		if field.name() == "message" {
+			self.message = format!("{:?}", value);
+		} else {
+			self.fields.push((field.name().to_owned(), format!("{:?}", value)));
+		}
+	}
+}
+
+/// The reading end of the [`LogPipe`], in the worker.
+pub struct JobLogReader {
+	pipe: PipeFd,
+	/// What was read of a record that isn't complete yet.
+	buf: Vec<u8>,
+}
+
+impl JobLogReader {
+	/// Returns the records the job wrote so far, without blocking.
+	pub fn read_records(&mut self) -> Vec<JobLogRecord> {
+		let mut chunk = [0; MAX_FRAME_SIZE];
+		loop {
+			match self.pipe.read(&mut chunk) {
+				Ok(0) => break,
+				Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+				Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+				// Either there is nothing more to read, or nothing can be read anymore.
+				Err(_) => break,
+			}
+		}
+
+		let mut records = Vec::new();
+		let mut frames = &self.buf[..];
+		while frames.len() >= LEN_PREFIX_SIZE {
+			let (len, rest) = frames.split_at(LEN_PREFIX_SIZE);
+			let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+			if rest.len() < len {
+				break
+			}
+			if let Ok(record) = JobLogRecord::decode(&mut &rest[..len]) {
+				records.push(record);
+			}
+			frames = &rest[len..];
+		}
+		let consumed = self.buf.len() - frames.len();
+		self.buf.drain(..consumed);
+		records
+	}
+
+	/// Logs the records the job wrote so far, see [`Self::read_records`].
+	pub fn forward(&mut self, job_pid: Pid) {
+		for record in self.read_records() {
+			macro_rules! forward {
+				($level:ident) => {
+					gum::$level!(
+						target: LOG_TARGET,
+						%job_pid,
+						job_log_target = %record.target,
+						"worker job: {}",
+						record,
+					)
+				};
+			}
+			match record.level {
+				JobLogLevel::Error => forward!(error),
+				JobLogLevel::Warn => forward!(warn),
+				JobLogLevel::Info => forward!(info),
+				JobLogLevel::Debug => forward!(debug),
+				JobLogLevel::Trace => forward!(trace),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Mutex;
+
+	/// A [`Layer`] keeping the records logged while it is the default.
+	struct Captured(Arc<Mutex<Vec<String>>>);
+
+	impl<S: Subscriber> Layer<S> for Captured {
+		fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+			let mut visitor = RecordVisitor::default();
+			event.record(&mut visitor);
+			let record = JobLogRecord {
+				level: event.metadata().level().into(),
+				target: event.metadata().target().to_owned(),
+				message: visitor.message,
+				fields: visitor.fields,
+			};
+			self.0.lock().unwrap().push(record.to_string());
+		}
+	}
+
+	fn capture(f: impl FnOnce()) -> Vec<String> {
+		let captured = Arc::new(Mutex::new(Vec::new()));
+		let dispatch = Dispatch::new(
+			sp_tracing::tracing_subscriber::registry().with(Captured(Arc::clone(&captured))),
+		);
+		sp_tracing::tracing::dispatcher::with_default(&dispatch, f);
+		let captured = captured.lock().unwrap().clone();
+		captured
+	}
+
+	#[test]
+	fn records_logged_in_the_job_are_logged_by_the_worker() {
+		let pipe = LogPipe::new().unwrap();
+
+		// SAFETY: the child only logs into the pipe and exits right away.
+		let job_pid = match unsafe { libc::fork() } {
+			-1 => panic!("fork failed: {}", io::Error::last_os_error()),
+			0 => {
+				// SAFETY: the child owns its copies of the file descriptors.
+				let writer = unsafe { pipe.into_writer() };
+				sp_tracing::tracing::dispatcher::with_default(
+					&writer.dispatch(LevelFilter::DEBUG),
+					|| {
+						gum::warn!(target: LOG_TARGET, answer = 42, "logged in the job");
+						gum::trace!(target: LOG_TARGET, "too verbose");
+					},
+				);
+				unsafe { libc::_exit(0) }
+			},
+			child => {
+				let mut status = 0;
+				assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+				Pid::from_raw(child)
+			},
+		};
+
+		// SAFETY: the worker owns its copies of the file descriptors.
+		let mut reader = unsafe { pipe.into_reader() };
+		let logged = capture(|| reader.forward(job_pid));
+
+		assert_eq!(logged.len(), 1, "{:?}", logged);
+		assert!(logged[0].starts_with("worker job: logged in the job answer=42"), "{:?}", logged);
+		assert!(logged[0].contains(&format!("job_log_target={}", LOG_TARGET)), "{:?}", logged);
+	}
+
+	#[test]
+	fn logging_into_a_full_pipe_does_not_block() {
+		let pipe = LogPipe::new().unwrap();
+		// SAFETY: the file descriptors were just created and are only used here.
+		let (writer, mut reader) = unsafe {
+			let writer = JobLogWriter { pipe: Arc::new(PipeFd::from_raw_fd(pipe.write_fd)) };
+			(writer, JobLogReader { pipe: PipeFd::from_raw_fd(pipe.read_fd), buf: Vec::new() })
+		};
+
+		let message = "x".repeat(1024);
+		sp_tracing::tracing::dispatcher::with_default(&writer.dispatch(LevelFilter::INFO), || {
+			for _ in 0..1024 {
+				gum::info!(target: LOG_TARGET, "{}", message);
+			}
+		});
+
+		let records = reader.read_records();
+		assert!(!records.is_empty() && records.len() < 1024, "{}", records.len());
+		assert!(records.iter().all(|record| record.message == message));
+	}
+
+	#[test]
+	fn oversized_records_are_cut_to_fit_a_single_write() {
+		let record = JobLogRecord {
+			level: JobLogLevel::Info,
+			target: LOG_TARGET.to_owned(),
+			message: "ü".repeat(MAX_FRAME_SIZE),
+			fields: vec![("field".to_owned(), "value".to_owned())],
+		};
+
+		let frame = frame(record);
+
+		assert!(frame.len() <= MAX_FRAME_SIZE);
+		let decoded = JobLogRecord::decode(&mut &frame[LEN_PREFIX_SIZE..]).unwrap();
+		assert!(decoded.fields.is_empty());
+		assert!(decoded.message.chars().all(|c| c == 'ü'));
+		assert!(decoded.message.len() > MAX_FRAME_SIZE / 2);
+	}
+
+	#[test]
+	fn records_with_an_oversized_target_are_cut_to_fit_a_single_write() {
+		let record = JobLogRecord {
+			level: JobLogLevel::Info,
+			target: "ü".repeat(MAX_FRAME_SIZE),
+			message: String::new(),
+			fields: vec![("field".to_owned(), "value".to_owned())],
+		};
+
+		let frame = frame(record);
+
+		assert!(frame.len() <= MAX_FRAME_SIZE);
+		let decoded = JobLogRecord::decode(&mut &frame[LEN_PREFIX_SIZE..]).unwrap();
+		assert!(decoded.message.is_empty());
+		assert!(decoded.target.chars().all(|c| c == 'ü'));
+		assert!(decoded.target.len() > MAX_FRAME_SIZE / 2);
+	}
+}
@@ -0,0 +1,102 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A guard on the number of jobs a worker runs at once.
+//!
+//! The worker runs one job at a time for now, so the guard only trips if a job is started while
+//! another one is still running. It is meant to keep workers that run several jobs at once from
+//! taking on more than they can handle, and lets the host know how busy a worker is.
+
+use std::sync::{
+	atomic::{AtomicU32, Ordering},
+	Arc,
+};
+
+/// The maximum number of jobs a worker runs at once.
+pub const MAX_CONCURRENT_JOBS: u32 = 1;
+
+/// Counts the jobs that are running, up to a maximum.
+#[derive(Clone)]
+pub struct JobSlots {
+	running: Arc<AtomicU32>,
+	max: u32,
+}
+
+impl Default for JobSlots {
+	fn default() -> Self {
+		Self::new(MAX_CONCURRENT_JOBS)
+	}
+}
+
+impl JobSlots {
+	/// Returns slots for at most `max` jobs at once.
+	pub fn new(max: u32) -> Self {
+		Self { running: Arc::new(AtomicU32::new(0)), max }
+	}
+
+	/// Takes a slot for a job, which is given back when the returned [`JobSlot`] is dropped.
+	/// Returns `None` if all slots are taken.
+	pub fn try_acquire(&self) -> Option<JobSlot> {
+		self.running
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+				(running < self.max).then_some(running + 1)
+			})
+			.ok()
+			.map(|_| JobSlot { running: Arc::clone(&self.running) })
+	}
+
+	/// Returns the number of jobs that are running.
+	pub fn running(&self) -> u32 {
+		self.running.load(Ordering::Acquire)
+	}
+
+	/// Returns the maximum number of jobs that may run at once.
+	pub fn max(&self) -> u32 {
+		self.max
+	}
+}
+
+/// A slot taken by a running job, see [`JobSlots::try_acquire`].
+pub struct JobSlot {
+	running: Arc<AtomicU32>,
+}
+
+impl Drop for JobSlot {
+	fn drop(&mut self) {
+		self.running.fetch_sub(1, Ordering::AcqRel);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn slots_are_given_back_on_drop() {
+		let slots = JobSlots::new(2);
+
+		let first = slots.try_acquire().unwrap();
+		let second = slots.try_acquire().unwrap();
+		assert_eq!(slots.running(), 2);
+		assert!(slots.try_acquire().is_none());
+
+		drop(first);
+		assert_eq!(slots.running(), 1);
+		assert!(slots.try_acquire().is_some());
+		drop(second);
+		assert_eq!(slots.running(), 0);
+	}
+}
@@ -16,7 +16,18 @@
 
 //! Contains the logic for preparing PVFs. Used by the polkadot-prepare-worker binary.
 
+mod artifact_cache;
+mod job_log;
+mod job_slots;
 mod memory_stats;
+mod observer;
+mod panic_backtrace;
+mod persistent_cache;
+mod post_processor;
+#[cfg(target_os = "linux")]
+mod sched_stats;
+mod self_test;
+mod stage_checkpoint;
 
 // NOTE: Initializing logging in e.g. tests will not have an effect in the workers, as they are
 //       separate spawned processes. Run with e.g. `RUST_LOG=parachain::pvf-prepare-worker=trace`.
@@ -25,48 +36,82 @@ const LOG_TARGET: &str = "parachain::pvf-prepare-worker";
 #[cfg(target_os = "linux")]
 use crate::memory_stats::max_rss_stat::{extract_max_rss_stat, get_max_rss_thread};
 #[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
-use crate::memory_stats::memory_tracker::{get_memory_tracker_loop_stats, memory_tracker_loop};
+use crate::memory_stats::memory_tracker::{
+	get_memory_tracker_loop_stats, memory_tracker_loop, spawn_memory_tracker, ProcStatus,
+	SmapsRollup,
+};
+use crate::{
+	artifact_cache::{ArtifactCache, CacheKey, CachedArtifact},
+	job_log::LogPipe,
+	job_slots::{JobSlot, JobSlots},
+	persistent_cache::PersistentCache,
+	stage_checkpoint::StageCheckpoint,
+};
 use nix::{
 	errno::Errno,
 	sys::{
 		resource::{Usage, UsageWho},
+		signal::Signal,
 		wait::WaitStatus,
 	},
 	unistd::{ForkResult, Pid},
 };
+#[cfg(any(not(target_os = "macos"), test))]
+use polkadot_node_core_pvf_common::worker::pipe2_cloexec;
 use polkadot_node_core_pvf_common::{
-	executor_interface::{prepare, prevalidate},
-	worker::{pipe2_cloexec, PipeFd, WorkerInfo},
+	executor_interface::{
+		prepare, prevalidate, trust_prevalidated, PrepareStageError, RuntimeBlob,
+	},
+	worker::{PipeFd, WorkerInfo},
 };
+#[cfg(target_os = "linux")]
+use polkadot_node_core_pvf_common::{prepare::SchedStats, worker::IoPriority};
 use polkadot_node_primitives::VALIDATION_CODE_BOMB_LIMIT;
 
-use codec::{Decode, Encode};
+use codec::{Compact, Decode, Encode};
 use polkadot_node_core_pvf_common::{
-	error::{PrepareError, PrepareWorkerResult},
-	executor_interface::create_runtime_from_artifact_bytes,
-	framed_recv_blocking, framed_send_blocking,
-	prepare::{MemoryStats, PrepareJobKind, PrepareStats, PrepareWorkerSuccess},
-	pvf::PvfPrepData,
+	artifact::{
+		self, ArtifactFlags, ArtifactHeaderBuilder, ArtifactHeaderError, ArtifactVerifier,
+		ARTIFACT_COMPRESSION_LEVEL, ARTIFACT_HEADER_LEN, MAX_COMPILED_ARTIFACT_SIZE,
+	},
+	error::{PrepareError, PrepareWorkerResult, PrevalidationError, MAX_PREPARE_ERROR_MESSAGE_LEN},
+	executor_interface::{artifact_flags, create_runtime_from_artifact_bytes},
+	framed_recv_blocking, framed_recv_blocking_bounded, framed_send_blocking,
+	prepare::{
+		AppliedSecurity, BusyHint, MemoryAllocator, MemoryStats, ModuleShape, PrepareJobKind,
+		PrepareStage, PrepareStageTimings, PrepareStats, PrepareWorkerMessage,
+		PrepareWorkerRequest, PrepareWorkerSuccess, WorkerMetrics, PROTOCOL_VERSION,
+	},
+	pvf::{ArtifactFormat, CodeCompression, PvfPrepData},
 	worker::{
-		cpu_time_monitor_loop, get_total_cpu_usage, recv_child_response, run_worker, send_result,
-		stringify_errno, stringify_panic_payload,
+		cpu_time_monitor_loop, disable_graceful_shutdown, enable_graceful_shutdown,
+		get_cpu_time_elapsed, recv_fd, run_worker_with_options, send_result_with, stringify_errno,
+		stringify_panic_payload,
 		thread::{self, spawn_worker_thread, WaitOutcome},
+		wait_for_request, worker_shutdown_gracefully, worker_shutdown_idle,
+		worker_shutdown_version_mismatch, JobPriority, RequestWait, RunWorkerOptions, WorkerArgs,
 		WorkerKind,
 	},
-	worker_dir, ProcessTime,
+	worker_dir, FrameTooLarge, ProcessTime, SecurityStatus,
 };
-use polkadot_primitives::ExecutorParams;
+use polkadot_primitives::{ExecutorParams, ValidationCodeHash, MAX_CODE_SIZE};
+use sp_tracing::tracing::{dispatcher, level_filters::LevelFilter};
 use std::{
+	borrow::Cow,
 	fs,
-	io::{self, Read},
+	io::{self, Read, Seek, SeekFrom, Write},
+	mem,
 	os::{
 		fd::{AsRawFd, FromRawFd, RawFd},
 		unix::net::UnixStream,
 	},
 	path::{Path, PathBuf},
 	process,
-	sync::{mpsc::channel, Arc},
-	time::Duration,
+	sync::{
+		mpsc::{channel, RecvTimeoutError, Sender},
+		Arc,
+	},
+	time::{Duration, Instant},
 };
 use tracking_allocator::TrackingAllocator;
 
@@ -74,10 +119,14 @@ use tracking_allocator::TrackingAllocator;
 #[global_allocator]
 static ALLOC: TrackingAllocator<tikv_jemallocator::Jemalloc> =
 	TrackingAllocator(tikv_jemallocator::Jemalloc);
+#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+const ALLOCATOR: MemoryAllocator = MemoryAllocator::Jemalloc;
 
 #[cfg(not(any(target_os = "linux", feature = "jemalloc-allocator")))]
 #[global_allocator]
 static ALLOC: TrackingAllocator<std::alloc::System> = TrackingAllocator(std::alloc::System);
+#[cfg(not(any(target_os = "linux", feature = "jemalloc-allocator")))]
+const ALLOCATOR: MemoryAllocator = MemoryAllocator::System;
 
 /// The number of threads for the child process:
 /// 1 - Main thread
@@ -89,39 +138,259 @@ static ALLOC: TrackingAllocator<std::alloc::System> = TrackingAllocator(std::all
 /// the child process changes in the future, this value must be changed as well.
 pub const PREPARE_WORKER_THREAD_NUMBER: u32 = 4;
 
-/// Contains the bytes for a successfully compiled artifact.
-#[derive(Encode, Decode)]
-pub struct CompiledArtifact(Vec<u8>);
+/// The maximum size of the decompressed Wasm code accepted by prevalidation. Compressed code can't
+/// exceed it without failing to decompress, but code that was not compressed could otherwise be
+/// arbitrarily large.
+pub const MAX_PREVALIDATION_WASM_SIZE: usize = VALIDATION_CODE_BOMB_LIMIT;
 
-impl CompiledArtifact {
-	/// Creates a `CompiledArtifact`.
-	pub fn new(code: Vec<u8>) -> Self {
-		Self(code)
-	}
-}
+/// The default interval at which the memory tracker polls the memory stats of a job, see
+/// [`WorkerOptions::memory_tracker_interval`].
+///
+/// NOTE: This doesn't need to be too fine-grained since preparation currently takes 3-10s or more.
+/// Apart from that, there is not really a science to this number.
+pub const DEFAULT_MEMORY_TRACKER_INTERVAL: Duration = Duration::from_millis(100);
 
-impl AsRef<[u8]> for CompiledArtifact {
-	fn as_ref(&self) -> &[u8] {
-		self.0.as_slice()
-	}
-}
+pub use observer::{NoopPrepareObserver, PrepareObserver};
+pub use polkadot_node_core_pvf_common::artifact::CompiledArtifact;
+pub use post_processor::{ArtifactPostProcessor, NoopArtifactPostProcessor};
+pub use self_test::{self_test, SelfTestCheck, SelfTestReport};
 
-#[derive(Encode, Decode)]
 pub struct PrepareOutcome {
-	pub compiled_artifact: CompiledArtifact,
+	/// The uncompressed artifact, as produced by the compiler. `None` for
+	/// [`PrepareJobKind::ValidateOnly`] jobs.
+	pub compiled_artifact: Option<Vec<u8>>,
 	pub observed_wasm_code_len: u32,
+	pub stage_timings: Option<PrepareStageTimings>,
+	/// The warnings the compiler emitted, see [`PrepareStats::compiler_warnings`].
+	pub compiler_warnings: Vec<String>,
+	/// See [`PrepareStats::compile_cpu_time`].
+	pub compile_cpu_time: Duration,
+	pub module_shape: ModuleShape,
+	/// When the compilation started. `None` for [`PrepareJobKind::ValidateOnly`] jobs.
+	pub compile_start: Option<Instant>,
+}
+
+/// Receives the protocol version of the host and replies whether the worker speaks it too, was
+/// built from the same version as the node, and can write to its worker dir. Returns the error
+/// told to the host, if any.
+fn negotiate_protocol_version(
+	stream: &mut UnixStream,
+	worker_dir_path: &Path,
+	node_version: Option<&str>,
+	worker_version: Option<&str>,
+) -> io::Result<Result<(), PrepareError>> {
+	let host_version = framed_recv_blocking(stream)?;
+	let host_version = u32::decode(&mut &host_version[..]).map_err(|e| {
+		io::Error::new(
+			io::ErrorKind::Other,
+			format!("prepare pvf negotiate_protocol_version: failed to decode version: {}", e),
+		)
+	})?;
+
+	// A worker of another version likely speaks another protocol too, but the version mismatch is
+	// what the node owner has to act on.
+	let result = match (node_version, worker_version) {
+		(Some(node), Some(worker)) if node != worker =>
+			Err(PrepareError::VersionMismatch { node: node.into(), worker: worker.into() }),
+		_ if host_version != PROTOCOL_VERSION =>
+			Err(PrepareError::ProtocolMismatch { host: host_version, worker: PROTOCOL_VERSION }),
+		_ => check_worker_dir_writable(worker_dir_path),
+	};
+	framed_send_blocking(stream, &result.encode())?;
+	Ok(result)
+}
+
+/// Checks that the permissions of the worker dir let the worker write to it. Jobs would otherwise
+/// only fail once they write their artifact, with a less clear error.
+///
+/// This runs after landlock was enabled, which doesn't let the worker create or remove files, so
+/// rather than writing a file we ask `access(2)`, which landlock doesn't restrict.
+fn check_worker_dir_writable(worker_dir_path: &Path) -> Result<(), PrepareError> {
+	nix::unistd::access(worker_dir_path, nix::unistd::AccessFlags::W_OK).map_err(|errno| {
+		PrepareError::WorkerDirUnwritable {
+			path: worker_dir_path.display().to_string(),
+			err: io::Error::from(errno).to_string(),
+		}
+	})
+}
+
+/// Whether a job with the given security features applied can capture the backtrace of a panic.
+/// Symbolizing the backtrace opens the worker binary, which seccomp doesn't allow, so the job would
+/// be killed instead of reporting the panic.
+fn can_capture_panic_backtraces(applied_security: AppliedSecurity) -> bool {
+	!applied_security.contains(AppliedSecurity::SECCOMP)
 }
 
-/// Get a worker request.
-fn recv_request(stream: &mut UnixStream) -> io::Result<PvfPrepData> {
-	let pvf = framed_recv_blocking(stream)?;
-	let pvf = PvfPrepData::decode(&mut &pvf[..]).map_err(|e| {
+/// The maximum size of a request from the host, in bytes. A request carries the code, which is
+/// bounded by [`MAX_CODE_SIZE`], and the executor params, which are small, so anything bigger is
+/// bogus. A batch has to fit into this as a whole.
+const MAX_REQUEST_SIZE: usize = MAX_CODE_SIZE as usize + 1024 * 1024;
+
+/// Get a worker request. If the host left the code out of a single request, it is read from the
+/// code file in the worker dir. As there is only one code file, the PVFs of a batch must carry
+/// their code.
+///
+/// A probing request only carries the code hash and the executor params. If the artifact is cached,
+/// it is returned as a single request without code, which [`prepare_job`] serves from the cache.
+/// Otherwise the host is asked for the code, and the single request it answers with is returned.
+///
+/// The file descriptor following a [`PrepareWorkerRequest::SingleToFd`] is left for the caller to
+/// receive.
+///
+/// PVFs that left out their executor params get the default ones, see
+/// [`PrepareWorkerRequest::SetDefaultExecutorParams`].
+///
+/// A request larger than [`MAX_REQUEST_SIZE`] is rejected before it is read, and the host is told
+/// so. As the stream can't be read any further in that case, this returns an error too.
+fn recv_request(
+	stream: &mut UnixStream,
+	worker_dir_path: &Path,
+	artifact_cache: &ArtifactCache,
+	default_executor_params: Option<&Arc<ExecutorParams>>,
+) -> io::Result<PrepareWorkerRequest> {
+	let (pvf, to_fd) = match recv_request_frame(stream, default_executor_params)? {
+		PrepareWorkerRequest::Single(pvf) => (pvf, false),
+		PrepareWorkerRequest::SingleToFd(pvf) => (pvf, true),
+		PrepareWorkerRequest::Batch(pvfs) => {
+			if pvfs.iter().any(|pvf| pvf.code_in_file()) {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					"prepare pvf recv_request: batched PVFs must carry their code",
+				))
+			}
+			return Ok(PrepareWorkerRequest::Batch(pvfs))
+		},
+		PrepareWorkerRequest::Reset => return Ok(PrepareWorkerRequest::Reset),
+		PrepareWorkerRequest::Cancel => return Ok(PrepareWorkerRequest::Cancel),
+		PrepareWorkerRequest::QuerySecurityStatus =>
+			return Ok(PrepareWorkerRequest::QuerySecurityStatus),
+		PrepareWorkerRequest::SetDefaultExecutorParams(executor_params) =>
+			return Ok(PrepareWorkerRequest::SetDefaultExecutorParams(executor_params)),
+		PrepareWorkerRequest::Probe(pvf) => {
+			if is_cacheable(&pvf) && artifact_cache.contains(&CacheKey::new(&pvf)) {
+				return Ok(PrepareWorkerRequest::Single(pvf))
+			}
+			framed_send_blocking(stream, &PrepareWorkerMessage::CodeRequested.encode())?;
+			match recv_request_frame(stream, default_executor_params)? {
+				PrepareWorkerRequest::Single(full) if full.code_hash() == pvf.code_hash() =>
+					(full, false),
+				// The host gave up on the job before sending the code.
+				PrepareWorkerRequest::Cancel => {
+					let message = PrepareWorkerMessage::Result {
+						request_id: pvf.request_id(),
+						reached_stage: None,
+						result: Err(PrepareError::Cancelled),
+					};
+					framed_send_blocking(stream, &message.encode())?;
+					return Ok(PrepareWorkerRequest::Cancel)
+				},
+				_ =>
+					return Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						"prepare pvf recv_request: expected the probed PVF in full",
+					)),
+			}
+		},
+	};
+	let single =
+		if to_fd { PrepareWorkerRequest::SingleToFd } else { PrepareWorkerRequest::Single };
+	if !pvf.code_in_file() {
+		return Ok(single(pvf))
+	}
+
+	let code = fs::read(worker_dir::prepare_code(worker_dir_path))?;
+	pvf.with_code(code).map(single).ok_or_else(|| {
+		io::Error::new(
+			io::ErrorKind::InvalidData,
+			"prepare pvf recv_request: code file does not match the code hash",
+		)
+	})
+}
+
+/// Reads and decodes a single request frame, see [`recv_request`].
+fn recv_request_frame(
+	stream: &mut UnixStream,
+	default_executor_params: Option<&Arc<ExecutorParams>>,
+) -> io::Result<PrepareWorkerRequest> {
+	let request = match framed_recv_blocking_bounded(stream, MAX_REQUEST_SIZE) {
+		Ok(request) => request,
+		Err(err) => {
+			if let Some(too_large) =
+				err.get_ref().and_then(|err| err.downcast_ref::<FrameTooLarge>())
+			{
+				let result: PrepareWorkerResult = Err(PrepareError::MessageTooLarge {
+					len: too_large.len as u64,
+					max: too_large.max_len as u64,
+				});
+				let message =
+					PrepareWorkerMessage::Result { request_id: None, reached_stage: None, result };
+				framed_send_blocking(stream, &message.encode())?;
+			}
+			return Err(err)
+		},
+	};
+	let request = PrepareWorkerRequest::decode(&mut &request[..]).map_err(|e| {
 		io::Error::new(
 			io::ErrorKind::Other,
-			format!("prepare pvf recv_request: failed to decode PvfPrepData: {}", e),
+			format!("prepare pvf recv_request: failed to decode PrepareWorkerRequest: {}", e),
 		)
 	})?;
-	Ok(pvf)
+	fill_in_executor_params(request, default_executor_params)
+}
+
+/// Gives the default executor params to the PVFs of the request that left theirs out. Fails if
+/// there are no default executor params.
+fn fill_in_executor_params(
+	request: PrepareWorkerRequest,
+	default_executor_params: Option<&Arc<ExecutorParams>>,
+) -> io::Result<PrepareWorkerRequest> {
+	let fill_in = |pvf: PvfPrepData| {
+		if !pvf.executor_params_omitted() {
+			return Ok(pvf)
+		}
+		default_executor_params
+			.map(|executor_params| pvf.with_executor_params(executor_params.clone()))
+			.ok_or_else(|| {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					"prepare pvf recv_request: no default executor params were set",
+				)
+			})
+	};
+	Ok(match request {
+		PrepareWorkerRequest::Single(pvf) => PrepareWorkerRequest::Single(fill_in(pvf)?),
+		PrepareWorkerRequest::SingleToFd(pvf) => PrepareWorkerRequest::SingleToFd(fill_in(pvf)?),
+		PrepareWorkerRequest::Probe(pvf) => PrepareWorkerRequest::Probe(fill_in(pvf)?),
+		PrepareWorkerRequest::Batch(pvfs) =>
+			PrepareWorkerRequest::Batch(pvfs.into_iter().map(fill_in).collect::<io::Result<_>>()?),
+		request => request,
+	})
+}
+
+/// Makes the PVFs of the request go through prevalidation, even if the host vouched for their code,
+/// see [`WorkerOptions::trust_prevalidated_code`].
+fn enforce_prevalidation(request: PrepareWorkerRequest) -> PrepareWorkerRequest {
+	map_pvfs(request, PvfPrepData::with_prevalidation_enforced)
+}
+
+/// Keeps the jobs of the request from dumping their core, see [`WorkerOptions::allow_core_dumps`].
+fn deny_core_dumps(request: PrepareWorkerRequest) -> PrepareWorkerRequest {
+	map_pvfs(request, PvfPrepData::without_core_dumps)
+}
+
+/// Applies `f` to every PVF of the request.
+fn map_pvfs(
+	request: PrepareWorkerRequest,
+	f: impl Fn(PvfPrepData) -> PvfPrepData,
+) -> PrepareWorkerRequest {
+	match request {
+		PrepareWorkerRequest::Single(pvf) => PrepareWorkerRequest::Single(f(pvf)),
+		PrepareWorkerRequest::SingleToFd(pvf) => PrepareWorkerRequest::SingleToFd(f(pvf)),
+		PrepareWorkerRequest::Probe(pvf) => PrepareWorkerRequest::Probe(f(pvf)),
+		PrepareWorkerRequest::Batch(pvfs) =>
+			PrepareWorkerRequest::Batch(pvfs.into_iter().map(f).collect()),
+		request => request,
+	}
 }
 
 fn start_memory_tracking(fd: RawFd, limit: Option<isize>) {
@@ -170,19 +439,28 @@ fn end_memory_tracking() -> isize {
 ///
 /// # Parameters
 ///
-/// - `socket_path`: specifies the path to the socket used to communicate with the host.
+/// - `socket_path`: specifies the path to the socket used to communicate with the host. A path
+///   starting with a NUL byte is an abstract address, see
+///   [`connect_to_host`](polkadot_node_core_pvf_common::worker::connect_to_host).
 ///
 /// - `worker_dir_path`: specifies the path to the worker-specific temporary directory.
 ///
-/// - `node_version`: if `Some`, is checked against the `worker_version`. A mismatch results in
-///   immediate worker termination. `None` is used for tests and in other situations when version
-///   check is not necessary.
+/// - `node_version`: if `Some`, is checked against the `worker_version`. A mismatch is reported to
+///   the host as [`PrepareError::VersionMismatch`] while negotiating the protocol version, after
+///   which the worker tears down the node and exits. `None` is used for tests and in other
+///   situations when version check is not necessary.
 ///
 /// - `worker_version`: see above
 ///
+/// - `idle_timeout`: if `Some`, the worker exits cleanly once it received no request for this long,
+///   see [`WorkerOptions::idle_timeout`].
+///
+/// - `args`: the options only the prepare worker takes, see [`PrepareWorkerArgs`].
+///
 /// # Flow
 ///
-/// This runs the following in a loop:
+/// First the protocol version is negotiated with the host, see [`PROTOCOL_VERSION`]. Then this runs
+/// the following in a loop:
 ///
 /// 1. Get the code and parameters for preparation from the host.
 ///
@@ -196,592 +474,4646 @@ fn end_memory_tracking() -> isize {
 ///
 /// 6. Pipe the result back to the parent process and exit from child process.
 ///
-/// 7. If compilation succeeded, write the compiled artifact into a temporary file.
+/// 7. If compilation succeeded, write the compiled artifact into the temporary file created by the
+///    host and run the [`ArtifactPostProcessor`] on it. For a [`PrepareWorkerRequest::SingleToFd`],
+///    the artifact is then copied into the file the host sent along with the request.
 ///
 /// 8. Send the result of preparation back to the host, including the checksum of the artifact. If
-///    any error occurred in the above steps, we send that in the `PrepareWorkerResult`.
+///    any error occurred in the above steps, we send that in the `PrepareWorkerResult`. If the host
+///    asked for the metrics of the job or a [`BusyHint`], they are sent right before the result.
+///
+/// A request may also carry a batch of PVFs, see [`PrepareWorkerRequest::Batch`]. Steps 2 to 7 are
+/// then run for each of them in turn, and their results are sent back together once all are done.
+///
+/// On macOS there is no job process: the preparation runs in a thread of the worker instead, see
+/// [`handle_in_process`]. This is only meant for debugging and must not be used in production.
 pub fn worker_entrypoint(
 	socket_path: PathBuf,
 	worker_dir_path: PathBuf,
 	node_version: Option<&str>,
 	worker_version: Option<&str>,
+	idle_timeout: Option<Duration>,
+	args: PrepareWorkerArgs,
 ) {
-	run_worker(
-		WorkerKind::Prepare,
+	worker_entrypoint_with_options(
 		socket_path,
 		worker_dir_path,
 		node_version,
 		worker_version,
+		WorkerOptions {
+			idle_timeout,
+			require_secure: args.require_secure,
+			cpu_affinity: args.cpu_affinity,
+			job_priority: args.job_priority,
+			allow_core_dumps: args.allow_core_dumps,
+			..Default::default()
+		},
+	)
+}
+
+/// The command-line options only the prepare worker takes, parsed by `decl_worker_main!`.
+#[derive(Debug, Default)]
+pub struct PrepareWorkerArgs {
+	/// `--require-secure`, see [`WorkerOptions::require_secure`].
+	pub require_secure: bool,
+	/// `--cpu-affinity-mask <mask>`, with the mask in hexadecimal, see
+	/// [`WorkerOptions::cpu_affinity`].
+	pub cpu_affinity: Option<u64>,
+	/// `--job-nice <nice>` and `--job-io-priority <idle|best-effort:<level>>`, see
+	/// [`WorkerOptions::job_priority`].
+	pub job_priority: JobPriority,
+	/// `--allow-core-dumps`, see [`WorkerOptions::allow_core_dumps`].
+	pub allow_core_dumps: bool,
+}
+
+impl WorkerArgs for PrepareWorkerArgs {
+	fn parse_arg<'a>(&mut self, arg: &str, values: &mut impl Iterator<Item = &'a str>) -> bool {
+		match arg {
+			"--require-secure" => self.require_secure = true,
+			"--allow-core-dumps" => self.allow_core_dumps = true,
+			"--cpu-affinity-mask" => {
+				let mask = values
+					.next()
+					.and_then(|mask| u64::from_str_radix(mask.trim_start_matches("0x"), 16).ok())
+					.expect("the --cpu-affinity-mask argument must be a hexadecimal core mask");
+				self.cpu_affinity = Some(mask);
+			},
+			"--job-nice" => {
+				let nice = values
+					.next()
+					.and_then(|nice| nice.parse().ok())
+					.expect("the --job-nice argument must be a nice value");
+				self.job_priority.nice = Some(nice);
+			},
+			"--job-io-priority" => {
+				let io_priority = values.next().and_then(|priority| priority.parse().ok()).expect(
+					"the --job-io-priority argument must be `idle` or `best-effort:<level>`",
+				);
+				self.job_priority.io_priority = Some(io_priority);
+			},
+			_ => return false,
+		}
+		true
+	}
+}
+
+/// Optional behavior of the worker, for downstream builds of it. The defaults are what
+/// [`worker_entrypoint`] uses.
+pub struct WorkerOptions<'a> {
+	/// Runs on every artifact before it is handed to the host, e.g. to sign it.
+	pub post_processor: &'a dyn ArtifactPostProcessor,
+	/// Notified of the milestones of every job, e.g. to supervise the worker.
+	pub observer: &'a dyn PrepareObserver,
+	/// The directory to write artifacts to, if not the worker dir. This allows keeping artifacts
+	/// on different storage than the scratch space of the worker.
+	///
+	/// Several workers may share the directory, so each names its artifacts after its worker dir,
+	/// see [`worker_dir::prepare_shared_tmp_artifact`]. The worker reports where it wrote the
+	/// artifact, but the host only picks it up from there if it was configured with the same
+	/// directory. The directory must also be accessible to the worker, which rules out changing
+	/// its root or enabling landlock for it.
+	///
+	/// Identical artifacts written there share one file, through hard links kept in its
+	/// `deduplicated-artifacts` subdir. Links to artifacts that were removed in the meantime are
+	/// dropped whenever a new artifact is added. Artifacts in the worker dir are never
+	/// deduplicated.
+	pub artifact_dir: Option<PathBuf>,
+	/// Whether to persist prepared artifacts in the worker dir, along with an index of them, so
+	/// that a worker restarted on the same worker dir can reuse them instead of compiling again.
+	/// The host gives every worker a new worker dir, so this is for embedders that keep theirs.
+	///
+	/// Ignored if [`Self::allow_core_dumps`] is set, as jobs dumping their core may write to the
+	/// worker dir, and could plant artifacts that later requests for other PVFs would be served.
+	pub persistent_cache: bool,
+	/// How long the worker waits for a request before exiting with a success status, if at all.
+	/// This frees the resources held by workers that are no longer needed, and is unrelated to the
+	/// graceful shutdown on `SIGTERM`.
+	pub idle_timeout: Option<Duration>,
+	/// The interval at which the memory tracker polls the memory stats of a job. Shorter intervals
+	/// catch short peaks of short jobs, at the cost of more CPU time for long ones.
+	pub memory_tracker_interval: Duration,
+	/// Whether to refuse preparing anything if landlock or seccomp is not available, instead of
+	/// preparing without them. Each request then fails with [`PrepareError::InsecureEnvironment`]
+	/// without starting a job.
+	pub require_secure: bool,
+	/// The cores to pin job processes to, where bit `n` of the mask stands for core `n`. On hosts
+	/// with several NUMA nodes, keeping the compilation on one core set avoids thrashing the
+	/// caches of the others. Only supported on Linux, and ignored elsewhere.
+	pub cpu_affinity: Option<u64>,
+	/// The nice value and IO scheduling class of job processes, e.g. to keep background
+	/// preparation from slowing down the validator. Only supported on Linux, and ignored
+	/// elsewhere.
+	pub job_priority: JobPriority,
+	/// Whether to skip prevalidating the code of PVFs the host vouches for, see
+	/// [`PvfPrepData::with_prevalidated_code`]. The size of the code is still checked. Only to be
+	/// set if the host is trusted to only vouch for code it prevalidated itself, as the code could
+	/// use features that are not enabled otherwise. If unset, all code is prevalidated.
+	pub trust_prevalidated_code: bool,
+	/// Whether jobs may dump their core into the worker dir if their PVF asks for it, see
+	/// [`PvfPrepData::with_core_dumps`]. With landlock, this lets the worker and its jobs create
+	/// files in the worker dir, so it is only meant for debugging. If unset, PVFs asking for core
+	/// dumps are prepared without.
+	pub allow_core_dumps: bool,
+}
+
+impl Default for WorkerOptions<'_> {
+	fn default() -> Self {
+		Self {
+			post_processor: &NoopArtifactPostProcessor,
+			observer: &NoopPrepareObserver,
+			artifact_dir: None,
+			persistent_cache: false,
+			idle_timeout: None,
+			memory_tracker_interval: DEFAULT_MEMORY_TRACKER_INTERVAL,
+			require_secure: false,
+			cpu_affinity: None,
+			job_priority: JobPriority::default(),
+			trust_prevalidated_code: false,
+			allow_core_dumps: false,
+		}
+	}
+}
+
+impl WorkerOptions<'_> {
+	/// The file the worker writes the artifact of a job to.
+	fn tmp_artifact_dest(&self, worker_dir_path: &Path) -> PathBuf {
+		match &self.artifact_dir {
+			Some(artifact_dir) =>
+				worker_dir::prepare_shared_tmp_artifact(artifact_dir, worker_dir_path),
+			None => worker_dir::prepare_tmp_artifact(worker_dir_path),
+		}
+	}
+
+	/// The file the worker writes the artifact of the job at `index` of a batch to.
+	fn tmp_batch_artifact_dest(&self, worker_dir_path: &Path, index: usize) -> PathBuf {
+		match &self.artifact_dir {
+			Some(artifact_dir) =>
+				worker_dir::prepare_shared_tmp_batch_artifact(artifact_dir, worker_dir_path, index),
+			None => worker_dir::prepare_tmp_batch_artifact(worker_dir_path, index),
+		}
+	}
+}
+
+/// Like [`worker_entrypoint`], but with the given options.
+pub fn worker_entrypoint_with_options(
+	socket_path: PathBuf,
+	worker_dir_path: PathBuf,
+	node_version: Option<&str>,
+	worker_version: Option<&str>,
+	options: WorkerOptions,
+) {
+	let delivery = ArtifactDelivery::new(&options);
+	let observer = options.observer;
+	// The worker dir as seen by the host, even if the worker changes its root.
+	let host_worker_dir_path = worker_dir_path.clone();
+	// The versions are checked once connected to the host, so that it learns about a mismatch.
+	run_worker_with_options(
+		WorkerKind::Prepare,
+		socket_path,
+		worker_dir_path,
+		None,
+		worker_version,
+		RunWorkerOptions { allow_core_dumps: options.allow_core_dumps },
 		|mut stream, worker_info, security_status| {
-			let temp_artifact_dest = worker_dir::prepare_tmp_artifact(&worker_info.worker_dir_path);
+			let temp_artifact_dest = options.tmp_artifact_dest(&worker_info.worker_dir_path);
+			let host_artifact_dest = options.tmp_artifact_dest(&host_worker_dir_path);
 
-			loop {
-				let pvf = recv_request(&mut stream)?;
-				gum::debug!(
+			enable_graceful_shutdown()
+				.map_err(|errno| io::Error::new(io::ErrorKind::Other, errno.to_string()))?;
+
+			let negotiated = negotiate_protocol_version(
+				&mut stream,
+				&worker_info.worker_dir_path,
+				node_version,
+				worker_version,
+			)?;
+			match negotiated {
+				Ok(()) => {},
+				Err(PrepareError::VersionMismatch { node, .. }) =>
+					worker_shutdown_version_mismatch(worker_info, &node),
+				Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+			}
+
+			let mut artifact_cache = ArtifactCache::default();
+			if options.persistent_cache && options.allow_core_dumps {
+				gum::warn!(
 					target: LOG_TARGET,
 					?worker_info,
-					?security_status,
-					"worker: preparing artifact",
+					"worker: not persisting artifacts, as jobs may dump their core into the worker dir",
 				);
+			}
+			let mut persistent_cache = (options.persistent_cache && !options.allow_core_dumps)
+				.then(|| PersistentCache::load(&worker_info.worker_dir_path));
+			let mut default_executor_params = None;
+			let job_slots = JobSlots::default();
+			let env = JobEnvironment {
+				worker_info,
+				security_status: &security_status,
+				delivery,
+				observer,
+				memory_tracker_interval: options.memory_tracker_interval,
+				require_secure: options.require_secure,
+				cpu_affinity: options.cpu_affinity,
+				job_priority: options.job_priority,
+				job_slots: &job_slots,
+			};
 
-				let preparation_timeout = pvf.prep_timeout();
-				let prepare_job_kind = pvf.prep_kind();
-				let executor_params = pvf.executor_params();
-
-				let (pipe_read_fd, pipe_write_fd) = pipe2_cloexec()?;
-
-				let usage_before = match nix::sys::resource::getrusage(UsageWho::RUSAGE_CHILDREN) {
-					Ok(usage) => usage,
-					Err(errno) => {
-						let result: PrepareWorkerResult =
-							Err(error_from_errno("getrusage before", errno));
-						send_result(&mut stream, result, worker_info)?;
-						continue
+			loop {
+				// A job in progress, including writing its artifact, is always finished before
+				// we get here, so we only need to check for shutdown in between jobs.
+				match wait_for_request(&stream, options.idle_timeout)? {
+					RequestWait::Request => {},
+					// By the time the host asks us to shut down, or stops sending requests, it has
+					// handled the result of the last job, so any leftover artifact is stale.
+					RequestWait::Shutdown => {
+						remove_stale_artifact(&temp_artifact_dest, worker_info);
+						worker_shutdown_gracefully(worker_info);
 					},
-				};
+					RequestWait::Idle => {
+						remove_stale_artifact(&temp_artifact_dest, worker_info);
+						worker_shutdown_idle(worker_info);
+					},
+				}
 
-				let stream_fd = stream.as_raw_fd();
-
-				cfg_if::cfg_if! {
-					if #[cfg(target_os = "linux")] {
-						let result = if security_status.can_do_secure_clone {
-							handle_clone(
-								&pvf,
-								pipe_write_fd,
-								pipe_read_fd,
-								stream_fd,
-								preparation_timeout,
-								prepare_job_kind,
-								&executor_params,
-								worker_info,
-								security_status.can_unshare_user_namespace_and_change_root,
-								&temp_artifact_dest,
-								usage_before,
-							)
-						} else {
-							// Fall back to using fork.
-							handle_fork(
-								&pvf,
-								pipe_write_fd,
-								pipe_read_fd,
-								stream_fd,
-								preparation_timeout,
-								prepare_job_kind,
-								&executor_params,
-								worker_info,
-								&temp_artifact_dest,
-								usage_before,
-							)
-						};
-					} else {
-						let result = handle_fork(
+				let request = recv_request(
+					&mut stream,
+					&worker_info.worker_dir_path,
+					&artifact_cache,
+					default_executor_params.as_ref(),
+				)?;
+				let request = if options.trust_prevalidated_code {
+					request
+				} else {
+					enforce_prevalidation(request)
+				};
+				let request =
+					if options.allow_core_dumps { request } else { deny_core_dumps(request) };
+				// Closed once the job concluded.
+				let output = match request {
+					PrepareWorkerRequest::SingleToFd(_) => Some(fs::File::from(recv_fd(&stream)?)),
+					_ => None,
+				};
+				match request {
+					// Probes are resolved into single requests by `recv_request`.
+					PrepareWorkerRequest::Single(pvf) |
+					PrepareWorkerRequest::Probe(pvf) |
+					PrepareWorkerRequest::SingleToFd(pvf) => {
+						let job_start = Instant::now();
+						let (result, reached_stage) = prepare_job(
 							&pvf,
-							pipe_write_fd,
-							pipe_read_fd,
-							stream_fd,
-							preparation_timeout,
-							prepare_job_kind,
-							&executor_params,
-							worker_info,
+							job_start,
 							&temp_artifact_dest,
-							usage_before,
+							output.as_ref(),
+							&mut artifact_cache,
+							persistent_cache.as_mut(),
+							&stream,
+							&env,
+						)?;
+						send_response(
+							&mut stream,
+							result,
+							reached_stage,
+							&pvf,
+							job_start,
+							output.is_none().then_some(host_artifact_dest.as_path()),
+							&job_slots,
+							worker_info,
+						)?;
+					},
+					PrepareWorkerRequest::Batch(pvfs) => {
+						gum::debug!(
+							target: LOG_TARGET,
+							?worker_info,
+							batch_len = pvfs.len(),
+							"worker: preparing batch",
 						);
-					}
+						let mut results = Vec::with_capacity(pvfs.len());
+						for (index, pvf) in pvfs.iter().enumerate() {
+							if matches!(results.last(), Some(Err(PrepareError::Cancelled))) {
+								results.push(Err(PrepareError::Cancelled));
+								continue
+							}
+							let temp_artifact_dest = options
+								.tmp_batch_artifact_dest(&worker_info.worker_dir_path, index);
+							let (result, _reached_stage) = prepare_job(
+								pvf,
+								Instant::now(),
+								&temp_artifact_dest,
+								None,
+								&mut artifact_cache,
+								persistent_cache.as_mut(),
+								&stream,
+								&env,
+							)?;
+							let host_artifact_dest =
+								options.tmp_batch_artifact_dest(&host_worker_dir_path, index);
+							results.push(
+								result.map(|success| {
+									with_artifact_path(success, &host_artifact_dest)
+								}),
+							);
+						}
+						send_batch_response(&mut stream, results, worker_info)?;
+					},
+					PrepareWorkerRequest::Reset => {
+						gum::debug!(target: LOG_TARGET, ?worker_info, "worker: resetting");
+						artifact_cache.clear();
+						default_executor_params = None;
+						remove_stale_artifact(&temp_artifact_dest, worker_info);
+						framed_send_blocking(
+							&mut stream,
+							&PrepareWorkerMessage::ResetDone.encode(),
+						)?;
+					},
+					// The job the host meant to cancel concluded before the cancellation arrived,
+					// or was a probe that was cancelled by `recv_request`.
+					PrepareWorkerRequest::Cancel => {
+						gum::debug!(target: LOG_TARGET, ?worker_info, "worker: no job to cancel");
+					},
+					PrepareWorkerRequest::SetDefaultExecutorParams(executor_params) => {
+						gum::debug!(
+							target: LOG_TARGET,
+							?worker_info,
+							"worker: setting the default executor params",
+						);
+						default_executor_params = Some(Arc::new(executor_params));
+					},
+					PrepareWorkerRequest::QuerySecurityStatus => {
+						framed_send_blocking(
+							&mut stream,
+							&PrepareWorkerMessage::SecurityStatus(security_status.clone()).encode(),
+						)?;
+					},
 				}
-
-				gum::trace!(
-					target: LOG_TARGET,
-					?worker_info,
-					"worker: sending result to host: {:?}",
-					result
-				);
-				send_result(&mut stream, result, worker_info)?;
 			}
 		},
 	);
 }
 
-fn prepare_artifact(pvf: PvfPrepData) -> Result<PrepareOutcome, PrepareError> {
-	let maybe_compressed_code = pvf.maybe_compressed_code();
-	let raw_validation_code =
-		sp_maybe_compressed_blob::decompress(&maybe_compressed_code, VALIDATION_CODE_BOMB_LIMIT)
-			.map_err(|e| PrepareError::CouldNotDecompressCodeBlob(e.to_string()))?;
-	let observed_wasm_code_len = raw_validation_code.len() as u32;
-
-	let blob = match prevalidate(&raw_validation_code) {
-		Err(err) => return Err(PrepareError::Prevalidation(format!("{:?}", err))),
-		Ok(b) => b,
-	};
-
-	match prepare(blob, &pvf.executor_params()) {
-		Ok(compiled_artifact) => Ok(PrepareOutcome {
-			compiled_artifact: CompiledArtifact::new(compiled_artifact),
-			observed_wasm_code_len,
-		}),
-		Err(err) => Err(PrepareError::Preparation(format!("{:?}", err))),
+/// Fails with [`PrepareError::InsecureEnvironment`] if `require_secure` is set, but landlock or
+/// seccomp is not available. See [`WorkerOptions::require_secure`].
+fn check_secure_environment(
+	security_status: &SecurityStatus,
+	require_secure: bool,
+) -> Result<(), PrepareError> {
+	if !require_secure {
+		return Ok(())
+	}
+	let missing: Vec<_> = [
+		(security_status.can_enable_landlock, "landlock"),
+		(security_status.can_enable_seccomp, "seccomp"),
+	]
+	.into_iter()
+	.filter_map(|(available, feature)| (!available).then_some(feature))
+	.collect();
+	if missing.is_empty() {
+		Ok(())
+	} else {
+		Err(PrepareError::InsecureEnvironment(missing.join(" and ")))
 	}
 }
 
-/// Try constructing the runtime to catch any instantiation errors during pre-checking.
-fn runtime_construction_check(
-	artifact_bytes: &[u8],
-	executor_params: &ExecutorParams,
-) -> Result<(), PrepareError> {
-	// SAFETY: We just compiled this artifact.
-	let result = unsafe { create_runtime_from_artifact_bytes(artifact_bytes, executor_params) };
-	result
-		.map(|_runtime| ())
-		.map_err(|err| PrepareError::RuntimeConstruction(format!("{:?}", err)))
+/// Takes a slot for a job, or fails with [`PrepareError::Overloaded`] if the worker is already
+/// running as many jobs as it can.
+fn acquire_job_slot(job_slots: &JobSlots) -> Result<JobSlot, PrepareError> {
+	job_slots
+		.try_acquire()
+		.ok_or(PrepareError::Overloaded { max_jobs: job_slots.max() })
 }
 
-#[derive(Encode, Decode)]
-struct JobResponse {
-	artifact: CompiledArtifact,
-	memory_stats: MemoryStats,
-	observed_wasm_code_len: u32,
+/// Returns how busy the worker is, see [`BusyHint`].
+fn busy_hint(stream: &UnixStream, job_slots: &JobSlots) -> BusyHint {
+	let mut pending: libc::c_int = 0;
+	// SAFETY: `FIONREAD` writes the number of unread bytes of the socket to the given `c_int`.
+	let res = unsafe { libc::ioctl(stream.as_raw_fd(), libc::FIONREAD, &mut pending) };
+	BusyHint {
+		running_jobs: job_slots.running(),
+		max_jobs: job_slots.max(),
+		// The hint is best effort, so a failure just leaves out the pending requests.
+		pending_request_bytes: if res == 0 { pending as u64 } else { 0 },
+	}
 }
 
-#[cfg(target_os = "linux")]
-fn handle_clone(
-	pvf: &PvfPrepData,
-	pipe_write_fd: i32,
-	pipe_read_fd: i32,
-	stream_fd: i32,
-	preparation_timeout: Duration,
-	prepare_job_kind: PrepareJobKind,
-	executor_params: &Arc<ExecutorParams>,
-	worker_info: &WorkerInfo,
-	have_unshare_newuser: bool,
-	temp_artifact_dest: &Path,
-	usage_before: Usage,
-) -> Result<PrepareWorkerSuccess, PrepareError> {
-	use polkadot_node_core_pvf_common::worker::security;
-
-	// SAFETY: new process is spawned within a single threaded process. This invariant
-	// is enforced by tests. Stack size being specified to ensure child doesn't overflow
-	match unsafe {
-		security::clone::clone_on_worker(
-			worker_info,
-			have_unshare_newuser,
-			Box::new(|| {
-				handle_child_process(
-					pvf.clone(),
-					pipe_write_fd,
-					pipe_read_fd,
-					stream_fd,
-					preparation_timeout,
-					prepare_job_kind,
-					Arc::clone(&executor_params),
-				)
-			}),
-		)
-	} {
-		Ok(child) => handle_parent_process(
-			pipe_read_fd,
-			pipe_write_fd,
-			worker_info,
-			child,
-			temp_artifact_dest,
-			usage_before,
-			preparation_timeout,
-		),
-		Err(security::clone::Error::Clone(errno)) => Err(error_from_errno("clone", errno)),
+/// Removes the temporary artifact of the last job before the worker exits.
+fn remove_stale_artifact(temp_artifact_dest: &Path, worker_info: &WorkerInfo) {
+	if let Err(err) = fs::remove_file(temp_artifact_dest) {
+		// Landlock doesn't let the worker remove files, but the host removes the worker dir anyway.
+		if !matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied) {
+			gum::warn!(
+				target: LOG_TARGET,
+				?worker_info,
+				"failed to remove the temporary artifact on shutdown: {}",
+				err,
+			);
+		}
 	}
 }
 
-fn handle_fork(
+/// Prepares the given PVF in a job process, or serves it from the cache, writing the artifact to
+/// `output` if given, or to `temp_artifact_dest` otherwise. Artifacts are persisted as well, if
+/// there is a `persistent_cache`, which is consulted when the artifact is not cached in memory.
+/// Errors of the job are part of the returned result, while an error returned here means that the
+/// worker can't go on. The result comes with the last stage the job reached, see
+/// [`PrepareWorkerMessage::Result`].
+fn prepare_job(
 	pvf: &PvfPrepData,
-	pipe_write_fd: i32,
-	pipe_read_fd: i32,
-	stream_fd: i32,
-	preparation_timeout: Duration,
-	prepare_job_kind: PrepareJobKind,
-	executor_params: &Arc<ExecutorParams>,
-	worker_info: &WorkerInfo,
+	job_start: Instant,
 	temp_artifact_dest: &Path,
-	usage_before: Usage,
-) -> Result<PrepareWorkerSuccess, PrepareError> {
-	// SAFETY: new process is spawned within a single threaded process. This invariant
-	// is enforced by tests.
-	match unsafe { nix::unistd::fork() } {
-		Ok(ForkResult::Child) => handle_child_process(
-			pvf.clone(),
-			pipe_write_fd,
-			pipe_read_fd,
-			stream_fd,
-			preparation_timeout,
-			prepare_job_kind,
-			Arc::clone(executor_params),
-		),
-		Ok(ForkResult::Parent { child }) => handle_parent_process(
-			pipe_read_fd,
-			pipe_write_fd,
-			worker_info,
-			child,
-			temp_artifact_dest,
-			usage_before,
-			preparation_timeout,
-		),
-		Err(errno) => Err(error_from_errno("fork", errno)),
-	}
-}
-
-/// This is used to handle child process during pvf prepare worker.
-/// It prepares the artifact and tracks memory stats during preparation
-/// and pipes back the response to the parent process.
-///
-/// # Returns
-///
-/// - If any error occur, pipe response back with `PrepareError`.
-///
-/// - If success, pipe back `JobResponse`.
-fn handle_child_process(
-	pvf: PvfPrepData,
-	pipe_write_fd: i32,
-	pipe_read_fd: i32,
-	stream_fd: i32,
-	preparation_timeout: Duration,
-	prepare_job_kind: PrepareJobKind,
-	executor_params: Arc<ExecutorParams>,
-) -> ! {
-	// SAFETY: pipe_writer is an open and owned file descriptor at this point.
-	let mut pipe_write = unsafe { PipeFd::from_raw_fd(pipe_write_fd) };
+	output: Option<&fs::File>,
+	artifact_cache: &mut ArtifactCache,
+	mut persistent_cache: Option<&mut PersistentCache>,
+	#[cfg_attr(target_os = "macos", allow(unused_variables))] stream: &UnixStream,
+	env: &JobEnvironment,
+) -> io::Result<(PrepareWorkerResult, Option<PrepareStage>)> {
+	let JobEnvironment {
+		worker_info,
+		security_status,
+		delivery,
+		observer,
+		require_secure,
+		job_slots,
+		..
+	} = *env;
+	let code_hash = pvf.code_hash();
+	gum::debug!(
+		target: LOG_TARGET,
+		?worker_info,
+		?code_hash,
+		?security_status,
+		"worker: preparing artifact",
+	);
+	observer.request_received(code_hash, job_start);
 
-	// Drop the read end so we don't have too many FDs open.
-	if let Err(errno) = nix::unistd::close(pipe_read_fd) {
-		send_child_response(
-			&mut pipe_write,
-			JobResult::Err(error_from_errno("closing pipe", errno)),
+	if let Err(err) = check_secure_environment(security_status, require_secure) {
+		gum::warn!(
+			target: LOG_TARGET,
+			?worker_info,
+			?code_hash,
+			"worker: {}",
+			err,
 		);
+		return Ok((Err(err), None))
 	}
 
-	// Dropping the stream closes the underlying socket. We want to make sure
-	// that the sandboxed child can't get any kind of information from the
-	// outside world. The only IPC it should be able to do is sending its
-	// response over the pipe.
-	if let Err(errno) = nix::unistd::close(stream_fd) {
-		send_child_response(
-			&mut pipe_write,
-			JobResult::Err(error_from_errno("error closing stream", errno)),
-		);
+	// Held until the job concluded, including writing its artifact.
+	let _job_slot = match acquire_job_slot(job_slots) {
+		Ok(job_slot) => job_slot,
+		Err(err) => return Ok((Err(err), None)),
+	};
+
+	let deadline = JobDeadline::new(pvf, job_start);
+	let executor_params = pvf.executor_params();
+	delivery.clear(temp_artifact_dest);
+
+	let cache_key = CacheKey::new(pvf);
+	let cacheable = is_cacheable(pvf);
+	if cacheable {
+		// Artifacts persisted by an earlier run of the worker are cached in memory on first use.
+		if !artifact_cache.contains(&cache_key) {
+			if let Some(persisted) = persistent_cache
+				.as_deref_mut()
+				.and_then(|cache| cache.get(&cache_key, artifact_flags(&executor_params)))
+			{
+				artifact_cache.insert(cache_key, persisted);
+			}
+		}
+		if let Some(cached) = artifact_cache.get(&cache_key) {
+			gum::debug!(
+				target: LOG_TARGET,
+				?worker_info,
+				?code_hash,
+				"worker: using cached artifact",
+			);
+			let result = write_cached_artifact(cached, temp_artifact_dest, output, delivery);
+			if result.is_ok() {
+				observer.artifact_written(code_hash, temp_artifact_dest, Instant::now());
+			}
+			return Ok((result, Some(PrepareStage::ArtifactWrite)))
+		}
 	}
 
-	let worker_job_pid = process::id();
-	gum::debug!(
-		target: LOG_TARGET,
-		%worker_job_pid,
-		?prepare_job_kind,
-		?preparation_timeout,
-		"worker job: preparing artifact",
-	);
+	#[cfg(not(target_os = "macos"))]
+	let (pipe_read_fd, pipe_write_fd) = pipe2_cloexec()?;
+	#[cfg(not(target_os = "macos"))]
+	let log_pipe = LogPipe::new()?;
 
-	// Conditional variable to notify us when a thread is done.
-	let condvar = thread::get_condvar();
+	#[cfg(not(target_os = "macos"))]
+	let usage_before = match nix::sys::resource::getrusage(UsageWho::RUSAGE_CHILDREN) {
+		Ok(usage) => usage,
+		Err(errno) => return Ok((Err(error_from_errno("getrusage before", errno)), None)),
+	};
 
-	// Run the memory tracker in a regular, non-worker thread.
-	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
-	let condvar_memory = Arc::clone(&condvar);
-	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
-	let memory_tracker_thread = std::thread::spawn(|| memory_tracker_loop(condvar_memory));
+	// The job checkpoints the stages it enters into memory shared with the worker, so that the
+	// worker knows the stage the job reached even if it died. Without it, the stage is only known
+	// for jobs that wrote their artifact.
+	#[cfg(not(target_os = "macos"))]
+	let checkpoint = StageCheckpoint::new()
+		.map_err(|err| {
+			gum::warn!(
+				target: LOG_TARGET,
+				?worker_info,
+				?code_hash,
+				"worker: could not map the stage checkpoint: {}",
+				err,
+			);
+		})
+		.ok()
+		.map(Arc::new);
 
-	start_memory_tracking(
-		pipe_write.as_raw_fd(),
-		executor_params.prechecking_max_memory().map(|v| {
-			v.try_into().unwrap_or_else(|_| {
-				gum::warn!(
-					LOG_TARGET,
-					%worker_job_pid,
-					"Illegal pre-checking max memory value {} discarded",
-					v,
-				);
-				0
-			})
-		}),
-	);
+	#[cfg(not(target_os = "macos"))]
+	let ctx = JobContext {
+		pvf,
+		deadline,
+		env,
+		stream,
+		pipe_read_fd,
+		pipe_write_fd,
+		log_pipe,
+		usage_before,
+		temp_artifact_dest,
+		output,
+		checkpoint,
+	};
 
-	let cpu_time_start = ProcessTime::now();
+	cfg_if::cfg_if! {
+		if #[cfg(target_os = "linux")] {
+			let result = if security_status.can_do_secure_clone {
+				handle_clone(&ctx)
+			} else {
+				// Fall back to using fork.
+				handle_fork(&ctx)
+			};
+		} else if #[cfg(target_os = "macos")] {
+			let result = handle_in_process(
+				pvf,
+				deadline,
+				temp_artifact_dest,
+				output,
+				delivery,
+				env.memory_tracker_interval,
+			);
+		} else {
+			let result = handle_fork(&ctx);
+		}
+	}
 
-	// Spawn a new thread that runs the CPU time monitor.
-	let (cpu_time_monitor_tx, cpu_time_monitor_rx) = channel::<()>();
-	let cpu_time_monitor_thread = thread::spawn_worker_thread(
-		"cpu time monitor thread",
-		move || cpu_time_monitor_loop(cpu_time_start, preparation_timeout, cpu_time_monitor_rx),
-		Arc::clone(&condvar),
-		WaitOutcome::TimedOut,
-	)
-	.unwrap_or_else(|err| {
-		send_child_response(&mut pipe_write, Err(PrepareError::IoErr(err.to_string())))
-	});
-
-	let prepare_thread = spawn_worker_thread(
-		"prepare worker",
-		move || {
-			#[allow(unused_mut)]
-			let mut result = prepare_artifact(pvf).map(|o| (o,));
+	#[cfg(not(target_os = "macos"))]
+	let checkpointed_stage = ctx.checkpoint.as_deref().and_then(StageCheckpoint::current);
+	#[cfg(target_os = "macos")]
+	let checkpointed_stage = None;
+	let reached_stage = match &result {
+		Ok((_, Some(_))) => {
+			observer.artifact_written(code_hash, temp_artifact_dest, Instant::now());
+			Some(PrepareStage::ArtifactWrite)
+		},
+		_ => checkpointed_stage,
+	};
 
-			// Get the `ru_maxrss` stat. If supported, call getrusage for the thread.
-			#[cfg(target_os = "linux")]
-			let mut result = result.map(|outcome| (outcome.0, get_max_rss_thread()));
-
-			// If we are pre-checking, check for runtime construction errors.
-			//
-			// As pre-checking is more strict than just preparation in terms of memory
-			// and time, it is okay to do extra checks here. This takes negligible time
-			// anyway.
-			if let PrepareJobKind::Prechecking = prepare_job_kind {
-				result = result.and_then(|output| {
-					runtime_construction_check(
-						output.0.compiled_artifact.as_ref(),
-						&executor_params,
-					)?;
-					Ok(output)
-				});
+	let result = match result {
+		Ok((success, artifact)) => {
+			if let Some(artifact) = artifact {
+				let cached = CachedArtifact {
+					artifact,
+					observed_wasm_code_len: success.stats.observed_wasm_code_len,
+					code_size: success.stats.code_size,
+				};
+				if let Some(persistent_cache) = persistent_cache.filter(|_| cacheable) {
+					// Failing to persist the artifact only costs compiling it again after a
+					// restart.
+					if let Err(err) = persistent_cache.insert(cache_key, &cached) {
+						gum::warn!(
+							target: LOG_TARGET,
+							?worker_info,
+							?code_hash,
+							"worker: could not persist the artifact: {}",
+							err,
+						);
+					}
+				}
+				artifact_cache.insert(cache_key, cached);
 			}
-			result
+			Ok(success)
+		},
+		Err(err) => {
+			// The job may have run out of memory because the system is low on memory,
+			// so free up what we can.
+			if matches!(err, PrepareError::OutOfMemory | PrepareError::JobKilled { .. }) {
+				artifact_cache.clear();
+			}
+			Err(err)
 		},
-		Arc::clone(&condvar),
-		WaitOutcome::Finished,
-	)
-	.unwrap_or_else(|err| {
-		send_child_response(&mut pipe_write, Err(PrepareError::IoErr(err.to_string())))
-	});
-
-	let outcome = thread::wait_for_threads(condvar);
-
-	let peak_alloc = {
-		let peak = end_memory_tracking();
-		gum::debug!(
-			target: LOG_TARGET,
-			%worker_job_pid,
-			"prepare job peak allocation is {} bytes",
-			peak,
-		);
-		peak
 	};
 
-	let result = match outcome {
-		WaitOutcome::Finished => {
-			let _ = cpu_time_monitor_tx.send(());
+	gum::trace!(
+		target: LOG_TARGET,
+		?worker_info,
+		?code_hash,
+		?reached_stage,
+		"worker: job result: {:?}",
+		result,
+	);
+	Ok((result, reached_stage))
+}
 
-			match prepare_thread.join().unwrap_or_else(|err| {
-				send_child_response(
-					&mut pipe_write,
-					Err(PrepareError::JobError(stringify_panic_payload(err))),
-				)
-			}) {
-				Err(err) => Err(err),
-				Ok(ok) => {
-					cfg_if::cfg_if! {
-						if #[cfg(target_os = "linux")] {
-							let (PrepareOutcome { compiled_artifact, observed_wasm_code_len }, max_rss) = ok;
-						} else {
-							let (PrepareOutcome { compiled_artifact, observed_wasm_code_len },) = ok;
-						}
-					}
+/// Checks that the given (decompressed) code is a valid PVF under the given executor params,
+/// without compiling it. This runs in the calling process and doesn't need a worker, so it can be
+/// used to quickly reject invalid code. Returns the shape of the module.
+pub fn validate_code(
+	code: &[u8],
+	executor_params: &ExecutorParams,
+) -> Result<ModuleShape, PrepareError> {
+	check_code_size(code)?;
+	prevalidate(code, executor_params).map(|blob| blob.shape()).map_err(stage_error)
+}
 
-					// Stop the memory stats worker and get its observed memory stats.
-					#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
-					let memory_tracker_stats = get_memory_tracker_loop_stats(memory_tracker_thread, process::id());
-
-					let memory_stats = MemoryStats {
-						#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
-						memory_tracker_stats,
-						#[cfg(target_os = "linux")]
-						max_rss: extract_max_rss_stat(max_rss, process::id()),
-						// Negative peak allocation values are legit; they are narrow
-						// corner cases and shouldn't affect overall statistics
-						// significantly
-						peak_tracked_alloc: if peak_alloc > 0 { peak_alloc as u64 } else { 0u64 },
-					};
+/// Converts the error of a preparation stage, truncating the message it takes over from the
+/// library, see [`MAX_PREPARE_ERROR_MESSAGE_LEN`].
+fn stage_error(err: PrepareStageError) -> PrepareError {
+	PrepareError::from(err).with_truncated_message(MAX_PREPARE_ERROR_MESSAGE_LEN)
+}
 
-					Ok(JobResponse {
-						artifact: compiled_artifact,
-						observed_wasm_code_len,
-						memory_stats,
-					})
-				},
+/// Rejects code larger than [`MAX_PREVALIDATION_WASM_SIZE`], before spending any time on parsing
+/// it.
+fn check_code_size(code: &[u8]) -> Result<(), PrepareError> {
+	if code.len() > MAX_PREVALIDATION_WASM_SIZE {
+		return Err(PrepareError::PrevalidationWasmTooLarge {
+			size: code.len() as u64,
+			max: MAX_PREVALIDATION_WASM_SIZE as u64,
+		})
+	}
+	Ok(())
+}
+
+/// Decompresses the code of a PVF as told by its [`CodeCompression`].
+fn decompress_code(code: &[u8], compression: CodeCompression) -> Result<Cow<[u8]>, PrepareError> {
+	match compression {
+		CodeCompression::Detect =>
+			sp_maybe_compressed_blob::decompress(code, VALIDATION_CODE_BOMB_LIMIT)
+				.map_err(|e| PrepareError::CouldNotDecompressCodeBlob(e.to_string())),
+		CodeCompression::Zstd { max_size } => {
+			let limit = max_size.min(VALIDATION_CODE_BOMB_LIMIT as u64);
+			match sp_maybe_compressed_blob::decompress(code, limit as usize) {
+				// The blob is only borrowed back if it lacks the prefix of compressed code.
+				Ok(Cow::Borrowed(_)) => Err(PrepareError::CouldNotDecompressCodeBlob(
+					"code was expected to be compressed".into(),
+				)),
+				Ok(code) => Ok(code),
+				Err(sp_maybe_compressed_blob::Error::PossibleBomb) =>
+					Err(PrepareError::DecompressionLimit { limit }),
+				Err(e) => Err(PrepareError::CouldNotDecompressCodeBlob(e.to_string())),
 			}
 		},
+	}
+}
 
-		// If the CPU thread is not selected, we signal it to end, the join handle is
-		// dropped and the thread will finish in the background.
-		WaitOutcome::TimedOut => match cpu_time_monitor_thread.join() {
-			Ok(Some(_cpu_time_elapsed)) => Err(PrepareError::TimedOut),
-			Ok(None) => Err(PrepareError::IoErr("error communicating over closed channel".into())),
-			Err(err) => Err(PrepareError::IoErr(stringify_panic_payload(err))),
+/// Prepares the artifact, recording every stage it enters in `checkpoint`, if any.
+///
+/// The code is prevalidated once. [`PrepareJobKind::VerifyDeterminism`] jobs compile the
+/// prevalidated code twice and fail if the artifacts differ, the second compilation counting
+/// against the same time and memory limits.
+fn prepare_artifact(
+	pvf: PvfPrepData,
+	checkpoint: Option<&StageCheckpoint>,
+) -> Result<PrepareOutcome, PrepareError> {
+	let code = prevalidate_artifact(&pvf, checkpoint)?;
+	match pvf.prep_kind() {
+		PrepareJobKind::ValidateOnly => Ok(PrepareOutcome {
+			compiled_artifact: None,
+			observed_wasm_code_len: code.observed_wasm_code_len,
+			stage_timings: None,
+			compiler_warnings: Vec::new(),
+			compile_cpu_time: Duration::ZERO,
+			module_shape: code.blob.shape(),
+			compile_start: None,
+		}),
+		PrepareJobKind::VerifyDeterminism => {
+			let outcome = prepare_prevalidated_artifact(code.clone(), &pvf, checkpoint)?;
+			verify_determinism(&outcome, &prepare_prevalidated_artifact(code, &pvf, None)?)?;
+			Ok(outcome)
 		},
-		WaitOutcome::Pending =>
-			unreachable!("we run wait_while until the outcome is no longer pending; qed"),
-	};
+		PrepareJobKind::Compilation | PrepareJobKind::Prechecking | PrepareJobKind::Benchmark =>
+			prepare_prevalidated_artifact(code, &pvf, checkpoint),
+	}
+}
 
-	send_child_response(&mut pipe_write, result);
+/// The code of a PVF that passed prevalidation, see [`prevalidate_artifact`].
+#[derive(Clone)]
+struct PrevalidatedCode {
+	blob: RuntimeBlob,
+	observed_wasm_code_len: u32,
+	/// The time prevalidation took, only measured for benchmarking jobs.
+	prevalidation_elapsed: Option<Duration>,
 }
 
-/// Waits for child process to finish and handle child response from pipe.
-///
-/// # Returns
+/// Decompresses and prevalidates the code of the PVF, the first stages of [`prepare_artifact`].
+/// Prevalidation is skipped for code the host vouched for, see
+/// [`PvfPrepData::with_prevalidated_code`], but the size of the code is checked either way.
+fn prevalidate_artifact(
+	pvf: &PvfPrepData,
+	checkpoint: Option<&StageCheckpoint>,
+) -> Result<PrevalidatedCode, PrepareError> {
+	// Only benchmarking jobs read the clock, so that regular jobs don't pay for it.
+	let collect_stage_timings = matches!(pvf.prep_kind(), PrepareJobKind::Benchmark);
+
+	enter_stage(checkpoint, PrepareStage::Decompression);
+	let maybe_compressed_code = pvf.maybe_compressed_code();
+	let raw_validation_code = decompress_code(&maybe_compressed_code, pvf.code_compression())?;
+	let observed_wasm_code_len = raw_validation_code.len() as u32;
+
+	enter_stage(checkpoint, PrepareStage::Prevalidation);
+	let prevalidation_start = collect_stage_timings.then(Instant::now);
+	check_code_size(&raw_validation_code)?;
+	// Whether the host may vouch for the code was decided by the worker on receiving the request.
+	let blob = if pvf.code_prevalidated() {
+		trust_prevalidated(&raw_validation_code)
+	} else {
+		prevalidate(&raw_validation_code, &pvf.executor_params())
+	}
+	.map_err(stage_error)?;
+	let prevalidation_elapsed = prevalidation_start.map(|start| start.elapsed());
+	Ok(PrevalidatedCode { blob, observed_wasm_code_len, prevalidation_elapsed })
+}
+
+/// Compiles code prevalidated by [`prevalidate_artifact`], the last stage of [`prepare_artifact`].
+fn prepare_prevalidated_artifact(
+	code: PrevalidatedCode,
+	pvf: &PvfPrepData,
+	checkpoint: Option<&StageCheckpoint>,
+) -> Result<PrepareOutcome, PrepareError> {
+	let PrevalidatedCode { blob, observed_wasm_code_len, prevalidation_elapsed } = code;
+	let module_shape = blob.shape();
+
+	enter_stage(checkpoint, PrepareStage::Preparation);
+	let compile_start = Instant::now();
+	match prepare(blob, &pvf.executor_params()) {
+		Ok(prepared) => Ok(PrepareOutcome {
+			compiled_artifact: Some(prepared.artifact),
+			compiler_warnings: prepared.warnings,
+			compile_cpu_time: prepared.compile_cpu_time,
+			module_shape,
+			observed_wasm_code_len,
+			stage_timings: prevalidation_elapsed.map(|prevalidation| PrepareStageTimings {
+				prevalidation,
+				preparation: compile_start.elapsed(),
+				// Filled in by the worker once the artifact is written.
+				artifact_write: Duration::ZERO,
+			}),
+			compile_start: Some(compile_start),
+		}),
+		Err(err) => Err(stage_error(err)),
+	}
+}
+
+/// Prepares the given PVF in the calling process, and returns the artifact along with the stats
+/// that are cheap to collect: the CPU time, the code and artifact sizes and, for benchmarking jobs,
+/// the stage timings.
 ///
-/// - If the child send response without an error, this function returns `Ok(PrepareStats)`
-///   containing memory and CPU usage statistics.
+/// # Not sandboxed
 ///
-/// - If the child send response with an error, it returns a `PrepareError` with that error.
+/// Unlike [`worker_entrypoint`], this neither spawns a job process nor applies any of the security
+/// features, timeouts or memory limits to the preparation. It is meant for tooling that compiles a
+/// single PVF, e.g. to reproduce an issue, and must not be used to validate candidates.
 ///
-/// - If the child process timeout, it returns `PrepareError::TimedOut`.
-fn handle_parent_process(
-	pipe_read_fd: i32,
-	pipe_write_fd: i32,
-	worker_info: &WorkerInfo,
-	job_pid: Pid,
-	temp_artifact_dest: &Path,
-	usage_before: Usage,
-	timeout: Duration,
-) -> Result<PrepareWorkerSuccess, PrepareError> {
-	// the read end will wait until all write ends have been closed,
-	// this drop is necessary to avoid deadlock
-	if let Err(errno) = nix::unistd::close(pipe_write_fd) {
-		return Err(error_from_errno("closing pipe write fd", errno));
-	};
-
-	// SAFETY: this is an open and owned file descriptor at this point.
-	let mut pipe_read = unsafe { PipeFd::from_raw_fd(pipe_read_fd) };
+/// Validation-only jobs fail with [`PrepareError::JobError`], as they don't produce an artifact.
+pub fn prepare_blocking(
+	pvf: PvfPrepData,
+) -> Result<(CompiledArtifact, PrepareStats), PrepareError> {
+	let construction_check = wants_runtime_construction_check(&pvf);
+	let executor_params = pvf.executor_params();
+	let code_hash = pvf.code_hash();
+	let code_size = pvf.maybe_compressed_code().len() as u64;
+	let cpu_time_start = ProcessTime::now();
 
-	// Read from the child. Don't decode unless the process exited normally, which we check later.
-	let mut received_data = Vec::new();
-	pipe_read
-		.read_to_end(&mut received_data)
-		.map_err(|err| PrepareError::IoErr(err.to_string()))?;
+	let PrepareOutcome {
+		compiled_artifact,
+		observed_wasm_code_len,
+		stage_timings,
+		compiler_warnings,
+		compile_cpu_time,
+		module_shape,
+		compile_start: _,
+	} = prepare_artifact(pvf, None)?;
+	let compiled_artifact = compiled_artifact.ok_or_else(|| {
+		PrepareError::JobError("validation-only jobs don't produce an artifact".into())
+	})?;
+	if construction_check {
+		runtime_construction_check(&compiled_artifact, &executor_params)?;
+	}
+	check_artifact_size(&compiled_artifact, executor_params.max_artifact_size())?;
 
-	let status = nix::sys::wait::waitpid(job_pid, None);
-	gum::trace!(
-		target: LOG_TARGET,
-		?worker_info,
-		%job_pid,
-		"prepare worker received wait status from job: {:?}",
-		status,
-	);
+	let artifact = artifact::compress(&compiled_artifact, ARTIFACT_COMPRESSION_LEVEL)
+		.map(|payload| {
+			CompiledArtifact::with_flags(payload, code_hash, artifact_flags(&executor_params))
+		})
+		.map_err(artifact_compression_error)?;
+	let stats = PrepareStats {
+		cpu_time_elapsed: cpu_time_start.elapsed(),
+		observed_wasm_code_len,
+		code_size,
+		compiled_artifact_size: artifact.as_ref().len() as u64,
+		stage_timings,
+		artifact_produced: true,
+		compiler_warnings,
+		compile_cpu_time,
+		module_shape,
+		..Default::default()
+	};
+	Ok((artifact, stats))
+}
 
-	let usage_after = nix::sys::resource::getrusage(UsageWho::RUSAGE_CHILDREN)
-		.map_err(|errno| error_from_errno("getrusage after", errno))?;
+/// Fails with [`PrepareError::ArtifactTooLarge`] if the compiled artifact is larger than the limit
+/// set by [`ExecutorParam::MaxArtifactSize`](polkadot_primitives::ExecutorParam::MaxArtifactSize).
+fn check_artifact_size(compiled_artifact: &[u8], limit: Option<u64>) -> Result<(), PrepareError> {
+	let size = compiled_artifact.len() as u64;
+	match limit {
+		Some(limit) if size > limit => Err(PrepareError::ArtifactTooLarge { size, limit }),
+		_ => Ok(()),
+	}
+}
 
-	// Using `getrusage` is needed to check whether child has timedout since we cannot rely on
-	// child to report its own time.
-	// As `getrusage` returns resource usage from all terminated child processes,
-	// it is necessary to subtract the usage before the current child process to isolate its cpu
-	// time
-	let cpu_tv = get_total_cpu_usage(usage_after) - get_total_cpu_usage(usage_before);
-	if cpu_tv >= timeout {
-		gum::warn!(
-			target: LOG_TARGET,
-			?worker_info,
-			%job_pid,
-			"prepare job took {}ms cpu time, exceeded prepare timeout {}ms",
-			cpu_tv.as_millis(),
-			timeout.as_millis(),
-		);
-		return Err(PrepareError::TimedOut)
+/// Checks that two compilations of the same code by a [`PrepareJobKind::VerifyDeterminism`] job
+/// produced the same artifact.
+fn verify_determinism(
+	outcome: &PrepareOutcome,
+	other: &PrepareOutcome,
+) -> Result<(), PrepareError> {
+	match (&outcome.compiled_artifact, &other.compiled_artifact) {
+		(Some(artifact), Some(other_artifact)) => check_artifacts_match(artifact, other_artifact),
+		_ => Ok(()),
 	}
+}
 
-	match status {
-		Ok(WaitStatus::Exited(_pid, exit_status)) => {
-			let mut reader = io::BufReader::new(received_data.as_slice());
-			let result = recv_child_response(&mut reader, "prepare")
-				.map_err(|err| PrepareError::JobError(err.to_string()))?;
+/// Fails with [`PrepareError::NonDeterministicCompilation`] if the artifacts differ, naming the
+/// offset of the first byte that differs.
+fn check_artifacts_match(artifact: &[u8], other: &[u8]) -> Result<(), PrepareError> {
+	if artifact == other {
+		return Ok(())
+	}
+	let offset = artifact
+		.iter()
+		.zip(other)
+		.position(|(byte, other_byte)| byte != other_byte)
+		.unwrap_or_else(|| artifact.len().min(other.len()));
+	Err(PrepareError::NonDeterministicCompilation {
+		len: artifact.len() as u64,
+		other_len: other.len() as u64,
+		offset: offset as u64,
+	})
+}
 
-			match result {
-				Err(err) => Err(err),
-				Ok(JobResponse { artifact, memory_stats, observed_wasm_code_len }) => {
-					// The exit status should have been zero if no error occurred.
-					if exit_status != 0 {
-						return Err(PrepareError::JobError(format!(
-							"unexpected exit status: {}",
-							exit_status
-						)))
-					}
+/// The absolute deadlines of a job, derived once from the time its request was received. Passing
+/// these down instead of timeouts means that time spent before the job starts, e.g. on forking,
+/// counts against its budget instead of extending it.
+#[derive(Debug, Clone, Copy)]
+struct JobDeadline {
+	/// The time the request of the job was received.
+	job_start: Instant,
+	/// The job must not use more CPU time than the wall clock time left until this instant.
+	cpu_time: Instant,
+	/// The job is killed if it is still running at this instant.
+	wall_clock: Instant,
+}
 
-					// Write the serialized artifact into a temp file.
-					//
-					// PVF host only keeps artifacts statuses in its memory,
-					// successfully compiled code gets stored on the disk (and
-					// consequently deserialized by execute-workers). The prepare worker
-					// is only required to send `Ok` to the pool to indicate the
-					// success.
-					gum::debug!(
-						target: LOG_TARGET,
-						?worker_info,
-						%job_pid,
-						"worker: writing artifact to {}",
-						temp_artifact_dest.display(),
-					);
-					// Write to the temp file created by the host.
-					if let Err(err) = fs::write(temp_artifact_dest, &artifact) {
-						return Err(PrepareError::IoErr(err.to_string()))
-					};
+impl JobDeadline {
+	fn new(pvf: &PvfPrepData, job_start: Instant) -> Self {
+		Self {
+			job_start,
+			cpu_time: job_start + pvf.cpu_timeout(),
+			wall_clock: job_start + pvf.prep_wall_clock_timeout(),
+		}
+	}
 
-					let checksum = blake3::hash(&artifact.as_ref()).to_hex().to_string();
-					Ok(PrepareWorkerSuccess {
-						checksum,
-						stats: PrepareStats {
-							memory_stats,
-							cpu_time_elapsed: cpu_tv,
-							observed_wasm_code_len,
-						},
-					})
-				},
-			}
-		},
-		// The job was killed by the given signal.
-		//
-		// The job gets SIGSYS on seccomp violations, but this signal may have been sent for some
-		// other reason, so we still need to check for seccomp violations elsewhere.
-		Ok(WaitStatus::Signaled(_pid, signal, _core_dump)) => Err(PrepareError::JobDied {
-			err: format!("received signal: {signal:?}"),
-			job_pid: job_pid.as_raw(),
-		}),
-		Err(errno) => Err(error_from_errno("waitpid", errno)),
+	/// The CPU time the job may use if it starts now.
+	fn cpu_time_budget(&self) -> Duration {
+		self.cpu_time.saturating_duration_since(Instant::now())
+	}
+}
 
-		// An attacker can make the child process return any exit status it wants. So we can treat
-		// all unexpected cases the same way.
-		Ok(unexpected_wait_status) => Err(PrepareError::JobDied {
-			err: format!("unexpected status from wait: {unexpected_wait_status:?}"),
-			job_pid: job_pid.as_raw(),
-		}),
+/// Records that the job entered the given stage, if it checkpoints its stages.
+fn enter_stage(checkpoint: Option<&StageCheckpoint>, stage: PrepareStage) {
+	if let Some(checkpoint) = checkpoint {
+		checkpoint.enter(stage);
 	}
 }
 
-/// Write a job response to the pipe and exit process after.
-///
-/// # Arguments
+/// Returns whether the job constructs the runtime from its artifact. Pre-checking jobs always do,
+/// other jobs only if asked to, see [`PvfPrepData::with_runtime_construction_check`].
+fn wants_runtime_construction_check(pvf: &PvfPrepData) -> bool {
+	matches!(pvf.prep_kind(), PrepareJobKind::Prechecking) || pvf.runtime_construction_check()
+}
+
+/// Try constructing the runtime to catch any instantiation errors during pre-checking, or for jobs
+/// that asked for it.
+fn runtime_construction_check(
+	artifact_bytes: &[u8],
+	executor_params: &ExecutorParams,
+) -> Result<(), PrepareError> {
+	// SAFETY: We just compiled this artifact, or were given a trusted one by `verify_artifact`.
+	let result = unsafe { create_runtime_from_artifact_bytes(artifact_bytes, executor_params) };
+	result.map(|_runtime| ()).map_err(|err| {
+		PrepareError::RuntimeConstruction(format!("{:?}", err))
+			.with_truncated_message(MAX_PREPARE_ERROR_MESSAGE_LEN)
+	})
+}
+
+/// Checks that `artifact`, e.g. one taken over from another artifact cache, is one that preparing
+/// `pvf` would produce, without preparing it again: the header must be valid and name the code hash
+/// of the PVF, and the runtime must be constructible from the artifact with the executor parameters
+/// of the PVF.
 ///
-/// - `pipe_write`: A `PipeFd` structure, the writing end of a pipe.
+/// # Not sandboxed
 ///
-/// - `response`: Child process response
-fn send_child_response(pipe_write: &mut PipeFd, response: JobResult) -> ! {
-	framed_send_blocking(pipe_write, response.encode().as_slice())
-		.unwrap_or_else(|_| process::exit(libc::EXIT_FAILURE));
+/// Like [`prepare_blocking`], this runs in the calling process. The checksum in the header catches
+/// corrupted artifacts, but constructing the runtime loads the compiled code, so the artifact must
+/// come from a trusted source.
+pub fn verify_artifact(artifact: &[u8], pvf: &PvfPrepData) -> Result<(), PrepareError> {
+	let (code_hash, payload) =
+		CompiledArtifact::parse_with_code_hash(artifact).map_err(artifact_header_error)?;
+	if code_hash != pvf.code_hash() {
+		return Err(PrepareError::CodeHashMismatch { found: code_hash, expected: pvf.code_hash() })
+	}
+	let payload = artifact::decompress(payload)
+		.map_err(|err| PrepareError::IoErr(format!("invalid artifact: {}", err)))?;
+	runtime_construction_check(&payload, &pvf.executor_params())
+}
 
-	if response.is_ok() {
-		process::exit(libc::EXIT_SUCCESS)
-	} else {
-		process::exit(libc::EXIT_FAILURE)
+/// Maps a failure to compress a compiled artifact to the error reported for the job. Any PVF that
+/// compiles can be compressed, so this points at the machine, e.g. a failed allocation, and must
+/// not be treated as deterministic.
+fn artifact_compression_error(err: artifact::Error) -> PrepareError {
+	PrepareError::IoErr(err.to_string())
+}
+
+/// Maps an invalid artifact header to the error reported for the artifact. A corrupted artifact
+/// points at the machine it was written or read on rather than at the PVF, so this is never a
+/// deterministic error.
+fn artifact_header_error(err: ArtifactHeaderError) -> PrepareError {
+	match err {
+		ArtifactHeaderError::CompilerVersionMismatch { found, expected } =>
+			PrepareError::CompilerVersionMismatch { found, expected },
+		err => PrepareError::IoErr(format!("invalid artifact: {}", err)),
 	}
 }
 
-fn error_from_errno(context: &'static str, errno: Errno) -> PrepareError {
-	PrepareError::Kernel(stringify_errno(context, errno))
+/// The result of a job, along with the artifact that was written if it is small enough to be
+/// cached.
+type JobOutcome = Result<(PrepareWorkerSuccess, Option<CompiledArtifact>), PrepareError>;
+
+/// The response of a successful job. If the job produced an artifact, the artifact follows the
+/// response on the pipe, see [`send_child_response_with_artifact`].
+#[derive(Encode, Decode)]
+struct JobResponse {
+	/// The length of the artifact that follows the response, if any.
+	artifact_len: Option<u64>,
+	/// Whether the artifact follows the response in frames instead, see [`ArtifactStreamWriter`].
+	artifact_streamed: bool,
+	memory_stats: MemoryStats,
+	observed_wasm_code_len: u32,
+	code_size: u64,
+	stage_timings: Option<PrepareStageTimings>,
+	applied_security: AppliedSecurity,
+	compiler_warnings: Vec<String>,
+	compile_cpu_time: Duration,
+	module_shape: ModuleShape,
+	/// See [`PrepareStats::fork_latency`].
+	fork_latency: Duration,
+	/// How long after [`JobDeadline::job_start`] the compilation started, if it did. See
+	/// [`PrepareObserver::compile_started`].
+	compile_start: Option<Duration>,
+	/// See [`SchedStats::run_queue_delay`].
+	#[cfg(target_os = "linux")]
+	run_queue_delay: Option<Duration>,
 }
 
-type JobResult = Result<JobResponse, PrepareError>;
+/// The artifact a job sends along with its response.
+enum JobArtifact {
+	/// The artifact, compressed and ready to be sent as a whole.
+	Compressed(CompiledArtifact),
+	/// The output of the compiler, compressed while it is streamed to the worker, see
+	/// [`PvfPrepData::with_artifact_streaming`].
+	Streamed(Vec<u8>),
+}
 
-/// Pre-encoded length-prefixed `JobResult::Err(PrepareError::OutOfMemory)`
-const OOM_PAYLOAD: &[u8] = b"\x02\x00\x00\x00\x00\x00\x00\x00\x01\x08";
+/// The size of the chunks in which the artifact is piped from the job to the worker.
+const ARTIFACT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The maximum size of the response the job sends ahead of the artifact. It only holds stats or an
+/// error, so anything larger is bogus.
+const MAX_JOB_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// What the worker runs its jobs with, the same for every job, see [`prepare_job`].
+struct JobEnvironment<'a> {
+	worker_info: &'a WorkerInfo,
+	security_status: &'a SecurityStatus,
+	delivery: ArtifactDelivery<'a>,
+	observer: &'a dyn PrepareObserver,
+	/// See [`WorkerOptions::memory_tracker_interval`].
+	#[cfg_attr(
+		not(any(target_os = "linux", target_os = "macos", feature = "jemalloc-allocator")),
+		allow(dead_code)
+	)]
+	memory_tracker_interval: Duration,
+	/// See [`WorkerOptions::require_secure`].
+	require_secure: bool,
+	/// See [`WorkerOptions::cpu_affinity`].
+	#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+	cpu_affinity: Option<u64>,
+	/// See [`WorkerOptions::job_priority`].
+	#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+	job_priority: JobPriority,
+	job_slots: &'a JobSlots,
+}
+
+/// The inputs of a job process, built once by [`prepare_job`]. They are shared by the job, which
+/// runs [`handle_child_process`], and the worker, which waits for it in [`handle_parent_process`].
+#[cfg_attr(target_os = "macos", allow(dead_code))]
+struct JobContext<'a> {
+	pvf: &'a PvfPrepData,
+	deadline: JobDeadline,
+	env: &'a JobEnvironment<'a>,
+	/// The connection to the host. The job closes it, while the worker watches it for the host
+	/// cancelling the job.
+	stream: &'a UnixStream,
+	/// The pipe the job sends its response on.
+	pipe_read_fd: i32,
+	pipe_write_fd: i32,
+	/// The pipe the job sends its log records on, see `job_log`.
+	log_pipe: LogPipe,
+	/// The usage of the terminated children of the worker before the job was spawned, which the
+	/// usage of the job is told apart from.
+	usage_before: Usage,
+	temp_artifact_dest: &'a Path,
+	output: Option<&'a fs::File>,
+	checkpoint: Option<Arc<StageCheckpoint>>,
+}
+
+/// Where the network and mount namespaces of a prepare job come from.
+#[derive(Clone, Copy)]
+enum JobNamespaces {
+	/// The job was spawned with `clone`, which already puts it into new namespaces.
+	#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+	Cloned,
+	/// The job should try to unshare the namespaces of the worker.
+	Unshare,
+	/// The job stays in the namespaces of the worker.
+	Worker,
+}
+
+#[cfg(target_os = "linux")]
+fn handle_clone(ctx: &JobContext) -> JobOutcome {
+	use polkadot_node_core_pvf_common::worker::security;
 
-#[test]
-fn pre_encoded_payloads() {
-	// NOTE: This must match the type of `response` in `send_child_response`.
-	let oom_unencoded: JobResult = JobResult::Err(PrepareError::OutOfMemory);
-	let oom_encoded = oom_unencoded.encode();
-	// The payload is prefixed with	its length in `framed_send`.
-	let mut oom_payload = oom_encoded.len().to_le_bytes().to_vec();
-	oom_payload.extend(oom_encoded);
-	assert_eq!(oom_payload, OOM_PAYLOAD);
+	// SAFETY: new process is spawned within a single threaded process. This invariant
+	// is enforced by tests. Stack size being specified to ensure child doesn't overflow
+	match unsafe {
+		security::clone::clone_on_worker(
+			ctx.env.worker_info,
+			ctx.env.security_status.can_unshare_user_namespace_and_change_root,
+			Box::new(|| handle_child_process(ctx, JobNamespaces::Cloned)),
+		)
+	} {
+		Ok(child) => handle_parent_process(ctx, child),
+		Err(security::clone::Error::Clone(errno)) => Err(error_from_errno("clone", errno)),
+	}
+}
+
+/// Whether forked jobs can move into their own network and mount namespaces. Unsharing them takes
+/// privileges the worker only has in its own user namespace, so this also requires the worker to
+/// have unshared that.
+#[cfg_attr(target_os = "macos", allow(dead_code))]
+fn can_unshare_job_namespaces(security_status: &SecurityStatus) -> bool {
+	security_status.can_unshare_network_and_mount_namespaces &&
+		security_status.can_unshare_user_namespace_and_change_root
+}
+
+#[cfg_attr(target_os = "macos", allow(dead_code))]
+fn handle_fork(ctx: &JobContext) -> JobOutcome {
+	// SAFETY: new process is spawned within a single threaded process. This invariant
+	// is enforced by tests.
+	match fork_job(|| unsafe { nix::unistd::fork() }) {
+		Ok(ForkResult::Child) => {
+			let namespaces = if can_unshare_job_namespaces(ctx.env.security_status) {
+				JobNamespaces::Unshare
+			} else {
+				JobNamespaces::Worker
+			};
+			handle_child_process(ctx, namespaces)
+		},
+		Ok(ForkResult::Parent { child }) => handle_parent_process(ctx, child),
+		Err(err) => Err(err),
+	}
+}
+
+/// Runs the job in threads of the worker instead of a job process, as a fallback for developers on
+/// macOS, where the sandboxing of the job process is not available.
+///
+/// # Not for production
+///
+/// None of the security features or memory limits are applied, and no heartbeats are sent. The CPU
+/// time limit is best-effort: it is measured for the whole worker process, and on a timeout the
+/// preparation keeps running in the background as the thread can't be killed. The memory stats only
+/// hold what is available without a job process.
+#[cfg(target_os = "macos")]
+fn handle_in_process(
+	pvf: &PvfPrepData,
+	deadline: JobDeadline,
+	temp_artifact_dest: &Path,
+	output: Option<&fs::File>,
+	delivery: ArtifactDelivery,
+	#[cfg_attr(not(feature = "jemalloc-allocator"), allow(unused_variables))]
+	memory_tracker_interval: Duration,
+) -> JobOutcome {
+	let condvar = thread::get_condvar();
+
+	#[cfg(feature = "jemalloc-allocator")]
+	let condvar_memory = Arc::clone(&condvar);
+	#[cfg(feature = "jemalloc-allocator")]
+	let memory_tracker_thread = spawn_memory_tracker(move || {
+		memory_tracker_loop(condvar_memory, memory_tracker_interval, None, None)
+	});
+
+	let preparation_timeout = deadline.cpu_time_budget();
+	let cpu_time_start = ProcessTime::now();
+	let (cpu_time_monitor_tx, cpu_time_monitor_rx) = channel::<()>();
+	let cpu_time_monitor_thread = thread::spawn_worker_thread(
+		"cpu time monitor thread",
+		move || cpu_time_monitor_loop(cpu_time_start, preparation_timeout, cpu_time_monitor_rx),
+		Arc::clone(&condvar),
+		WaitOutcome::TimedOut,
+	)
+	.map_err(|err| PrepareError::IoErr(err.to_string()))?;
+
+	let pvf = pvf.clone();
+	let code_size = pvf.maybe_compressed_code().len() as u64;
+	let prepare_thread = spawn_worker_thread(
+		"prepare worker",
+		move || {
+			if pvf.panic_backtraces() {
+				panic_backtrace::capture_on_this_thread();
+			}
+			match pvf.prep_kind() {
+				PrepareJobKind::ValidateOnly => prepare_artifact(pvf, None).map(|outcome| {
+					let stats = PrepareStats {
+						observed_wasm_code_len: outcome.observed_wasm_code_len,
+						code_size,
+						module_shape: outcome.module_shape,
+						..Default::default()
+					};
+					(None, stats)
+				}),
+				_ => prepare_blocking(pvf).map(|(artifact, stats)| (Some(artifact), stats)),
+			}
+		},
+		Arc::clone(&condvar),
+		WaitOutcome::Finished,
+	)
+	.map_err(|err| PrepareError::IoErr(err.to_string()))?;
+
+	match thread::wait_for_threads(condvar) {
+		WaitOutcome::Finished => {
+			let _ = cpu_time_monitor_tx.send(());
+
+			let prepare_thread_id = prepare_thread.thread().id();
+			let (artifact, mut stats) = prepare_thread.join().map_err(|err| {
+				PrepareError::JobError(panic_backtrace::describe_panic(prepare_thread_id, err))
+			})??;
+
+			#[cfg(feature = "jemalloc-allocator")]
+			{
+				stats.memory_stats.memory_tracker_stats =
+					get_memory_tracker_loop_stats(memory_tracker_thread, process::id());
+			}
+			stats.memory_stats.allocator = Some(ALLOCATOR);
+
+			let Some(artifact) = artifact else {
+				return Ok((
+					PrepareWorkerSuccess { checksum: String::new(), artifact_path: None, stats },
+					None,
+				))
+			};
+
+			let write_start = Instant::now();
+			let checksum = blake3::hash(artifact.as_ref()).to_hex().to_string();
+			write_artifact(temp_artifact_dest, output, artifact.as_ref(), delivery)
+				.map_err(|err| PrepareError::IoErr(err.to_string()))?;
+			if let Some(stage_timings) = stats.stage_timings.as_mut() {
+				stage_timings.artifact_write = write_start.elapsed();
+			}
+
+			Ok((PrepareWorkerSuccess { checksum, artifact_path: None, stats }, Some(artifact)))
+		},
+		WaitOutcome::TimedOut => match cpu_time_monitor_thread.join() {
+			Ok(Some(_cpu_time_elapsed)) => Err(PrepareError::TimedOut),
+			Ok(None) => Err(PrepareError::IoErr("error communicating over closed channel".into())),
+			Err(err) => Err(PrepareError::IoErr(stringify_panic_payload(err))),
+		},
+		WaitOutcome::Pending =>
+			unreachable!("we run wait_while until the outcome is no longer pending; qed"),
+	}
+}
+
+/// This is used to handle child process during pvf prepare worker.
+/// It prepares the artifact and tracks memory stats during preparation
+/// and pipes back the response to the parent process.
+///
+/// # Returns
+///
+/// - If any error occur, pipe response back with `PrepareError`.
+///
+/// - If success, pipe back `JobResponse`, followed by the artifact if there is one.
+fn handle_child_process(ctx: &JobContext, namespaces: JobNamespaces) -> ! {
+	let JobContext { deadline, pipe_write_fd, pipe_read_fd, log_pipe, .. } = *ctx;
+	// Taken first thing, so that only the time until the job process runs is measured.
+	let fork_latency = deadline.job_start.elapsed();
+
+	// SAFETY: pipe_writer is an open and owned file descriptor at this point.
+	let mut pipe_write = unsafe { PipeFd::from_raw_fd(pipe_write_fd) };
+
+	// Log into the log pipe from here on, see `job_log`. Only records the worker would log are
+	// piped, which is decided before setting the dispatch lifts the level.
+	let max_log_level = LevelFilter::current();
+	// SAFETY: both ends of the log pipe are open and owned at this point.
+	let log_dispatch = unsafe { log_pipe.into_writer() }.dispatch(max_log_level);
+	// The job never returns, so the dispatch stays the default of this thread.
+	std::mem::forget(dispatcher::set_default(&log_dispatch));
+
+	let pvf = ctx.pvf.clone();
+	let executor_params = pvf.executor_params();
+	let prepare_job_kind = pvf.prep_kind();
+	let can_enable_landlock = ctx.env.security_status.can_enable_landlock;
+	#[cfg(target_os = "linux")]
+	let worker_dir_path = &ctx.env.worker_info.worker_dir_path;
+	let checkpoint = ctx.checkpoint.clone();
+	let code_hash = pvf.code_hash();
+	let artifact_flags = artifact_flags(&pvf.executor_params());
+	let artifact_streaming = pvf.artifact_streaming();
+	let artifact_format = pvf.artifact_format();
+	let max_artifact_size = executor_params.max_artifact_size();
+
+	// Pin the job to its cores before it allocates or spawns anything, so that all of its threads
+	// inherit the affinity.
+	#[cfg(target_os = "linux")]
+	if let Some(mask) = ctx.env.cpu_affinity {
+		if let Err(errno) = set_cpu_affinity(mask) {
+			send_child_response(
+				&mut pipe_write,
+				JobResult::Err(error_from_errno("setting the CPU affinity", errno)),
+			);
+		}
+	}
+	#[cfg(target_os = "linux")]
+	if let Err(errno) = set_job_priority(ctx.env.job_priority) {
+		send_child_response(
+			&mut pipe_write,
+			JobResult::Err(error_from_errno("setting the job priority", errno)),
+		);
+	}
+
+	// The job should not outlive a `SIGTERM` like the worker does.
+	if let Err(errno) = disable_graceful_shutdown() {
+		send_child_response(
+			&mut pipe_write,
+			JobResult::Err(error_from_errno("restoring SIGTERM handler", errno)),
+		);
+	}
+
+	// Drop the read end so we don't have too many FDs open.
+	if let Err(errno) = nix::unistd::close(pipe_read_fd) {
+		send_child_response(
+			&mut pipe_write,
+			JobResult::Err(error_from_errno("closing pipe", errno)),
+		);
+	}
+
+	// Dropping the stream closes the underlying socket. We want to make sure
+	// that the sandboxed child can't get any kind of information from the
+	// outside world. The only IPC it should be able to do is sending its
+	// response and its log records over the pipes.
+	if let Err(errno) = nix::unistd::close(ctx.stream.as_raw_fd()) {
+		send_child_response(
+			&mut pipe_write,
+			JobResult::Err(error_from_errno("error closing stream", errno)),
+		);
+	}
+
+	// The security features applied to the job, reported in the stats.
+	let mut applied_security = AppliedSecurity::empty();
+
+	// Isolate the job from the network and from the mounts of the worker. If this fails we carry on
+	// in the namespaces of the worker, and report it in the stats.
+	match namespaces {
+		JobNamespaces::Cloned => applied_security
+			.insert(AppliedSecurity::SECURE_CLONE | AppliedSecurity::NETWORK_AND_MOUNT_NAMESPACES),
+		#[cfg(target_os = "linux")]
+		JobNamespaces::Unshare => {
+			use polkadot_node_core_pvf_common::worker::security;
+
+			match security::unshare::unshare_for_prepare_job() {
+				Ok(()) => applied_security.insert(AppliedSecurity::NETWORK_AND_MOUNT_NAMESPACES),
+				Err(err) => {
+					gum::warn!(
+						target: LOG_TARGET,
+						?code_hash,
+						"worker job: {}, falling back to the namespaces of the worker",
+						err,
+					);
+				},
+			}
+		},
+		#[cfg(not(target_os = "linux"))]
+		JobNamespaces::Unshare => {},
+		JobNamespaces::Worker => {},
+	}
+
+	// Open the memory stats file of the job for the memory tracker, as it can't be opened once
+	// landlock is enabled. Without it, the job falls back to `getrusage`.
+	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+	let smaps_rollup = pvf.smaps_rollup_sampling().then(SmapsRollup::open).and_then(|file| {
+		file.map_err(|err| {
+			gum::warn!(
+				target: LOG_TARGET,
+				?code_hash,
+				"worker job: could not open smaps_rollup, falling back to getrusage: {}",
+				err,
+			);
+		})
+		.ok()
+	});
+
+	// The same goes for the status of the job, which holds its swap usage.
+	#[cfg(target_os = "linux")]
+	let proc_status = ProcStatus::open()
+		.map_err(|err| {
+			gum::warn!(
+				target: LOG_TARGET,
+				?code_hash,
+				"worker job: could not open the process status, not sampling swap usage: {}",
+				err,
+			);
+		})
+		.ok();
+	#[cfg(all(not(target_os = "linux"), feature = "jemalloc-allocator"))]
+	let proc_status: Option<ProcStatus> = None;
+
+	// Let the kernel dump the core of the job into the worker dir, if the host asked for it. This
+	// is only for debugging, so the job carries on without it if it can't be enabled.
+	#[cfg(target_os = "linux")]
+	let core_dumps = pvf.core_dumps() &&
+		enable_core_dumps(worker_dir_path)
+			.map_err(|errno| {
+				gum::warn!(
+					target: LOG_TARGET,
+					?code_hash,
+					"worker job: could not enable core dumps: {}",
+					errno,
+				);
+			})
+			.is_ok();
+
+	// Restrict filesystem access of the job to the worker dir. This must happen before seccomp is
+	// enabled, as the landlock syscalls are not allowed for the job.
+	#[cfg(target_os = "linux")]
+	if can_enable_landlock {
+		use polkadot_node_core_pvf_common::worker::security;
+
+		if let Err(err) = security::landlock::enable_for_prepare_job(worker_dir_path, core_dumps) {
+			send_child_response(
+				&mut pipe_write,
+				JobResult::Err(PrepareError::Kernel(format!(
+					"could not enable landlock for the job: {}",
+					err
+				))),
+			);
+		}
+		applied_security.insert(AppliedSecurity::LANDLOCK);
+	}
+
+	// Restrict the job to the syscalls needed for compilation. This must happen before any threads
+	// are spawned, so that they inherit the filter.
+	#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+	if ctx.env.security_status.can_enable_seccomp {
+		use polkadot_node_core_pvf_common::worker::security;
+
+		panic_backtrace::disable_for_this_process();
+		if let Err(err) = security::seccomp::enable_for_prepare_job() {
+			send_child_response(
+				&mut pipe_write,
+				JobResult::Err(PrepareError::Kernel(format!(
+					"could not enable seccomp for the job: {}",
+					err
+				))),
+			);
+		}
+		applied_security.insert(AppliedSecurity::SECCOMP);
+	}
+
+	// Time spent since the request was received, e.g. on forking, counts against the budget.
+	let preparation_timeout = deadline.cpu_time_budget();
+	let worker_job_pid = process::id();
+	gum::debug!(
+		target: LOG_TARGET,
+		%worker_job_pid,
+		?code_hash,
+		?prepare_job_kind,
+		?preparation_timeout,
+		"worker job: preparing artifact",
+	);
+
+	// Conditional variable to notify us when a thread is done.
+	let condvar = thread::get_condvar();
+
+	// Run the memory tracker in a regular, non-worker thread.
+	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+	let condvar_memory = Arc::clone(&condvar);
+	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+	let memory_tracker_interval = ctx.env.memory_tracker_interval;
+	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+	let memory_tracker_thread = spawn_memory_tracker(move || {
+		memory_tracker_loop(condvar_memory, memory_tracker_interval, smaps_rollup, proc_status)
+	});
+
+	start_memory_tracking(
+		pipe_write.as_raw_fd(),
+		executor_params.prechecking_max_memory().map(|v| {
+			v.try_into().unwrap_or_else(|_| {
+				gum::warn!(
+					LOG_TARGET,
+					%worker_job_pid,
+					?code_hash,
+					"Illegal pre-checking max memory value {} discarded",
+					v,
+				);
+				0
+			})
+		}),
+	);
+
+	let cpu_time_start = ProcessTime::now();
+
+	// Spawn a new thread that runs the CPU time monitor.
+	let (cpu_time_monitor_tx, cpu_time_monitor_rx) = channel::<()>();
+	let cpu_time_monitor_thread = thread::spawn_worker_thread(
+		"cpu time monitor thread",
+		move || cpu_time_monitor_loop(cpu_time_start, preparation_timeout, cpu_time_monitor_rx),
+		Arc::clone(&condvar),
+		WaitOutcome::TimedOut,
+	)
+	.unwrap_or_else(|err| {
+		send_child_response(&mut pipe_write, Err(PrepareError::IoErr(err.to_string())))
+	});
+
+	#[cfg(target_os = "linux")]
+	let address_space_limit = pvf.address_space_limit();
+	let code_size = pvf.maybe_compressed_code().len() as u64;
+	let construction_check = wants_runtime_construction_check(&pvf);
+	let prepare_checkpoint = checkpoint.clone();
+	let panic_backtraces = pvf.panic_backtraces() && can_capture_panic_backtraces(applied_security);
+	let prepare_log_dispatch = log_dispatch.clone();
+	// The sandbox doesn't let the job read its run queue delay, see `sched_stats`.
+	#[cfg(target_os = "linux")]
+	let sample_run_queue_delay = !applied_security.contains(AppliedSecurity::LANDLOCK) &&
+		!applied_security.contains(AppliedSecurity::SECCOMP);
+
+	let prepare_thread = spawn_worker_thread(
+		"prepare worker",
+		move || {
+			let _log = dispatcher::set_default(&prepare_log_dispatch);
+			if panic_backtraces {
+				panic_backtrace::capture_on_this_thread();
+			}
+			#[cfg(target_os = "linux")]
+			let run_queue_delay_before =
+				if sample_run_queue_delay { sched_stats::thread_run_queue_delay() } else { None };
+			let result = (|| -> Result<PrepareOutcome, PrepareError> {
+				// The limit is only set now, as the other threads of the job need to map their
+				// stacks first.
+				#[cfg(target_os = "linux")]
+				if let Some(limit) = address_space_limit {
+					set_address_space_limit(limit)?;
+				}
+
+				let outcome = prepare_artifact(pvf, prepare_checkpoint.as_deref())?;
+
+				// If we are pre-checking, or were asked to, check for runtime construction
+				// errors.
+				//
+				// As pre-checking is more strict than just preparation in terms of memory
+				// and time, it is okay to do extra checks here. This takes negligible time
+				// anyway.
+				if construction_check {
+					if let Some(compiled_artifact) = &outcome.compiled_artifact {
+						enter_stage(
+							prepare_checkpoint.as_deref(),
+							PrepareStage::RuntimeConstruction,
+						);
+						runtime_construction_check(compiled_artifact, &executor_params)?;
+					}
+				}
+				Ok(outcome)
+			})();
+			let result = if can_enable_landlock {
+				result.map_err(attribute_landlock_denial)
+			} else {
+				result
+			};
+
+			#[cfg(target_os = "linux")]
+			let run_queue_delay = run_queue_delay_before.and_then(|before| {
+				Some(sched_stats::thread_run_queue_delay()?.saturating_sub(before))
+			});
+
+			// Get the `ru_maxrss` stat, also if preparation failed. If supported, call getrusage
+			// for the thread.
+			#[cfg(target_os = "linux")]
+			let output = (result, get_max_rss_thread(), run_queue_delay);
+			#[cfg(not(target_os = "linux"))]
+			let output = (result,);
+			output
+		},
+		Arc::clone(&condvar),
+		WaitOutcome::Finished,
+	)
+	.unwrap_or_else(|err| {
+		send_child_response(&mut pipe_write, Err(PrepareError::IoErr(err.to_string())))
+	});
+
+	let outcome = thread::wait_for_threads(condvar);
+
+	let peak_alloc = {
+		let peak = end_memory_tracking();
+		gum::debug!(
+			target: LOG_TARGET,
+			%worker_job_pid,
+			?code_hash,
+			"prepare job peak allocation is {} bytes",
+			peak,
+		);
+		peak
+	};
+
+	let result = match outcome {
+		WaitOutcome::Finished => {
+			let _ = cpu_time_monitor_tx.send(());
+
+			let prepare_thread_id = prepare_thread.thread().id();
+			let output = prepare_thread.join().unwrap_or_else(|err| {
+				send_child_response(
+					&mut pipe_write,
+					Err(PrepareError::JobError(panic_backtrace::describe_panic(
+						prepare_thread_id,
+						err,
+					))),
+				)
+			});
+			cfg_if::cfg_if! {
+				if #[cfg(target_os = "linux")] {
+					let (result, max_rss, run_queue_delay) = output;
+				} else {
+					let (result,) = output;
+				}
+			}
+
+			// Stop the memory stats worker and get its observed memory stats. They are sent
+			// along with an error as well, as they help to diagnose failures.
+			#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+			let memory_tracker_stats = get_memory_tracker_loop_stats(memory_tracker_thread, process::id());
+
+			// Prefer the peak sampled from smaps_rollup, which accounts for shared mappings.
+			#[cfg(target_os = "linux")]
+			let max_rss = memory_tracker_stats
+				.as_ref()
+				.and_then(|stats| stats.peak_rss)
+				.map(|rss| (rss / 1024) as i64)
+				.or_else(|| extract_max_rss_stat(max_rss, process::id()));
+			#[cfg(target_os = "linux")]
+			let peak_swap = memory_tracker_stats.as_ref().and_then(|stats| stats.peak_swap);
+
+			let memory_stats = MemoryStats {
+				#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+				memory_tracker_stats,
+				#[cfg(target_os = "linux")]
+				max_rss,
+				// Page faults can only be measured by the parent once the job has exited.
+				#[cfg(target_os = "linux")]
+				minor_page_faults: None,
+				#[cfg(target_os = "linux")]
+				major_page_faults: None,
+				#[cfg(target_os = "linux")]
+				peak_swap,
+				// Negative peak allocation values are legit; they are narrow
+				// corner cases and shouldn't affect overall statistics
+				// significantly
+				peak_tracked_alloc: if peak_alloc > 0 { peak_alloc as u64 } else { 0u64 },
+				allocator: Some(ALLOCATOR),
+			};
+
+			match result {
+				Err(err) => Err(PrepareError::PreparationWithStats {
+					stats: memory_stats,
+					inner: Box::new(err),
+				}),
+				Ok(PrepareOutcome {
+					compiled_artifact,
+					observed_wasm_code_len,
+					stage_timings,
+					compiler_warnings,
+					compile_cpu_time,
+					module_shape,
+					compile_start,
+				}) => {
+					// Fail before the artifact is piped back and written to disk, not after.
+					if let Some(compiled_artifact) = &compiled_artifact {
+						if let Err(err) = check_artifact_size(compiled_artifact, max_artifact_size)
+						{
+							send_child_response(&mut pipe_write, Err(err))
+						}
+					}
+
+					// Compress the artifact before piping it back, to cut down on IPC volume and
+					// on the disk space taken by the artifact. A streamed artifact is compressed
+					// while it is piped back.
+					if compiled_artifact.is_some() {
+						enter_stage(checkpoint.as_deref(), PrepareStage::ArtifactCompression);
+					}
+					let artifact = match compiled_artifact {
+						Some(compiled_artifact) if artifact_streaming =>
+							Ok(Some(JobArtifact::Streamed(compiled_artifact))),
+						Some(compiled_artifact) =>
+							artifact::compress(&compiled_artifact, ARTIFACT_COMPRESSION_LEVEL).map(
+								|payload| {
+									Some(JobArtifact::Compressed(CompiledArtifact::with_flags(
+										payload,
+										code_hash,
+										artifact_flags,
+									)))
+								},
+							),
+						None => Ok(None),
+					};
+					artifact
+						.map(|artifact| {
+							let response = JobResponse {
+								artifact_len: match &artifact {
+									Some(JobArtifact::Compressed(artifact)) =>
+										Some(artifact.as_ref().len() as u64),
+									_ => None,
+								},
+								artifact_streamed: matches!(
+									artifact,
+									Some(JobArtifact::Streamed(_))
+								),
+								observed_wasm_code_len,
+								code_size,
+								memory_stats,
+								stage_timings,
+								applied_security,
+								compiler_warnings,
+								compile_cpu_time,
+								module_shape,
+								fork_latency,
+								compile_start: compile_start
+									.map(|at| at.saturating_duration_since(deadline.job_start)),
+								#[cfg(target_os = "linux")]
+								run_queue_delay,
+							};
+							(response, artifact)
+						})
+						.map_err(artifact_compression_error)
+				},
+			}
+		},
+
+		// If the CPU thread is not selected, we signal it to end, the join handle is
+		// dropped and the thread will finish in the background.
+		WaitOutcome::TimedOut => match cpu_time_monitor_thread.join() {
+			Ok(Some(_cpu_time_elapsed)) => Err(PrepareError::TimedOut),
+			Ok(None) => Err(PrepareError::IoErr("error communicating over closed channel".into())),
+			Err(err) => Err(PrepareError::IoErr(stringify_panic_payload(err))),
+		},
+		WaitOutcome::Pending =>
+			unreachable!("we run wait_while until the outcome is no longer pending; qed"),
+	};
+
+	match result {
+		Ok((response, Some(JobArtifact::Compressed(artifact)))) =>
+			send_child_response_with_artifact(
+				&mut pipe_write,
+				response,
+				artifact.as_ref(),
+				artifact_format,
+			),
+		Ok((response, Some(JobArtifact::Streamed(artifact)))) =>
+			send_child_response_with_streamed_artifact(
+				&mut pipe_write,
+				response,
+				&artifact,
+				code_hash,
+				artifact_flags,
+			),
+		Ok((response, None)) => send_child_response(&mut pipe_write, Ok(response)),
+		Err(err) => send_child_response(&mut pipe_write, Err(err)),
+	}
+}
+
+/// Returns whether the artifact of the given PVF may be served from the cache. Only plain
+/// compilation jobs are. Pre-checking jobs and jobs asking for the runtime construction check run
+/// additional checks, benchmarks measure the compilation itself and validation-only jobs don't want
+/// an artifact.
+fn is_cacheable(pvf: &PvfPrepData) -> bool {
+	matches!(pvf.prep_kind(), PrepareJobKind::Compilation) && !pvf.runtime_construction_check()
+}
+
+/// Writes a cached artifact to the temporary artifact file, in place of running a job.
+fn write_cached_artifact(
+	cached: &CachedArtifact,
+	temp_artifact_dest: &Path,
+	output: Option<&fs::File>,
+	delivery: ArtifactDelivery,
+) -> PrepareWorkerResult {
+	let checksum = blake3::hash(cached.artifact.as_ref()).to_hex().to_string();
+	write_artifact(temp_artifact_dest, output, cached.artifact.as_ref(), delivery)
+		.map_err(|err| PrepareError::IoErr(err.to_string()))?;
+
+	Ok(PrepareWorkerSuccess {
+		checksum,
+		artifact_path: None,
+		stats: PrepareStats {
+			observed_wasm_code_len: cached.observed_wasm_code_len,
+			code_size: cached.code_size,
+			compiled_artifact_size: cached.artifact.as_ref().len() as u64,
+			artifact_produced: true,
+			cache_hit: true,
+			..Default::default()
+		},
+	})
+}
+
+/// Writes the artifact to `dest`, the temporary artifact file created by the host, and hands it
+/// over with [`ArtifactDelivery::deliver`]. The file is written in place, so that the worker needs
+/// no rights to create or remove files in the worker dir. The host only reads it once the worker
+/// reported the result, so it never picks up a partially written artifact, even if the worker is
+/// killed while writing, and moves it into the cache itself.
+fn write_artifact(
+	dest: &Path,
+	output: Option<&fs::File>,
+	artifact: &[u8],
+	delivery: ArtifactDelivery,
+) -> io::Result<()> {
+	fs::write(dest, artifact)?;
+	delivery.deliver(dest, output)
+}
+
+/// What happens to an artifact once it is completely written to the temporary artifact file.
+#[derive(Clone, Copy)]
+struct ArtifactDelivery<'a> {
+	post_processor: &'a dyn ArtifactPostProcessor,
+	/// Whether identical artifacts share one file, see [`deduplicate_artifact`].
+	deduplicate: bool,
+}
+
+impl<'a> ArtifactDelivery<'a> {
+	/// Deduplication needs the rights to create and remove files, which the worker only has outside
+	/// of the worker dir. The host also removes the worker dir after every job, which would leave
+	/// nothing to share the file with anyway. So only artifacts written to an
+	/// [`WorkerOptions::artifact_dir`] are deduplicated.
+	fn new(options: &WorkerOptions<'a>) -> Self {
+		Self { post_processor: options.post_processor, deduplicate: options.artifact_dir.is_some() }
+	}
+
+	/// Makes room for a new artifact at `dest`. A deduplicated artifact left there shares its file
+	/// with identical ones, so it is unlinked instead of being overwritten along with them.
+	fn clear(&self, dest: &Path) {
+		if self.deduplicate {
+			let _ = fs::remove_file(dest);
+		}
+	}
+
+	/// Post-processes the artifact at `dest` and hands it over to the host. It is copied into the
+	/// `output` file if the host passed one, see [`PrepareWorkerRequest::SingleToFd`], and `dest`
+	/// is truncated. Otherwise it stays at `dest`, deduplicated if enabled.
+	fn deliver(&self, dest: &Path, output: Option<&fs::File>) -> io::Result<()> {
+		self.post_processor.process(dest)?;
+		if let Some(mut output) = output {
+			io::copy(&mut fs::File::open(dest)?, &mut output)?;
+			return fs::File::create(dest).map(|_| ())
+		}
+		if self.deduplicate {
+			deduplicate_artifact(dest);
+		}
+		Ok(())
+	}
+}
+
+impl Default for ArtifactDelivery<'_> {
+	fn default() -> Self {
+		Self { post_processor: &NoopArtifactPostProcessor, deduplicate: false }
+	}
+}
+
+/// Replaces the post-processed artifact at `dest` with a link to an identical one, if there is one.
+///
+/// Every artifact is also hard-linked under the checksum of its file, as written to the disk, in
+/// the [`DEDUPLICATED_ARTIFACTS_DIR`] next to `dest`. If an artifact with the same checksum is
+/// already there, `dest` becomes another link to it, so identical artifacts only take up space
+/// once. Links that are the last ones to their file are removed whenever a new artifact is added,
+/// so that artifacts that were removed elsewhere don't keep taking up space. If linking fails for
+/// any reason, the artifact is simply left as it is.
+fn deduplicate_artifact(dest: &Path) {
+	let checksum = match file_checksum(dest) {
+		Ok(checksum) => checksum,
+		Err(err) => {
+			gum::debug!(
+				target: LOG_TARGET,
+				"failed to compute the checksum of {}: {}",
+				dest.display(),
+				err,
+			);
+			return
+		},
+	};
+	let deduplicated = deduplicated_artifact_path(dest, &checksum);
+	if deduplicated.exists() {
+		let link = artifact_link_path(dest);
+		let _ = fs::remove_file(&link);
+		match fs::hard_link(&deduplicated, &link).and_then(|()| fs::rename(&link, dest)) {
+			Ok(()) => return,
+			Err(err) => {
+				let _ = fs::remove_file(&link);
+				gum::debug!(
+					target: LOG_TARGET,
+					"failed to link artifact to {}: {}",
+					deduplicated.display(),
+					err,
+				);
+			},
+		}
+	}
+
+	// Failing to remember the artifact only costs the deduplication of later ones.
+	let Some(dir) = deduplicated.parent() else { return };
+	prune_deduplicated_artifacts(dir);
+	let _ = fs::create_dir_all(dir);
+	let _ = fs::remove_file(&deduplicated);
+	let _ = fs::hard_link(dest, &deduplicated);
+}
+
+/// The dir next to the artifacts that identical ones are linked from, see [`deduplicate_artifact`].
+const DEDUPLICATED_ARTIFACTS_DIR: &str = "deduplicated-artifacts";
+
+/// Returns the path an artifact with the given checksum is kept at for deduplication.
+fn deduplicated_artifact_path(dest: &Path, checksum: &str) -> PathBuf {
+	dest.with_file_name(DEDUPLICATED_ARTIFACTS_DIR).join(checksum)
+}
+
+/// Removes the artifacts kept for deduplication that no artifact is linked to anymore.
+fn prune_deduplicated_artifacts(dir: &Path) {
+	use std::os::unix::fs::MetadataExt;
+
+	let Ok(entries) = fs::read_dir(dir) else { return };
+	for entry in entries.flatten() {
+		if entry.metadata().map_or(false, |metadata| metadata.nlink() == 1) {
+			let _ = fs::remove_file(entry.path());
+		}
+	}
+}
+
+/// Computes the checksum of the file at `path`, like the one of an artifact.
+fn file_checksum(path: &Path) -> io::Result<String> {
+	let mut hasher = blake3::Hasher::new();
+	io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+	Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Returns the path a deduplicated artifact is linked to before being renamed to `dest`.
+fn artifact_link_path(dest: &Path) -> PathBuf {
+	let mut link = dest.as_os_str().to_owned();
+	link.push(".link");
+	PathBuf::from(link)
+}
+
+/// Landlock denials surface as `EACCES` errors, which the executor only reports as text. The job is
+/// not supposed to access the filesystem, so with landlock enabled such errors are attributed to
+/// it. This keeps them from being treated as deterministic failures of the PVF.
+fn attribute_landlock_denial(err: PrepareError) -> PrepareError {
+	let denied = io::Error::from(Errno::EACCES).to_string();
+	match err {
+		PrepareError::Prevalidation(PrevalidationError::Invalid(ref msg)) |
+		PrepareError::Translation(ref msg) |
+		PrepareError::Compilation(ref msg) |
+		PrepareError::Preparation(ref msg) |
+		PrepareError::RuntimeConstruction(ref msg) |
+		PrepareError::JobError(ref msg) |
+		PrepareError::IoErr(ref msg)
+			if msg.contains(&denied) =>
+			PrepareError::LandlockViolation(msg.clone()),
+		err => err,
+	}
+}
+
+/// Waits for child process to finish and handle child response from pipe.
+///
+/// # Returns
+///
+/// - If the child send response without an error, this function returns `Ok(PrepareStats)`
+///   containing memory and CPU usage statistics.
+///
+/// - If the child send response with an error, it returns a `PrepareError` with that error.
+///
+/// - If the child process timeout, it returns `PrepareError::TimedOut`. The child is killed if it
+///   is still running after the wall clock deadline, which also results in
+///   `PrepareError::TimedOut`. Benchmarking jobs report `PrepareError::TimedOutAtStage` instead.
+///
+/// - If the child was killed by a signal and dumped its core into the worker dir, which it only
+///   does if `core_dumps` is set, the error is wrapped in `PrepareError::CoreDumped`.
+fn handle_parent_process(ctx: &JobContext, job_pid: Pid) -> JobOutcome {
+	let JobContext {
+		pvf,
+		deadline,
+		stream,
+		pipe_read_fd,
+		pipe_write_fd,
+		log_pipe,
+		usage_before,
+		temp_artifact_dest,
+		output,
+		..
+	} = *ctx;
+	let JobEnvironment { worker_info, delivery, observer, .. } = *ctx.env;
+	let code_hash = pvf.code_hash();
+	let prepare_job_kind = pvf.prep_kind();
+	let checkpoint = ctx.checkpoint.as_deref();
+
+	observer.job_spawned(code_hash, job_pid.as_raw(), Instant::now());
+
+	// Benchmarking jobs report the stage they timed out in, see `StageCheckpoint`.
+	let timeout_error = |cpu_used| {
+		let stage = checkpoint
+			.filter(|_| matches!(prepare_job_kind, PrepareJobKind::Benchmark))
+			.and_then(StageCheckpoint::current);
+		match stage {
+			Some(stage) => PrepareError::TimedOutAtStage { stage, cpu_used },
+			None => PrepareError::TimedOut,
+		}
+	};
+
+	// The records the job logged are read once it ended, see `job_log`.
+	// SAFETY: both ends of the log pipe are open and owned at this point.
+	let mut job_log = unsafe { log_pipe.into_reader() };
+
+	// the read end will wait until all write ends have been closed,
+	// this drop is necessary to avoid deadlock
+	if let Err(errno) = nix::unistd::close(pipe_write_fd) {
+		return Err(error_from_errno("closing pipe write fd", errno));
+	};
+
+	// Let the host know that we are still alive while the job is running. The heartbeats stop
+	// before returning, so they always arrive before the result.
+	let _heartbeat = pvf
+		.heartbeat_interval()
+		.map(|interval| Heartbeat::start(stream, interval))
+		.transpose()
+		.map_err(|err| PrepareError::IoErr(err.to_string()))?;
+
+	// The job measures its budget a little later, so this is never less than the job's own limit.
+	let timeout = deadline.cpu_time_budget();
+
+	// SAFETY: this is an open and owned file descriptor at this point.
+	let pipe_read = unsafe { PipeFd::from_raw_fd(pipe_read_fd) };
+
+	// Read from the child, streaming the artifact straight into the temporary artifact file. The
+	// response is not trusted unless the process exited normally, which we check later.
+	//
+	// The CPU time limit is enforced by the child itself, but a child that is blocked without
+	// using CPU time would hold up the worker indefinitely. So we also enforce a wall clock limit.
+	//
+	// The socket is watched for the host cancelling the job meanwhile.
+	let mut pipe_read = DeadlineReader::new(pipe_read, deadline.wall_clock, Some(stream));
+	let received = recv_job_response(&mut pipe_read, temp_artifact_dest, pvf.artifact_format());
+	// Read whatever the child sends beyond its response, e.g. after a malformed one, so that it is
+	// not blocked on writing and can exit.
+	let drained = io::copy(&mut pipe_read, &mut io::sink());
+	if pipe_read.cancelled {
+		gum::debug!(
+			target: LOG_TARGET,
+			?worker_info,
+			%job_pid,
+			?code_hash,
+			"prepare job cancelled by the host, killing it",
+		);
+		kill_job(job_pid)?;
+		job_log.forward(job_pid);
+		return Err(PrepareError::Cancelled)
+	}
+	let timed_out = |err: &io::Error| err.kind() == io::ErrorKind::TimedOut;
+	if matches!(&received, Err(RecvJobError::Pipe(err)) if timed_out(err)) ||
+		matches!(&drained, Err(err) if timed_out(err))
+	{
+		gum::warn!(
+			target: LOG_TARGET,
+			?worker_info,
+			%job_pid,
+			?code_hash,
+			"prepare job exceeded its wall clock deadline, killing it",
+		);
+		kill_job(job_pid)?;
+		job_log.forward(job_pid);
+		let usage_after = nix::sys::resource::getrusage(UsageWho::RUSAGE_CHILDREN)
+			.map_err(|errno| error_from_errno("getrusage after", errno))?;
+		return Err(timeout_error(get_cpu_time_elapsed(usage_before, usage_after)))
+	}
+	drained.map_err(|err| PrepareError::IoErr(err.to_string()))?;
+
+	let status = nix::sys::wait::waitpid(job_pid, None);
+	job_log.forward(job_pid);
+	gum::trace!(
+		target: LOG_TARGET,
+		?worker_info,
+		%job_pid,
+		"prepare worker received wait status from job: {:?}",
+		status,
+	);
+
+	let usage_after = nix::sys::resource::getrusage(UsageWho::RUSAGE_CHILDREN)
+		.map_err(|errno| error_from_errno("getrusage after", errno))?;
+
+	// Using `getrusage` is needed to check whether child has timedout since we cannot rely on
+	// child to report its own time.
+	// As `getrusage` returns resource usage from all terminated child processes,
+	// it is necessary to subtract the usage before the current child process to isolate its cpu
+	// time
+	let cpu_tv = get_cpu_time_elapsed(usage_before, usage_after);
+	let status = status.map_err(|errno| error_from_errno("waitpid", errno))?;
+	let classified = classify_wait_status(status, cpu_tv, timeout, job_pid, code_hash);
+	if let Err(PrepareError::TimedOut) = classified {
+		gum::warn!(
+			target: LOG_TARGET,
+			?worker_info,
+			%job_pid,
+			?code_hash,
+			"prepare job took {}ms cpu time, exceeded prepare timeout {}ms",
+			cpu_tv.as_millis(),
+			timeout.as_millis(),
+		);
+		return Err(timeout_error(cpu_tv))
+	}
+
+	// Like the CPU time, page faults of the job have to be isolated from those of previously
+	// terminated children.
+	#[cfg(target_os = "linux")]
+	let (minor_page_faults, major_page_faults) = (
+		i64::from(usage_after.minor_page_faults()) - i64::from(usage_before.minor_page_faults()),
+		i64::from(usage_after.major_page_faults()) - i64::from(usage_before.major_page_faults()),
+	);
+	#[cfg(target_os = "linux")]
+	let (involuntary_context_switches, voluntary_context_switches) = (
+		i64::from(usage_after.involuntary_context_switches()) -
+			i64::from(usage_before.involuntary_context_switches()),
+		i64::from(usage_after.voluntary_context_switches()) -
+			i64::from(usage_before.voluntary_context_switches()),
+	);
+
+	// A response that was cut short means the job died while writing it, whatever the reason. This
+	// is reported instead of the error the incomplete response fails to decode with.
+	if let Err(RecvJobError::Truncated { expected, received }) = received {
+		gum::warn!(
+			target: LOG_TARGET,
+			?worker_info,
+			%job_pid,
+			?code_hash,
+			"prepare job died while writing its response ({} of {} bytes), wait status: {:?}",
+			received,
+			expected,
+			status,
+		);
+		return Err(PrepareError::ChildDiedDuringWrite { expected, received })
+	}
+
+	match classified {
+		Ok(exit_status) => {
+			let ReceivedJobResponse { result, artifact } = received.map_err(|err| match err {
+				RecvJobError::Pipe(err) => PrepareError::JobError(err.to_string()),
+				RecvJobError::Write(err) => {
+					enter_stage(checkpoint, PrepareStage::ArtifactWrite);
+					PrepareError::IoErr(err.to_string())
+				},
+				RecvJobError::Truncated { expected, received } =>
+					PrepareError::ChildDiedDuringWrite { expected, received },
+			})?;
+
+			match result {
+				#[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+				Err(PrepareError::PreparationWithStats { mut stats, inner }) => {
+					#[cfg(target_os = "linux")]
+					{
+						stats.minor_page_faults = Some(minor_page_faults);
+						stats.major_page_faults = Some(major_page_faults);
+					}
+					Err(PrepareError::PreparationWithStats { stats, inner })
+				},
+				// The job enforces its CPU time limit itself, slightly ahead of the check above.
+				Err(PrepareError::TimedOut) => Err(timeout_error(cpu_tv)),
+				Err(err) => Err(err),
+				#[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+				Ok(JobResponse {
+					artifact_len: _,
+					artifact_streamed: _,
+					mut memory_stats,
+					observed_wasm_code_len,
+					code_size,
+					mut stage_timings,
+					applied_security,
+					compiler_warnings,
+					compile_cpu_time,
+					module_shape,
+					fork_latency,
+					compile_start,
+					#[cfg(target_os = "linux")]
+					run_queue_delay,
+				}) => {
+					// The exit status should have been zero if no error occurred.
+					if exit_status != 0 {
+						return Err(PrepareError::JobError(format!(
+							"unexpected exit status: {}",
+							exit_status
+						)))
+					}
+
+					if let Some(compile_start) = compile_start {
+						observer.compile_started(code_hash, deadline.job_start + compile_start);
+					}
+
+					#[cfg(target_os = "linux")]
+					{
+						memory_stats.minor_page_faults = Some(minor_page_faults);
+						memory_stats.major_page_faults = Some(major_page_faults);
+					}
+					#[cfg(target_os = "linux")]
+					let sched_stats = Some(SchedStats {
+						involuntary_context_switches,
+						voluntary_context_switches,
+						run_queue_delay,
+					});
+
+					// Validation-only jobs don't produce an artifact, so there is nothing to write.
+					let Some(ReceivedArtifact { artifact, len, checksum, write_elapsed }) =
+						artifact
+					else {
+						let success = PrepareWorkerSuccess {
+							checksum: String::new(),
+							artifact_path: None,
+							stats: PrepareStats {
+								memory_stats,
+								cpu_time_elapsed: cpu_tv,
+								observed_wasm_code_len,
+								code_size,
+								compiled_artifact_size: 0,
+								stage_timings,
+								applied_security,
+								artifact_produced: false,
+								cache_hit: false,
+								compiler_warnings,
+								compile_cpu_time,
+								module_shape,
+								fork_latency,
+								#[cfg(target_os = "linux")]
+								sched_stats,
+							},
+						};
+						return Ok((success, None))
+					};
+
+					enter_stage(checkpoint, PrepareStage::ArtifactWrite);
+					// Make sure the artifact was not corrupted on its way from the job.
+					let artifact = artifact.map_err(artifact_header_error)?;
+
+					// The artifact is complete in the temporary artifact file by now.
+					//
+					// PVF host only keeps artifacts statuses in its memory,
+					// successfully compiled code gets stored on the disk (and
+					// consequently deserialized by execute-workers). The prepare worker
+					// is only required to send `Ok` to the pool to indicate the
+					// success.
+					gum::debug!(
+						target: LOG_TARGET,
+						?worker_info,
+						%job_pid,
+						?code_hash,
+						to_output_file = output.is_some(),
+						"worker: moving artifact to {}",
+						temp_artifact_dest.display(),
+					);
+					// Leave the artifact in the temp file created by the host, sharing the file
+					// of an identical artifact if there is one, unless the host passed a file
+					// for it.
+					if let Err(err) = delivery.deliver(temp_artifact_dest, output) {
+						return Err(PrepareError::IoErr(err.to_string()))
+					};
+					if let Some(stage_timings) = stage_timings.as_mut() {
+						stage_timings.artifact_write = write_elapsed;
+					}
+
+					let success = PrepareWorkerSuccess {
+						checksum,
+						artifact_path: None,
+						stats: PrepareStats {
+							memory_stats,
+							cpu_time_elapsed: cpu_tv,
+							observed_wasm_code_len,
+							code_size,
+							compiled_artifact_size: len,
+							stage_timings,
+							applied_security,
+							artifact_produced: true,
+							cache_hit: false,
+							compiler_warnings,
+							compile_cpu_time,
+							module_shape,
+							fork_latency,
+							#[cfg(target_os = "linux")]
+							sched_stats,
+						},
+					};
+					Ok((success, artifact))
+				},
+			}
+		},
+		Err(err) => match status {
+			WaitStatus::Signaled(_pid, _signal, true) if pvf.core_dumps() =>
+				match find_core_dump(&worker_info.worker_dir_path) {
+					Some(core_path) =>
+						Err(PrepareError::CoreDumped { core_path, inner: Box::new(err) }),
+					None => {
+						gum::warn!(
+							target: LOG_TARGET,
+							%job_pid,
+							"prepare job dumped its core, but not into the worker dir",
+						);
+						Err(err)
+					},
+				},
+			_ => Err(err),
+		},
+	}
+}
+
+/// Sends the result of a job to the host, preceded by the metrics of the job and a busy hint if the
+/// host asked for them. There is no `artifact_path` if the artifact was written to a file of the
+/// host, see [`PrepareWorkerRequest::SingleToFd`].
+fn send_response(
+	stream: &mut UnixStream,
+	result: PrepareWorkerResult,
+	reached_stage: Option<PrepareStage>,
+	pvf: &PvfPrepData,
+	job_start: Instant,
+	artifact_path: Option<&Path>,
+	job_slots: &JobSlots,
+	worker_info: &WorkerInfo,
+) -> io::Result<()> {
+	let result = match artifact_path {
+		Some(artifact_path) => result.map(|success| with_artifact_path(success, artifact_path)),
+		None => result,
+	};
+	if pvf.worker_metrics() {
+		let metrics = worker_metrics(&result, job_start);
+		framed_send_blocking(stream, &PrepareWorkerMessage::Metrics(metrics).encode())?;
+	}
+	if pvf.busy_hint() {
+		let busy_hint = busy_hint(stream, job_slots);
+		framed_send_blocking(stream, &PrepareWorkerMessage::BusyHint(busy_hint).encode())?;
+	}
+	let request_id = pvf.request_id();
+	send_result_with(stream, result, worker_info, |result| {
+		PrepareWorkerMessage::Result { request_id, reached_stage, result }.encode()
+	})
+}
+
+/// Tells the host where the artifact of a successful job was written to, if it produced one.
+/// `artifact_path` is the path as seen by the host, which differs from the one the worker writes to
+/// if it changed its root.
+fn with_artifact_path(
+	mut success: PrepareWorkerSuccess,
+	artifact_path: &Path,
+) -> PrepareWorkerSuccess {
+	if success.stats.artifact_produced {
+		success.artifact_path = artifact_path.to_str().map(String::from);
+	}
+	success
+}
+
+/// Sends the results of the jobs of a batch to the host, in the order of the request. Unlike with
+/// [`send_response`], no metrics are sent for batches.
+fn send_batch_response(
+	stream: &mut UnixStream,
+	results: Vec<PrepareWorkerResult>,
+	worker_info: &WorkerInfo,
+) -> io::Result<()> {
+	gum::trace!(
+		target: LOG_TARGET,
+		?worker_info,
+		"worker: sending batch results to host: {:?}",
+		results
+	);
+	framed_send_blocking(stream, &PrepareWorkerMessage::BatchResult(results).encode())
+}
+
+/// Collects the metrics of a job from its result.
+fn worker_metrics(result: &PrepareWorkerResult, job_start: Instant) -> WorkerMetrics {
+	let stats = result.as_ref().ok().map(|success| &success.stats);
+	cfg_if::cfg_if! {
+		if #[cfg(target_os = "linux")] {
+			let max_rss = match result {
+				Ok(success) => success.stats.memory_stats.max_rss,
+				Err(PrepareError::PreparationWithStats { stats, .. }) => stats.max_rss,
+				Err(_) => None,
+			};
+		} else {
+			let max_rss = None;
+		}
+	}
+	WorkerMetrics {
+		preparation_duration: job_start.elapsed(),
+		artifact_size: stats
+			.filter(|stats| stats.artifact_produced)
+			.map(|stats| stats.compiled_artifact_size),
+		max_rss,
+	}
+}
+
+/// A thread sending [`PrepareWorkerMessage::Heartbeat`] to the host at a fixed interval. The thread
+/// is stopped when this is dropped.
+struct Heartbeat {
+	stop_tx: Option<Sender<()>>,
+	thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Heartbeat {
+	fn start(stream: &UnixStream, interval: Duration) -> io::Result<Self> {
+		let mut stream = stream.try_clone()?;
+		let (stop_tx, stop_rx) = channel::<()>();
+		let thread = std::thread::Builder::new().name("heartbeat".into()).spawn(move || {
+			let heartbeat = PrepareWorkerMessage::Heartbeat.encode();
+			// Stops once the sender is dropped.
+			while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+				if framed_send_blocking(&mut stream, &heartbeat).is_err() {
+					break
+				}
+			}
+		})?;
+		Ok(Self { stop_tx: Some(stop_tx), thread: Some(thread) })
+	}
+}
+
+impl Drop for Heartbeat {
+	fn drop(&mut self) {
+		drop(self.stop_tx.take());
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+/// Reads from the pipe of the job until the wall clock `deadline` passes, after which reads fail
+/// with [`io::ErrorKind::TimedOut`].
+struct DeadlineReader<'a> {
+	pipe_read: PipeFd,
+	deadline: Instant,
+	/// The socket to the host, watched for a [`PrepareWorkerRequest::Cancel`] until anything else
+	/// arrives on it.
+	control: Option<&'a UnixStream>,
+	/// Whether the host cancelled the job. Reading fails from then on.
+	cancelled: bool,
+}
+
+impl<'a> DeadlineReader<'a> {
+	fn new(pipe_read: PipeFd, deadline: Instant, control: Option<&'a UnixStream>) -> Self {
+		Self { pipe_read, deadline, control, cancelled: false }
+	}
+}
+
+impl Read for DeadlineReader<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		loop {
+			if self.cancelled {
+				return Err(io::Error::new(io::ErrorKind::Other, "job cancelled by the host"))
+			}
+			let remaining = self.deadline.saturating_duration_since(Instant::now());
+			if remaining.is_zero() {
+				return Err(io::Error::new(io::ErrorKind::TimedOut, "wall clock deadline passed"))
+			}
+
+			// A negative fd is ignored by `poll`.
+			let control_fd = self.control.map_or(-1, |control| control.as_raw_fd());
+			let mut poll_fds = [
+				libc::pollfd { fd: self.pipe_read.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+				libc::pollfd { fd: control_fd, events: libc::POLLIN, revents: 0 },
+			];
+			let poll_timeout =
+				remaining.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int;
+			// SAFETY: `poll_fds` is an array of valid `pollfd` structures, matching the count.
+			match unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as _, poll_timeout) } {
+				-1 => {
+					let err = io::Error::last_os_error();
+					if err.kind() != io::ErrorKind::Interrupted {
+						return Err(err)
+					}
+				},
+				// Nothing to read yet. The deadline is checked again above.
+				0 => {},
+				_ if poll_fds[1].revents != 0 => match self.control.map(recv_cancel) {
+					Some(CancelCheck::Cancelled) => self.cancelled = true,
+					// Wait for the rest of the frame.
+					Some(CancelCheck::Incomplete) => std::thread::sleep(Duration::from_millis(1)),
+					// The next request, or the host hanging up, is left to the main loop.
+					Some(CancelCheck::Other) | None => self.control = None,
+				},
+				_ => match self.pipe_read.read(buf) {
+					Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+					result => return result,
+				},
+			}
+		}
+	}
+}
+
+/// What arrived on the socket to the host while a job was running.
+enum CancelCheck {
+	/// A [`PrepareWorkerRequest::Cancel`], which was consumed.
+	Cancelled,
+	/// The start of a frame that may still turn out to be a cancellation.
+	Incomplete,
+	/// Anything else, which was left on the socket.
+	Other,
+}
+
+/// Checks whether the next frame on the socket cancels the running job, consuming it only if it
+/// does. Any other frame is a request that the host pipelined behind the job.
+fn recv_cancel(mut stream: &UnixStream) -> CancelCheck {
+	let request = PrepareWorkerRequest::Cancel.encode();
+	let mut frame = request.len().to_le_bytes().to_vec();
+	frame.extend(request);
+
+	let mut peeked = vec![0; frame.len()];
+	// SAFETY: `peeked` is valid for writes of its length.
+	let len = unsafe {
+		libc::recv(stream.as_raw_fd(), peeked.as_mut_ptr().cast(), peeked.len(), libc::MSG_PEEK)
+	};
+	let Ok(len @ 1..) = usize::try_from(len) else { return CancelCheck::Other };
+	if peeked[..len] != frame[..len] {
+		return CancelCheck::Other
+	}
+	if len < frame.len() {
+		return CancelCheck::Incomplete
+	}
+	// The frame was peeked in full, so this doesn't block.
+	match stream.read_exact(&mut peeked) {
+		Ok(()) => CancelCheck::Cancelled,
+		Err(_) => CancelCheck::Other,
+	}
+}
+
+/// Kills the job and reaps it. The job has not been reaped yet, so its pid cannot have been
+/// reused.
+fn kill_job(job_pid: Pid) -> Result<(), PrepareError> {
+	nix::sys::signal::kill(job_pid, Signal::SIGKILL)
+		.map_err(|errno| error_from_errno("kill", errno))?;
+	nix::sys::wait::waitpid(job_pid, None).map_err(|errno| error_from_errno("waitpid", errno))?;
+	Ok(())
+}
+
+/// The response of the job as received by the worker, with the artifact, if any, already written
+/// to the temporary artifact file.
+struct ReceivedJobResponse {
+	result: JobResult,
+	artifact: Option<ReceivedArtifact>,
+}
+
+/// An artifact streamed from the job into the temporary artifact file.
+struct ReceivedArtifact {
+	/// The artifact if it is small enough to be cached, or an error if its header is invalid.
+	artifact: Result<Option<CompiledArtifact>, ArtifactHeaderError>,
+	len: u64,
+	checksum: String,
+	write_elapsed: Duration,
+}
+
+/// An error that occurred while receiving the response of the job.
+enum RecvJobError {
+	/// Reading from the pipe failed, or the job sent a malformed response.
+	Pipe(io::Error),
+	/// The artifact could not be written to the temporary artifact file.
+	Write(io::Error),
+	/// The pipe was closed after only `received` of the `expected` bytes of the response, counting
+	/// the length prefix and the artifact. If the pipe was closed within the length prefix,
+	/// `expected` is the length of the prefix. The length of a streamed artifact is not known up
+	/// front, so `expected` then only counts up to the end of the frame that was cut short.
+	Truncated { expected: u64, received: u64 },
+}
+
+/// Receives the response of the job. An artifact that follows the response in the given format is
+/// written to the temporary artifact file at `temp_artifact_dest`. A raw or streamed artifact is
+/// written in chunks of [`ARTIFACT_CHUNK_SIZE`] bytes, so that the worker never holds more of it in
+/// memory than the artifact cache could.
+///
+/// The response is framed like with [`framed_recv_blocking_bounded`], but a response that is cut
+/// short is reported as [`RecvJobError::Truncated`].
+fn recv_job_response(
+	pipe_read: &mut impl Read,
+	temp_artifact_dest: &Path,
+	artifact_format: ArtifactFormat,
+) -> Result<ReceivedJobResponse, RecvJobError> {
+	let mut len_buf = [0u8; mem::size_of::<usize>()];
+	let prefix_len = len_buf.len() as u64;
+	read_response_part(pipe_read, &mut len_buf, 0, prefix_len)?;
+	let len = usize::from_le_bytes(len_buf);
+	if len > MAX_JOB_RESPONSE_SIZE {
+		return Err(RecvJobError::Pipe(io::Error::new(
+			io::ErrorKind::InvalidData,
+			FrameTooLarge { len, max_len: MAX_JOB_RESPONSE_SIZE },
+		)))
+	}
+	let mut response = vec![0; len];
+	let received = prefix_len + len as u64;
+	read_response_part(pipe_read, &mut response, prefix_len, received)?;
+	let result = JobResult::decode(&mut response.as_slice())
+		.map_err(|err| RecvJobError::Pipe(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+
+	let artifact = match &result {
+		Ok(JobResponse { artifact_len: Some(len), .. }) => Some(match artifact_format {
+			ArtifactFormat::Raw => recv_artifact(pipe_read, received, *len, temp_artifact_dest)?,
+			ArtifactFormat::Scale =>
+				recv_scale_artifact(pipe_read, received, *len, temp_artifact_dest)?,
+		}),
+		Ok(JobResponse { artifact_streamed: true, .. }) =>
+			Some(recv_streamed_artifact(pipe_read, received, temp_artifact_dest)?),
+		_ => None,
+	};
+	Ok(ReceivedJobResponse { result, artifact })
+}
+
+/// Fills `buf` from the pipe. `received` is the number of bytes of the response read before, and
+/// `expected` the number of bytes it has once `buf` is filled, for reporting a response that is cut
+/// short. A pipe that is closed before any byte of the response arrived is not a truncated
+/// response, but a job that died before writing one.
+fn read_response_part(
+	pipe_read: &mut impl Read,
+	buf: &mut [u8],
+	received: u64,
+	expected: u64,
+) -> Result<(), RecvJobError> {
+	let mut filled = 0;
+	while filled < buf.len() {
+		match pipe_read.read(&mut buf[filled..]) {
+			Ok(0) if received == 0 && filled == 0 =>
+				return Err(RecvJobError::Pipe(io::ErrorKind::UnexpectedEof.into())),
+			Ok(0) =>
+				return Err(RecvJobError::Truncated { expected, received: received + filled as u64 }),
+			Ok(n) => filled += n,
+			Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+			Err(err) => return Err(RecvJobError::Pipe(err)),
+		}
+	}
+	Ok(())
+}
+
+/// Streams an artifact of `len` bytes from the pipe into the file at `path`, after `received` bytes
+/// of the response. Artifacts larger than [`MAX_COMPILED_ARTIFACT_SIZE`] are rejected, as they
+/// could not be loaded anyway.
+fn recv_artifact(
+	pipe_read: &mut impl Read,
+	received: u64,
+	len: u64,
+	path: &Path,
+) -> Result<ReceivedArtifact, RecvJobError> {
+	check_artifact_len(len)?;
+
+	let write_start = Instant::now();
+	let mut file = fs::File::create(path).map_err(RecvJobError::Write)?;
+	let mut digest = ArtifactDigest::new(len);
+
+	let mut buf = vec![0u8; ARTIFACT_CHUNK_SIZE];
+	let mut remaining = len;
+	while remaining > 0 {
+		let chunk = &mut buf[..remaining.min(ARTIFACT_CHUNK_SIZE as u64) as usize];
+		read_response_part(pipe_read, chunk, received + len - remaining, received + len)?;
+		file.write_all(chunk).map_err(RecvJobError::Write)?;
+		digest.update(chunk);
+		remaining -= chunk.len() as u64;
+	}
+
+	let (artifact, checksum) = digest.finish();
+	Ok(ReceivedArtifact { artifact, len, checksum, write_elapsed: write_start.elapsed() })
+}
+
+/// Receives an artifact of `len` bytes that is SCALE-encoded in a frame of its own, see
+/// [`ArtifactFormat::Scale`], into the file at `path`, after `received` bytes of the response.
+/// Unlike with [`recv_artifact`], the whole artifact is held in memory.
+fn recv_scale_artifact(
+	pipe_read: &mut impl Read,
+	received: u64,
+	len: u64,
+	path: &Path,
+) -> Result<ReceivedArtifact, RecvJobError> {
+	check_artifact_len(len)?;
+
+	let mut len_buf = [0u8; mem::size_of::<usize>()];
+	let prefix_len = len_buf.len() as u64;
+	read_response_part(pipe_read, &mut len_buf, received, received + prefix_len)?;
+	let frame_len = usize::from_le_bytes(len_buf);
+	let expected_frame_len = Compact(len).encoded_size() + len as usize;
+	if frame_len != expected_frame_len {
+		return Err(RecvJobError::Pipe(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("artifact frame of {} bytes, expected {} bytes", frame_len, expected_frame_len),
+		)))
+	}
+	let mut frame = vec![0; frame_len];
+	let received = received + prefix_len;
+	read_response_part(pipe_read, &mut frame, received, received + frame_len as u64)?;
+	let artifact = Vec::<u8>::decode(&mut frame.as_slice())
+		.map_err(|err| RecvJobError::Pipe(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+	if artifact.len() as u64 != len {
+		return Err(RecvJobError::Pipe(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("artifact of {} bytes, expected {} bytes", artifact.len(), len),
+		)))
+	}
+
+	let write_start = Instant::now();
+	fs::write(path, &artifact).map_err(RecvJobError::Write)?;
+	let mut digest = ArtifactDigest::new(len);
+	digest.update(&artifact);
+
+	let (artifact, checksum) = digest.finish();
+	Ok(ReceivedArtifact { artifact, len, checksum, write_elapsed: write_start.elapsed() })
+}
+
+/// Rejects artifacts larger than [`MAX_COMPILED_ARTIFACT_SIZE`], as they could not be loaded
+/// anyway.
+fn check_artifact_len(len: u64) -> Result<(), RecvJobError> {
+	if len > MAX_COMPILED_ARTIFACT_SIZE as u64 {
+		return Err(RecvJobError::Pipe(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!(
+				"artifact of {} bytes exceeds the maximum of {} bytes",
+				len, MAX_COMPILED_ARTIFACT_SIZE
+			),
+		)))
+	}
+	Ok(())
+}
+
+/// Receives an artifact streamed by [`ArtifactStreamWriter`] into the file at `path`, after
+/// `received` bytes of the response. The payload is written to the file as its frames arrive, and
+/// the header in front of it once the payload is complete. As the header comes last, the artifact
+/// is only verified afterwards, by reading the file back.
+fn recv_streamed_artifact(
+	pipe_read: &mut impl Read,
+	mut received: u64,
+	path: &Path,
+) -> Result<ReceivedArtifact, RecvJobError> {
+	let write_start = Instant::now();
+	let mut file = fs::OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(path)
+		.map_err(RecvJobError::Write)?;
+	file.seek(SeekFrom::Start(ARTIFACT_HEADER_LEN as u64))
+		.map_err(RecvJobError::Write)?;
+
+	let mut buf = vec![0u8; ARTIFACT_CHUNK_SIZE];
+	let mut len = ARTIFACT_HEADER_LEN as u64;
+	loop {
+		let mut len_buf = [0u8; mem::size_of::<usize>()];
+		let prefix_len = len_buf.len() as u64;
+		read_response_part(pipe_read, &mut len_buf, received, received + prefix_len)?;
+		received += prefix_len;
+		// An empty frame marks the end of the payload.
+		let frame_len = usize::from_le_bytes(len_buf);
+		if frame_len == 0 {
+			break
+		}
+		if frame_len > ARTIFACT_CHUNK_SIZE {
+			return Err(RecvJobError::Pipe(io::Error::new(
+				io::ErrorKind::InvalidData,
+				FrameTooLarge { len: frame_len, max_len: ARTIFACT_CHUNK_SIZE },
+			)))
+		}
+		len += frame_len as u64;
+		if len > MAX_COMPILED_ARTIFACT_SIZE as u64 {
+			return Err(RecvJobError::Pipe(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!(
+					"streamed artifact exceeds the maximum of {} bytes",
+					MAX_COMPILED_ARTIFACT_SIZE
+				),
+			)))
+		}
+		let chunk = &mut buf[..frame_len];
+		read_response_part(pipe_read, chunk, received, received + frame_len as u64)?;
+		received += frame_len as u64;
+		file.write_all(chunk).map_err(RecvJobError::Write)?;
+	}
+
+	let header = &mut buf[..ARTIFACT_HEADER_LEN];
+	read_response_part(pipe_read, header, received, received + ARTIFACT_HEADER_LEN as u64)?;
+	file.seek(SeekFrom::Start(0)).map_err(RecvJobError::Write)?;
+	file.write_all(header).map_err(RecvJobError::Write)?;
+
+	file.seek(SeekFrom::Start(0)).map_err(RecvJobError::Write)?;
+	let mut digest = ArtifactDigest::new(len);
+	let mut remaining = len;
+	while remaining > 0 {
+		let chunk = &mut buf[..remaining.min(ARTIFACT_CHUNK_SIZE as u64) as usize];
+		file.read_exact(chunk).map_err(RecvJobError::Write)?;
+		digest.update(chunk);
+		remaining -= chunk.len() as u64;
+	}
+
+	let (artifact, checksum) = digest.finish();
+	Ok(ReceivedArtifact { artifact, len, checksum, write_elapsed: write_start.elapsed() })
+}
+
+/// Computes the checksum of an artifact whose bytes go by in chunks and verifies its header.
+/// Artifacts that can be cached are kept in memory and verified as a whole once complete, the
+/// others are verified as they go by.
+struct ArtifactDigest {
+	hasher: blake3::Hasher,
+	cacheable: Option<Vec<u8>>,
+	verifier: ArtifactVerifier,
+}
+
+impl ArtifactDigest {
+	/// Creates a digest of an artifact of `len` bytes.
+	fn new(len: u64) -> Self {
+		Self {
+			hasher: blake3::Hasher::new(),
+			cacheable: ArtifactCache::can_hold(len).then(|| Vec::with_capacity(len as usize)),
+			verifier: ArtifactVerifier::new(),
+		}
+	}
+
+	/// Feeds the next bytes of the artifact to the digest.
+	fn update(&mut self, chunk: &[u8]) {
+		self.hasher.update(chunk);
+		match self.cacheable.as_mut() {
+			Some(bytes) => bytes.extend_from_slice(chunk),
+			None => self.verifier.update(chunk),
+		}
+	}
+
+	/// Returns the artifact if it can be cached, or an error if its header is invalid, along with
+	/// its checksum.
+	fn finish(self) -> (Result<Option<CompiledArtifact>, ArtifactHeaderError>, String) {
+		let artifact = match self.cacheable {
+			Some(bytes) => CompiledArtifact::from_bytes(bytes).map(Some),
+			None => self.verifier.finish().map(|()| None),
+		};
+		(artifact, self.hasher.finalize().to_hex().to_string())
+	}
+}
+
+/// Classifies how a job concluded, from its wait status and the CPU time it used.
+///
+/// A job that used up its CPU time budget timed out, however it ended, as it may have been killed
+/// or crashed for running out of time. Otherwise, a job that exited by itself returns its exit
+/// status, and its response can be trusted. A failed job exits with a non-zero status too, and its
+/// response tells why. Any other status means that the job did not end by itself, see
+/// [`error_from_wait_status`].
+fn classify_wait_status(
+	status: WaitStatus,
+	cpu_tv: Duration,
+	timeout: Duration,
+	job_pid: Pid,
+	code_hash: ValidationCodeHash,
+) -> Result<i32, PrepareError> {
+	if cpu_tv >= timeout {
+		return Err(PrepareError::TimedOut)
+	}
+	match status {
+		WaitStatus::Exited(_pid, exit_status) => Ok(exit_status),
+		status => Err(error_from_wait_status(status, job_pid, code_hash)),
+	}
+}
+
+/// Returns the error for a job which did not exit normally, i.e. for any wait status other than
+/// [`WaitStatus::Exited`]. The error names the code the job was preparing.
+fn error_from_wait_status(
+	status: WaitStatus,
+	job_pid: Pid,
+	code_hash: ValidationCodeHash,
+) -> PrepareError {
+	let job_pid = job_pid.as_raw();
+	let code_hash = Some(code_hash);
+	match status {
+		// The job was killed by the given signal.
+		WaitStatus::Signaled(_pid, signal, _core_dump) => match signal {
+			Signal::SIGKILL =>
+				PrepareError::JobKilled { signal: signal as i32, job_pid, code_hash },
+			Signal::SIGSEGV =>
+				PrepareError::JobSegfault { signal: signal as i32, job_pid, code_hash },
+			Signal::SIGABRT =>
+				PrepareError::JobAborted { signal: signal as i32, job_pid, code_hash },
+			// The job gets SIGSYS on seccomp violations. This signal may have been sent for some
+			// other reason, which is fine since the error is not deterministic either way.
+			Signal::SIGSYS =>
+				PrepareError::SeccompViolation { signal: signal as i32, job_pid, code_hash },
+			_ => PrepareError::JobDied {
+				err: format!("received signal: {signal:?}"),
+				job_pid,
+				code_hash,
+			},
+		},
+		// An attacker can make the child process return any exit status it wants. So we can treat
+		// all unexpected cases the same way.
+		unexpected_wait_status => PrepareError::JobDied {
+			err: format!("unexpected status from wait: {unexpected_wait_status:?}"),
+			job_pid,
+			code_hash,
+		},
+	}
+}
+
+/// Write a job response to the pipe and exit process after.
+///
+/// # Arguments
+///
+/// - `pipe_write`: A `PipeFd` structure, the writing end of a pipe.
+///
+/// - `response`: Child process response
+fn send_child_response(pipe_write: &mut PipeFd, response: JobResult) -> ! {
+	framed_send_blocking(pipe_write, response.encode().as_slice())
+		.unwrap_or_else(|_| process::exit(libc::EXIT_FAILURE));
+
+	if response.is_ok() {
+		process::exit(libc::EXIT_SUCCESS)
+	} else {
+		process::exit(libc::EXIT_FAILURE)
+	}
+}
+
+/// Write a successful job response to the pipe, followed by the artifact in the given format, see
+/// [`write_response_with_artifact`], and exit process after.
+fn send_child_response_with_artifact(
+	pipe_write: &mut PipeFd,
+	response: JobResponse,
+	artifact: &[u8],
+	format: ArtifactFormat,
+) -> ! {
+	write_response_with_artifact(pipe_write, response, artifact, format)
+		.unwrap_or_else(|_| process::exit(libc::EXIT_FAILURE));
+
+	process::exit(libc::EXIT_SUCCESS)
+}
+
+/// Writes a successful job response, followed by the artifact. A raw artifact is written in chunks
+/// of [`ARTIFACT_CHUNK_SIZE`] bytes, a SCALE-encoded one in a single frame.
+fn write_response_with_artifact(
+	writer: &mut (impl Write + Unpin),
+	response: JobResponse,
+	artifact: &[u8],
+	format: ArtifactFormat,
+) -> io::Result<()> {
+	let response: JobResult = Ok(response);
+	framed_send_blocking(writer, response.encode().as_slice())?;
+	match format {
+		ArtifactFormat::Raw => artifact
+			.chunks(ARTIFACT_CHUNK_SIZE)
+			.try_for_each(|chunk| writer.write_all(chunk)),
+		ArtifactFormat::Scale => framed_send_blocking(writer, &artifact.encode()),
+	}
+}
+
+/// Write a successful job response to the pipe, followed by the artifact as it is compressed, see
+/// [`ArtifactStreamWriter`], and exit process after.
+fn send_child_response_with_streamed_artifact(
+	pipe_write: &mut PipeFd,
+	response: JobResponse,
+	compiled_artifact: &[u8],
+	code_hash: ValidationCodeHash,
+	artifact_flags: ArtifactFlags,
+) -> ! {
+	let response: JobResult = Ok(response);
+	framed_send_blocking(pipe_write, response.encode().as_slice())
+		.and_then(|()| {
+			let mut writer = ArtifactStreamWriter::new(&mut *pipe_write);
+			artifact::compress_to(compiled_artifact, ARTIFACT_COMPRESSION_LEVEL, &mut writer)
+				.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+			writer.finish(code_hash, artifact_flags)
+		})
+		.unwrap_or_else(|_| process::exit(libc::EXIT_FAILURE));
+
+	process::exit(libc::EXIT_SUCCESS)
+}
+
+/// Streams the payload of an artifact to the worker in frames of up to [`ARTIFACT_CHUNK_SIZE`]
+/// bytes, for jobs requested with [`PvfPrepData::with_artifact_streaming`]. A frame is sent as soon
+/// as it is full or the writer is flushed, so the worker writes the artifact to disk while it is
+/// still being produced. An empty frame marks the end of the payload, and is followed by the
+/// header of the artifact, which can only be built once the payload is complete.
+struct ArtifactStreamWriter<W: Write + Unpin> {
+	writer: W,
+	frame: Vec<u8>,
+	header: ArtifactHeaderBuilder,
+}
+
+impl<W: Write + Unpin> ArtifactStreamWriter<W> {
+	fn new(writer: W) -> Self {
+		Self {
+			writer,
+			frame: Vec::with_capacity(ARTIFACT_CHUNK_SIZE),
+			header: ArtifactHeaderBuilder::new(),
+		}
+	}
+
+	/// Sends the buffered payload as a frame, if there is any.
+	fn send_frame(&mut self) -> io::Result<()> {
+		if self.frame.is_empty() {
+			return Ok(())
+		}
+		framed_send_blocking(&mut self.writer, &self.frame)?;
+		self.header.update(&self.frame);
+		self.frame.clear();
+		Ok(())
+	}
+
+	/// Sends the rest of the payload, followed by the end marker and the header of the artifact of
+	/// the code with the given hash and the given flags.
+	fn finish(mut self, code_hash: ValidationCodeHash, flags: ArtifactFlags) -> io::Result<()> {
+		self.send_frame()?;
+		framed_send_blocking(&mut self.writer, &[])?;
+		self.writer.write_all(&self.header.finish(code_hash, flags))?;
+		self.writer.flush()
+	}
+}
+
+impl<W: Write + Unpin> Write for ArtifactStreamWriter<W> {
+	fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+		let len = bytes.len().min(ARTIFACT_CHUNK_SIZE - self.frame.len());
+		self.frame.extend_from_slice(&bytes[..len]);
+		if self.frame.len() == ARTIFACT_CHUNK_SIZE {
+			self.send_frame()?;
+		}
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.send_frame()?;
+		self.writer.flush()
+	}
+}
+
+/// Limits the address space of the job process. Once the limit is reached, allocations fail and are
+/// reported as [`PrepareError::OutOfMemory`] by the tracking allocator.
+#[cfg(target_os = "linux")]
+fn set_address_space_limit(limit: u64) -> Result<(), PrepareError> {
+	use nix::sys::resource::{setrlimit, Resource};
+
+	setrlimit(Resource::RLIMIT_AS, limit, limit)
+		.map_err(|errno| error_from_errno("setrlimit", errno))
+}
+
+/// Lets the kernel dump the core of the job if it is killed by a signal. A relative
+/// `core_pattern`, such as the default `core`, is resolved against the working directory of the
+/// job, so the job moves to the worker dir.
+#[cfg(target_os = "linux")]
+fn enable_core_dumps(worker_dir_path: &Path) -> nix::Result<()> {
+	use nix::sys::resource::{getrlimit, setrlimit, Resource};
+
+	// Raising the soft limit up to the hard limit doesn't need any privileges.
+	let (_soft, hard) = getrlimit(Resource::RLIMIT_CORE)?;
+	setrlimit(Resource::RLIMIT_CORE, hard, hard)?;
+	// The job is not dumpable if e.g. it changed its credentials when entering a user namespace.
+	// SAFETY: `PR_SET_DUMPABLE` only takes an integer argument.
+	Errno::result(unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 1) })?;
+	nix::unistd::chdir(worker_dir_path)
+}
+
+/// Returns the path of the core the job dumped into the worker dir, relative to the worker dir.
+/// The kernel names it after `core_pattern`, which by default is `core`, optionally followed by
+/// the pid of the job as seen in its own pid namespace. The most recent core is taken, in case one
+/// was left over from an earlier job.
+fn find_core_dump(worker_dir_path: &Path) -> Option<String> {
+	fs::read_dir(worker_dir_path)
+		.ok()?
+		.filter_map(Result::ok)
+		.filter_map(|entry| {
+			let name = entry.file_name().into_string().ok()?;
+			let is_core = name == "core" || name.starts_with("core.");
+			let modified = entry.metadata().and_then(|metadata| metadata.modified()).ok()?;
+			is_core.then_some((modified, name))
+		})
+		.max()
+		.map(|(_modified, name)| name)
+}
+
+/// Pins the calling thread, and the threads it spawns from then on, to the cores set in `mask`.
+/// Bit `n` of the mask stands for core `n`.
+#[cfg(target_os = "linux")]
+fn set_cpu_affinity(mask: u64) -> nix::Result<()> {
+	use nix::sched::{sched_setaffinity, CpuSet};
+
+	let mut cpu_set = CpuSet::new();
+	for core in (0..u64::BITS as usize).filter(|core| mask & (1 << core) != 0) {
+		cpu_set.set(core)?;
+	}
+	sched_setaffinity(Pid::from_raw(0), &cpu_set)
+}
+
+/// Sets the nice value and the IO scheduling class of the calling thread, which the threads it
+/// spawns from then on inherit. Unset parts of the priority are left alone.
+#[cfg(target_os = "linux")]
+fn set_job_priority(priority: JobPriority) -> nix::Result<()> {
+	if let Some(nice) = priority.nice {
+		// SAFETY: `setpriority` only takes integer arguments. On Linux, `PRIO_PROCESS` with a pid
+		// of 0 targets the calling thread.
+		Errno::result(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
+	}
+	if let Some(io_priority) = priority.io_priority {
+		// SAFETY: `ioprio_set` only takes integer arguments.
+		Errno::result(unsafe {
+			libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio_value(io_priority))
+		})?;
+	}
+	Ok(())
+}
+
+/// Makes `ioprio_set` and `ioprio_get` target a single thread.
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// Encodes the IO priority like the kernel expects it: the class in the upper bits and the level
+/// within the class in the lower ones.
+#[cfg(target_os = "linux")]
+fn ioprio_value(io_priority: IoPriority) -> libc::c_int {
+	const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+	const IOPRIO_CLASS_BE: libc::c_int = 2;
+	const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+	match io_priority {
+		IoPriority::BestEffort(level) =>
+			(IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level),
+		IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+	}
+}
+
+/// How many times forking a job is retried after failing with `EAGAIN`.
+const FORK_RETRIES: u32 = 3;
+
+/// How long to wait before retrying to fork a job for the first time. The backoff doubles with
+/// every retry.
+const FORK_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Forks the job process with the given `fork`, which is only swapped out in tests. A failure is
+/// reported along with its errno, which tells e.g. a process limit (`EAGAIN`) from a lack of memory
+/// (`ENOMEM`).
+///
+/// Running into the process limit is often momentary, e.g. while other processes are spawned in
+/// bulk, so forking is retried up to [`FORK_RETRIES`] times on `EAGAIN` before giving up. This
+/// spares the host retrying the whole job. Other failures are not retried.
+fn fork_job(mut fork: impl FnMut() -> nix::Result<ForkResult>) -> Result<ForkResult, PrepareError> {
+	let mut backoff = FORK_RETRY_BACKOFF;
+	for _ in 0..FORK_RETRIES {
+		match fork() {
+			Err(Errno::EAGAIN) => {
+				std::thread::sleep(backoff);
+				backoff *= 2;
+			},
+			result =>
+				return result.map_err(|errno| PrepareError::ForkFailed { errno: errno as i32 }),
+		}
+	}
+	fork().map_err(|errno| PrepareError::ForkFailed { errno: errno as i32 })
+}
+
+fn error_from_errno(context: &'static str, errno: Errno) -> PrepareError {
+	PrepareError::Kernel(stringify_errno(context, errno))
+}
+
+type JobResult = Result<JobResponse, PrepareError>;
+
+/// Pre-encoded length-prefixed `JobResult::Err(PrepareError::OutOfMemory)`
+const OOM_PAYLOAD: &[u8] = b"\x02\x00\x00\x00\x00\x00\x00\x00\x01\x08";
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The response of a job that has nothing to report, and no artifact either.
+	fn job_response() -> JobResponse {
+		JobResponse {
+			artifact_len: None,
+			artifact_streamed: false,
+			memory_stats: MemoryStats::default(),
+			observed_wasm_code_len: 0,
+			code_size: 0,
+			stage_timings: None,
+			applied_security: AppliedSecurity::empty(),
+			compiler_warnings: Vec::new(),
+			compile_cpu_time: Duration::ZERO,
+			module_shape: ModuleShape::default(),
+			fork_latency: Duration::ZERO,
+			compile_start: None,
+			#[cfg(target_os = "linux")]
+			run_queue_delay: None,
+		}
+	}
+
+	#[test]
+	fn pre_encoded_payloads() {
+		// NOTE: This must match the type of `response` in `send_child_response`.
+		let oom_unencoded: JobResult = JobResult::Err(PrepareError::OutOfMemory);
+		let oom_encoded = oom_unencoded.encode();
+		// The payload is prefixed with	its length in `framed_send`.
+		let mut oom_payload = oom_encoded.len().to_le_bytes().to_vec();
+		oom_payload.extend(oom_encoded);
+		assert_eq!(oom_payload, OOM_PAYLOAD);
+	}
+
+	#[test]
+	fn signaled_jobs_report_the_signal() {
+		let job_pid = Pid::from_raw(42);
+		let code_hash = ValidationCodeHash::from([0; 32]);
+		let signaled = |signal| WaitStatus::Signaled(job_pid, signal, false);
+
+		assert!(matches!(
+			error_from_wait_status(signaled(Signal::SIGKILL), job_pid, code_hash),
+			PrepareError::JobKilled { signal: 9, job_pid: 42, .. }
+		));
+		assert!(matches!(
+			error_from_wait_status(signaled(Signal::SIGSEGV), job_pid, code_hash),
+			PrepareError::JobSegfault { signal: 11, job_pid: 42, .. }
+		));
+		assert!(matches!(
+			error_from_wait_status(signaled(Signal::SIGABRT), job_pid, code_hash),
+			PrepareError::JobAborted { signal: 6, job_pid: 42, .. }
+		));
+		assert!(matches!(
+			error_from_wait_status(signaled(Signal::SIGSYS), job_pid, code_hash),
+			PrepareError::SeccompViolation { signal: 31, job_pid: 42, .. }
+		));
+		assert!(matches!(
+			error_from_wait_status(signaled(Signal::SIGTERM), job_pid, code_hash),
+			PrepareError::JobDied { job_pid: 42, .. }
+		));
+	}
+
+	#[test]
+	fn unexpected_wait_statuses_report_job_died() {
+		let job_pid = Pid::from_raw(42);
+		let code_hash = ValidationCodeHash::from([0; 32]);
+
+		assert!(matches!(
+			error_from_wait_status(
+				WaitStatus::Stopped(job_pid, Signal::SIGSTOP),
+				job_pid,
+				code_hash
+			),
+			PrepareError::JobDied { job_pid: 42, .. }
+		));
+		assert!(matches!(
+			error_from_wait_status(WaitStatus::StillAlive, job_pid, code_hash),
+			PrepareError::JobDied { job_pid: 42, .. }
+		));
+	}
+
+	#[test]
+	fn wait_statuses_are_classified() {
+		let job_pid = Pid::from_raw(42);
+		let code_hash = ValidationCodeHash::from([0; 32]);
+		let timeout = Duration::from_secs(10);
+		let classify = |status| {
+			classify_wait_status(status, Duration::from_secs(1), timeout, job_pid, code_hash)
+		};
+
+		assert_eq!(classify(WaitStatus::Exited(job_pid, 0)), Ok(0));
+		// The response of a failed job tells why it failed.
+		assert_eq!(classify(WaitStatus::Exited(job_pid, 1)), Ok(1));
+		assert!(matches!(
+			classify(WaitStatus::Signaled(job_pid, Signal::SIGKILL, false)),
+			Err(PrepareError::JobKilled { signal: 9, job_pid: 42, .. })
+		));
+		assert!(matches!(
+			classify(WaitStatus::Stopped(job_pid, Signal::SIGSTOP)),
+			Err(PrepareError::JobDied { job_pid: 42, .. })
+		));
+		assert!(matches!(
+			classify(WaitStatus::Continued(job_pid)),
+			Err(PrepareError::JobDied { job_pid: 42, .. })
+		));
+	}
+
+	#[test]
+	fn jobs_over_their_cpu_time_budget_time_out_however_they_ended() {
+		let job_pid = Pid::from_raw(42);
+		let code_hash = ValidationCodeHash::from([0; 32]);
+		let timeout = Duration::from_secs(10);
+
+		for status in [
+			WaitStatus::Exited(job_pid, 0),
+			WaitStatus::Exited(job_pid, 1),
+			WaitStatus::Signaled(job_pid, Signal::SIGKILL, false),
+			WaitStatus::Stopped(job_pid, Signal::SIGSTOP),
+			WaitStatus::Continued(job_pid),
+		] {
+			assert_eq!(
+				classify_wait_status(status, timeout, timeout, job_pid, code_hash),
+				Err(PrepareError::TimedOut)
+			);
+		}
+		assert_eq!(
+			classify_wait_status(
+				WaitStatus::Exited(job_pid, 0),
+				timeout - Duration::from_millis(1),
+				timeout,
+				job_pid,
+				code_hash
+			),
+			Ok(0)
+		);
+	}
+
+	#[test]
+	fn job_errors_name_the_code_being_prepared() {
+		let job_pid = Pid::from_raw(42);
+		let code_hash = ValidationCodeHash::from([7; 32]);
+
+		let err = error_from_wait_status(
+			WaitStatus::Signaled(job_pid, Signal::SIGKILL, false),
+			job_pid,
+			code_hash,
+		);
+
+		assert!(
+			matches!(err, PrepareError::JobKilled { code_hash: Some(hash), .. } if hash == code_hash)
+		);
+		assert!(err.to_string().contains(&format!("{:?}", code_hash)));
+	}
+
+	#[test]
+	fn landlock_denials_are_attributed_to_landlock() {
+		let denied = io::Error::from(Errno::EACCES).to_string();
+
+		assert!(matches!(
+			attribute_landlock_denial(PrepareError::Compilation(format!("Other(\"{denied}\")"))),
+			PrepareError::LandlockViolation(_)
+		));
+		assert!(matches!(
+			attribute_landlock_denial(PrepareError::IoErr(denied)),
+			PrepareError::LandlockViolation(_)
+		));
+		assert!(matches!(
+			attribute_landlock_denial(PrepareError::Compilation("InvalidModule".into())),
+			PrepareError::Compilation(_)
+		));
+		assert!(matches!(
+			attribute_landlock_denial(PrepareError::OutOfMemory),
+			PrepareError::OutOfMemory
+		));
+	}
+
+	#[test]
+	fn validate_code_accepts_valid_code() {
+		// The smallest valid Wasm module: the magic number and the version.
+		let code = b"\0asm\x01\0\0\0";
+
+		assert!(validate_code(code, &ExecutorParams::default()).is_ok());
+	}
+
+	#[test]
+	fn validate_code_rejects_invalid_code() {
+		assert!(matches!(
+			validate_code(b"not wasm", &ExecutorParams::default()),
+			Err(PrepareError::Prevalidation(_))
+		));
+	}
+
+	#[test]
+	fn validate_code_rejects_empty_code() {
+		assert!(matches!(
+			validate_code(&[], &ExecutorParams::default()),
+			Err(PrepareError::Prevalidation(_))
+		));
+	}
+
+	#[test]
+	fn job_cpu_timeout_depends_on_job_kind() {
+		use polkadot_primitives::{ExecutorParam, PvfPrepKind};
+
+		let executor_params = ExecutorParams::from(
+			&[
+				ExecutorParam::PvfPrepTimeout(PvfPrepKind::Precheck, 1_000),
+				ExecutorParam::PvfPrepTimeout(PvfPrepKind::Prepare, 5_000),
+			][..],
+		);
+		let pvf = |kind| {
+			PvfPrepData::from_code(vec![], executor_params.clone(), Duration::from_secs(30), kind)
+		};
+
+		assert_eq!(pvf(PrepareJobKind::Prechecking).cpu_timeout(), Duration::from_secs(1));
+		assert_eq!(pvf(PrepareJobKind::ValidateOnly).cpu_timeout(), Duration::from_secs(1));
+		assert_eq!(pvf(PrepareJobKind::Compilation).cpu_timeout(), Duration::from_secs(5));
+		assert_eq!(pvf(PrepareJobKind::Benchmark).cpu_timeout(), Duration::from_secs(5));
+		assert_eq!(pvf(PrepareJobKind::VerifyDeterminism).cpu_timeout(), Duration::from_secs(5));
+	}
+
+	#[test]
+	fn job_cpu_timeout_falls_back_to_the_request_timeout() {
+		let pvf = PvfPrepData::from_code(
+			vec![],
+			ExecutorParams::default(),
+			Duration::from_secs(30),
+			PrepareJobKind::Compilation,
+		);
+
+		assert_eq!(pvf.cpu_timeout(), Duration::from_secs(30));
+	}
+
+	#[test]
+	fn job_cpu_timeout_scales_with_the_code_size() {
+		use polkadot_primitives::{ExecutorParam, PvfPrepKind};
+
+		let executor_params = ExecutorParams::from(
+			&[
+				ExecutorParam::PvfPrepTimeout(PvfPrepKind::Prepare, 5_000),
+				ExecutorParam::PvfPrepTimeoutScaling {
+					kind: PvfPrepKind::Prepare,
+					per_mib_ms: 10_000,
+					min_ms: 2_000,
+					max_ms: 60_000,
+				},
+			][..],
+		);
+		let pvf = |code_size, kind| {
+			PvfPrepData::from_code(
+				vec![0; code_size],
+				executor_params.clone(),
+				Duration::from_secs(30),
+				kind,
+			)
+		};
+		const MIB: usize = 1024 * 1024;
+
+		// Small code is clamped to the minimum.
+		assert_eq!(pvf(1024, PrepareJobKind::Compilation).cpu_timeout(), Duration::from_secs(2));
+		assert_eq!(pvf(MIB / 2, PrepareJobKind::Compilation).cpu_timeout(), Duration::from_secs(5));
+		assert_eq!(
+			pvf(3 * MIB, PrepareJobKind::Compilation).cpu_timeout(),
+			Duration::from_secs(30)
+		);
+		// Large code is clamped to the maximum.
+		assert_eq!(
+			pvf(10 * MIB, PrepareJobKind::Compilation).cpu_timeout(),
+			Duration::from_secs(60)
+		);
+		// Only the timeout of the given kind scales.
+		assert_eq!(
+			pvf(10 * MIB, PrepareJobKind::Prechecking).cpu_timeout(),
+			Duration::from_secs(30)
+		);
+	}
+
+	#[test]
+	fn fork_failures_report_the_errno() {
+		let err = fork_job(|| Err(Errno::EAGAIN)).unwrap_err();
+
+		assert!(matches!(err, PrepareError::ForkFailed { errno } if errno == libc::EAGAIN));
+		assert!(err
+			.to_string()
+			.contains(&io::Error::from_raw_os_error(libc::EAGAIN).to_string()));
+	}
+
+	#[test]
+	fn fork_is_retried_on_eagain() {
+		let mut attempts = 0;
+		let result = fork_job(|| {
+			attempts += 1;
+			if attempts == 1 {
+				Err(Errno::EAGAIN)
+			} else {
+				Ok(ForkResult::Parent { child: Pid::from_raw(42) })
+			}
+		});
+
+		assert!(matches!(result, Ok(ForkResult::Parent { child }) if child.as_raw() == 42));
+		assert_eq!(attempts, 2);
+	}
+
+	#[test]
+	fn fork_retries_are_bounded() {
+		let mut attempts = 0;
+		let result = fork_job(|| {
+			attempts += 1;
+			Err(Errno::EAGAIN)
+		});
+
+		assert!(matches!(result, Err(PrepareError::ForkFailed { errno }) if errno == libc::EAGAIN));
+		assert_eq!(attempts, FORK_RETRIES + 1);
+	}
+
+	#[test]
+	fn fork_is_not_retried_on_other_errors() {
+		let mut attempts = 0;
+		let result = fork_job(|| {
+			attempts += 1;
+			Err(Errno::ENOMEM)
+		});
+
+		assert!(matches!(result, Err(PrepareError::ForkFailed { errno }) if errno == libc::ENOMEM));
+		assert_eq!(attempts, 1);
+	}
+
+	#[test]
+	fn artifact_is_written_in_place_of_the_file_created_by_the_host() {
+		use std::os::unix::fs::MetadataExt;
+
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		// The host creates the destination before the job starts.
+		fs::write(&dest, b"").unwrap();
+		let inode = fs::metadata(&dest).unwrap().ino();
+		let artifact = vec![42u8; 1024];
+
+		write_artifact(&dest, None, &artifact, ArtifactDelivery::default()).unwrap();
+
+		assert_eq!(fs::read(&dest).unwrap(), artifact);
+		assert_eq!(fs::metadata(&dest).unwrap().ino(), inode);
+	}
+
+	#[test]
+	fn identical_artifacts_share_one_inode() {
+		use std::os::unix::fs::MetadataExt;
+
+		let dir = tempfile::tempdir().unwrap();
+		let first = dir.path().join("first");
+		let second = dir.path().join("second");
+		let artifact = vec![42u8; 1024];
+		let delivery = ArtifactDelivery { deduplicate: true, ..Default::default() };
+
+		write_artifact(&first, None, &artifact, delivery).unwrap();
+		write_artifact(&second, None, &artifact, delivery).unwrap();
+
+		assert_eq!(fs::read(&second).unwrap(), artifact);
+		assert_eq!(fs::metadata(&first).unwrap().ino(), fs::metadata(&second).unwrap().ino());
+		assert!(!artifact_link_path(&second).exists());
+	}
+
+	#[test]
+	fn different_artifacts_are_not_deduplicated() {
+		use std::os::unix::fs::MetadataExt;
+
+		let dir = tempfile::tempdir().unwrap();
+		let first = dir.path().join("first");
+		let second = dir.path().join("second");
+		let delivery = ArtifactDelivery { deduplicate: true, ..Default::default() };
+
+		write_artifact(&first, None, b"first", delivery).unwrap();
+		write_artifact(&second, None, b"second", delivery).unwrap();
+
+		assert_eq!(fs::read(&second).unwrap(), b"second");
+		assert_ne!(fs::metadata(&first).unwrap().ino(), fs::metadata(&second).unwrap().ino());
+	}
+
+	#[test]
+	fn artifacts_in_the_worker_dir_are_not_deduplicated() {
+		use std::os::unix::fs::MetadataExt;
+
+		let dir = tempfile::tempdir().unwrap();
+		let first = dir.path().join("first");
+		let second = dir.path().join("second");
+		let delivery = ArtifactDelivery::new(&WorkerOptions::default());
+
+		write_artifact(&first, None, b"artifact", delivery).unwrap();
+		write_artifact(&second, None, b"artifact", delivery).unwrap();
+
+		assert_ne!(fs::metadata(&first).unwrap().ino(), fs::metadata(&second).unwrap().ino());
+		assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+	}
+
+	#[test]
+	fn artifacts_are_deduplicated_by_their_post_processed_bytes() {
+		use std::os::unix::fs::MetadataExt;
+
+		struct Overwrite;
+
+		impl ArtifactPostProcessor for Overwrite {
+			fn process(&self, path: &Path) -> io::Result<()> {
+				fs::write(path, b"post-processed")
+			}
+		}
+
+		let dir = tempfile::tempdir().unwrap();
+		let first = dir.path().join("first");
+		let second = dir.path().join("second");
+		let delivery = ArtifactDelivery { post_processor: &Overwrite, deduplicate: true };
+
+		// Different artifacts that end up the same on disk.
+		write_artifact(&first, None, b"first", delivery).unwrap();
+		write_artifact(&second, None, b"second", delivery).unwrap();
+
+		assert_eq!(fs::metadata(&first).unwrap().ino(), fs::metadata(&second).unwrap().ino());
+		let checksum = blake3::hash(b"post-processed").to_hex().to_string();
+		assert!(deduplicated_artifact_path(&first, &checksum).exists());
+	}
+
+	#[test]
+	fn links_of_removed_artifacts_are_pruned() {
+		let dir = tempfile::tempdir().unwrap();
+		let first = dir.path().join("first");
+		let second = dir.path().join("second");
+		let delivery = ArtifactDelivery { deduplicate: true, ..Default::default() };
+
+		write_artifact(&first, None, b"first", delivery).unwrap();
+		// Picked up and removed by whatever spawned the worker.
+		fs::remove_file(&first).unwrap();
+		write_artifact(&second, None, b"second", delivery).unwrap();
+
+		let linked: Vec<_> = fs::read_dir(dir.path().join(DEDUPLICATED_ARTIFACTS_DIR))
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name())
+			.collect();
+		assert_eq!(linked, [blake3::hash(b"second").to_hex().as_str()]);
+	}
+
+	#[test]
+	fn deduplicated_artifact_is_not_overwritten_by_the_next_one() {
+		let dir = tempfile::tempdir().unwrap();
+		let first = dir.path().join("first");
+		let second = dir.path().join("second");
+		let delivery = ArtifactDelivery { deduplicate: true, ..Default::default() };
+		write_artifact(&first, None, b"artifact", delivery).unwrap();
+		write_artifact(&second, None, b"artifact", delivery).unwrap();
+
+		// The next job of the worker writing to `second`.
+		delivery.clear(&second);
+		write_artifact(&second, None, b"another artifact", delivery).unwrap();
+
+		assert_eq!(fs::read(&first).unwrap(), b"artifact");
+		assert_eq!(fs::read(&second).unwrap(), b"another artifact");
+	}
+
+	#[test]
+	fn artifact_is_copied_into_the_output_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		// The host creates the destination before the job starts.
+		fs::write(&dest, b"").unwrap();
+		let output = tempfile::tempfile().unwrap();
+
+		write_artifact(&dest, Some(&output), b"artifact", ArtifactDelivery::default()).unwrap();
+
+		// Only the emptied temporary artifact file is left behind.
+		assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+		assert!(fs::read(&dest).unwrap().is_empty());
+		let mut written = Vec::new();
+		(&output).seek(SeekFrom::Start(0)).unwrap();
+		(&output).read_to_end(&mut written).unwrap();
+		assert_eq!(written, b"artifact");
+	}
+
+	#[test]
+	fn code_size_is_checked_against_the_limit() {
+		assert!(check_code_size(&vec![0; MAX_PREVALIDATION_WASM_SIZE - 1]).is_ok());
+		assert!(check_code_size(&vec![0; MAX_PREVALIDATION_WASM_SIZE]).is_ok());
+		assert!(matches!(
+			check_code_size(&vec![0; MAX_PREVALIDATION_WASM_SIZE + 1]),
+			Err(PrepareError::PrevalidationWasmTooLarge { size, max })
+				if size == MAX_PREVALIDATION_WASM_SIZE as u64 + 1 &&
+					max == MAX_PREVALIDATION_WASM_SIZE as u64
+		));
+	}
+
+	#[test]
+	fn validate_code_rejects_oversized_code() {
+		assert!(matches!(
+			validate_code(&vec![0; MAX_PREVALIDATION_WASM_SIZE + 1], &ExecutorParams::default()),
+			Err(PrepareError::PrevalidationWasmTooLarge { .. })
+		));
+	}
+
+	#[test]
+	fn large_artifacts_are_streamed_to_disk() {
+		const PAYLOAD_CHUNKS: usize = 4 * 1024;
+		const PAYLOAD_LEN: usize = PAYLOAD_CHUNKS * ARTIFACT_CHUNK_SIZE;
+
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		let payload_chunk: Vec<u8> = (0..ARTIFACT_CHUNK_SIZE).map(|i| i as u8).collect();
+		assert!(!ArtifactCache::can_hold(PAYLOAD_LEN as u64));
+
+		// Build the header of the artifact without ever holding its payload in memory.
+		let mut payload_hasher = blake2b_simd::Params::new().hash_length(32).to_state();
+		(0..PAYLOAD_CHUNKS).for_each(|_| {
+			payload_hasher.update(&payload_chunk);
+		});
+		let mut header = CompiledArtifact::new(Vec::new(), ValidationCodeHash::from([0; 32]))
+			.as_ref()
+			.to_vec();
+		let checksum_start = header.len() - 32;
+		header[checksum_start..].copy_from_slice(payload_hasher.finalize().as_bytes());
+		let artifact_len = (header.len() + PAYLOAD_LEN) as u64;
+
+		let (pipe_read_fd, pipe_write_fd) = pipe2_cloexec().unwrap();
+		// SAFETY: both are open and owned file descriptors at this point.
+		let (pipe_read, mut pipe_write) =
+			unsafe { (PipeFd::from_raw_fd(pipe_read_fd), PipeFd::from_raw_fd(pipe_write_fd)) };
+		let job = std::thread::spawn(move || {
+			let response: JobResult =
+				Ok(JobResponse { artifact_len: Some(artifact_len), ..job_response() });
+			framed_send_blocking(&mut pipe_write, &response.encode()).unwrap();
+			pipe_write.write_all(&header).unwrap();
+			(0..PAYLOAD_CHUNKS).for_each(|_| pipe_write.write_all(&payload_chunk).unwrap());
+		});
+
+		// SAFETY: Tracking is not started with a failure handler, so nothing is called while the
+		// allocator is locked.
+		unsafe { ALLOC.start_tracking(None, None) };
+		let mut pipe_read =
+			DeadlineReader::new(pipe_read, Instant::now() + Duration::from_secs(60), None);
+		let received = recv_job_response(&mut pipe_read, &dest, ArtifactFormat::Raw);
+		let peak_alloc = ALLOC.end_tracking();
+		job.join().unwrap();
+
+		let Ok(ReceivedJobResponse { result: Ok(_), artifact: Some(artifact) }) = received else {
+			panic!("the job response was not received")
+		};
+		assert!(matches!(artifact.artifact, Ok(None)));
+		assert_eq!(artifact.len, artifact_len);
+		assert_eq!(fs::metadata(&dest).unwrap().len(), artifact_len);
+		// Other tests may allocate concurrently, so only check that the artifact was not buffered.
+		assert!(peak_alloc < (PAYLOAD_LEN / 4) as isize, "peak allocation: {}", peak_alloc);
+	}
+
+	#[test]
+	fn streamed_artifacts_are_delivered_incrementally() {
+		const PAYLOAD_CHUNKS: usize = 8;
+
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		let code_hash = ValidationCodeHash::from([1; 32]);
+		let payload: Vec<u8> =
+			(0..PAYLOAD_CHUNKS * ARTIFACT_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+		let (first_half, second_half) = payload.split_at(payload.len() / 2);
+		let (first_half, second_half) = (first_half.to_vec(), second_half.to_vec());
+
+		let (pipe_read_fd, pipe_write_fd) = pipe2_cloexec().unwrap();
+		// SAFETY: both are open and owned file descriptors at this point.
+		let (pipe_read, mut pipe_write) =
+			unsafe { (PipeFd::from_raw_fd(pipe_read_fd), PipeFd::from_raw_fd(pipe_write_fd)) };
+		let (resume_tx, resume_rx) = channel::<()>();
+		let job = std::thread::spawn(move || {
+			let response: JobResult = Ok(JobResponse { artifact_streamed: true, ..job_response() });
+			framed_send_blocking(&mut pipe_write, &response.encode()).unwrap();
+			let mut writer = ArtifactStreamWriter::new(&mut pipe_write);
+			writer.write_all(&first_half).unwrap();
+			// The rest is only produced once the first half reached the disk.
+			resume_rx.recv().unwrap();
+			writer.write_all(&second_half).unwrap();
+			writer.finish(code_hash, ArtifactFlags::default()).unwrap();
+		});
+		let worker = std::thread::spawn({
+			let dest = dest.clone();
+			move || {
+				let mut pipe_read =
+					DeadlineReader::new(pipe_read, Instant::now() + Duration::from_secs(60), None);
+				recv_job_response(&mut pipe_read, &dest, ArtifactFormat::Raw)
+			}
+		});
+
+		let first_half_len = (ARTIFACT_HEADER_LEN + payload.len() / 2) as u64;
+		let deadline = Instant::now() + Duration::from_secs(60);
+		while fs::metadata(&dest).map_or(0, |metadata| metadata.len()) < first_half_len {
+			assert!(Instant::now() < deadline, "the first half was not written");
+			std::thread::sleep(Duration::from_millis(10));
+		}
+		resume_tx.send(()).unwrap();
+		let received = worker.join().unwrap();
+		job.join().unwrap();
+
+		let Ok(ReceivedJobResponse { result: Ok(_), artifact: Some(artifact) }) = received else {
+			panic!("the job response was not received")
+		};
+		let expected = CompiledArtifact::new(payload, code_hash);
+		assert!(artifact.artifact.is_ok());
+		assert_eq!(artifact.len, expected.as_ref().len() as u64);
+		assert_eq!(artifact.checksum, blake3::hash(expected.as_ref()).to_hex().to_string());
+		assert_eq!(fs::read(&dest).unwrap(), expected.as_ref());
+	}
+
+	#[test]
+	fn artifacts_are_post_processed_before_being_handed_over() {
+		struct AppendTrailer;
+
+		impl ArtifactPostProcessor for AppendTrailer {
+			fn process(&self, path: &Path) -> io::Result<()> {
+				fs::OpenOptions::new().append(true).open(path)?.write_all(b"trailer")
+			}
+		}
+
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		let cached = CachedArtifact {
+			artifact: CompiledArtifact::new(
+				b"some compiled artifact".to_vec(),
+				ValidationCodeHash::from([0; 32]),
+			),
+			observed_wasm_code_len: 1,
+			code_size: 1,
+		};
+
+		let delivery = ArtifactDelivery { post_processor: &AppendTrailer, ..Default::default() };
+		write_cached_artifact(&cached, &dest, None, delivery).unwrap();
+
+		let written = fs::read(&dest).unwrap();
+		let (artifact, trailer) = written.split_at(cached.artifact.as_ref().len());
+		assert_eq!(artifact, cached.artifact.as_ref());
+		assert_eq!(trailer, b"trailer");
+	}
+
+	#[test]
+	fn scheduling_delay_counts_against_the_cpu_time_budget() {
+		let pvf = PvfPrepData::from_code(
+			vec![],
+			ExecutorParams::default(),
+			Duration::from_secs(10),
+			PrepareJobKind::Compilation,
+		);
+		let delay = Duration::from_secs(4);
+
+		let deadline = JobDeadline::new(&pvf, Instant::now() - delay);
+
+		assert!(deadline.cpu_time_budget() <= Duration::from_secs(6));
+		assert!(deadline.cpu_time_budget() > Duration::ZERO);
+	}
+
+	#[test]
+	fn job_past_its_deadline_has_no_cpu_time_left() {
+		let pvf = PvfPrepData::from_code(
+			vec![],
+			ExecutorParams::default(),
+			Duration::from_secs(1),
+			PrepareJobKind::Compilation,
+		);
+
+		let deadline = JobDeadline::new(&pvf, Instant::now() - Duration::from_secs(2));
+
+		assert_eq!(deadline.cpu_time_budget(), Duration::ZERO);
+		assert!(deadline.wall_clock > deadline.cpu_time);
+	}
+
+	#[test]
+	fn job_deadline_applies_the_cpu_timeout_the_host_enforces() {
+		use polkadot_primitives::{ExecutorParam, PvfPrepKind};
+
+		// The executor params disagree with the timeout of the request, so that the test fails if
+		// the deadline is derived from the latter.
+		let executor_params = ExecutorParams::from(
+			&[ExecutorParam::PvfPrepTimeout(PvfPrepKind::Prepare, 50_000)][..],
+		);
+		let pvf = PvfPrepData::from_code(
+			vec![],
+			executor_params,
+			Duration::from_secs(10),
+			PrepareJobKind::Compilation,
+		);
+		let job_start = Instant::now();
+
+		let deadline = JobDeadline::new(&pvf, job_start);
+
+		assert_eq!(pvf.cpu_timeout(), Duration::from_secs(50));
+		assert_eq!(deadline.cpu_time - job_start, pvf.cpu_timeout());
+		assert!(deadline.wall_clock - job_start >= pvf.cpu_timeout());
+	}
+
+	#[test]
+	fn artifacts_go_to_the_worker_dir_by_default() {
+		let worker_dir = Path::new("/worker");
+
+		assert_eq!(
+			WorkerOptions::default().tmp_artifact_dest(worker_dir),
+			worker_dir::prepare_tmp_artifact(worker_dir)
+		);
+	}
+
+	#[test]
+	fn artifacts_are_written_to_the_configured_artifact_dir() {
+		let worker_dir = tempfile::tempdir().unwrap();
+		let artifact_dir = tempfile::tempdir().unwrap();
+		let options = WorkerOptions {
+			artifact_dir: Some(artifact_dir.path().to_owned()),
+			..Default::default()
+		};
+		let cached = CachedArtifact {
+			artifact: CompiledArtifact::new(
+				b"some compiled artifact".to_vec(),
+				ValidationCodeHash::from([0; 32]),
+			),
+			observed_wasm_code_len: 1,
+			code_size: 1,
+		};
+
+		let dest = options.tmp_artifact_dest(worker_dir.path());
+		write_cached_artifact(&cached, &dest, None, ArtifactDelivery::new(&options)).unwrap();
+
+		assert_eq!(
+			dest,
+			worker_dir::prepare_shared_tmp_artifact(artifact_dir.path(), worker_dir.path())
+		);
+		assert_eq!(fs::read(&dest).unwrap(), cached.artifact.as_ref());
+		assert_eq!(fs::read_dir(worker_dir.path()).unwrap().count(), 0);
+	}
+
+	#[test]
+	fn workers_sharing_an_artifact_dir_write_to_different_files() {
+		let artifact_dir = Path::new("/artifacts");
+		let options =
+			WorkerOptions { artifact_dir: Some(artifact_dir.to_owned()), ..Default::default() };
+		let first_worker_dir = Path::new("/workers/worker-dir-prepare-1");
+		let second_worker_dir = Path::new("/workers/worker-dir-prepare-2");
+
+		let first = options.tmp_artifact_dest(first_worker_dir);
+		let second = options.tmp_artifact_dest(second_worker_dir);
+
+		assert_ne!(first, second);
+		assert_eq!(first.parent(), Some(artifact_dir));
+		assert_eq!(second.parent(), Some(artifact_dir));
+		assert_ne!(
+			options.tmp_batch_artifact_dest(first_worker_dir, 0),
+			options.tmp_batch_artifact_dest(second_worker_dir, 0)
+		);
+	}
+
+	#[test]
+	fn oversized_artifacts_are_rejected_before_being_written() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("artifact");
+
+		let result =
+			recv_artifact(&mut io::empty(), 0, MAX_COMPILED_ARTIFACT_SIZE as u64 + 1, &path);
+
+		assert!(
+			matches!(result, Err(RecvJobError::Pipe(err)) if err.kind() == io::ErrorKind::InvalidData)
+		);
+		assert!(!path.exists());
+	}
+
+	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+	#[test]
+	fn shorter_memory_tracker_interval_takes_more_samples() {
+		let track = |poll_interval| {
+			let condvar = thread::get_condvar();
+			let condvar_memory = Arc::clone(&condvar);
+			let tracker = std::thread::spawn(move || {
+				memory_tracker_loop(condvar_memory, poll_interval, None, None)
+			});
+			let workload = spawn_worker_thread(
+				"workload",
+				|| std::thread::sleep(Duration::from_millis(500)),
+				condvar,
+				WaitOutcome::Finished,
+			)
+			.unwrap();
+			workload.join().unwrap();
+			tracker.join().unwrap().unwrap()
+		};
+
+		let coarse = track(Duration::from_millis(200));
+		let fine = track(Duration::from_millis(10));
+
+		assert_eq!(coarse.poll_interval, Duration::from_millis(200));
+		assert_eq!(fine.poll_interval, Duration::from_millis(10));
+		assert!(fine.samples > coarse.samples, "{} <= {}", fine.samples, coarse.samples);
+	}
+
+	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+	#[test]
+	fn panicking_memory_tracker_only_costs_its_stats() {
+		let code = wat::parse_str(
+			r#"(module
+				(memory (export "memory") 1)
+				(func (export "validate_block") (param i32 i32) (result i64)
+					i64.const 0)
+			)"#,
+		)
+		.unwrap();
+		let pvf = PvfPrepData::from_code(
+			code,
+			ExecutorParams::default(),
+			Duration::from_secs(30),
+			PrepareJobKind::Compilation,
+		);
+
+		let condvar = thread::get_condvar();
+		let tracker = spawn_memory_tracker(|| panic!("injected memory tracker panic"));
+		let prepare = spawn_worker_thread(
+			"prepare worker",
+			move || prepare_artifact(pvf, None),
+			condvar,
+			WaitOutcome::Finished,
+		)
+		.unwrap();
+
+		let outcome = prepare.join().unwrap();
+		assert!(matches!(outcome, Ok(PrepareOutcome { compiled_artifact: Some(_), .. })));
+		assert!(tracker.is_some());
+		assert!(get_memory_tracker_loop_stats(tracker, process::id()).is_none());
+	}
+
+	/// A PVF small enough to be prepared in tests, whose code differs for each `result`.
+	fn minimal_pvf(result: i64) -> PvfPrepData {
+		let code = wat::parse_str(format!(
+			r#"(module
+				(memory (export "memory") 1)
+				(global (export "__heap_base") i32 (i32.const 0))
+				(func (export "validate_block") (param i32 i32) (result i64)
+					i64.const {result})
+			)"#
+		))
+		.unwrap();
+		PvfPrepData::from_code(
+			code,
+			ExecutorParams::default(),
+			Duration::from_secs(30),
+			PrepareJobKind::Compilation,
+		)
+	}
+
+	#[test]
+	fn artifact_of_the_pvf_verifies() {
+		let pvf = minimal_pvf(0);
+		let (artifact, _stats) = prepare_blocking(pvf.clone()).unwrap();
+
+		assert!(verify_artifact(artifact.as_ref(), &pvf).is_ok());
+	}
+
+	#[test]
+	fn artifact_of_other_code_fails_to_verify() {
+		let pvf = minimal_pvf(0);
+		let (artifact, _stats) = prepare_blocking(pvf.clone()).unwrap();
+		let other_pvf = minimal_pvf(1);
+
+		assert!(matches!(
+			verify_artifact(artifact.as_ref(), &other_pvf),
+			Err(PrepareError::CodeHashMismatch { found, expected })
+				if found == pvf.code_hash() && expected == other_pvf.code_hash()
+		));
+	}
+
+	#[test]
+	fn corrupt_artifact_fails_to_verify() {
+		let pvf = minimal_pvf(0);
+		let (artifact, _stats) = prepare_blocking(pvf.clone()).unwrap();
+		let mut bytes = artifact.as_ref().to_vec();
+		*bytes.last_mut().unwrap() ^= 0xff;
+
+		let err = verify_artifact(&bytes, &pvf).unwrap_err();
+		assert!(matches!(&err, PrepareError::IoErr(message) if message.contains("checksum")));
+		assert!(!err.is_deterministic());
+	}
+
+	#[cfg(not(target_os = "macos"))]
+	#[test]
+	fn observer_sees_the_milestones_of_a_job() {
+		use std::cell::RefCell;
+
+		#[derive(Default)]
+		struct RecordingObserver(RefCell<Vec<(&'static str, ValidationCodeHash, Instant)>>);
+
+		impl PrepareObserver for RecordingObserver {
+			fn request_received(&self, code_hash: ValidationCodeHash, at: Instant) {
+				self.0.borrow_mut().push(("request_received", code_hash, at));
+			}
+
+			fn job_spawned(&self, code_hash: ValidationCodeHash, job_pid: i32, at: Instant) {
+				assert_ne!(job_pid, process::id() as i32);
+				self.0.borrow_mut().push(("job_spawned", code_hash, at));
+			}
+
+			fn compile_started(&self, code_hash: ValidationCodeHash, at: Instant) {
+				self.0.borrow_mut().push(("compile_started", code_hash, at));
+			}
+
+			fn artifact_written(&self, code_hash: ValidationCodeHash, path: &Path, at: Instant) {
+				assert!(path.exists());
+				self.0.borrow_mut().push(("artifact_written", code_hash, at));
+			}
+		}
+
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		let (stream, _host_stream) = UnixStream::pair().unwrap();
+		let worker_info = WorkerInfo {
+			pid: process::id(),
+			kind: WorkerKind::Prepare,
+			version: None,
+			worker_dir_path: dir.path().to_owned(),
+		};
+		let pvf = minimal_pvf(0);
+		let mut artifact_cache = ArtifactCache::default();
+		let observer = RecordingObserver::default();
+		let env = JobEnvironment {
+			worker_info: &worker_info,
+			security_status: &SecurityStatus::default(),
+			delivery: ArtifactDelivery::default(),
+			observer: &observer,
+			memory_tracker_interval: DEFAULT_MEMORY_TRACKER_INTERVAL,
+			require_secure: false,
+			cpu_affinity: None,
+			job_priority: JobPriority::default(),
+			job_slots: &JobSlots::default(),
+		};
+		let mut prepare = || {
+			let job_start = Instant::now();
+			let (result, _reached_stage) =
+				prepare_job(&pvf, job_start, &dest, None, &mut artifact_cache, None, &stream, &env)
+					.unwrap();
+			assert!(result.is_ok());
+			let events = observer.0.take();
+			assert!(events.iter().all(|(_, code_hash, _)| *code_hash == pvf.code_hash()));
+			assert!(events.iter().all(|(_, _, at)| *at >= job_start));
+			assert!(events.iter().all(|(_, _, at)| *at <= events.last().unwrap().2));
+			events.into_iter().map(|(event, _, _)| event).collect::<Vec<_>>()
+		};
+
+		assert_eq!(
+			prepare(),
+			["request_received", "job_spawned", "compile_started", "artifact_written"]
+		);
+		// The second time, the artifact is served from the cache.
+		assert_eq!(prepare(), ["request_received", "artifact_written"]);
+	}
+
+	#[test]
+	fn restarted_worker_reuses_persisted_artifacts() {
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		let (stream, _host_stream) = UnixStream::pair().unwrap();
+		let worker_info = WorkerInfo {
+			pid: process::id(),
+			kind: WorkerKind::Prepare,
+			version: None,
+			worker_dir_path: dir.path().to_owned(),
+		};
+		let pvf = minimal_pvf(0);
+		let env = JobEnvironment {
+			worker_info: &worker_info,
+			security_status: &SecurityStatus::default(),
+			delivery: ArtifactDelivery::default(),
+			observer: &NoopPrepareObserver,
+			memory_tracker_interval: DEFAULT_MEMORY_TRACKER_INTERVAL,
+			require_secure: false,
+			cpu_affinity: None,
+			job_priority: JobPriority::default(),
+			job_slots: &JobSlots::default(),
+		};
+		// Every run of the worker starts with an empty cache in memory.
+		let run_worker = || {
+			let mut persistent_cache = PersistentCache::load(dir.path());
+			let (result, _reached_stage) = prepare_job(
+				&pvf,
+				Instant::now(),
+				&dest,
+				None,
+				&mut ArtifactCache::default(),
+				Some(&mut persistent_cache),
+				&stream,
+				&env,
+			)
+			.unwrap();
+			(result.unwrap(), fs::read(&dest).unwrap())
+		};
+
+		let (compiled, artifact) = run_worker();
+		assert!(!compiled.stats.cache_hit);
+
+		let (reused, reused_artifact) = run_worker();
+		assert!(reused.stats.cache_hit);
+		assert_eq!(reused.checksum, compiled.checksum);
+		assert_eq!(reused_artifact, artifact);
+	}
+
+	#[test]
+	fn identical_artifacts_pass_the_determinism_check() {
+		assert!(check_artifacts_match(b"artifact", b"artifact").is_ok());
+	}
+
+	#[test]
+	fn differing_artifacts_fail_the_determinism_check() {
+		assert!(matches!(
+			check_artifacts_match(b"artifact", b"artefact"),
+			Err(PrepareError::NonDeterministicCompilation { len: 8, other_len: 8, offset: 3 })
+		));
+		// An artifact that is a prefix of the other differs right after its end.
+		assert!(matches!(
+			check_artifacts_match(b"art", b"artifact"),
+			Err(PrepareError::NonDeterministicCompilation { len: 3, other_len: 8, offset: 3 })
+		));
+	}
+
+	#[test]
+	fn artifacts_up_to_the_size_limit_pass() {
+		assert!(check_artifact_size(b"artifact", None).is_ok());
+		assert!(check_artifact_size(b"artifact", Some(9)).is_ok());
+		assert!(check_artifact_size(b"artifact", Some(8)).is_ok());
+	}
+
+	#[test]
+	fn artifacts_over_the_size_limit_fail() {
+		assert!(matches!(
+			check_artifact_size(b"artifact", Some(7)),
+			Err(PrepareError::ArtifactTooLarge { size: 8, limit: 7 })
+		));
+	}
+
+	#[test]
+	fn insecure_environment_is_refused_if_required() {
+		let security_status = SecurityStatus { can_enable_landlock: true, ..Default::default() };
+
+		assert!(matches!(
+			check_secure_environment(&security_status, true),
+			Err(PrepareError::InsecureEnvironment(missing)) if missing == "seccomp"
+		));
+		assert!(matches!(
+			check_secure_environment(&SecurityStatus::default(), true),
+			Err(PrepareError::InsecureEnvironment(missing)) if missing == "landlock and seccomp"
+		));
+	}
+
+	#[test]
+	fn insecure_environment_is_tolerated_unless_required() {
+		assert!(check_secure_environment(&SecurityStatus::default(), false).is_ok());
+
+		let security_status = SecurityStatus {
+			can_enable_landlock: true,
+			can_enable_seccomp: true,
+			..Default::default()
+		};
+		assert!(check_secure_environment(&security_status, true).is_ok());
+	}
+
+	#[test]
+	fn compressed_code_is_decompressed() {
+		let code = b"\0asm some code".to_vec();
+		let compressed = sp_maybe_compressed_blob::compress(&code, code.len()).unwrap();
+
+		let decompressed =
+			decompress_code(&compressed, CodeCompression::Zstd { max_size: code.len() as u64 })
+				.unwrap();
+		assert_eq!(decompressed.as_ref(), &code[..]);
+	}
+
+	#[test]
+	fn compressed_code_over_the_limit_is_rejected() {
+		let code = vec![1u8; 1024];
+		let compressed = sp_maybe_compressed_blob::compress(&code, code.len()).unwrap();
+
+		assert!(matches!(
+			decompress_code(&compressed, CodeCompression::Zstd { max_size: 1023 }),
+			Err(PrepareError::DecompressionLimit { limit: 1023 })
+		));
+	}
+
+	#[test]
+	fn corrupt_compressed_code_is_rejected() {
+		let code = vec![1u8; 1024];
+		let mut compressed = sp_maybe_compressed_blob::compress(&code, code.len()).unwrap();
+		// Break the magic number of the zstd frame, which follows the 8 byte prefix.
+		compressed[8] ^= 0xff;
+
+		assert!(matches!(
+			decompress_code(&compressed, CodeCompression::Zstd { max_size: 1024 }),
+			Err(PrepareError::CouldNotDecompressCodeBlob(_))
+		));
+		// Uncompressed code is only accepted if the compression is detected.
+		assert!(matches!(
+			decompress_code(&code, CodeCompression::Zstd { max_size: 1024 }),
+			Err(PrepareError::CouldNotDecompressCodeBlob(_))
+		));
+		assert!(decompress_code(&code, CodeCompression::Detect).is_ok());
+	}
+
+	#[test]
+	fn saturated_job_slots_overload_the_worker() {
+		let job_slots = JobSlots::new(1);
+		let job_slot = acquire_job_slot(&job_slots).unwrap();
+
+		assert!(matches!(
+			acquire_job_slot(&job_slots),
+			Err(PrepareError::Overloaded { max_jobs: 1 })
+		));
+
+		drop(job_slot);
+		assert!(acquire_job_slot(&job_slots).is_ok());
+	}
+
+	#[test]
+	fn huge_stage_errors_are_truncated() {
+		use sc_executor_common::error::WasmError;
+
+		let err =
+			stage_error(PrepareStageError::Compilation(WasmError::Other("x".repeat(1 << 20))));
+
+		assert!(matches!(
+			err,
+			PrepareError::Compilation(ref message) if message.len() == MAX_PREPARE_ERROR_MESSAGE_LEN
+		));
+		assert!(err.to_string().len() < 2 * MAX_PREPARE_ERROR_MESSAGE_LEN);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn cpu_affinity_pins_to_a_single_core() {
+		use nix::sched::{sched_getaffinity, sched_getcpu, CpuSet};
+
+		// Pin a fresh thread, so that the affinity of the test process is left alone.
+		std::thread::spawn(|| {
+			let allowed = sched_getaffinity(Pid::from_raw(0)).unwrap();
+			let core = (0..u64::BITS as usize).find(|core| allowed.is_set(*core).unwrap()).unwrap();
+
+			set_cpu_affinity(1 << core).unwrap();
+
+			let pinned = sched_getaffinity(Pid::from_raw(0)).unwrap();
+			let pinned_cores: Vec<_> =
+				(0..CpuSet::count()).filter(|core| pinned.is_set(*core).unwrap()).collect();
+			assert_eq!(pinned_cores, vec![core]);
+			assert_eq!(sched_getcpu().unwrap(), core);
+		})
+		.join()
+		.unwrap();
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn job_priority_applies_to_the_calling_thread() {
+		// As above, so that the priority of the test process is left alone. The nice value is only
+		// raised, which needs no privileges.
+		std::thread::spawn(|| {
+			Errno::clear();
+			// SAFETY: `getpriority` only takes integer arguments.
+			let nice_before = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+			assert_eq!(Errno::last_raw(), 0);
+			let nice = (nice_before + 5).min(19);
+
+			set_job_priority(JobPriority { nice: Some(nice), io_priority: Some(IoPriority::Idle) })
+				.unwrap();
+
+			Errno::clear();
+			// SAFETY: see above.
+			assert_eq!(unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }, nice);
+			assert_eq!(Errno::last_raw(), 0);
+			// SAFETY: `ioprio_get` only takes integer arguments.
+			let io_priority = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+			assert_eq!(io_priority, libc::c_long::from(ioprio_value(IoPriority::Idle)));
+		})
+		.join()
+		.unwrap();
+	}
+
+	#[test]
+	fn truncated_job_response_is_reported() {
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		let mut payload = Vec::new();
+		framed_send_blocking(&mut payload, &JobResult::Err(PrepareError::OutOfMemory).encode())
+			.unwrap();
+		let expected = payload.len() as u64;
+
+		// Simulate the job dying in the middle of writing the response.
+		let result =
+			recv_job_response(&mut &payload[..payload.len() - 1], &dest, ArtifactFormat::Raw);
+
+		assert!(matches!(
+			result,
+			Err(RecvJobError::Truncated { expected: e, received: r }) if e == expected && r == expected - 1
+		));
+	}
+
+	#[test]
+	fn truncated_artifact_is_reported() {
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		let response: JobResult = Ok(JobResponse { artifact_len: Some(100), ..job_response() });
+		let mut payload = Vec::new();
+		framed_send_blocking(&mut payload, &response.encode()).unwrap();
+		let response_len = payload.len() as u64;
+		payload.extend([0u8; 40]);
+
+		let result = recv_job_response(&mut &payload[..], &dest, ArtifactFormat::Raw);
+
+		assert!(matches!(
+			result,
+			Err(RecvJobError::Truncated { expected, received })
+				if expected == response_len + 100 && received == response_len + 40
+		));
+	}
+
+	#[test]
+	fn artifacts_round_trip_in_both_formats() {
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+		let artifact = CompiledArtifact::new(
+			vec![7; 3 * ARTIFACT_CHUNK_SIZE / 2],
+			ValidationCodeHash::from([1; 32]),
+		);
+		let artifact_len = artifact.as_ref().len() as u64;
+
+		for format in [ArtifactFormat::Raw, ArtifactFormat::Scale] {
+			let response = JobResponse { artifact_len: Some(artifact_len), ..job_response() };
+			let mut payload = Vec::new();
+			write_response_with_artifact(&mut payload, response, artifact.as_ref(), format)
+				.unwrap();
+
+			let Ok(ReceivedJobResponse { result: Ok(_), artifact: Some(received) }) =
+				recv_job_response(&mut &payload[..], &dest, format)
+			else {
+				panic!("the job response was not received in the {:?} format", format)
+			};
+			assert_eq!(received.len, artifact_len);
+			assert_eq!(received.checksum, blake3::hash(artifact.as_ref()).to_hex().to_string());
+			assert_eq!(fs::read(&dest).unwrap(), artifact.as_ref());
+			assert!(
+				matches!(received.artifact, Ok(Some(cached)) if cached.as_ref() == artifact.as_ref())
+			);
+		}
+	}
+
+	#[test]
+	fn missing_job_response_is_not_a_truncated_one() {
+		let dir = tempfile::tempdir().unwrap();
+		let dest = dir.path().join("tmp-artifact");
+
+		let result = recv_job_response(&mut io::empty(), &dest, ArtifactFormat::Raw);
+
+		assert!(
+			matches!(result, Err(RecvJobError::Pipe(err)) if err.kind() == io::ErrorKind::UnexpectedEof)
+		);
+	}
+
+	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+	#[test]
+	fn smaps_rollup_is_parsed_into_bytes() {
+		use crate::memory_stats::memory_tracker::parse_smaps_rollup;
+
+		let contents = "\
+			55d0c0a00000-7ffd2b9f5000 ---p 00000000 00:00 0                          [rollup]\n\
+			Rss:               12345 kB\n\
+			Pss:                6789 kB\n\
+			Pss_Anon:           4000 kB\n\
+			Shared_Clean:       1000 kB\n\
+		";
+
+		assert_eq!(parse_smaps_rollup(contents), Some((12345 * 1024, 6789 * 1024)));
+		assert_eq!(parse_smaps_rollup("Rss: 1 kB\n"), None);
+	}
+
+	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
+	#[test]
+	fn vm_swap_is_parsed_into_bytes() {
+		use crate::memory_stats::memory_tracker::parse_vm_swap;
+
+		let contents = "\
+			VmRSS:\t   12345 kB\n\
+			VmSwap:\t     678 kB\n\
+			Threads:\t4\n\
+		";
+
+		assert_eq!(parse_vm_swap(contents), Some(678 * 1024));
+		assert_eq!(parse_vm_swap("VmRSS:	1 kB\n"), None);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn memory_tracker_samples_the_swap_usage() {
+		let condvar = thread::get_condvar();
+		let condvar_memory = Arc::clone(&condvar);
+		let proc_status = ProcStatus::open().unwrap();
+		let tracker = std::thread::spawn(move || {
+			memory_tracker_loop(condvar_memory, Duration::from_millis(10), None, Some(proc_status))
+		});
+		let workload = spawn_worker_thread(
+			"workload",
+			|| std::thread::sleep(Duration::from_millis(100)),
+			condvar,
+			WaitOutcome::Finished,
+		)
+		.unwrap();
+		workload.join().unwrap();
+
+		// Whether anything was swapped out depends on the machine, but the usage is always sampled.
+		let stats = tracker.join().unwrap().unwrap();
+		assert!(stats.peak_swap.is_some());
+	}
+
+	#[test]
+	fn verify_determinism_job_compiles_the_prevalidated_code_twice() {
+		let code = minimal_pvf(0).maybe_compressed_code().to_vec();
+		let pvf = |prep_kind| {
+			PvfPrepData::from_code(
+				code.clone(),
+				ExecutorParams::default(),
+				Duration::from_secs(30),
+				prep_kind,
+			)
+		};
+
+		let compiled = prepare_artifact(pvf(PrepareJobKind::Compilation), None).unwrap();
+		let verified = prepare_artifact(pvf(PrepareJobKind::VerifyDeterminism), None).unwrap();
+		assert!(verified.compiled_artifact.is_some());
+		assert_eq!(verified.compiled_artifact, compiled.compiled_artifact);
+		assert_eq!(verified.module_shape, compiled.module_shape);
+	}
+
+	#[test]
+	fn version_mismatch_is_reported_to_the_host() {
+		let worker_dir = tempfile::tempdir().unwrap();
+		let negotiate = |node_version, host_version: u32| {
+			let (mut host, mut worker) = UnixStream::pair().unwrap();
+			framed_send_blocking(&mut host, &host_version.encode()).unwrap();
+			let result = negotiate_protocol_version(
+				&mut worker,
+				worker_dir.path(),
+				node_version,
+				Some("1.0.1"),
+			)
+			.unwrap();
+			let response = framed_recv_blocking(&mut host).unwrap();
+			assert_eq!(Result::<(), PrepareError>::decode(&mut &response[..]).unwrap(), result);
+			result
+		};
+
+		let mismatch =
+			Err(PrepareError::VersionMismatch { node: "1.0.0".into(), worker: "1.0.1".into() });
+		assert_eq!(negotiate(Some("1.0.0"), PROTOCOL_VERSION), mismatch);
+		// The version mismatch is what needs fixing, even if the protocol differs too.
+		assert_eq!(negotiate(Some("1.0.0"), PROTOCOL_VERSION + 1), mismatch);
+
+		assert_eq!(negotiate(Some("1.0.1"), PROTOCOL_VERSION), Ok(()));
+		assert_eq!(negotiate(None, PROTOCOL_VERSION), Ok(()));
+	}
+
+	/// The minimal PVF with params that only prevalidation rejects it for, so that whether the code
+	/// was prevalidated shows in the result.
+	fn minimal_pvf_over_the_memory_pages_limit() -> PvfPrepData {
+		use polkadot_primitives::ExecutorParam;
+
+		PvfPrepData::from_code(
+			minimal_pvf(0).maybe_compressed_code().to_vec(),
+			ExecutorParams::from(&[ExecutorParam::MaxDeclaredMemoryPages(0)][..]),
+			Duration::from_secs(30),
+			PrepareJobKind::Compilation,
+		)
+	}
+
+	#[test]
+	fn prevalidation_is_skipped_for_code_vouched_for() {
+		let pvf = minimal_pvf_over_the_memory_pages_limit();
+		assert!(matches!(
+			prepare_artifact(pvf.clone(), None),
+			Err(PrepareError::MemoryPagesExceeded { declared: 1, limit: 0 })
+		));
+
+		let outcome = prepare_artifact(pvf.with_prevalidated_code(), None).unwrap();
+		assert!(outcome.compiled_artifact.is_some());
+	}
+
+	#[test]
+	fn code_vouched_for_is_still_checked_for_its_size() {
+		let pvf = PvfPrepData::from_code(
+			vec![0; MAX_PREVALIDATION_WASM_SIZE + 1],
+			ExecutorParams::default(),
+			Duration::from_secs(30),
+			PrepareJobKind::Compilation,
+		);
+		assert!(matches!(
+			prepare_artifact(pvf.with_prevalidated_code(), None),
+			Err(PrepareError::PrevalidationWasmTooLarge { .. })
+		));
+	}
+
+	#[test]
+	fn untrusted_hosts_cannot_vouch_for_code() {
+		let pvf = minimal_pvf_over_the_memory_pages_limit().with_prevalidated_code();
+		let pvfs_of = |request| match request {
+			PrepareWorkerRequest::Single(pvf) |
+			PrepareWorkerRequest::SingleToFd(pvf) |
+			PrepareWorkerRequest::Probe(pvf) => vec![pvf],
+			PrepareWorkerRequest::Batch(pvfs) => pvfs,
+			request => panic!("unexpected request: {:?}", request),
+		};
+
+		for request in [
+			PrepareWorkerRequest::Single(pvf.clone()),
+			PrepareWorkerRequest::SingleToFd(pvf.clone()),
+			PrepareWorkerRequest::Probe(pvf.clone()),
+			PrepareWorkerRequest::Batch(vec![pvf.clone(), pvf.clone()]),
+		] {
+			for pvf in pvfs_of(enforce_prevalidation(request)) {
+				assert!(!pvf.code_prevalidated());
+				assert!(matches!(
+					prepare_artifact(pvf, None),
+					Err(PrepareError::MemoryPagesExceeded { .. })
+				));
+			}
+		}
+	}
+
+	#[test]
+	fn core_dumps_are_denied_unless_allowed() {
+		let pvf = minimal_pvf(0).with_core_dumps();
+
+		let request = deny_core_dumps(PrepareWorkerRequest::Batch(vec![pvf.clone(), pvf]));
+
+		let PrepareWorkerRequest::Batch(pvfs) = request else { panic!("the request changed kind") };
+		assert!(pvfs.iter().all(|pvf| !pvf.core_dumps()));
+	}
+
+	#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+	#[test]
+	fn panics_under_seccomp_are_reported_without_backtrace() {
+		use polkadot_node_core_pvf_common::worker::security::seccomp;
+
+		if seccomp::check_can_fully_enable().is_err() {
+			return
+		}
+
+		assert!(!can_capture_panic_backtraces(AppliedSecurity::SECCOMP));
+		assert!(can_capture_panic_backtraces(AppliedSecurity::LANDLOCK));
+
+		// Seccomp can't be lifted again, so the job runs in a child, to not kill the test process.
+		let job_pid = match fork_job(|| unsafe { nix::unistd::fork() }).unwrap() {
+			ForkResult::Child => {
+				// The default panic hook would symbolize a backtrace with this set.
+				std::env::set_var("RUST_BACKTRACE", "1");
+				// Like a job, which enables seccomp after forking.
+				panic_backtrace::disable_for_this_process();
+				seccomp::enable_for_prepare_job().unwrap();
+				let handle = std::thread::spawn(|| panic!("injected prepare panic"));
+				let thread = handle.thread().id();
+				let description =
+					panic_backtrace::describe_panic(thread, handle.join().unwrap_err());
+				let code = if description == "injected prepare panic" { 0 } else { 1 };
+				unsafe { libc::_exit(code) }
+			},
+			ForkResult::Parent { child } => child,
+		};
+
+		assert_eq!(
+			nix::sys::wait::waitpid(job_pid, None).unwrap(),
+			nix::sys::wait::WaitStatus::Exited(job_pid, 0)
+		);
+	}
+
+	#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+	#[test]
+	fn job_making_a_forbidden_syscall_reports_a_seccomp_violation() {
+		use polkadot_node_core_pvf_common::worker::security::seccomp;
+
+		if seccomp::check_can_fully_enable().is_err() {
+			return
+		}
+
+		let job_pid = match fork_job(|| unsafe { nix::unistd::fork() }).unwrap() {
+			ForkResult::Child => {
+				// Like a job, which enables seccomp after forking.
+				seccomp::enable_for_prepare_job().unwrap();
+				// Not on the allowlist of prepare jobs.
+				unsafe { libc::syscall(libc::SYS_getppid) };
+				unsafe { libc::_exit(0) }
+			},
+			ForkResult::Parent { child } => child,
+		};
+		let status = nix::sys::wait::waitpid(job_pid, None).unwrap();
+
+		assert!(matches!(
+			classify_wait_status(
+				status,
+				Duration::ZERO,
+				Duration::from_secs(1),
+				job_pid,
+				ValidationCodeHash::from([0; 32])
+			),
+			Err(PrepareError::SeccompViolation { signal, job_pid: pid, .. })
+				if signal == libc::SIGSYS && pid == job_pid.as_raw()
+		));
+	}
+
+	#[test]
+	fn artifact_compression_failures_are_not_deterministic() {
+		let err = artifact_compression_error(artifact::Error::Compression("out of memory".into()));
+
+		assert!(matches!(&err, PrepareError::IoErr(msg) if msg.contains("out of memory")));
+		assert!(!err.is_deterministic());
+	}
+
+	#[test]
+	fn job_namespaces_are_only_unshared_with_a_user_namespace() {
+		let security_status =
+			SecurityStatus { can_unshare_network_and_mount_namespaces: true, ..Default::default() };
+		assert!(!can_unshare_job_namespaces(&security_status));
+
+		let security_status =
+			SecurityStatus { can_unshare_user_namespace_and_change_root: true, ..security_status };
+		assert!(can_unshare_job_namespaces(&security_status));
+	}
 }
@@ -22,6 +22,14 @@
 //! - `resident` memory stat provided by `tikv-malloc-ctl`.
 //! - `allocated` memory stat also from `tikv-malloc-ctl`.
 //!
+//! On request, the memory tracker also samples `Rss` and `Pss` from `/proc/self/smaps_rollup`,
+//! which account for shared mappings that `ru_maxrss` may undercount. On Linux, it always samples
+//! `VmSwap` from `/proc/self/status`, as memory that was swapped out is not resident and so
+//! missing from all of the above.
+//!
+//! None of these depend on the architecture: they are collected the same way on e.g. `x86_64` and
+//! `aarch64` Linux, and `ru_maxrss` is reported in kilobytes on both.
+//!
 //! Currently we are only logging these for the purposes of gathering data. In the future, we may
 //! use these stats to reject PVFs during pre-checking. See
 //! <https://github.com/paritytech/polkadot/issues/6472#issuecomment-1381941762> for more
@@ -38,7 +46,14 @@ pub mod memory_tracker {
 		prepare::MemoryAllocationStats,
 		worker::{stringify_panic_payload, thread},
 	};
-	use std::{thread::JoinHandle, time::Duration};
+	use std::{
+		fs::File,
+		io,
+		os::unix::fs::FileExt,
+		panic::{self, AssertUnwindSafe},
+		thread::JoinHandle,
+		time::Duration,
+	};
 	use tikv_jemalloc_ctl::{epoch, stats, Error};
 
 	#[derive(Clone)]
@@ -64,8 +79,100 @@ pub mod memory_tracker {
 			// Convert to `u64`, as `usize` is not `Encode`able.
 			let allocated = self.allocated.read()? as u64;
 			let resident = self.resident.read()? as u64;
-			Ok(MemoryAllocationStats { allocated, resident })
+			Ok(MemoryAllocationStats { allocated, resident, ..Default::default() })
+		}
+	}
+
+	/// The path of the rolled up memory stats of the current process. Only available on Linux.
+	const SMAPS_ROLLUP_PATH: &str = "/proc/self/smaps_rollup";
+
+	/// An open `/proc/self/smaps_rollup`, which is re-read for every sample. The file is opened up
+	/// front, as landlock denies opening it once the job is sandboxed.
+	pub struct SmapsRollup {
+		file: File,
+		buf: String,
+	}
+
+	impl SmapsRollup {
+		/// Opens `/proc/self/smaps_rollup`. Note that `self` is the process calling this, so the
+		/// job has to open it itself.
+		pub fn open() -> io::Result<Self> {
+			Ok(Self { file: File::open(SMAPS_ROLLUP_PATH)?, buf: String::new() })
+		}
+
+		/// Reads the current `Rss` and `Pss` of the process, in bytes.
+		fn sample(&mut self) -> io::Result<(u64, u64)> {
+			read_from_start(&self.file, &mut self.buf)?;
+			parse_smaps_rollup(&self.buf).ok_or_else(|| {
+				io::Error::new(io::ErrorKind::InvalidData, "no Rss or Pss in smaps_rollup")
+			})
+		}
+	}
+
+	/// Extracts `Rss` and `Pss` from the contents of `smaps_rollup`, converted from kilobytes to
+	/// bytes.
+	pub fn parse_smaps_rollup(contents: &str) -> Option<(u64, u64)> {
+		let field = |name: &str| {
+			contents.lines().find_map(|line| {
+				let value = line.strip_prefix(name)?.strip_prefix(':')?;
+				let kb: u64 = value.trim().strip_suffix("kB")?.trim().parse().ok()?;
+				Some(kb * 1024)
+			})
+		};
+		Some((field("Rss")?, field("Pss")?))
+	}
+
+	/// The path of the status of the current process. Only available on Linux.
+	const PROC_STATUS_PATH: &str = "/proc/self/status";
+
+	/// An open `/proc/self/status`, which is re-read for every sample of the swap usage. Like
+	/// [`SmapsRollup`], it is opened up front.
+	pub struct ProcStatus {
+		file: File,
+		buf: String,
+	}
+
+	impl ProcStatus {
+		/// Opens `/proc/self/status`. Note that `self` is the process calling this, so the job
+		/// has to open it itself.
+		pub fn open() -> io::Result<Self> {
+			Ok(Self { file: File::open(PROC_STATUS_PATH)?, buf: String::new() })
+		}
+
+		/// Reads the current `VmSwap` of the process, in bytes.
+		fn sample(&mut self) -> io::Result<u64> {
+			read_from_start(&self.file, &mut self.buf)?;
+			parse_vm_swap(&self.buf).ok_or_else(|| {
+				io::Error::new(io::ErrorKind::InvalidData, "no VmSwap in the process status")
+			})
+		}
+	}
+
+	/// Reads all of `file` into `buf`, from its start. This uses `pread` rather than seeking back
+	/// to the start, as seccomp doesn't let prepare jobs seek.
+	fn read_from_start(file: &File, buf: &mut String) -> io::Result<()> {
+		let mut bytes = std::mem::take(buf).into_bytes();
+		bytes.clear();
+		let mut chunk = [0; 4096];
+		loop {
+			match file.read_at(&mut chunk, bytes.len() as u64)? {
+				0 => break,
+				read => bytes.extend_from_slice(&chunk[..read]),
+			}
 		}
+		*buf = String::from_utf8(bytes)
+			.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		Ok(())
+	}
+
+	/// Extracts `VmSwap` from the contents of `/proc/self/status`, converted from kilobytes to
+	/// bytes.
+	pub fn parse_vm_swap(contents: &str) -> Option<u64> {
+		contents.lines().find_map(|line| {
+			let value = line.strip_prefix("VmSwap:")?;
+			let kb: u64 = value.trim().strip_suffix("kB")?.trim().parse().ok()?;
+			Some(kb * 1024)
+		})
 	}
 
 	/// Runs a thread in the background that observes memory statistics. The goal is to try to get
@@ -75,32 +182,75 @@ pub mod memory_tracker {
 	///
 	/// 1. Create the memory tracker.
 	///
-	/// 2. Sleep for some short interval. Whenever we wake up, take a snapshot by updating the
+	/// 2. Sleep for `poll_interval`. Whenever we wake up, take a snapshot by updating the
 	///    allocation epoch.
 	///
 	/// 3. When we are notified that preparation has completed, take one last snapshot and return
 	///    the maximum observed values.
 	///
+	/// If `smaps_rollup` is given, every snapshot also samples it. Should that fail, the peaks
+	/// sampled from it are dropped and the sampling is stopped, so that the job falls back to
+	/// `getrusage` for its max RSS.
+	///
+	/// Likewise, if `proc_status` is given, every snapshot samples the swap usage from it, until
+	/// that fails.
+	///
 	/// # Errors
 	///
 	/// For simplicity, any errors are returned as a string. As this is not a critical component,
 	/// errors are used for informational purposes (logging) only.
-	pub fn memory_tracker_loop(condvar: thread::Cond) -> Result<MemoryAllocationStats, String> {
-		// NOTE: This doesn't need to be too fine-grained since preparation currently takes 3-10s or
-		// more. Apart from that, there is not really a science to this number.
-		const POLL_INTERVAL: Duration = Duration::from_millis(100);
-
+	pub fn memory_tracker_loop(
+		condvar: thread::Cond,
+		poll_interval: Duration,
+		mut smaps_rollup: Option<SmapsRollup>,
+		mut proc_status: Option<ProcStatus>,
+	) -> Result<MemoryAllocationStats, String> {
 		let tracker = MemoryAllocationTracker::new().map_err(|err| err.to_string())?;
-		let mut max_stats = MemoryAllocationStats::default();
+		let mut max_stats = MemoryAllocationStats { poll_interval, ..Default::default() };
 
 		let mut update_stats = || -> Result<(), String> {
 			let current_stats = tracker.snapshot().map_err(|err| err.to_string())?;
+			max_stats.samples += 1;
 			if current_stats.resident > max_stats.resident {
 				max_stats.resident = current_stats.resident;
 			}
 			if current_stats.allocated > max_stats.allocated {
 				max_stats.allocated = current_stats.allocated;
 			}
+			if let Some(file) = smaps_rollup.as_mut() {
+				match file.sample() {
+					Ok((rss, pss)) => {
+						max_stats.peak_rss = max_stats.peak_rss.max(Some(rss));
+						max_stats.peak_pss = max_stats.peak_pss.max(Some(pss));
+					},
+					Err(err) => {
+						gum::warn!(
+							target: LOG_TARGET,
+							"worker: could not sample {}, falling back to getrusage: {}",
+							SMAPS_ROLLUP_PATH,
+							err,
+						);
+						smaps_rollup = None;
+						max_stats.peak_rss = None;
+						max_stats.peak_pss = None;
+					},
+				}
+			}
+			if let Some(file) = proc_status.as_mut() {
+				match file.sample() {
+					Ok(swap) => max_stats.peak_swap = max_stats.peak_swap.max(Some(swap)),
+					Err(err) => {
+						gum::warn!(
+							target: LOG_TARGET,
+							"worker: could not sample the swap usage from {}: {}",
+							PROC_STATUS_PATH,
+							err,
+						);
+						proc_status = None;
+						max_stats.peak_swap = None;
+					},
+				}
+			}
 			Ok(())
 		};
 
@@ -111,7 +261,7 @@ pub mod memory_tracker {
 			// Sleep for the poll interval, or wake up if the condvar is triggered. Note that
 			// `wait_timeout_while` is documented as not being very precise or reliable, which is
 			// fine here -- see note above.
-			match thread::wait_for_threads_with_timeout(&condvar, POLL_INTERVAL) {
+			match thread::wait_for_threads_with_timeout(&condvar, poll_interval) {
 				Some(_outcome) => {
 					update_stats()?;
 					return Ok(max_stats)
@@ -121,13 +271,47 @@ pub mod memory_tracker {
 		}
 	}
 
+	/// The handle of the memory tracker thread, see [`spawn_memory_tracker`].
+	pub type MemoryTrackerThread = JoinHandle<Result<MemoryAllocationStats, String>>;
+
+	/// Runs the given memory tracker, usually [`memory_tracker_loop`], in a regular, non-worker
+	/// thread. The tracker is not critical, so its failures only cost the memory stats: a panic is
+	/// caught and turned into an error, and `None` is returned if the thread can't be spawned.
+	pub fn spawn_memory_tracker<F>(tracker: F) -> Option<MemoryTrackerThread>
+	where
+		F: FnOnce() -> Result<MemoryAllocationStats, String> + Send + 'static,
+	{
+		let spawned = std::thread::Builder::new().name("memory tracker".into()).spawn(move || {
+			panic::catch_unwind(AssertUnwindSafe(tracker)).unwrap_or_else(|payload| {
+				Err(format!("memory tracker panicked: {}", stringify_panic_payload(payload)))
+			})
+		});
+		spawned
+			.map_err(|err| {
+				gum::warn!(target: LOG_TARGET, "worker: could not spawn the memory tracker: {}", err);
+			})
+			.ok()
+	}
+
 	/// Helper function to get the stats from the memory tracker. Helps isolate this error handling.
+	/// The stats include the poll interval that was used. Returns `None` if the tracker failed, or
+	/// was never spawned.
 	pub fn get_memory_tracker_loop_stats(
-		thread: JoinHandle<Result<MemoryAllocationStats, String>>,
+		thread: Option<MemoryTrackerThread>,
 		worker_pid: u32,
 	) -> Option<MemoryAllocationStats> {
-		match thread.join() {
-			Ok(Ok(stats)) => Some(stats),
+		match thread?.join() {
+			Ok(Ok(stats)) => {
+				gum::trace!(
+					target: LOG_TARGET,
+					%worker_pid,
+					allocator = ?crate::ALLOCATOR,
+					poll_interval = ?stats.poll_interval,
+					samples = stats.samples,
+					"worker: memory tracker finished",
+				);
+				Some(stats)
+			},
 			Ok(Err(err)) => {
 				gum::warn!(
 					target: LOG_TARGET,
@@ -173,9 +357,11 @@ pub mod max_rss_stat {
 		unsafe { Ok(result.assume_init()) }
 	}
 
-	/// Gets the `ru_maxrss` for the current thread.
+	/// Gets the `ru_maxrss` for the current thread, in kilobytes.
 	pub fn get_max_rss_thread() -> io::Result<i64> {
-		// `c_long` is either `i32` or `i64` depending on architecture. `i64::from` always works.
+		// `c_long` is either `i32` or `i64` depending on architecture. `i64::from` always works,
+		// and Linux reports the value in kilobytes regardless of the page size of the
+		// architecture.
 		getrusage_thread().map(|rusage| i64::from(rusage.ru_maxrss))
 	}
 
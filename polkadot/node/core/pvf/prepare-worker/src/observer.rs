@@ -0,0 +1,51 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A hook for observing the lifecycle of prepare jobs, e.g. for a supervisor of a downstream build
+//! of the worker.
+
+use polkadot_primitives::ValidationCodeHash;
+use std::{path::Path, time::Instant};
+
+/// Observes the milestones of every job the worker runs, along with the time each was reached. All
+/// methods do nothing by default. They are called in the worker process, outside of the sandbox of
+/// the job, on the thread handling requests, so they should return quickly.
+pub trait PrepareObserver {
+	/// The worker received a request to prepare the code with the given hash. Each PVF of a batch
+	/// is reported when the worker gets to it.
+	fn request_received(&self, _code_hash: ValidationCodeHash, _at: Instant) {}
+
+	/// The worker spawned the job process with the given pid. Not reported for artifacts served
+	/// from the cache, nor on macOS, where jobs run in the worker process.
+	fn job_spawned(&self, _code_hash: ValidationCodeHash, _job_pid: i32, _at: Instant) {}
+
+	/// The job started compiling the code. The job can't reach the worker from its sandbox, so
+	/// this is only reported once it sent its response, and only if the compilation succeeded.
+	/// Not reported on macOS either.
+	fn compile_started(&self, _code_hash: ValidationCodeHash, _at: Instant) {}
+
+	/// The artifact was written to `path`, for the host to pick it up. This includes artifacts
+	/// served from the cache. An artifact requested with
+	/// [`PrepareWorkerRequest::SingleToFd`](polkadot_node_core_pvf_common::prepare::PrepareWorkerRequest::SingleToFd)
+	/// was copied from `path` into the file of the host instead, and is no longer at `path`.
+	fn artifact_written(&self, _code_hash: ValidationCodeHash, _path: &Path, _at: Instant) {}
+}
+
+/// The default [`PrepareObserver`], which ignores all milestones.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopPrepareObserver;
+
+impl PrepareObserver for NoopPrepareObserver {}
@@ -0,0 +1,115 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Backtraces of panics in the prepare thread, for debugging.
+//!
+//! Joining a panicked thread only yields the panic payload, usually its message. The backtrace is
+//! gone by then, so a panic hook captures it on the panicking thread, for the joining thread to
+//! report it along with the message. Only threads that asked for it capture their backtrace, see
+//! [`PvfPrepData::with_panic_backtraces`](polkadot_node_core_pvf_common::pvf::PvfPrepData::with_panic_backtraces).
+//!
+//! The backtrace is symbolized right away, which reads the worker binary. Threads under seccomp
+//! must not capture it, as they are not allowed to open files. Neither may the default panic hook,
+//! which does so if `RUST_BACKTRACE` is set, see [`disable_for_this_process`].
+
+use polkadot_node_core_pvf_common::worker::stringify_panic_payload;
+use std::{
+	any::Any,
+	backtrace::Backtrace,
+	cell::Cell,
+	io::{self, Write},
+	panic,
+	sync::{Mutex, Once, PoisonError},
+	thread::{self, ThreadId},
+};
+
+thread_local! {
+	/// Whether a panic on this thread captures its backtrace.
+	static CAPTURE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The backtraces captured by panics, until they are taken by [`describe_panic`].
+static BACKTRACES: Mutex<Vec<(ThreadId, String)>> = Mutex::new(Vec::new());
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// Makes a panic on the calling thread capture its backtrace. Installs the panic hook doing so,
+/// unless it is installed already. The hook that was installed before still runs after it.
+pub fn capture_on_this_thread() {
+	INSTALL_HOOK.call_once(|| {
+		let previous_hook = panic::take_hook();
+		panic::set_hook(Box::new(move |info| {
+			if CAPTURE.with(Cell::get) {
+				let backtrace = Backtrace::force_capture().to_string();
+				BACKTRACES
+					.lock()
+					.unwrap_or_else(PoisonError::into_inner)
+					.push((thread::current().id(), backtrace));
+			}
+			previous_hook(info);
+		}));
+	});
+	CAPTURE.with(|capture| capture.set(true));
+}
+
+/// Replaces the panic hook of the process with one that only prints the panic message, whatever
+/// `RUST_BACKTRACE` says. For jobs about to enable seccomp, which would get killed by the default
+/// hook symbolizing a backtrace instead of reporting the panic.
+pub fn disable_for_this_process() {
+	panic::set_hook(Box::new(|info| {
+		let _ = writeln!(io::stderr(), "{}", info);
+	}));
+}
+
+/// Describes the panic of the given thread from its payload: the panic message, followed by the
+/// backtrace if the thread captured it.
+pub fn describe_panic(thread: ThreadId, payload: Box<dyn Any + Send + 'static>) -> String {
+	let message = stringify_panic_payload(payload);
+	let mut backtraces = BACKTRACES.lock().unwrap_or_else(PoisonError::into_inner);
+	match backtraces.iter().position(|(id, _)| *id == thread) {
+		Some(index) => format!("{}\nbacktrace:\n{}", message, backtraces.swap_remove(index).1),
+		None => message,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn panic_on_thread(capture: bool) -> String {
+		let handle = thread::spawn(move || {
+			if capture {
+				capture_on_this_thread();
+			}
+			panic!("injected prepare panic");
+		});
+		let thread = handle.thread().id();
+		describe_panic(thread, handle.join().unwrap_err())
+	}
+
+	#[test]
+	fn backtrace_is_reported_if_captured() {
+		let description = panic_on_thread(true);
+		let (message, backtrace) = description.split_once("\nbacktrace:\n").unwrap();
+		assert_eq!(message, "injected prepare panic");
+		assert!(!backtrace.trim().is_empty());
+	}
+
+	#[test]
+	fn only_the_message_is_reported_by_default() {
+		assert_eq!(panic_on_thread(false), "injected prepare panic");
+	}
+}
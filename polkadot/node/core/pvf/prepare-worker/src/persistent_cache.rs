@@ -0,0 +1,253 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Artifacts prepared by a worker, persisted in the worker dir along with an index of them, so
+//! that a worker restarted on the same dir doesn't have to compile them again.
+//!
+//! The [`ArtifactCache`](crate::artifact_cache::ArtifactCache) is lost when the worker exits. The
+//! index maps the code and executor params of every persisted artifact to its file and the
+//! checksum of the file. Entries whose file is missing or doesn't match its checksum, e.g. because
+//! the worker was killed while writing it, are ignored.
+//!
+//! The checksum only guards against accidents, as whoever can write the artifact can write the
+//! index too. Under landlock, jobs can't write to the worker dir unless they may dump their core,
+//! in which case nothing is persisted. An artifact is also only served if its header names the
+//! code and flags it is loaded for.
+
+use crate::{
+	artifact_cache::{CacheKey, CachedArtifact},
+	LOG_TARGET,
+};
+use codec::{Decode, Encode};
+use polkadot_node_core_pvf_common::artifact::{ArtifactFlags, CompiledArtifact};
+use std::{
+	fs, io,
+	path::{Path, PathBuf},
+};
+
+/// The maximum number of artifacts persisted. Every artifact gets a file of its own, which is
+/// reused once the artifact is evicted.
+const MAX_ENTRIES: usize = 8;
+
+/// The version of the index format. An index of another version is ignored.
+const INDEX_VERSION: u8 = 1;
+
+const INDEX_FILE_NAME: &str = "prepare-cache-index";
+const ARTIFACT_FILE_PREFIX: &str = "prepare-cache-artifact-";
+
+#[derive(Debug, Clone, Encode, Decode)]
+struct IndexEntry {
+	key: CacheKey,
+	/// The name of the artifact file, in the same dir as the index.
+	file_name: String,
+	/// The blake3 hash of the artifact file.
+	checksum: [u8; 32],
+	observed_wasm_code_len: u32,
+	code_size: u64,
+}
+
+/// The persisted artifacts of a worker dir. The most recently persisted artifact comes first in
+/// the index.
+pub struct PersistentCache {
+	dir: PathBuf,
+	entries: Vec<IndexEntry>,
+}
+
+impl PersistentCache {
+	/// Loads the index from the given dir. A missing or unreadable index is treated as empty.
+	pub fn load(dir: &Path) -> Self {
+		let entries = match fs::read(dir.join(INDEX_FILE_NAME)) {
+			Ok(bytes) => decode_index(&bytes).unwrap_or_else(|| {
+				gum::debug!(target: LOG_TARGET, ?dir, "ignoring unreadable artifact index");
+				Vec::new()
+			}),
+			Err(_) => Vec::new(),
+		};
+		Self { dir: dir.to_owned(), entries }
+	}
+
+	/// Reads the persisted artifact for the given key, which must have been compiled with the given
+	/// flags, if any. An entry whose file fails the checksum, or whose artifact header doesn't
+	/// match, is dropped from the index.
+	pub fn get(&mut self, key: &CacheKey, flags: ArtifactFlags) -> Option<CachedArtifact> {
+		let index = self.entries.iter().position(|entry| entry.key == *key)?;
+		let entry = &self.entries[index];
+		let artifact = fs::read(self.dir.join(&entry.file_name))
+			.ok()
+			.filter(|bytes| *blake3::hash(bytes).as_bytes() == entry.checksum)
+			.filter(|bytes| {
+				CompiledArtifact::parse_with_code_hash(bytes)
+					.is_ok_and(|(code_hash, _payload)| code_hash == key.code_hash())
+			})
+			.and_then(|bytes| CompiledArtifact::from_bytes(bytes).ok())
+			.filter(|artifact| artifact.flags() == flags);
+		match artifact {
+			Some(artifact) => Some(CachedArtifact {
+				artifact,
+				observed_wasm_code_len: entry.observed_wasm_code_len,
+				code_size: entry.code_size,
+			}),
+			None => {
+				gum::debug!(
+					target: LOG_TARGET,
+					file_name = %entry.file_name,
+					"ignoring persisted artifact failing its checksum or header",
+				);
+				self.entries.remove(index);
+				None
+			},
+		}
+	}
+
+	/// Persists the artifact under the given key, replacing the least recently persisted artifact
+	/// if there are too many.
+	pub fn insert(&mut self, key: CacheKey, cached: &CachedArtifact) -> io::Result<()> {
+		let file_name = match self.entries.iter().position(|entry| entry.key == key) {
+			Some(index) => self.entries.remove(index).file_name,
+			None if self.entries.len() >= MAX_ENTRIES =>
+				self.entries.pop().map(|entry| entry.file_name).unwrap_or_default(),
+			None => (0..MAX_ENTRIES)
+				.map(|slot| format!("{}{}", ARTIFACT_FILE_PREFIX, slot))
+				.find(|name| self.entries.iter().all(|entry| entry.file_name != *name))
+				.unwrap_or_default(),
+		};
+
+		// The entry is only added once the file is written. Should the worker be killed in between,
+		// an entry still referring to the file fails its checksum.
+		let artifact = cached.artifact.as_ref();
+		fs::write(self.dir.join(&file_name), artifact)?;
+		self.entries.insert(
+			0,
+			IndexEntry {
+				key,
+				file_name,
+				checksum: *blake3::hash(artifact).as_bytes(),
+				observed_wasm_code_len: cached.observed_wasm_code_len,
+				code_size: cached.code_size,
+			},
+		);
+		fs::write(self.dir.join(INDEX_FILE_NAME), (INDEX_VERSION, &self.entries).encode())
+	}
+}
+
+fn decode_index(mut bytes: &[u8]) -> Option<Vec<IndexEntry>> {
+	let (version, entries) = <(u8, Vec<IndexEntry>)>::decode(&mut bytes).ok()?;
+	(version == INDEX_VERSION).then_some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use polkadot_node_core_pvf_common::{prepare::PrepareJobKind, pvf::PvfPrepData};
+	use polkadot_primitives::ExecutorParams;
+	use std::time::Duration;
+
+	fn key(discriminator: u8) -> CacheKey {
+		CacheKey::new(&PvfPrepData::from_code(
+			vec![discriminator],
+			ExecutorParams::default(),
+			Duration::from_secs(1),
+			PrepareJobKind::Compilation,
+		))
+	}
+
+	/// An artifact of the code of the given key, with the given payload.
+	fn cached(key: CacheKey, payload: u8) -> CachedArtifact {
+		CachedArtifact {
+			artifact: CompiledArtifact::new(vec![payload; 10], key.code_hash()),
+			observed_wasm_code_len: 1,
+			code_size: 2,
+		}
+	}
+
+	#[test]
+	fn artifacts_survive_a_restart() {
+		let dir = tempfile::tempdir().unwrap();
+		PersistentCache::load(dir.path()).insert(key(0), &cached(key(0), 7)).unwrap();
+
+		let mut restarted = PersistentCache::load(dir.path());
+
+		let persisted = restarted.get(&key(0), ArtifactFlags::default()).unwrap();
+		assert_eq!(persisted.artifact.as_ref(), cached(key(0), 7).artifact.as_ref());
+		assert_eq!((persisted.observed_wasm_code_len, persisted.code_size), (1, 2));
+		assert!(restarted.get(&key(1), ArtifactFlags::default()).is_none());
+	}
+
+	#[test]
+	fn corrupt_artifacts_are_ignored() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut cache = PersistentCache::load(dir.path());
+		cache.insert(key(0), &cached(key(0), 7)).unwrap();
+
+		let file = dir.path().join(&cache.entries[0].file_name);
+		let mut bytes = fs::read(&file).unwrap();
+		*bytes.last_mut().unwrap() ^= 1;
+		fs::write(&file, bytes).unwrap();
+
+		assert!(PersistentCache::load(dir.path())
+			.get(&key(0), ArtifactFlags::default())
+			.is_none());
+	}
+
+	#[test]
+	fn artifacts_of_other_code_or_flags_are_ignored() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut cache = PersistentCache::load(dir.path());
+		// The checksum in the index matches, as if both had been written by a compromised job.
+		cache.insert(key(0), &cached(key(1), 7)).unwrap();
+		cache.insert(key(2), &cached(key(2), 7)).unwrap();
+
+		let mut restarted = PersistentCache::load(dir.path());
+		assert!(restarted.get(&key(0), ArtifactFlags::default()).is_none());
+		let hugepage_layout = ArtifactFlags { hugepage_layout: true };
+		assert!(restarted.get(&key(2), hugepage_layout).is_none());
+	}
+
+	#[test]
+	fn corrupt_index_is_treated_as_empty() {
+		let dir = tempfile::tempdir().unwrap();
+		fs::write(dir.path().join(INDEX_FILE_NAME), b"garbage").unwrap();
+
+		let mut cache = PersistentCache::load(dir.path());
+		assert!(cache.entries.is_empty());
+
+		cache.insert(key(0), &cached(key(0), 7)).unwrap();
+		assert!(PersistentCache::load(dir.path())
+			.get(&key(0), ArtifactFlags::default())
+			.is_some());
+	}
+
+	#[test]
+	fn evicts_the_least_recently_persisted_artifact() {
+		let dir = tempfile::tempdir().unwrap();
+		let mut cache = PersistentCache::load(dir.path());
+		for i in 0..=MAX_ENTRIES as u8 {
+			cache.insert(key(i), &cached(key(i), i)).unwrap();
+		}
+
+		let mut restarted = PersistentCache::load(dir.path());
+		assert!(restarted.get(&key(0), ArtifactFlags::default()).is_none());
+		for i in 1..=MAX_ENTRIES as u8 {
+			assert_eq!(
+				restarted.get(&key(i), ArtifactFlags::default()).unwrap().artifact.as_ref(),
+				cached(key(i), i).artifact.as_ref()
+			);
+		}
+		// The files of evicted artifacts are reused.
+		let files = fs::read_dir(dir.path()).unwrap().count();
+		assert_eq!(files, MAX_ENTRIES + 1);
+	}
+}
@@ -0,0 +1,39 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A hook for post-processing prepared artifacts before they are handed to the host, e.g. to sign
+//! or re-encode them in a downstream build of the worker.
+
+use std::{io, path::Path};
+
+/// Post-processes every artifact the worker hands to the host, including those served from its
+/// cache. It runs in the worker process, outside of the sandbox of the job.
+pub trait ArtifactPostProcessor {
+	/// Processes the complete artifact in the file at `path`, e.g. by rewriting the file. The host
+	/// only reads the file once the worker reported the result, so it never sees an artifact that
+	/// was not processed.
+	fn process(&self, path: &Path) -> io::Result<()>;
+}
+
+/// The default [`ArtifactPostProcessor`], which leaves artifacts as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopArtifactPostProcessor;
+
+impl ArtifactPostProcessor for NoopArtifactPostProcessor {
+	fn process(&self, _path: &Path) -> io::Result<()> {
+		Ok(())
+	}
+}
@@ -0,0 +1,58 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The run queue delay of the thread preparing a PVF, for
+//! [`SchedStats`](polkadot_node_core_pvf_common::prepare::SchedStats).
+//!
+//! The context switches of a job are measured by the worker with `getrusage`, like its page faults.
+//! The run queue delay is only exposed per thread in `/proc`, so the preparing thread reads it
+//! itself, before and after preparing. This needs opening a file, which neither landlock nor
+//! seccomp allow the job to do.
+
+use std::{fs, time::Duration};
+
+/// Returns the total time the calling thread spent runnable but waiting for a CPU so far.
+pub fn thread_run_queue_delay() -> Option<Duration> {
+	parse_run_queue_delay(&fs::read_to_string("/proc/thread-self/schedstat").ok()?)
+}
+
+/// Parses the run queue delay from the contents of a `schedstat` file, which holds the time spent
+/// on the CPU and the time spent waiting on the run queue in nanoseconds, and the number of time
+/// slices run.
+fn parse_run_queue_delay(schedstat: &str) -> Option<Duration> {
+	let nanos = schedstat.split_whitespace().nth(1)?.parse().ok()?;
+	Some(Duration::from_nanos(nanos))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn run_queue_delay_is_parsed_from_the_second_field() {
+		assert_eq!(
+			parse_run_queue_delay("2914519386 167264617 5391\n"),
+			Some(Duration::from_nanos(167264617))
+		);
+		assert_eq!(parse_run_queue_delay("2914519386\n"), None);
+		assert_eq!(parse_run_queue_delay(""), None);
+	}
+
+	#[test]
+	fn run_queue_delay_of_this_thread_is_read() {
+		assert!(thread_run_queue_delay().is_some());
+	}
+}
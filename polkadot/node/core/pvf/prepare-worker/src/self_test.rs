@@ -0,0 +1,255 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A self-test of the prepare worker on the current host, for operators.
+//!
+//! It compiles an embedded PVF and applies each of the security features used by the worker and
+//! its jobs, without a node to talk to. The security features can't be lifted once applied, so
+//! each is applied in a thread or a process of its own.
+
+use crate::prepare_artifact;
+use polkadot_node_core_pvf_common::{prepare::PrepareJobKind, pvf::PvfPrepData};
+use polkadot_primitives::ExecutorParams;
+use std::{
+	fmt,
+	time::{Duration, Instant},
+};
+
+/// A minimal PVF, returning 0 from `validate_block`:
+///
+/// ```wat
+/// (module
+/// 	(memory (export "memory") 1)
+/// 	(global (export "__heap_base") i32 (i32.const 0))
+/// 	(func (export "validate_block") (param i32 i32) (result i64)
+/// 		i64.const 0)
+/// )
+/// ```
+#[rustfmt::skip]
+const SELF_TEST_PVF: &[u8] = &[
+	// Magic and version.
+	0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+	// Type section: `(i32, i32) -> i64`.
+	0x01, 0x07, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+	// Function section.
+	0x03, 0x02, 0x01, 0x00,
+	// Memory section: a single page.
+	0x05, 0x03, 0x01, 0x00, 0x01,
+	// Global section: `__heap_base`.
+	0x06, 0x06, 0x01, 0x7f, 0x00, 0x41, 0x00, 0x0b,
+	// Export section: `memory`, `__heap_base` and `validate_block`.
+	0x07, 0x29, 0x03,
+	0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+	0x0b, b'_', b'_', b'h', b'e', b'a', b'p', b'_', b'b', b'a', b's', b'e', 0x03, 0x00,
+	0x0e, b'v', b'a', b'l', b'i', b'd', b'a', b't', b'e', b'_', b'b', b'l', b'o', b'c', b'k', 0x00,
+	0x00,
+	// Code section: `i64.const 0`.
+	0x0a, 0x06, 0x01, 0x04, 0x00, 0x42, 0x00, 0x0b,
+];
+
+/// The preparation timeout of the embedded PVF. It compiles in milliseconds.
+const SELF_TEST_PREPARATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The outcome of a single check of [`self_test`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestCheck {
+	/// What was checked.
+	pub name: &'static str,
+	/// Why the check failed, or `None` if it passed.
+	pub error: Option<String>,
+	/// How long the check took.
+	pub elapsed: Duration,
+}
+
+impl SelfTestCheck {
+	fn run(name: &'static str, check: impl FnOnce() -> Result<(), String>) -> Self {
+		let start = Instant::now();
+		let error = check().err();
+		Self { name, error, elapsed: start.elapsed() }
+	}
+
+	/// Whether the check passed.
+	pub fn passed(&self) -> bool {
+		self.error.is_none()
+	}
+}
+
+/// The report of [`self_test`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+	/// Compiling the embedded PVF.
+	pub compilation: SelfTestCheck,
+	/// Applying each of the security features, in the order the worker and its jobs apply them.
+	pub security: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+	/// Whether the embedded PVF compiled and all of the security features could be applied.
+	pub fn passed(&self) -> bool {
+		self.compilation.passed() && self.security.iter().all(SelfTestCheck::passed)
+	}
+}
+
+impl fmt::Display for SelfTestReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for check in std::iter::once(&self.compilation).chain(&self.security) {
+			match &check.error {
+				None => writeln!(f, "ok      {} ({:?})", check.name, check.elapsed)?,
+				Some(err) => writeln!(f, "failed  {} ({:?}): {}", check.name, check.elapsed, err)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Checks that the prepare worker can compile PVFs and secure itself on the current host.
+///
+/// This compiles an embedded PVF like a prepare job does, then tries to apply each security
+/// feature. The self-test is meant to run from the worker binary or a small tool of its own, as it
+/// forks for the security features that apply to the whole process.
+pub fn self_test() -> SelfTestReport {
+	let compilation = SelfTestCheck::run("compilation", || {
+		let pvf = PvfPrepData::from_code(
+			SELF_TEST_PVF.to_vec(),
+			ExecutorParams::default(),
+			SELF_TEST_PREPARATION_TIMEOUT,
+			PrepareJobKind::Compilation,
+		);
+		match prepare_artifact(pvf, None) {
+			Ok(outcome) if outcome.compiled_artifact.is_some() => Ok(()),
+			Ok(_) => Err("no artifact was produced".into()),
+			Err(err) => Err(err.to_string()),
+		}
+	});
+
+	SelfTestReport { compilation, security: check_security_features() }
+}
+
+#[cfg(target_os = "linux")]
+fn check_security_features() -> Vec<SelfTestCheck> {
+	use polkadot_node_core_pvf_common::worker::security;
+
+	let mut checks = vec![
+		SelfTestCheck::run("unshare user namespace and change root", || {
+			let tempdir =
+				std::env::temp_dir().join(format!("pvf-self-test-{}", std::process::id()));
+			std::fs::create_dir_all(&tempdir).map_err(|err| err.to_string())?;
+			let result = check_in_child_process(|| {
+				security::change_root::check_can_fully_enable(&tempdir)
+					.map_err(|err| err.to_string())
+			});
+			let _ = std::fs::remove_dir_all(&tempdir);
+			result
+		}),
+		SelfTestCheck::run("landlock", || {
+			security::landlock::check_can_fully_enable().map_err(|err| err.to_string())
+		}),
+	];
+	#[cfg(target_arch = "x86_64")]
+	checks.push(SelfTestCheck::run("seccomp", || {
+		security::seccomp::check_can_fully_enable().map_err(|err| err.to_string())
+	}));
+	checks.push(SelfTestCheck::run("secure clone", || {
+		check_in_child_process(|| {
+			// SAFETY: the forked child is single-threaded.
+			unsafe { security::clone::check_can_fully_clone() }.map_err(|err| err.to_string())
+		})
+	}));
+	checks.push(SelfTestCheck::run("unshare network and mount namespaces", || {
+		check_in_child_process(|| {
+			security::unshare::check_can_fully_enable().map_err(|err| err.to_string())
+		})
+	}));
+	checks
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_security_features() -> Vec<SelfTestCheck> {
+	Vec::new()
+}
+
+/// Runs the check in a forked child process, as it changes the whole process, or needs it to be
+/// single-threaded. The child sends the error back over a pipe.
+#[cfg(target_os = "linux")]
+fn check_in_child_process(check: impl FnOnce() -> Result<(), String>) -> Result<(), String> {
+	use polkadot_node_core_pvf_common::worker::{pipe2_cloexec, PipeFd};
+	use std::{
+		io::{Read, Write},
+		os::fd::FromRawFd,
+	};
+
+	let (pipe_read_fd, pipe_write_fd) = pipe2_cloexec().map_err(|err| err.to_string())?;
+	// SAFETY: both ends are open and owned from here on.
+	let (mut pipe_read, mut pipe_write) =
+		unsafe { (PipeFd::from_raw_fd(pipe_read_fd), PipeFd::from_raw_fd(pipe_write_fd)) };
+
+	// SAFETY: the child only runs the check, reports its result and exits.
+	match unsafe { libc::fork() } {
+		-1 => Err(format!("fork failed: {}", std::io::Error::last_os_error())),
+		0 => {
+			let status = match check() {
+				Ok(()) => 0,
+				Err(err) => {
+					let _ = pipe_write.write_all(err.as_bytes());
+					1
+				},
+			};
+			// SAFETY: exits without running the destructors and exit handlers of the parent.
+			unsafe { libc::_exit(status) }
+		},
+		child => {
+			// Only the child writes, so reading ends once it exited.
+			drop(pipe_write);
+			let mut message = String::new();
+			let _ = pipe_read.read_to_string(&mut message);
+
+			let mut status = 0;
+			// SAFETY: the child was forked above and is not waited for elsewhere.
+			if unsafe { libc::waitpid(child, &mut status, 0) } == -1 {
+				return Err(format!("waitpid failed: {}", std::io::Error::last_os_error()))
+			}
+			if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+				Ok(())
+			} else if message.is_empty() {
+				Err(format!("the check process ended with status {}", status))
+			} else {
+				Err(message)
+			}
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn embedded_pvf_matches_its_source() {
+		let source = r#"(module
+			(memory (export "memory") 1)
+			(global (export "__heap_base") i32 (i32.const 0))
+			(func (export "validate_block") (param i32 i32) (result i64)
+				i64.const 0)
+		)"#;
+		assert_eq!(SELF_TEST_PVF, wat::parse_str(source).unwrap());
+	}
+
+	#[test]
+	fn self_test_compiles_the_embedded_pvf() {
+		let report = self_test();
+		assert!(report.compilation.passed(), "{}", report);
+	}
+}
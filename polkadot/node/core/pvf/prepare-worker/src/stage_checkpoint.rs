@@ -0,0 +1,124 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The stage a job is in, checkpointed into memory shared with the worker.
+//!
+//! The job can't be asked where it is once it timed out, as it may be killed or stuck. So a
+//! benchmarking job records every stage it enters in a shared mapping created by the worker before
+//! forking, and the worker reads the last one to enrich the timeout error.
+
+use codec::Decode;
+use polkadot_node_core_pvf_common::prepare::PrepareStage;
+use std::{
+	io,
+	ptr::{self, NonNull},
+	sync::atomic::{AtomicU8, Ordering},
+};
+
+/// A single byte of memory shared between the worker and its jobs, holding the stage the job
+/// entered last. Zero means that the job did not enter any stage yet.
+pub struct StageCheckpoint {
+	stage: NonNull<AtomicU8>,
+}
+
+// SAFETY: the mapping is only accessed atomically, and only unmapped on drop.
+unsafe impl Send for StageCheckpoint {}
+// SAFETY: see above.
+unsafe impl Sync for StageCheckpoint {}
+
+impl StageCheckpoint {
+	/// Maps the shared memory. It must be created before forking the job, so that both processes
+	/// see the same memory.
+	#[cfg_attr(target_os = "macos", allow(dead_code))]
+	pub fn new() -> io::Result<Self> {
+		// SAFETY: an anonymous mapping doesn't alias any existing memory. It is zero-initialized,
+		// which is a valid `AtomicU8`.
+		let ptr = unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				std::mem::size_of::<AtomicU8>(),
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+				-1,
+				0,
+			)
+		};
+		if ptr == libc::MAP_FAILED {
+			return Err(io::Error::last_os_error())
+		}
+		let stage = NonNull::new(ptr.cast())
+			.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "mmap returned a null pointer"))?;
+		Ok(Self { stage })
+	}
+
+	/// Records that the job entered the given stage.
+	pub fn enter(&self, stage: PrepareStage) {
+		self.stage().store(stage as u8 + 1, Ordering::Release);
+	}
+
+	/// Returns the stage the job entered last, if any.
+	pub fn current(&self) -> Option<PrepareStage> {
+		let stage = self.stage().load(Ordering::Acquire).checked_sub(1)?;
+		PrepareStage::decode(&mut &[stage][..]).ok()
+	}
+
+	fn stage(&self) -> &AtomicU8 {
+		// SAFETY: the mapping is valid until `self` is dropped.
+		unsafe { self.stage.as_ref() }
+	}
+}
+
+impl Drop for StageCheckpoint {
+	fn drop(&mut self) {
+		// SAFETY: the mapping was created in `new` with this size, and is not used after this.
+		unsafe { libc::munmap(self.stage.as_ptr().cast(), std::mem::size_of::<AtomicU8>()) };
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn no_stage_until_one_is_entered() {
+		let checkpoint = StageCheckpoint::new().unwrap();
+		assert_eq!(checkpoint.current(), None);
+
+		checkpoint.enter(PrepareStage::Prevalidation);
+		checkpoint.enter(PrepareStage::Preparation);
+		assert_eq!(checkpoint.current(), Some(PrepareStage::Preparation));
+	}
+
+	#[test]
+	fn stage_is_visible_across_fork() {
+		let checkpoint = StageCheckpoint::new().unwrap();
+
+		// SAFETY: the child only touches the shared memory and exits right away.
+		match unsafe { libc::fork() } {
+			-1 => panic!("fork failed: {}", io::Error::last_os_error()),
+			0 => {
+				checkpoint.enter(PrepareStage::ArtifactCompression);
+				unsafe { libc::_exit(0) }
+			},
+			child => {
+				let mut status = 0;
+				assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+			},
+		}
+
+		assert_eq!(checkpoint.current(), Some(PrepareStage::ArtifactCompression));
+	}
+}
@@ -172,6 +172,9 @@ pub struct Config {
 	pub prepare_worker_program_path: PathBuf,
 	/// The time allotted for a prepare worker to spawn and report to the host.
 	pub prepare_worker_spawn_timeout: Duration,
+	/// The directory the prepare workers were configured to write their artifacts to instead of
+	/// their worker dirs, if any. The host only picks artifacts up from there if set.
+	pub prepare_worker_artifact_dir: Option<PathBuf>,
 	/// The maximum number of workers that can be spawned in the prepare pool for tasks with the
 	/// priority below critical.
 	pub prepare_workers_soft_max_num: usize,
@@ -205,6 +208,7 @@ impl Config {
 
 			prepare_worker_program_path,
 			prepare_worker_spawn_timeout: Duration::from_secs(3),
+			prepare_worker_artifact_dir: None,
 			prepare_workers_soft_max_num,
 			prepare_workers_hard_max_num,
 
@@ -270,6 +274,7 @@ pub async fn start(
 		config.prepare_worker_program_path.clone(),
 		config.cache_path.clone(),
 		config.prepare_worker_spawn_timeout,
+		config.prepare_worker_artifact_dir.clone(),
 		config.node_version.clone(),
 		security_status.clone(),
 	);
@@ -789,6 +794,12 @@ async fn handle_prepare_done(
 		}
 
 		let path = match &result {
+			Ok(success) if !success.stats.artifact_produced => {
+				let _ = result_tx.send(Err(ValidationError::from(PrepareError::IoErr(
+					"the PVF was only validated, without compiling an artifact".into(),
+				))));
+				continue
+			},
 			Ok(success) => success.path.clone(),
 			Err(error) => {
 				let _ = result_tx.send(Err(ValidationError::from(error.clone())));
@@ -812,6 +823,13 @@ async fn handle_prepare_done(
 		.await?;
 	}
 
+	// A job that only validated the code leaves nothing to execute. Forget about the artifact, so
+	// that it is prepared for real once needed.
+	if matches!(&result, Ok(success) if !success.stats.artifact_produced) {
+		artifacts.remove(artifact_id);
+		return Ok(())
+	}
+
 	*state = match result {
 		Ok(PrepareSuccess { path, stats: prepare_stats, size }) => ArtifactState::Prepared {
 			path,
@@ -976,13 +994,21 @@ pub(crate) mod tests {
 	use crate::{artifacts::generate_artifact_path, testing::artifact_id, PossiblyInvalidError};
 	use assert_matches::assert_matches;
 	use futures::future::BoxFuture;
-	use polkadot_node_core_pvf_common::prepare::PrepareStats;
+	use polkadot_node_core_pvf_common::{error::PrevalidationError, prepare::PrepareStats};
 	use polkadot_node_primitives::BlockData;
 	use sp_core::H256;
 
 	const TEST_EXECUTION_TIMEOUT: Duration = Duration::from_secs(3);
 	pub(crate) const TEST_PREPARATION_TIMEOUT: Duration = Duration::from_secs(30);
 
+	/// The result of a job that produced an artifact.
+	fn prepared() -> PrepareSuccess {
+		PrepareSuccess {
+			stats: PrepareStats { artifact_produced: true, ..Default::default() },
+			..Default::default()
+		}
+	}
+
 	#[tokio::test]
 	async fn pulse_test() {
 		let pulse = pulse_every(Duration::from_millis(100));
@@ -1290,10 +1316,7 @@ pub(crate) mod tests {
 		);
 
 		test.from_prepare_queue_tx
-			.send(prepare::FromQueue {
-				artifact_id: artifact_id(1),
-				result: Ok(PrepareSuccess::default()),
-			})
+			.send(prepare::FromQueue { artifact_id: artifact_id(1), result: Ok(prepared()) })
 			.await
 			.unwrap();
 		let result_tx_pvf_1_1 = assert_matches!(
@@ -1306,10 +1329,7 @@ pub(crate) mod tests {
 		);
 
 		test.from_prepare_queue_tx
-			.send(prepare::FromQueue {
-				artifact_id: artifact_id(2),
-				result: Ok(PrepareSuccess::default()),
-			})
+			.send(prepare::FromQueue { artifact_id: artifact_id(2), result: Ok(prepared()) })
 			.await
 			.unwrap();
 		let result_tx_pvf_2 = assert_matches!(
@@ -1360,10 +1380,7 @@ pub(crate) mod tests {
 		);
 		// Send `Ok` right away and poll the host.
 		test.from_prepare_queue_tx
-			.send(prepare::FromQueue {
-				artifact_id: artifact_id(1),
-				result: Ok(PrepareSuccess::default()),
-			})
+			.send(prepare::FromQueue { artifact_id: artifact_id(1), result: Ok(prepared()) })
 			.await
 			.unwrap();
 		// No pending execute requests.
@@ -1482,10 +1499,7 @@ pub(crate) mod tests {
 			prepare::ToQueue::Enqueue { .. }
 		);
 		test.from_prepare_queue_tx
-			.send(prepare::FromQueue {
-				artifact_id: artifact_id(2),
-				result: Ok(PrepareSuccess::default()),
-			})
+			.send(prepare::FromQueue { artifact_id: artifact_id(2), result: Ok(prepared()) })
 			.await
 			.unwrap();
 		// The execute queue receives new request, preckecking is finished and we can
@@ -1648,10 +1662,7 @@ pub(crate) mod tests {
 		);
 
 		test.from_prepare_queue_tx
-			.send(prepare::FromQueue {
-				artifact_id: artifact_id(1),
-				result: Ok(PrepareSuccess::default()),
-			})
+			.send(prepare::FromQueue { artifact_id: artifact_id(1), result: Ok(prepared()) })
 			.await
 			.unwrap();
 
@@ -1708,7 +1719,9 @@ pub(crate) mod tests {
 		test.from_prepare_queue_tx
 			.send(prepare::FromQueue {
 				artifact_id: artifact_id(1),
-				result: Err(PrepareError::Prevalidation("reproducible error".into())),
+				result: Err(PrepareError::Prevalidation(PrevalidationError::Invalid(
+					"reproducible error".into(),
+				))),
 			})
 			.await
 			.unwrap();
@@ -1833,10 +1846,7 @@ pub(crate) mod tests {
 		);
 
 		test.from_prepare_queue_tx
-			.send(prepare::FromQueue {
-				artifact_id: artifact_id(1),
-				result: Ok(PrepareSuccess::default()),
-			})
+			.send(prepare::FromQueue { artifact_id: artifact_id(1), result: Ok(prepared()) })
 			.await
 			.unwrap();
 
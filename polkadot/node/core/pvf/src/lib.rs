@@ -115,7 +115,7 @@ pub use worker_interface::{framed_recv, framed_send, JOB_TIMEOUT_WALL_CLOCK_FACT
 
 // Re-export some common types.
 pub use polkadot_node_core_pvf_common::{
-	error::{InternalValidationError, PrepareError},
+	error::{InternalValidationError, PrepareError, PrevalidationError},
 	prepare::{PrepareJobKind, PrepareStats},
 	pvf::PvfPrepData,
 	SecurityStatus,
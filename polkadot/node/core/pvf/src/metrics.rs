@@ -16,7 +16,10 @@
 
 //! Prometheus metrics related to the validation host.
 
-use polkadot_node_core_pvf_common::prepare::MemoryStats;
+use polkadot_node_core_pvf_common::{
+	error::PrepareWorkerResult,
+	prepare::{MemoryStats, WorkerMetrics},
+};
 use polkadot_node_metrics::metrics::{self, prometheus};
 
 /// Validation host metrics.
@@ -24,6 +27,11 @@ use polkadot_node_metrics::metrics::{self, prometheus};
 pub struct Metrics(Option<MetricsInner>);
 
 impl Metrics {
+	/// Whether the metrics are registered, i.e. whether there is any point in collecting them.
+	pub(crate) fn is_enabled(&self) -> bool {
+		self.0.is_some()
+	}
+
 	/// Returns a handle to submit prepare workers metrics.
 	pub(crate) fn prepare_worker(&'_ self) -> WorkerRelatedMetrics<'_> {
 		WorkerRelatedMetrics { metrics: self, flavor: WorkerFlavor::Prepare }
@@ -89,6 +97,11 @@ impl Metrics {
 				metrics.preparation_max_rss.observe(max_rss as f64);
 			}
 
+			#[cfg(target_os = "linux")]
+			if let Some(peak_swap) = memory_stats.peak_swap {
+				metrics.preparation_peak_swap.observe((peak_swap / 1024) as f64);
+			}
+
 			#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
 			if let Some(tracker_stats) = memory_stats.memory_tracker_stats {
 				// We convert these stats from B to KB to match the unit of `ru_maxrss` from
@@ -106,6 +119,28 @@ impl Metrics {
 		}
 	}
 
+	/// Observe the metrics a prepare worker sent along with the result of a job.
+	pub(crate) fn observe_prepare_worker_metrics(
+		&self,
+		worker_metrics: &WorkerMetrics,
+		result: &PrepareWorkerResult,
+	) {
+		if let Some(metrics) = &self.0 {
+			metrics
+				.prepare_worker_job_duration
+				.observe(worker_metrics.preparation_duration.as_secs_f64());
+			if let Some(artifact_size) = worker_metrics.artifact_size {
+				metrics.prepare_worker_artifact_size.observe(artifact_size as f64);
+			}
+			if let Some(max_rss) = worker_metrics.max_rss {
+				metrics.prepare_worker_job_max_rss.observe(max_rss as f64);
+			}
+			if let Err(err) = result {
+				metrics.prepare_worker_job_failures.with_label_values(&[err.kind_str()]).inc();
+			}
+		}
+	}
+
 	pub(crate) fn observe_code_size(&self, code_size: usize) {
 		if let Some(metrics) = &self.0 {
 			metrics.code_size.observe(code_size as f64);
@@ -136,6 +171,9 @@ struct MetricsInner {
 	execution_queued_time: prometheus::Histogram,
 	#[cfg(target_os = "linux")]
 	preparation_max_rss: prometheus::Histogram,
+	// Peak swap usage, sampled by the memory tracker. Any swapping degrades the preparation.
+	#[cfg(target_os = "linux")]
+	preparation_peak_swap: prometheus::Histogram,
 	// Max. allocated memory, tracked by Jemallocator, polling-based
 	#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
 	preparation_max_allocated: prometheus::Histogram,
@@ -146,6 +184,11 @@ struct MetricsInner {
 	preparation_peak_tracked_allocation: prometheus::Histogram,
 	pov_size: prometheus::HistogramVec,
 	code_size: prometheus::Histogram,
+	// Metrics sent by the prepare workers along with the results of their jobs
+	prepare_worker_job_duration: prometheus::Histogram,
+	prepare_worker_artifact_size: prometheus::Histogram,
+	prepare_worker_job_max_rss: prometheus::Histogram,
+	prepare_worker_job_failures: prometheus::CounterVec<prometheus::U64>,
 }
 
 impl metrics::Metrics for Metrics {
@@ -302,6 +345,24 @@ impl metrics::Metrics for Metrics {
 				)?,
 				registry,
 			)?,
+			#[cfg(target_os = "linux")]
+			preparation_peak_swap: prometheus::register(
+				prometheus::Histogram::with_opts(
+					prometheus::HistogramOpts::new(
+						"polkadot_pvf_preparation_peak_swap",
+						"Peak swap usage observed for preparation (in kilobytes)",
+					).buckets(
+						// Any swapping is worth noticing, so the first bucket only holds zero.
+						std::iter::once(0.0)
+							.chain(
+								prometheus::exponential_buckets(1024.0, 4.0, 8)
+									.expect("arguments are always valid; qed"),
+							)
+							.collect(),
+					),
+				)?,
+				registry,
+			)?,
 			#[cfg(any(target_os = "linux", feature = "jemalloc-allocator"))]
 			preparation_max_resident: prometheus::register(
 				prometheus::Histogram::with_opts(
@@ -369,6 +430,63 @@ impl metrics::Metrics for Metrics {
 				)?,
 				registry,
 			)?,
+			prepare_worker_job_duration: prometheus::register(
+				prometheus::Histogram::with_opts(
+					prometheus::HistogramOpts::new(
+						"polkadot_pvf_prepare_worker_job_duration",
+						"Wall clock time spent by prepare workers on a job, as reported by the workers (in seconds)",
+					).buckets(vec![
+						0.1,
+						0.5,
+						1.0,
+						2.0,
+						3.0,
+						10.0,
+						20.0,
+						30.0,
+						60.0,
+						120.0,
+						240.0,
+						360.0,
+						480.0,
+					]),
+				)?,
+				registry,
+			)?,
+			prepare_worker_artifact_size: prometheus::register(
+				prometheus::Histogram::with_opts(
+					prometheus::HistogramOpts::new(
+						"polkadot_pvf_prepare_worker_artifact_size",
+						"Size of the artifacts compiled by prepare workers (in bytes)",
+					).buckets(
+						prometheus::exponential_buckets(65536.0, 2.0, 10)
+							.expect("arguments are always valid; qed"),
+					),
+				)?,
+				registry,
+			)?,
+			prepare_worker_job_max_rss: prometheus::register(
+				prometheus::Histogram::with_opts(
+					prometheus::HistogramOpts::new(
+						"polkadot_pvf_prepare_worker_job_max_rss",
+						"ru_maxrss of prepare jobs, as reported by the workers (in kilobytes)",
+					).buckets(
+						prometheus::exponential_buckets(8192.0, 2.0, 10)
+							.expect("arguments are always valid; qed"),
+					),
+				)?,
+				registry,
+			)?,
+			prepare_worker_job_failures: prometheus::register(
+				prometheus::CounterVec::new(
+					prometheus::Opts::new(
+						"polkadot_pvf_prepare_worker_job_failures",
+						"The total number of failed prepare jobs, as reported by the workers",
+					),
+					&["kind"],
+				)?,
+				registry,
+			)?,
 		};
 		Ok(Metrics(Some(inner)))
 	}
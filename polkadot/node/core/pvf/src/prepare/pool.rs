@@ -115,6 +115,7 @@ struct Pool {
 	program_path: PathBuf,
 	cache_path: PathBuf,
 	spawn_timeout: Duration,
+	artifact_dir: Option<PathBuf>,
 	node_version: Option<String>,
 	security_status: SecurityStatus,
 
@@ -134,6 +135,7 @@ async fn run(
 		program_path,
 		cache_path,
 		spawn_timeout,
+		artifact_dir,
 		node_version,
 		security_status,
 		to_pool,
@@ -163,6 +165,7 @@ async fn run(
 					&program_path,
 					&cache_path,
 					spawn_timeout,
+					artifact_dir.as_deref(),
 					node_version.clone(),
 					security_status.clone(),
 					&mut spawned,
@@ -211,6 +214,7 @@ fn handle_to_pool(
 	program_path: &Path,
 	cache_path: &Path,
 	spawn_timeout: Duration,
+	artifact_dir: Option<&Path>,
 	node_version: Option<String>,
 	security_status: SecurityStatus,
 	spawned: &mut HopSlotMap<Worker, WorkerData>,
@@ -243,6 +247,7 @@ fn handle_to_pool(
 							idle,
 							pvf,
 							cache_path,
+							artifact_dir.map(Path::to_owned),
 							preparation_timer,
 						)
 						.boxed(),
@@ -304,9 +309,10 @@ async fn start_work_task<Timer>(
 	idle: IdleWorker,
 	pvf: PvfPrepData,
 	cache_path: PathBuf,
+	artifact_dir: Option<PathBuf>,
 	_preparation_timer: Option<Timer>,
 ) -> PoolEvent {
-	let outcome = worker_interface::start_work(&metrics, idle, pvf, cache_path).await;
+	let outcome = worker_interface::start_work(&metrics, idle, pvf, cache_path, artifact_dir).await;
 	PoolEvent::StartWork(worker, outcome)
 }
 
@@ -388,20 +394,31 @@ fn handle_mux(
 					Ok(())
 				},
 				// The worker might still be usable, but we kill it just in case.
-				Outcome::JobDied { err, job_pid } => {
+				Outcome::JobDied { err, job_pid, code_hash } => {
 					if attempt_retire(metrics, spawned, worker) {
 						reply(
 							from_pool,
 							FromPool::Concluded {
 								worker,
 								rip: true,
-								result: Err(PrepareError::JobDied { err, job_pid }),
+								result: Err(PrepareError::JobDied { err, job_pid, code_hash }),
 							},
 						)?;
 					}
 
 					Ok(())
 				},
+				// Same as above.
+				Outcome::JobSignaled(err) => {
+					if attempt_retire(metrics, spawned, worker) {
+						reply(
+							from_pool,
+							FromPool::Concluded { worker, rip: true, result: Err(err) },
+						)?;
+					}
+
+					Ok(())
+				},
 				Outcome::TimedOut => {
 					if attempt_retire(metrics, spawned, worker) {
 						reply(
@@ -498,6 +515,7 @@ pub fn start(
 	program_path: PathBuf,
 	cache_path: PathBuf,
 	spawn_timeout: Duration,
+	artifact_dir: Option<PathBuf>,
 	node_version: Option<String>,
 	security_status: SecurityStatus,
 ) -> (mpsc::Sender<ToPool>, mpsc::UnboundedReceiver<FromPool>, impl Future<Output = ()>) {
@@ -509,6 +527,7 @@ pub fn start(
 		program_path,
 		cache_path,
 		spawn_timeout,
+		artifact_dir,
 		node_version,
 		security_status,
 		to_pool: to_pool_rx,
@@ -28,11 +28,15 @@ use crate::{
 use codec::{Decode, Encode};
 use polkadot_node_core_pvf_common::{
 	error::{PrepareError, PrepareResult, PrepareWorkerResult},
-	prepare::{PrepareStats, PrepareSuccess, PrepareWorkerSuccess},
+	prepare::{
+		PrepareJobKind, PrepareSuccess, PrepareWorkerMessage, PrepareWorkerRequest,
+		PrepareWorkerSuccess, PROTOCOL_VERSION,
+	},
 	pvf::PvfPrepData,
 	worker_dir, SecurityStatus,
 };
 
+use polkadot_parachain_primitives::primitives::ValidationCodeHash;
 use sp_core::hexdisplay::HexDisplay;
 use std::{
 	path::{Path, PathBuf},
@@ -55,7 +59,7 @@ pub async fn spawn(
 		extra_args.extend_from_slice(&["--node-impl-version", node_version]);
 	}
 
-	spawn_with_program_path(
+	let (mut idle_worker, worker_handle) = spawn_with_program_path(
 		"prepare",
 		program_path,
 		cache_path,
@@ -63,7 +67,28 @@ pub async fn spawn(
 		spawn_timeout,
 		security_status,
 	)
-	.await
+	.await?;
+	negotiate_protocol_version(&mut idle_worker.stream).await.map_err(|error| {
+		gum::warn!(
+			target: LOG_TARGET,
+			worker_pid = %idle_worker.pid,
+			"failed to negotiate the protocol version with the spawned worker: {}",
+			error
+		);
+		SpawnErr::Handshake { err: error }
+	})?;
+	Ok((idle_worker, worker_handle))
+}
+
+/// Sends our [`PROTOCOL_VERSION`] to the worker and waits for it to confirm that it speaks it too.
+async fn negotiate_protocol_version(stream: &mut UnixStream) -> Result<(), String> {
+	framed_send(stream, &PROTOCOL_VERSION.encode())
+		.await
+		.map_err(|err| err.to_string())?;
+	let response = framed_recv(stream).await.map_err(|err| err.to_string())?;
+	let result = Result::<(), PrepareError>::decode(&mut response.as_slice())
+		.map_err(|err| format!("failed to decode the protocol version response: {}", err))?;
+	result.map_err(|err| err.to_string())
 }
 
 /// Outcome of PVF preparation.
@@ -103,9 +128,23 @@ pub enum Outcome {
 	/// The preparation job process died, due to OOM, a seccomp violation, or some other factor.
 	///
 	/// The worker might still be usable, but we kill it just in case.
-	JobDied { err: String, job_pid: i32 },
+	JobDied { err: String, job_pid: i32, code_hash: Option<ValidationCodeHash> },
+	/// The preparation job process was killed by a signal which hints at the cause, e.g. `SIGKILL`
+	/// sent by the OOM killer.
+	///
+	/// Like with [`Outcome::JobDied`], the worker might still be usable, but we kill it just in
+	/// case.
+	JobSignaled(PrepareError),
 }
 
+/// The number of heartbeat intervals without any message from the worker after which the worker is
+/// considered hung, if heartbeats were requested.
+const HEARTBEAT_MISS_FACTOR: u32 = 3;
+
+/// The size of the code, in bytes, from which on it is passed to the worker in a file rather than
+/// in the request, to spare copying large runtimes through the socket.
+const CODE_IN_FILE_THRESHOLD: usize = 1024 * 1024;
+
 /// Given the idle token of a worker and parameters of work, communicates with the worker and
 /// returns the outcome.
 ///
@@ -116,8 +155,10 @@ pub async fn start_work(
 	worker: IdleWorker,
 	pvf: PvfPrepData,
 	cache_path: PathBuf,
+	artifact_dir: Option<PathBuf>,
 ) -> Outcome {
 	let IdleWorker { stream, pid, worker_dir } = worker;
+	let pvf = if metrics.is_enabled() { pvf.with_worker_metrics() } else { pvf };
 
 	gum::debug!(
 		target: LOG_TARGET,
@@ -132,9 +173,9 @@ pub async fn start_work(
 		stream,
 		pid,
 		|tmp_artifact_file, mut stream, worker_dir| async move {
-			let preparation_timeout = pvf.prep_timeout();
+			let preparation_timeout = pvf.cpu_timeout();
 
-			if let Err(err) = send_request(&mut stream, &pvf).await {
+			if let Err(err) = send_probe_or_request(&mut stream, &pvf, &worker_dir).await {
 				gum::warn!(
 					target: LOG_TARGET,
 					worker_pid = %pid,
@@ -154,7 +195,11 @@ pub async fn start_work(
 			// time under load, but the CPU resources of the child can only be measured from the
 			// parent after the child process terminates.
 			let timeout = preparation_timeout * JOB_TIMEOUT_WALL_CLOCK_FACTOR;
-			let result = tokio::time::timeout(timeout, recv_response(&mut stream, pid)).await;
+			let result = tokio::time::timeout(
+				timeout,
+				recv_response(&mut stream, pid, &pvf, &worker_dir, metrics),
+			)
+			.await;
 
 			match result {
 				// Received bytes from worker within the time limit.
@@ -165,6 +210,7 @@ pub async fn start_work(
 						prepare_worker_result,
 						pid,
 						tmp_artifact_file,
+						artifact_dir.as_deref(),
 						&cache_path,
 						preparation_timeout,
 					)
@@ -204,32 +250,80 @@ async fn handle_response(
 	result: PrepareWorkerResult,
 	worker_pid: u32,
 	tmp_file: PathBuf,
+	artifact_dir: Option<&Path>,
 	cache_path: &Path,
 	preparation_timeout: Duration,
 ) -> Outcome {
+	// A failed job may report the memory stats it observed until it failed. Record them, and carry
+	// on with the actual error.
+	let result = match result {
+		Err(PrepareError::PreparationWithStats { stats, inner }) => {
+			metrics.observe_preparation_memory_metrics(stats);
+			Err(*inner)
+		},
+		result => result,
+	};
+
 	// TODO: Add `checksum` to `ArtifactPathId`. See:
 	//       https://github.com/paritytech/polkadot-sdk/issues/2399
-	let PrepareWorkerSuccess {
-		checksum: _,
-		stats: PrepareStats { cpu_time_elapsed, memory_stats, observed_wasm_code_len },
-	} = match result.clone() {
+	let PrepareWorkerSuccess { checksum: _, artifact_path, stats } = match result.clone() {
 		Ok(result) => result,
 		// Timed out on the child. This should already be logged by the child.
 		Err(PrepareError::TimedOut) => return Outcome::TimedOut,
-		Err(PrepareError::JobDied { err, job_pid }) => return Outcome::JobDied { err, job_pid },
+		Err(PrepareError::JobDied { err, job_pid, code_hash }) =>
+			return Outcome::JobDied { err, job_pid, code_hash },
+		Err(
+			err @ (PrepareError::JobKilled { .. } |
+			PrepareError::JobSegfault { .. } |
+			PrepareError::JobAborted { .. } |
+			PrepareError::SeccompViolation { .. }),
+		) => return Outcome::JobSignaled(err),
 		Err(PrepareError::OutOfMemory) => return Outcome::OutOfMemory,
 		Err(err) => return Outcome::Concluded { worker, result: Err(err) },
 	};
 
-	metrics.observe_code_size(observed_wasm_code_len as usize);
+	// Take the artifact from where the worker says it wrote it, e.g. if it was configured to write
+	// artifacts outside of its worker dir.
+	let tmp_file = match reported_artifact_path(
+		artifact_path,
+		tmp_file,
+		worker.worker_dir.path(),
+		artifact_dir,
+	) {
+		Ok(tmp_file) => tmp_file,
+		Err(err) => {
+			gum::warn!(target: LOG_TARGET, %worker_pid, "{}", err);
+			return Outcome::IoErr(err)
+		},
+	};
 
-	if cpu_time_elapsed > preparation_timeout {
+	metrics.observe_code_size(stats.observed_wasm_code_len as usize);
+
+	// Nothing to promote if the job only validated the code, e.g. for a
+	// [`PrepareJobKind::ValidateOnly`] precheck.
+	if !stats.artifact_produced {
+		return Outcome::Concluded {
+			worker,
+			result: Ok(PrepareSuccess { path: PathBuf::new(), size: 0, stats }),
+		}
+	}
+
+	if !stats.compiler_warnings.is_empty() {
+		gum::debug!(
+			target: LOG_TARGET,
+			%worker_pid,
+			"the compiler emitted warnings: {:?}",
+			stats.compiler_warnings,
+		);
+	}
+
+	if stats.cpu_time_elapsed > preparation_timeout {
 		// The job didn't complete within the timeout.
 		gum::warn!(
 			target: LOG_TARGET,
 			%worker_pid,
 			"prepare job took {}ms cpu time, exceeded preparation timeout {}ms. Clearing WIP artifact {}",
-			cpu_time_elapsed.as_millis(),
+			stats.cpu_time_elapsed.as_millis(),
 			preparation_timeout.as_millis(),
 			tmp_file.display(),
 		);
@@ -266,15 +360,7 @@ async fn handle_response(
 	let outcome = match tokio::fs::rename(&tmp_file, &artifact_path).await {
 		Ok(()) => Outcome::Concluded {
 			worker,
-			result: Ok(PrepareSuccess {
-				path: artifact_path,
-				size,
-				stats: PrepareStats {
-					cpu_time_elapsed,
-					memory_stats: memory_stats.clone(),
-					observed_wasm_code_len,
-				},
-			}),
+			result: Ok(PrepareSuccess { path: artifact_path, size, stats: stats.clone() }),
 		},
 		Err(err) => {
 			gum::warn!(
@@ -296,12 +382,35 @@ async fn handle_response(
 	};
 
 	// If there were no errors up until now, log the memory stats for a successful preparation, if
-	// available.
-	metrics.observe_preparation_memory_metrics(memory_stats);
+	// available. Artifacts from the worker's cache come without them.
+	if !stats.cache_hit {
+		metrics.observe_preparation_memory_metrics(stats.memory_stats);
+	}
 
 	outcome
 }
 
+/// Where to take the artifact from, given where the worker says it wrote it.
+///
+/// The worker is not trusted, so it may only point at the temporary artifact in its worker dir, or
+/// at its own file in the artifact dir the host was configured with. Otherwise a compromised worker
+/// could make the host move arbitrary files into the cache.
+fn reported_artifact_path(
+	reported: Option<String>,
+	tmp_file: PathBuf,
+	worker_dir_path: &Path,
+	artifact_dir: Option<&Path>,
+) -> Result<PathBuf, String> {
+	let Some(reported) = reported.map(PathBuf::from) else { return Ok(tmp_file) };
+	let in_artifact_dir =
+		artifact_dir.map(|dir| worker_dir::prepare_shared_tmp_artifact(dir, worker_dir_path));
+	if reported == tmp_file || Some(&reported) == in_artifact_dir.as_ref() {
+		Ok(reported)
+	} else {
+		Err(format!("the worker reported an unexpected artifact path: {}", reported.display()))
+	}
+}
+
 /// Create a temporary file for an artifact in the worker cache, execute the given future/closure
 /// passing the file path in, and clean up the worker cache.
 ///
@@ -352,26 +461,187 @@ where
 	outcome
 }
 
-async fn send_request(stream: &mut UnixStream, pvf: &PvfPrepData) -> io::Result<()> {
-	framed_send(stream, &pvf.encode()).await?;
+/// Sends a probing request without the code for compilation jobs, whose artifact the worker may
+/// have cached, see [`PrepareWorkerRequest::Probe`]. The full request is only sent if the worker
+/// asks for it, in [`recv_response`]. Other jobs are never served from the cache, so they are sent
+/// in full right away.
+async fn send_probe_or_request(
+	stream: &mut UnixStream,
+	pvf: &PvfPrepData,
+	worker_dir: &WorkerDir,
+) -> io::Result<()> {
+	if matches!(pvf.prep_kind(), PrepareJobKind::Compilation) && !pvf.runtime_construction_check() {
+		framed_send(stream, &PrepareWorkerRequest::Probe(pvf.without_code()).encode()).await
+	} else {
+		send_request(stream, pvf, worker_dir).await
+	}
+}
+
+/// Sends the request to the worker. Large code is written into the code file of the worker dir
+/// instead of being sent over the socket along with the request.
+async fn send_request(
+	stream: &mut UnixStream,
+	pvf: &PvfPrepData,
+	worker_dir: &WorkerDir,
+) -> io::Result<()> {
+	if pvf.maybe_compressed_code().len() < CODE_IN_FILE_THRESHOLD {
+		framed_send(stream, &PrepareWorkerRequest::Single(pvf.clone()).encode()).await?;
+		return Ok(())
+	}
+
+	let code_file = worker_dir::prepare_code(worker_dir.path());
+	tokio::fs::write(&code_file, pvf.maybe_compressed_code().as_slice()).await?;
+	framed_send(stream, &PrepareWorkerRequest::Single(pvf.without_code()).encode()).await?;
 	Ok(())
 }
 
-async fn recv_response(stream: &mut UnixStream, pid: u32) -> io::Result<PrepareWorkerResult> {
-	let result = framed_recv(stream).await?;
-	let result = PrepareWorkerResult::decode(&mut &result[..]).map_err(|e| {
-		// We received invalid bytes from the worker.
-		let bound_bytes = &result[..result.len().min(4)];
-		gum::warn!(
-			target: LOG_TARGET,
-			worker_pid = %pid,
-			"received unexpected response from the prepare worker: {}",
-			HexDisplay::from(&bound_bytes),
+/// Receives the result of the job, skipping any heartbeats sent before it. Metrics sent by the
+/// worker along with the result are submitted to `metrics`. If the worker asks for the code of a
+/// probing request, the full request is sent.
+///
+/// If the PVF has a heartbeat interval, the worker is considered hung once it misses
+/// [`HEARTBEAT_MISS_FACTOR`] heartbeats in a row.
+async fn recv_response(
+	stream: &mut UnixStream,
+	pid: u32,
+	pvf: &PvfPrepData,
+	worker_dir: &WorkerDir,
+	metrics: &Metrics,
+) -> io::Result<PrepareWorkerResult> {
+	let heartbeat_interval = pvf.heartbeat_interval();
+	let mut worker_metrics = None;
+	loop {
+		let message = match heartbeat_interval {
+			Some(interval) =>
+				tokio::time::timeout(interval * HEARTBEAT_MISS_FACTOR, framed_recv(stream))
+					.await
+					.map_err(|_| {
+						io::Error::new(
+							io::ErrorKind::TimedOut,
+							"prepare pvf recv_response: missed heartbeats from the worker",
+						)
+					})??,
+			None => framed_recv(stream).await?,
+		};
+		let message = PrepareWorkerMessage::decode(&mut &message[..]).map_err(|e| {
+			// We received invalid bytes from the worker.
+			let bound_bytes = &message[..message.len().min(4)];
+			gum::warn!(
+				target: LOG_TARGET,
+				worker_pid = %pid,
+				"received unexpected response from the prepare worker: {}",
+				HexDisplay::from(&bound_bytes),
+			);
+			io::Error::new(
+				io::ErrorKind::Other,
+				format!("prepare pvf recv_response: failed to decode result: {:?}", e),
+			)
+		})?;
+
+		match message {
+			PrepareWorkerMessage::Heartbeat => {
+				gum::trace!(target: LOG_TARGET, worker_pid = %pid, "received heartbeat");
+			},
+			PrepareWorkerMessage::Metrics(m) => worker_metrics = Some(m),
+			PrepareWorkerMessage::BusyHint(busy_hint) => {
+				gum::trace!(target: LOG_TARGET, worker_pid = %pid, ?busy_hint, "received busy hint");
+			},
+			PrepareWorkerMessage::CodeRequested => {
+				gum::trace!(target: LOG_TARGET, worker_pid = %pid, "artifact not cached, sending code");
+				send_request(stream, pvf, worker_dir).await?;
+			},
+			// The host sends one request at a time, so it does not need request ids.
+			PrepareWorkerMessage::Result { request_id: _, reached_stage, result } => {
+				gum::trace!(target: LOG_TARGET, worker_pid = %pid, ?reached_stage, "received result");
+				if let Some(worker_metrics) = worker_metrics {
+					metrics.observe_prepare_worker_metrics(&worker_metrics, &result);
+				}
+				return Ok(result)
+			},
+			PrepareWorkerMessage::BatchResult(_) =>
+				return Err(io::Error::new(
+					io::ErrorKind::Other,
+					"prepare pvf recv_response: unexpected batch result for a single request",
+				)),
+			PrepareWorkerMessage::ResetDone =>
+				return Err(io::Error::new(
+					io::ErrorKind::Other,
+					"prepare pvf recv_response: unexpected reset acknowledgement",
+				)),
+			PrepareWorkerMessage::SecurityStatus(_) =>
+				return Err(io::Error::new(
+					io::ErrorKind::Other,
+					"prepare pvf recv_response: unexpected security status",
+				)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn artifact_is_taken_from_the_host_tmp_file_by_default() {
+		let worker_dir = Path::new("/cache/worker-dir-prepare-1");
+		let tmp_file = worker_dir::prepare_tmp_artifact(worker_dir);
+
+		assert_eq!(reported_artifact_path(None, tmp_file.clone(), worker_dir, None), Ok(tmp_file));
+	}
+
+	#[test]
+	fn artifact_is_taken_from_the_configured_artifact_dir() {
+		let worker_dir = Path::new("/cache/worker-dir-prepare-1");
+		let artifact_dir = Path::new("/artifacts");
+		let tmp_file = worker_dir::prepare_tmp_artifact(worker_dir);
+		let reported = worker_dir::prepare_shared_tmp_artifact(artifact_dir, worker_dir);
+
+		assert_eq!(
+			reported_artifact_path(
+				Some(reported.to_str().unwrap().to_owned()),
+				tmp_file,
+				worker_dir,
+				Some(artifact_dir)
+			),
+			Ok(reported)
+		);
+	}
+
+	#[test]
+	fn unexpected_artifact_paths_are_rejected() {
+		let worker_dir = Path::new("/cache/worker-dir-prepare-1");
+		let artifact_dir = Path::new("/artifacts");
+		let tmp_file = worker_dir::prepare_tmp_artifact(worker_dir);
+		let other_worker_artifact = worker_dir::prepare_shared_tmp_artifact(
+			artifact_dir,
+			Path::new("/cache/worker-dir-prepare-2"),
 		);
-		io::Error::new(
-			io::ErrorKind::Other,
-			format!("prepare pvf recv_response: failed to decode result: {:?}", e),
+
+		for reported in [
+			"/etc/passwd".into(),
+			"/cache/worker-dir-prepare-1/../some-artifact".into(),
+			other_worker_artifact.clone(),
+		] {
+			assert!(reported_artifact_path(
+				Some(reported.to_str().unwrap().to_owned()),
+				tmp_file.clone(),
+				worker_dir,
+				Some(artifact_dir)
+			)
+			.is_err());
+		}
+		// Without a configured artifact dir, the host doesn't look anywhere but the worker dir.
+		assert!(reported_artifact_path(
+			Some(
+				worker_dir::prepare_shared_tmp_artifact(artifact_dir, worker_dir)
+					.to_str()
+					.unwrap()
+					.to_owned()
+			),
+			tmp_file,
+			worker_dir,
+			None
 		)
-	})?;
-	Ok(result)
+		.is_err());
+	}
 }
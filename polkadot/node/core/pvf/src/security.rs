@@ -32,11 +32,12 @@ use std::{fmt, path::Path};
 pub async fn check_security_status(config: &Config) -> Result<SecurityStatus, String> {
 	let Config { prepare_worker_program_path, secure_validator_mode, cache_path, .. } = config;
 
-	let (landlock, seccomp, change_root, secure_clone) = join!(
+	let (landlock, seccomp, change_root, secure_clone, unshare_namespaces) = join!(
 		check_landlock(prepare_worker_program_path),
 		check_seccomp(prepare_worker_program_path),
 		check_can_unshare_user_namespace_and_change_root(prepare_worker_program_path, cache_path),
 		check_can_do_secure_clone(prepare_worker_program_path),
+		check_can_unshare_network_and_mount_namespaces(prepare_worker_program_path),
 	);
 
 	let full_security_status = FullSecurityStatus::new(
@@ -45,6 +46,7 @@ pub async fn check_security_status(config: &Config) -> Result<SecurityStatus, St
 		seccomp,
 		change_root,
 		secure_clone,
+		unshare_namespaces,
 	);
 	let security_status = full_security_status.as_partial();
 
@@ -80,6 +82,7 @@ impl FullSecurityStatus {
 		seccomp: SecureModeResult,
 		change_root: SecureModeResult,
 		secure_clone: SecureModeResult,
+		unshare_namespaces: SecureModeResult,
 	) -> Self {
 		Self {
 			partial: SecurityStatus {
@@ -88,8 +91,9 @@ impl FullSecurityStatus {
 				can_enable_seccomp: seccomp.is_ok(),
 				can_unshare_user_namespace_and_change_root: change_root.is_ok(),
 				can_do_secure_clone: secure_clone.is_ok(),
+				can_unshare_network_and_mount_namespaces: unshare_namespaces.is_ok(),
 			},
-			errs: [landlock, seccomp, change_root, secure_clone]
+			errs: [landlock, seccomp, change_root, secure_clone, unshare_namespaces]
 				.into_iter()
 				.filter_map(|result| result.err())
 				.collect(),
@@ -132,6 +136,7 @@ enum SecureModeError {
 	CannotEnableSeccomp(String),
 	CannotUnshareUserNamespaceAndChangeRoot(String),
 	CannotDoSecureClone(String),
+	CannotUnshareNetworkAndMountNamespaces(String),
 }
 
 impl SecureModeError {
@@ -151,6 +156,9 @@ impl SecureModeError {
 			// We have not determined the kernel requirements for this capability, and it's also not
 			// necessary for FS or networking restrictions.
 			CannotDoSecureClone(_) => true,
+			// Jobs fall back to the namespaces of the worker, and seccomp already blocks
+			// networking.
+			CannotUnshareNetworkAndMountNamespaces(_) => true,
 		}
 	}
 }
@@ -163,6 +171,7 @@ impl fmt::Display for SecureModeError {
 			CannotEnableSeccomp(err) => write!(f, "Cannot enable seccomp, a Linux-specific kernel security feature: {err}"),
 			CannotUnshareUserNamespaceAndChangeRoot(err) => write!(f, "Cannot unshare user namespace and change root, which are Linux-specific kernel security features: {err}"),
 			CannotDoSecureClone(err) => write!(f, "Cannot call clone with all sandboxing flags, a Linux-specific kernel security features: {err}"),
+			CannotUnshareNetworkAndMountNamespaces(err) => write!(f, "Cannot unshare network and mount namespaces for prepare jobs, which are Linux-specific kernel security features: {err}"),
 		}
 	}
 }
@@ -272,6 +281,23 @@ async fn check_can_do_secure_clone(prepare_worker_program_path: &Path) -> Secure
 	.map_err(|err| SecureModeError::CannotDoSecureClone(err))
 }
 
+/// Check if prepare jobs can unshare the network and mount namespaces, and return an error if not.
+///
+/// We do this check by spawning a new process and trying to sandbox it. To get as close as possible
+/// to running the check in a worker, we try it... in a worker. The expected return status is 0 on
+/// success and -1 on failure.
+async fn check_can_unshare_network_and_mount_namespaces(
+	prepare_worker_program_path: &Path,
+) -> SecureModeResult {
+	spawn_process_for_security_check(
+		prepare_worker_program_path,
+		"--check-can-unshare-network-and-mount-namespaces",
+		std::iter::empty::<&str>(),
+	)
+	.await
+	.map_err(|err| SecureModeError::CannotUnshareNetworkAndMountNamespaces(err))
+}
+
 async fn spawn_process_for_security_check<I, S>(
 	prepare_worker_program_path: &Path,
 	check_arg: &'static str,
@@ -319,6 +345,7 @@ mod tests {
 			can_enable_seccomp: false,
 			can_unshare_user_namespace_and_change_root: true,
 			can_do_secure_clone: true,
+			can_unshare_network_and_mount_namespaces: false,
 		}));
 		assert!(!err.is_allowed_in_secure_mode(&SecurityStatus {
 			secure_validator_mode: true,
@@ -326,6 +353,7 @@ mod tests {
 			can_enable_seccomp: true,
 			can_unshare_user_namespace_and_change_root: false,
 			can_do_secure_clone: false,
+			can_unshare_network_and_mount_namespaces: false,
 		}));
 
 		let err = SecureModeError::CannotEnableSeccomp(String::new());
@@ -335,6 +363,7 @@ mod tests {
 			can_enable_seccomp: false,
 			can_unshare_user_namespace_and_change_root: true,
 			can_do_secure_clone: true,
+			can_unshare_network_and_mount_namespaces: false,
 		}));
 		assert!(!err.is_allowed_in_secure_mode(&SecurityStatus {
 			secure_validator_mode: true,
@@ -342,6 +371,7 @@ mod tests {
 			can_enable_seccomp: true,
 			can_unshare_user_namespace_and_change_root: false,
 			can_do_secure_clone: false,
+			can_unshare_network_and_mount_namespaces: false,
 		}));
 
 		let err = SecureModeError::CannotUnshareUserNamespaceAndChangeRoot(String::new());
@@ -351,6 +381,7 @@ mod tests {
 			can_enable_seccomp: false,
 			can_unshare_user_namespace_and_change_root: false,
 			can_do_secure_clone: false,
+			can_unshare_network_and_mount_namespaces: false,
 		}));
 		assert!(!err.is_allowed_in_secure_mode(&SecurityStatus {
 			secure_validator_mode: true,
@@ -358,6 +389,7 @@ mod tests {
 			can_enable_seccomp: true,
 			can_unshare_user_namespace_and_change_root: false,
 			can_do_secure_clone: false,
+			can_unshare_network_and_mount_namespaces: false,
 		}));
 
 		let err = SecureModeError::CannotDoSecureClone(String::new());
@@ -367,6 +399,7 @@ mod tests {
 			can_enable_seccomp: true,
 			can_unshare_user_namespace_and_change_root: true,
 			can_do_secure_clone: true,
+			can_unshare_network_and_mount_namespaces: false,
 		}));
 		assert!(err.is_allowed_in_secure_mode(&SecurityStatus {
 			secure_validator_mode: false,
@@ -374,6 +407,17 @@ mod tests {
 			can_enable_seccomp: false,
 			can_unshare_user_namespace_and_change_root: false,
 			can_do_secure_clone: false,
+			can_unshare_network_and_mount_namespaces: false,
+		}));
+
+		let err = SecureModeError::CannotUnshareNetworkAndMountNamespaces(String::new());
+		assert!(err.is_allowed_in_secure_mode(&SecurityStatus {
+			secure_validator_mode: true,
+			can_enable_landlock: true,
+			can_enable_seccomp: true,
+			can_unshare_user_namespace_and_change_root: true,
+			can_do_secure_clone: true,
+			can_unshare_network_and_mount_namespaces: false,
 		}));
 	}
 }
@@ -18,7 +18,7 @@
 
 pub use crate::{
 	host::{EXECUTE_BINARY_NAME, PREPARE_BINARY_NAME},
-	worker_interface::{spawn_with_program_path, SpawnErr},
+	worker_interface::{spawn_with_program_path, IdleWorker, SpawnErr, WorkerHandle},
 };
 
 use crate::{artifacts::ArtifactId, get_worker_version};
@@ -43,9 +43,9 @@ pub fn validate_candidate(
 	let code = sp_maybe_compressed_blob::decompress(code, 10 * 1024 * 1024)
 		.expect("Decompressing code failed");
 
-	let blob = prevalidate(&code)?;
 	let executor_params = ExecutorParams::default();
-	let compiled_artifact_blob = prepare(blob, &executor_params)?;
+	let blob = prevalidate(&code, &executor_params)?;
+	let compiled_artifact_blob = prepare(blob, &executor_params)?.artifact;
 
 	let result = unsafe {
 		// SAFETY: This is trivially safe since the artifact is obtained by calling `prepare`
@@ -21,7 +21,7 @@ use codec::Encode;
 use futures::FutureExt as _;
 use futures_timer::Delay;
 use pin_project::pin_project;
-use polkadot_node_core_pvf_common::{SecurityStatus, WorkerHandshake};
+use polkadot_node_core_pvf_common::{worker::socket_path_to_arg, SecurityStatus, WorkerHandshake};
 use rand::Rng;
 use std::{
 	fmt, mem,
@@ -245,7 +245,7 @@ impl WorkerHandle {
 		let mut child = command
 			.args(extra_args)
 			.arg("--socket-path")
-			.arg(socket_path.as_ref().as_os_str())
+			.arg(socket_path_to_arg(socket_path.as_ref()))
 			.arg("--worker-dir-path")
 			.arg(worker_dir_path.as_ref().as_os_str())
 			.stdout(std::process::Stdio::piped())
@@ -282,6 +282,11 @@ impl WorkerHandle {
 	pub fn id(&self) -> u32 {
 		self.child_id
 	}
+
+	/// Waits for the worker to exit and returns its exit status.
+	pub async fn wait(mut self) -> io::Result<std::process::ExitStatus> {
+		self.child.wait().await
+	}
 }
 
 impl futures::Future for WorkerHandle {
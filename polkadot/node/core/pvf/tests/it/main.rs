@@ -35,6 +35,7 @@ use std::{io::Write, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 
 mod adder;
+mod prepare_worker;
 #[cfg(target_os = "linux")]
 mod process;
 mod worker_common;
@@ -80,6 +81,16 @@ impl TestHost {
 		&self,
 		code: &[u8],
 		executor_params: ExecutorParams,
+	) -> Result<(), PrepareError> {
+		self.precheck_pvf_with_kind(code, executor_params, PrepareJobKind::Prechecking)
+			.await
+	}
+
+	async fn precheck_pvf_with_kind(
+		&self,
+		code: &[u8],
+		executor_params: ExecutorParams,
+		prep_kind: PrepareJobKind,
 	) -> Result<(), PrepareError> {
 		let (result_tx, result_rx) = futures::channel::oneshot::channel();
 
@@ -91,7 +102,7 @@ impl TestHost {
 					code.into(),
 					executor_params,
 					TEST_PREPARATION_TIMEOUT,
-					PrepareJobKind::Prechecking,
+					prep_kind,
 				),
 				result_tx,
 			)
@@ -321,6 +332,35 @@ async fn execute_queue_doesnt_stall_with_varying_executor_params() {
 	);
 }
 
+// Test that prechecks which only validate the code don't leave an artifact behind, neither on disk
+// nor in the artifacts table.
+#[tokio::test]
+async fn validate_only_precheck_does_not_promote_an_artifact() {
+	let host = TestHost::new().await;
+	let cache_dir = host.cache_dir.path();
+	let artifacts_in_cache = || {
+		std::fs::read_dir(cache_dir)
+			.unwrap()
+			.filter(|entry| entry.as_ref().unwrap().path().is_file())
+			.count()
+	};
+
+	host.precheck_pvf_with_kind(
+		test_parachain_halt::wasm_binary_unwrap(),
+		Default::default(),
+		PrepareJobKind::ValidateOnly,
+	)
+	.await
+	.unwrap();
+	assert_eq!(artifacts_in_cache(), 0);
+
+	// The artifact is prepared once actually needed.
+	host.precheck_pvf(test_parachain_halt::wasm_binary_unwrap(), Default::default())
+		.await
+		.unwrap();
+	assert_eq!(artifacts_in_cache(), 1);
+}
+
 // Test that deleting a prepared artifact does not lead to a dispute when we try to execute it.
 #[tokio::test]
 async fn deleting_prepared_artifact_does_not_dispute() {
@@ -547,6 +587,7 @@ async fn all_security_features_work() {
 			can_enable_seccomp: true,
 			can_unshare_user_namespace_and_change_root: true,
 			can_do_secure_clone: true,
+			can_unshare_network_and_mount_namespaces: true,
 		}
 	);
 }
@@ -0,0 +1,1342 @@
+// Copyright (C) Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tests talking to the prepare worker directly, bypassing the host's queue and pool.
+
+use super::TEST_PREPARATION_TIMEOUT;
+use assert_matches::assert_matches;
+use codec::{Decode, Encode};
+use polkadot_node_core_pvf::{
+	framed_recv, framed_send,
+	testing::{build_workers_and_get_paths, spawn_with_program_path, IdleWorker, WorkerHandle},
+	PrepareJobKind, PvfPrepData, SecurityStatus,
+};
+use polkadot_node_core_pvf_common::{
+	error::{PrepareError, PrepareWorkerResult},
+	prepare::{
+		AppliedSecurity, BusyHint, MemoryAllocator, PrepareStage, PrepareWorkerMessage,
+		PrepareWorkerRequest, PrepareWorkerSuccess, WorkerMetrics, PROTOCOL_VERSION,
+	},
+	worker::{pipe2_cloexec, send_fd, PipeFd},
+	worker_dir,
+};
+use polkadot_primitives::{ExecutorParam, ExecutorParams};
+use std::{
+	io::Read,
+	os::fd::{AsFd, FromRawFd, OwnedFd},
+	path::Path,
+	sync::Arc,
+	time::Duration,
+};
+
+const SPAWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn adder_pvf(prep_kind: PrepareJobKind) -> PvfPrepData {
+	PvfPrepData::from_code(
+		test_parachain_adder::wasm_binary_unwrap().to_vec(),
+		ExecutorParams::default(),
+		TEST_PREPARATION_TIMEOUT,
+		prep_kind,
+	)
+}
+
+/// Sends the given protocol version to a freshly spawned worker, like the host does, and returns
+/// its response.
+async fn negotiate_protocol_version(
+	stream: &mut tokio::net::UnixStream,
+	version: u32,
+) -> Result<(), PrepareError> {
+	framed_send(stream, &version.encode()).await.unwrap();
+	let response = framed_recv(stream).await.unwrap();
+	Result::<(), PrepareError>::decode(&mut &response[..]).unwrap()
+}
+
+/// Sends a request to a spawned worker and returns the result, along with the artifact, which is
+/// moved out of the worker dir like the host does.
+async fn prepare_and_take_artifact(
+	stream: &mut tokio::net::UnixStream,
+	tmp_artifact: &Path,
+	pvf: PvfPrepData,
+) -> (PrepareWorkerSuccess, Vec<u8>) {
+	std::fs::File::create(tmp_artifact).unwrap();
+	framed_send(stream, &PrepareWorkerRequest::Single(pvf).encode()).await.unwrap();
+	let message = framed_recv(stream).await.unwrap();
+	let success = match PrepareWorkerMessage::decode(&mut &message[..]).unwrap() {
+		PrepareWorkerMessage::Result { result, .. } => result.unwrap(),
+		PrepareWorkerMessage::Heartbeat => panic!("heartbeats were not requested"),
+		PrepareWorkerMessage::Metrics(_) => panic!("metrics were not requested"),
+		PrepareWorkerMessage::BatchResult(_) => panic!("no batch was requested"),
+		PrepareWorkerMessage::ResetDone => panic!("no reset was requested"),
+		PrepareWorkerMessage::BusyHint(_) => panic!("no busy hint was requested"),
+		PrepareWorkerMessage::CodeRequested => panic!("no probe was sent"),
+		PrepareWorkerMessage::SecurityStatus(_) => panic!("no security status was queried"),
+	};
+	let artifact = std::fs::read(tmp_artifact).unwrap();
+	std::fs::remove_file(tmp_artifact).unwrap();
+	(success, artifact)
+}
+
+/// Spawns a prepare worker and negotiates the protocol version with it, like the host does.
+///
+/// The returned dir holds the worker dir, so it must be kept alive for as long as the worker.
+async fn spawn_prepare_worker(
+	security_status: SecurityStatus,
+) -> (IdleWorker, WorkerHandle, tempfile::TempDir) {
+	let (mut worker, handle, cache_dir) =
+		spawn_prepare_worker_with_args(&[], security_status).await;
+	negotiate_protocol_version(&mut worker.stream, PROTOCOL_VERSION).await.unwrap();
+	(worker, handle, cache_dir)
+}
+
+/// Like [`spawn_prepare_worker`], but passes extra arguments to the worker and leaves the
+/// protocol version negotiation to the caller.
+async fn spawn_prepare_worker_with_args(
+	extra_args: &[&str],
+	security_status: SecurityStatus,
+) -> (IdleWorker, WorkerHandle, tempfile::TempDir) {
+	let (prepare_worker_path, _) = build_workers_and_get_paths();
+	let cache_dir = tempfile::tempdir().unwrap();
+	let args: Vec<&str> =
+		["prepare-worker"].into_iter().chain(extra_args.iter().copied()).collect();
+	let (worker, handle) = spawn_with_program_path(
+		"integration-test",
+		prepare_worker_path,
+		cache_dir.path(),
+		&args,
+		SPAWN_TIMEOUT,
+		security_status,
+	)
+	.await
+	.unwrap();
+	(worker, handle, cache_dir)
+}
+
+/// Spawns a prepare worker, sends it a single request and returns the result.
+async fn prepare_with_worker(pvf: PvfPrepData) -> PrepareWorkerResult {
+	prepare_with_worker_receiving_all(pvf).await.result
+}
+
+/// All the messages received from the worker for a single job.
+struct ReceivedMessages {
+	/// The number of heartbeats received before the result.
+	heartbeats: usize,
+	/// The metrics received before the result, if any.
+	metrics: Option<WorkerMetrics>,
+	/// The busy hint received before the result, if any.
+	busy_hint: Option<BusyHint>,
+	/// The request id echoed back with the result.
+	request_id: Option<u64>,
+	/// The last stage the job reached, sent with the result.
+	reached_stage: Option<PrepareStage>,
+	result: PrepareWorkerResult,
+}
+
+/// Like [`prepare_with_worker`], but also returns the other messages received before the result.
+async fn prepare_with_worker_receiving_all(pvf: PvfPrepData) -> ReceivedMessages {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	// Like the host, create the temporary artifact file before sending the request.
+	std::fs::File::create(worker_dir::prepare_tmp_artifact(worker.worker_dir.path())).unwrap();
+
+	framed_send(&mut worker.stream, &PrepareWorkerRequest::Single(pvf).encode())
+		.await
+		.unwrap();
+	let mut heartbeats = 0;
+	let mut metrics = None;
+	let mut busy_hint = None;
+	loop {
+		let message = framed_recv(&mut worker.stream).await.unwrap();
+		match PrepareWorkerMessage::decode(&mut &message[..]).unwrap() {
+			PrepareWorkerMessage::Heartbeat => heartbeats += 1,
+			PrepareWorkerMessage::Metrics(m) => {
+				assert!(metrics.replace(m).is_none(), "metrics should be sent once per job");
+			},
+			PrepareWorkerMessage::BusyHint(hint) => {
+				assert!(
+					busy_hint.replace(hint).is_none(),
+					"busy hints should be sent once per job"
+				);
+			},
+			PrepareWorkerMessage::Result { request_id, reached_stage, result } =>
+				return ReceivedMessages {
+					heartbeats,
+					metrics,
+					busy_hint,
+					request_id,
+					reached_stage,
+					result,
+				},
+			PrepareWorkerMessage::BatchResult(_) => panic!("no batch was requested"),
+			PrepareWorkerMessage::ResetDone => panic!("no reset was requested"),
+			PrepareWorkerMessage::CodeRequested => panic!("no probe was sent"),
+			PrepareWorkerMessage::SecurityStatus(_) => panic!("no security status was queried"),
+		}
+	}
+}
+
+#[tokio::test]
+async fn benchmark_job_reports_stage_timings() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Benchmark)).await.unwrap();
+
+	let stage_timings = success
+		.stats
+		.stage_timings
+		.expect("benchmark jobs should collect stage timings");
+	assert!(!stage_timings.prevalidation.is_zero());
+	assert!(!stage_timings.preparation.is_zero());
+	assert!(!stage_timings.artifact_write.is_zero());
+}
+
+#[tokio::test]
+async fn compilation_job_does_not_report_stage_timings() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+
+	assert!(success.stats.stage_timings.is_none());
+}
+
+#[tokio::test]
+async fn compile_cpu_time_is_part_of_the_total() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+
+	let stats = success.stats;
+	assert!(!stats.compile_cpu_time.is_zero());
+	assert!(
+		stats.compile_cpu_time <= stats.cpu_time_elapsed,
+		"{:?} > {:?}",
+		stats.compile_cpu_time,
+		stats.cpu_time_elapsed
+	);
+}
+
+#[tokio::test]
+async fn streamed_artifact_matches_the_artifact_sent_as_a_whole() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+	let streamed =
+		prepare_with_worker(adder_pvf(PrepareJobKind::Compilation).with_artifact_streaming())
+			.await
+			.unwrap();
+
+	assert!(streamed.stats.artifact_produced);
+	assert_eq!(streamed.stats.compiled_artifact_size, success.stats.compiled_artifact_size);
+	assert_eq!(streamed.checksum, success.checksum);
+}
+
+#[tokio::test]
+async fn fork_latency_is_reported() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+
+	let fork_latency = success.stats.fork_latency;
+	assert!(!fork_latency.is_zero());
+	assert!(fork_latency < TEST_PREPARATION_TIMEOUT, "{:?}", fork_latency);
+}
+
+#[tokio::test]
+async fn validate_only_job_does_not_produce_an_artifact() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::ValidateOnly)).await.unwrap();
+	assert!(!success.stats.artifact_produced);
+	assert!(success.stats.observed_wasm_code_len > 0);
+
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+	assert!(success.stats.artifact_produced);
+}
+
+#[tokio::test]
+async fn verify_determinism_job_accepts_a_reproducible_compile() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::VerifyDeterminism)).await.unwrap();
+	assert!(success.stats.artifact_produced);
+}
+
+/// Returns a PVF that compiles, but whose runtime can't be constructed, as the host only provides
+/// imports from the `env` module:
+///
+/// ```wat
+/// (module
+///   (import "other" "f" (func))
+///   (memory 1))
+/// ```
+fn unconstructible_pvf() -> PvfPrepData {
+	let sections: [&[u8]; 4] = [
+		// Magic and version.
+		b"\0asm\x01\0\0\0",
+		// Type section: `() -> ()`.
+		b"\x01\x04\x01\x60\x00\x00",
+		// Import section: `"other" "f"`, a function of type 0.
+		b"\x02\x0b\x01\x05other\x01f\x00\x00",
+		// Memory section: one memory of 1 page.
+		b"\x05\x03\x01\x00\x01",
+	];
+	PvfPrepData::from_code(
+		sections.concat(),
+		ExecutorParams::default(),
+		TEST_PREPARATION_TIMEOUT,
+		PrepareJobKind::Compilation,
+	)
+}
+
+fn adder_pvf_with_max_artifact_size(max_artifact_size: u64) -> PvfPrepData {
+	adder_pvf(PrepareJobKind::Compilation).with_executor_params(Arc::new(ExecutorParams::from(
+		&[ExecutorParam::MaxArtifactSize(max_artifact_size)][..],
+	)))
+}
+
+#[tokio::test]
+async fn artifact_over_the_size_limit_fails_preparation() {
+	let result = prepare_with_worker(adder_pvf_with_max_artifact_size(1)).await;
+
+	assert_matches!(result, Err(PrepareError::ArtifactTooLarge { limit: 1, .. }));
+}
+
+#[tokio::test]
+async fn artifact_under_the_size_limit_is_prepared() {
+	let success = prepare_with_worker(adder_pvf_with_max_artifact_size(u64::MAX)).await.unwrap();
+
+	assert!(success.stats.artifact_produced);
+}
+
+#[tokio::test]
+async fn worker_prevalidates_code_vouched_for_by_an_untrusted_host() {
+	// The params reject the code in prevalidation only. The worker binary doesn't trust its host.
+	let pvf = adder_pvf(PrepareJobKind::Compilation).with_executor_params(Arc::new(
+		ExecutorParams::from(&[ExecutorParam::MaxDeclaredMemoryPages(1)][..]),
+	));
+	let result = prepare_with_worker(pvf.with_prevalidated_code()).await;
+
+	assert_matches!(result, Err(PrepareError::MemoryPagesExceeded { limit: 1, .. }));
+}
+
+#[tokio::test]
+async fn compilation_job_skips_the_runtime_construction_check_by_default() {
+	let success = prepare_with_worker(unconstructible_pvf()).await.unwrap();
+	assert!(success.stats.artifact_produced);
+}
+
+#[tokio::test]
+async fn compilation_job_runs_the_runtime_construction_check_on_demand() {
+	let result = prepare_with_worker(unconstructible_pvf().with_runtime_construction_check()).await;
+
+	assert_matches!(
+		result,
+		Err(PrepareError::PreparationWithStats { inner, .. })
+			if matches!(*inner, PrepareError::RuntimeConstruction(_))
+	);
+}
+
+#[tokio::test]
+async fn validate_only_job_rejects_invalid_code() {
+	let pvf = PvfPrepData::from_code(
+		b"definitely not wasm".to_vec(),
+		ExecutorParams::default(),
+		TEST_PREPARATION_TIMEOUT,
+		PrepareJobKind::ValidateOnly,
+	);
+
+	let result = prepare_with_worker(pvf).await;
+
+	assert_matches!(
+		result,
+		Err(PrepareError::PreparationWithStats { inner, .. })
+			if matches!(*inner, PrepareError::Prevalidation(_))
+	);
+}
+
+#[tokio::test]
+async fn failed_prepare_reports_memory_stats() {
+	let pvf = PvfPrepData::from_code(
+		b"definitely not wasm".to_vec(),
+		ExecutorParams::default(),
+		TEST_PREPARATION_TIMEOUT,
+		PrepareJobKind::Compilation,
+	);
+
+	let result = prepare_with_worker(pvf).await;
+
+	let stats = assert_matches!(
+		result,
+		Err(PrepareError::PreparationWithStats { stats, inner }) => {
+			assert_matches!(*inner, PrepareError::Prevalidation(_));
+			stats
+		}
+	);
+	assert!(stats.peak_tracked_alloc > 0);
+	#[cfg(target_os = "linux")]
+	assert!(stats.max_rss.expect("ru_maxrss is supported on Linux") > 0);
+}
+
+#[tokio::test]
+async fn batch_results_arrive_in_order() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	let invalid_pvf = PvfPrepData::from_code(
+		b"definitely not wasm".to_vec(),
+		ExecutorParams::default(),
+		TEST_PREPARATION_TIMEOUT,
+		PrepareJobKind::Compilation,
+	);
+	let request =
+		PrepareWorkerRequest::Batch(vec![adder_pvf(PrepareJobKind::Compilation), invalid_pvf]);
+	framed_send(&mut worker.stream, &request.encode()).await.unwrap();
+
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	let results = match PrepareWorkerMessage::decode(&mut &message[..]).unwrap() {
+		PrepareWorkerMessage::BatchResult(results) => results,
+		_ => panic!("expected the results of the batch"),
+	};
+	assert_eq!(results.len(), 2);
+	assert_matches!(&results[0], Ok(success) if success.stats.artifact_produced);
+	assert_matches!(
+		&results[1],
+		Err(PrepareError::PreparationWithStats { inner, .. })
+			if matches!(**inner, PrepareError::Prevalidation(_))
+	);
+
+	// Only the successful job left an artifact behind.
+	assert!(worker_dir::prepare_tmp_batch_artifact(worker.worker_dir.path(), 0).exists());
+	assert!(!worker_dir::prepare_tmp_batch_artifact(worker.worker_dir.path(), 1).exists());
+}
+
+#[tokio::test]
+async fn artifact_is_written_to_the_fd_sent_with_the_request() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	let (pipe_read_fd, pipe_write_fd) = pipe2_cloexec().unwrap();
+	// SAFETY: both ends are open and owned from here on.
+	let (mut pipe_read, pipe_write) =
+		unsafe { (PipeFd::from_raw_fd(pipe_read_fd), OwnedFd::from_raw_fd(pipe_write_fd)) };
+	// The worker blocks writing to a full pipe, so it is read from while the job runs.
+	let reader = std::thread::spawn(move || {
+		let mut artifact = Vec::new();
+		pipe_read.read_to_end(&mut artifact).unwrap();
+		artifact
+	});
+
+	// Like the host, create the temporary artifact file the artifact is staged in.
+	std::fs::File::create(worker_dir::prepare_tmp_artifact(worker.worker_dir.path())).unwrap();
+	let request = PrepareWorkerRequest::SingleToFd(adder_pvf(PrepareJobKind::Compilation));
+	framed_send(&mut worker.stream, &request.encode()).await.unwrap();
+	send_fd(&worker.stream, pipe_write.as_fd()).unwrap();
+	// The pipe is closed once the worker is done with it.
+	drop(pipe_write);
+
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	let success = match PrepareWorkerMessage::decode(&mut &message[..]).unwrap() {
+		PrepareWorkerMessage::Result { result: Ok(success), .. } => success,
+		message => panic!("expected a successful result, got {:?}", message),
+	};
+	let artifact = reader.join().unwrap();
+
+	assert!(success.stats.artifact_produced);
+	assert_eq!(success.artifact_path, None);
+	assert_eq!(artifact.len() as u64, success.stats.compiled_artifact_size);
+	assert_eq!(blake3::hash(&artifact).to_hex().to_string(), success.checksum);
+	assert!(!worker_dir::prepare_tmp_artifact(worker.worker_dir.path()).exists());
+}
+
+#[tokio::test]
+async fn reset_is_acknowledged_and_clears_the_cache() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let pvf = adder_pvf(PrepareJobKind::Compilation);
+	let (first, first_artifact) =
+		prepare_and_take_artifact(&mut worker.stream, &tmp_artifact, pvf.clone()).await;
+
+	framed_send(&mut worker.stream, &PrepareWorkerRequest::Reset.encode())
+		.await
+		.unwrap();
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::ResetDone
+	);
+
+	// The worker still prepares, but no longer has the artifact cached.
+	let (second, second_artifact) =
+		prepare_and_take_artifact(&mut worker.stream, &tmp_artifact, pvf).await;
+	assert!(!first.stats.cache_hit);
+	assert!(!second.stats.cache_hit);
+	assert_eq!(first.checksum, second.checksum);
+	assert_eq!(first_artifact, second_artifact);
+}
+
+#[tokio::test]
+async fn worker_reports_the_security_status_it_was_started_with() {
+	// Only used by jobs, so the worker starts fine whether or not the system supports it.
+	let security_status =
+		SecurityStatus { can_unshare_network_and_mount_namespaces: true, ..Default::default() };
+
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(security_status.clone()).await;
+
+	framed_send(&mut worker.stream, &PrepareWorkerRequest::QuerySecurityStatus.encode())
+		.await
+		.unwrap();
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::SecurityStatus(status) if status == security_status
+	);
+}
+
+#[tokio::test]
+async fn request_without_executor_params_uses_the_default_ones() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	// The default params reject the code, so whether they are used shows in the result.
+	let default_executor_params =
+		ExecutorParams::from(&[ExecutorParam::MaxDeclaredMemoryPages(1)][..]);
+	framed_send(
+		&mut worker.stream,
+		&PrepareWorkerRequest::SetDefaultExecutorParams(default_executor_params).encode(),
+	)
+	.await
+	.unwrap();
+
+	let pvf = adder_pvf(PrepareJobKind::Compilation);
+	framed_send(
+		&mut worker.stream,
+		&PrepareWorkerRequest::Single(pvf.without_executor_params()).encode(),
+	)
+	.await
+	.unwrap();
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::Result {
+			result: Err(PrepareError::MemoryPagesExceeded { limit: 1, .. }),
+			..
+		}
+	);
+
+	// A PVF carrying its own params doesn't use the default ones.
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let (success, _artifact) =
+		prepare_and_take_artifact(&mut worker.stream, &tmp_artifact, pvf).await;
+	assert!(!success.stats.cache_hit);
+}
+
+#[tokio::test]
+async fn identical_request_is_served_from_the_cache() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let pvf = adder_pvf(PrepareJobKind::Compilation);
+	let (first, first_artifact) =
+		prepare_and_take_artifact(&mut worker.stream, &tmp_artifact, pvf.clone()).await;
+	let (second, second_artifact) =
+		prepare_and_take_artifact(&mut worker.stream, &tmp_artifact, pvf).await;
+
+	assert!(!first.stats.cache_hit);
+	assert!(!first.stats.cpu_time_elapsed.is_zero());
+	assert!(second.stats.cache_hit);
+	assert!(second.stats.cpu_time_elapsed.is_zero());
+	assert_eq!(first.checksum, second.checksum);
+	assert_eq!(first_artifact, second_artifact);
+	assert_eq!(first.stats.observed_wasm_code_len, second.stats.observed_wasm_code_len);
+}
+
+#[tokio::test]
+async fn probe_is_served_from_the_cache_without_the_code() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let pvf = adder_pvf(PrepareJobKind::Compilation);
+	let (first, first_artifact) =
+		prepare_and_take_artifact(&mut worker.stream, &tmp_artifact, pvf.clone()).await;
+
+	// The probe is a fraction of the size of the full request, and is all the worker gets.
+	let probe = PrepareWorkerRequest::Probe(pvf.without_code()).encode();
+	assert!(probe.len() < pvf.maybe_compressed_code().len());
+	std::fs::File::create(&tmp_artifact).unwrap();
+	framed_send(&mut worker.stream, &probe).await.unwrap();
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	let second = assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::Result { result: Ok(success), .. } => success
+	);
+
+	assert!(second.stats.cache_hit);
+	assert_eq!(second.stats.code_size, first.stats.code_size);
+	assert_eq!(second.checksum, first.checksum);
+	assert_eq!(std::fs::read(&tmp_artifact).unwrap(), first_artifact);
+}
+
+#[tokio::test]
+async fn probe_of_uncached_pvf_requests_the_code() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	let pvf = adder_pvf(PrepareJobKind::Compilation);
+	std::fs::File::create(worker_dir::prepare_tmp_artifact(worker.worker_dir.path())).unwrap();
+	framed_send(&mut worker.stream, &PrepareWorkerRequest::Probe(pvf.without_code()).encode())
+		.await
+		.unwrap();
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::CodeRequested
+	);
+
+	framed_send(&mut worker.stream, &PrepareWorkerRequest::Single(pvf).encode())
+		.await
+		.unwrap();
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	let success = assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::Result { result: Ok(success), .. } => success
+	);
+	assert!(!success.stats.cache_hit);
+}
+
+#[tokio::test]
+async fn result_carries_the_path_the_artifact_was_written_to() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let (success, artifact) = prepare_and_take_artifact(
+		&mut worker.stream,
+		&tmp_artifact,
+		adder_pvf(PrepareJobKind::Compilation),
+	)
+	.await;
+
+	assert_eq!(success.artifact_path, Some(tmp_artifact.to_str().unwrap().to_owned()));
+	assert_eq!(artifact.len() as u64, success.stats.compiled_artifact_size);
+}
+
+#[tokio::test]
+async fn validate_only_job_reports_no_artifact_path() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::ValidateOnly)).await.unwrap();
+
+	assert_eq!(success.artifact_path, None);
+}
+
+#[tokio::test]
+async fn code_from_file_prepares_like_inline_code() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	// Pre-checking jobs are not served from the cache, so both requests are prepared.
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let pvf = adder_pvf(PrepareJobKind::Prechecking);
+	let (inline, inline_artifact) =
+		prepare_and_take_artifact(&mut worker.stream, &tmp_artifact, pvf.clone()).await;
+
+	// Like the host, write the code into the code file and leave it out of the request.
+	let code_file = worker_dir::prepare_code(worker.worker_dir.path());
+	std::fs::write(&code_file, pvf.maybe_compressed_code().as_slice()).unwrap();
+	let (from_file, from_file_artifact) =
+		prepare_and_take_artifact(&mut worker.stream, &tmp_artifact, pvf.without_code()).await;
+
+	assert!(!from_file.stats.cache_hit);
+	assert_eq!(inline.checksum, from_file.checksum);
+	assert_eq!(inline_artifact, from_file_artifact);
+	assert_eq!(inline.stats.code_size, from_file.stats.code_size);
+	assert_eq!(inline.stats.observed_wasm_code_len, from_file.stats.observed_wasm_code_len);
+}
+
+#[tokio::test]
+async fn stats_report_code_and_artifact_sizes() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let (success, artifact) = prepare_and_take_artifact(
+		&mut worker.stream,
+		&tmp_artifact,
+		adder_pvf(PrepareJobKind::Compilation),
+	)
+	.await;
+
+	let code_size = test_parachain_adder::wasm_binary_unwrap().len() as u64;
+	assert!(code_size > 0);
+	assert_eq!(success.stats.code_size, code_size);
+	assert!(success.stats.compiled_artifact_size > 0);
+	assert_eq!(success.stats.compiled_artifact_size, artifact.len() as u64);
+}
+
+#[tokio::test]
+async fn no_security_features_are_applied_unless_available() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+
+	assert_eq!(success.stats.applied_security, AppliedSecurity::empty());
+}
+
+// Needs landlock and seccomp, which CI machines have.
+#[cfg(all(feature = "ci-only-tests", target_os = "linux", target_arch = "x86_64"))]
+#[tokio::test]
+async fn stats_report_the_applied_security_features() {
+	// Fork-based jobs, so that the namespaces are not part of the set.
+	let security_status = SecurityStatus {
+		can_enable_landlock: true,
+		can_enable_seccomp: true,
+		..Default::default()
+	};
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(security_status).await;
+
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let (success, _) = prepare_and_take_artifact(
+		&mut worker.stream,
+		&tmp_artifact,
+		adder_pvf(PrepareJobKind::Compilation),
+	)
+	.await;
+
+	assert_eq!(
+		success.stats.applied_security,
+		AppliedSecurity::LANDLOCK | AppliedSecurity::SECCOMP
+	);
+}
+
+// On macOS the worker prepares in-process, without a job process or any sandboxing.
+#[cfg(target_os = "macos")]
+#[tokio::test]
+async fn prepares_in_process_on_macos() {
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let (success, artifact) = prepare_and_take_artifact(
+		&mut worker.stream,
+		&tmp_artifact,
+		adder_pvf(PrepareJobKind::Compilation),
+	)
+	.await;
+
+	assert!(success.stats.artifact_produced);
+	assert_eq!(success.stats.applied_security, AppliedSecurity::empty());
+	assert_eq!(success.stats.compiled_artifact_size, artifact.len() as u64);
+	assert_eq!(success.checksum, blake3::hash(&artifact).to_hex().to_string());
+}
+
+#[tokio::test]
+async fn heartbeats_arrive_before_the_result() {
+	let pvf =
+		adder_pvf(PrepareJobKind::Compilation).with_heartbeat_interval(Duration::from_millis(1));
+
+	let received = prepare_with_worker_receiving_all(pvf).await;
+
+	assert!(received.heartbeats > 0);
+	assert!(received.result.is_ok());
+}
+
+#[tokio::test]
+async fn no_heartbeats_unless_requested() {
+	let received = prepare_with_worker_receiving_all(adder_pvf(PrepareJobKind::Compilation)).await;
+
+	assert_eq!(received.heartbeats, 0);
+	assert!(received.result.is_ok());
+}
+
+#[tokio::test]
+async fn metrics_accompany_a_successful_prepare() {
+	let received = prepare_with_worker_receiving_all(
+		adder_pvf(PrepareJobKind::Compilation).with_worker_metrics(),
+	)
+	.await;
+
+	let success = received.result.unwrap();
+	let metrics = received.metrics.expect("metrics were requested");
+	assert!(!metrics.preparation_duration.is_zero());
+	assert_eq!(metrics.artifact_size, Some(success.stats.compiled_artifact_size));
+	#[cfg(target_os = "linux")]
+	assert_eq!(metrics.max_rss, success.stats.memory_stats.max_rss);
+}
+
+#[tokio::test]
+async fn busy_hint_accompanies_the_result_if_requested() {
+	let received =
+		prepare_with_worker_receiving_all(adder_pvf(PrepareJobKind::Compilation).with_busy_hint())
+			.await;
+
+	assert!(received.result.is_ok());
+	assert_eq!(
+		received.busy_hint.expect("a busy hint was requested"),
+		BusyHint { running_jobs: 0, max_jobs: 1, pending_request_bytes: 0 }
+	);
+
+	let received = prepare_with_worker_receiving_all(adder_pvf(PrepareJobKind::Compilation)).await;
+	assert!(received.busy_hint.is_none());
+}
+
+// Runs on every Linux architecture in CI, so that the memory stats are known to work on e.g.
+// `aarch64` as well as on `x86_64`.
+#[cfg(all(feature = "ci-only-tests", target_os = "linux"))]
+#[tokio::test]
+async fn memory_stats_are_collected_for_a_real_compile() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+
+	let memory_stats = success.stats.memory_stats;
+	assert!(memory_stats.max_rss.expect("ru_maxrss is supported on Linux") > 0);
+	let tracker_stats = memory_stats.memory_tracker_stats.expect("jemalloc is used on Linux");
+	assert!(tracker_stats.resident > 0);
+	assert!(tracker_stats.allocated > 0);
+	assert!(memory_stats.peak_tracked_alloc > 0);
+}
+
+#[tokio::test]
+async fn result_echoes_the_request_id() {
+	let pvf = adder_pvf(PrepareJobKind::Compilation).with_request_id(42);
+
+	let received = prepare_with_worker_receiving_all(pvf).await;
+
+	assert!(received.result.is_ok());
+	assert_eq!(received.request_id, Some(42));
+}
+
+#[tokio::test]
+async fn result_has_no_request_id_unless_requested() {
+	let received = prepare_with_worker_receiving_all(adder_pvf(PrepareJobKind::Compilation)).await;
+
+	assert_eq!(received.request_id, None);
+}
+
+#[tokio::test]
+async fn memory_stats_name_the_allocator() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+
+	let expected = if cfg!(any(target_os = "linux", feature = "jemalloc-allocator")) {
+		MemoryAllocator::Jemalloc
+	} else {
+		MemoryAllocator::System
+	};
+	assert_eq!(success.stats.memory_stats.allocator, Some(expected));
+}
+
+// Needs seccomp, which CI machines have.
+#[cfg(all(feature = "ci-only-tests", target_os = "linux", target_arch = "x86_64"))]
+#[tokio::test]
+async fn swap_is_sampled_under_seccomp() {
+	let security_status = SecurityStatus { can_enable_seccomp: true, ..Default::default() };
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(security_status).await;
+
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let (success, _) = prepare_and_take_artifact(
+		&mut worker.stream,
+		&tmp_artifact,
+		adder_pvf(PrepareJobKind::Compilation),
+	)
+	.await;
+
+	assert!(success.stats.applied_security.contains(AppliedSecurity::SECCOMP));
+	let tracker_stats = success
+		.stats
+		.memory_stats
+		.memory_tracker_stats
+		.expect("jemalloc is used on Linux");
+	assert!(tracker_stats.peak_swap.is_some());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn smaps_rollup_sampling_reports_the_peak_of_a_real_compile() {
+	let pvf = adder_pvf(PrepareJobKind::Compilation).with_smaps_rollup_sampling();
+
+	let success = prepare_with_worker(pvf).await.unwrap();
+
+	let memory_stats = success.stats.memory_stats;
+	let tracker_stats = memory_stats.memory_tracker_stats.expect("jemalloc is used on Linux");
+	let peak_rss = tracker_stats.peak_rss.expect("smaps_rollup is available on Linux");
+	let peak_pss = tracker_stats.peak_pss.expect("smaps_rollup is available on Linux");
+	// Compiling even a small PVF takes more than a MiB.
+	assert!(peak_rss > 1024 * 1024, "implausible peak RSS: {}", peak_rss);
+	assert!(peak_pss > 0 && peak_pss <= peak_rss, "{} not in (0, {}]", peak_pss, peak_rss);
+	assert_eq!(memory_stats.max_rss, Some((peak_rss / 1024) as i64));
+}
+
+// Needs seccomp, which CI machines have.
+#[cfg(all(feature = "ci-only-tests", target_os = "linux", target_arch = "x86_64"))]
+#[tokio::test]
+async fn smaps_rollup_is_sampled_under_seccomp() {
+	let security_status = SecurityStatus { can_enable_seccomp: true, ..Default::default() };
+	let (mut worker, _handle, _cache_dir) = spawn_prepare_worker(security_status).await;
+
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	let (success, _) = prepare_and_take_artifact(
+		&mut worker.stream,
+		&tmp_artifact,
+		adder_pvf(PrepareJobKind::Compilation).with_smaps_rollup_sampling(),
+	)
+	.await;
+
+	assert!(success.stats.applied_security.contains(AppliedSecurity::SECCOMP));
+	let tracker_stats = success
+		.stats
+		.memory_stats
+		.memory_tracker_stats
+		.expect("jemalloc is used on Linux");
+	assert!(tracker_stats.peak_rss.is_some());
+	assert!(tracker_stats.peak_pss.is_some());
+}
+
+#[tokio::test]
+async fn smaps_rollup_is_not_sampled_unless_requested() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+
+	let tracker_stats = success
+		.stats
+		.memory_stats
+		.memory_tracker_stats
+		.expect("jemalloc is used on Linux");
+	assert_eq!(tracker_stats.peak_rss, None);
+	assert_eq!(tracker_stats.peak_pss, None);
+}
+
+/// A PVF that takes much longer than [`SHORT_PREPARATION_TIMEOUT`] to compile.
+fn slow_pvf(prep_kind: PrepareJobKind) -> PvfPrepData {
+	PvfPrepData::from_code(
+		rococo_runtime::WASM_BINARY.unwrap().to_vec(),
+		ExecutorParams::default(),
+		SHORT_PREPARATION_TIMEOUT,
+		prep_kind,
+	)
+}
+
+/// A timeout that is long enough to get through prevalidation, but not through compilation.
+const SHORT_PREPARATION_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[tokio::test]
+async fn benchmark_timeout_reports_the_stage_it_was_in() {
+	let result = prepare_with_worker(slow_pvf(PrepareJobKind::Benchmark)).await;
+
+	let cpu_used = assert_matches!(
+		result,
+		Err(PrepareError::TimedOutAtStage { stage: PrepareStage::Preparation, cpu_used }) => cpu_used
+	);
+	assert!(cpu_used > Duration::ZERO);
+}
+
+#[tokio::test]
+async fn other_timeouts_report_no_stage() {
+	let result = prepare_with_worker(slow_pvf(PrepareJobKind::Compilation)).await;
+
+	assert_matches!(result, Err(PrepareError::TimedOut));
+}
+
+#[tokio::test]
+async fn successful_job_reports_the_stage_it_reached() {
+	let compiled = prepare_with_worker_receiving_all(adder_pvf(PrepareJobKind::Compilation)).await;
+	assert!(compiled.result.is_ok());
+	assert_eq!(compiled.reached_stage, Some(PrepareStage::ArtifactWrite));
+
+	let validated =
+		prepare_with_worker_receiving_all(adder_pvf(PrepareJobKind::ValidateOnly)).await;
+	assert!(validated.result.is_ok());
+	assert_eq!(validated.reached_stage, Some(PrepareStage::Prevalidation));
+}
+
+#[tokio::test]
+async fn failed_job_reports_the_stage_it_failed_in() {
+	let invalid_code = PvfPrepData::from_code(
+		b"definitely not wasm".to_vec(),
+		ExecutorParams::default(),
+		TEST_PREPARATION_TIMEOUT,
+		PrepareJobKind::Compilation,
+	);
+	let cases = [
+		// The code is not compressed, as it claims to be.
+		(
+			adder_pvf(PrepareJobKind::Compilation).with_zstd_code(u64::MAX),
+			PrepareStage::Decompression,
+		),
+		(invalid_code, PrepareStage::Prevalidation),
+		(slow_pvf(PrepareJobKind::Compilation), PrepareStage::Preparation),
+		(
+			unconstructible_pvf().with_runtime_construction_check(),
+			PrepareStage::RuntimeConstruction,
+		),
+	];
+
+	for (pvf, stage) in cases {
+		let received = prepare_with_worker_receiving_all(pvf).await;
+		assert!(received.result.is_err(), "the job should fail at {:?}", stage);
+		assert_eq!(received.reached_stage, Some(stage));
+	}
+}
+
+#[tokio::test]
+async fn no_metrics_unless_requested() {
+	let received = prepare_with_worker_receiving_all(adder_pvf(PrepareJobKind::Compilation)).await;
+
+	assert!(received.metrics.is_none());
+	assert!(received.result.is_ok());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn address_space_limit_reports_out_of_memory() {
+	// Far below what the job has already mapped, so that any allocation needing fresh memory
+	// fails.
+	let pvf = adder_pvf(PrepareJobKind::Compilation).with_address_space_limit(1024 * 1024);
+
+	let result = prepare_with_worker(pvf).await;
+
+	assert_matches!(result, Err(PrepareError::OutOfMemory));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn sched_stats_are_reported() {
+	let success = prepare_with_worker(adder_pvf(PrepareJobKind::Compilation)).await.unwrap();
+
+	let sched_stats = success.stats.sched_stats.unwrap();
+	// The main thread of the job waits for the thread preparing the PVF.
+	assert!(sched_stats.voluntary_context_switches > 0);
+	assert!(sched_stats.involuntary_context_switches >= 0);
+	// The job is not sandboxed in tests, so it can read its run queue delay.
+	assert!(sched_stats.run_queue_delay.is_some());
+}
+
+/// Returns whether the kernel writes cores into the working directory of the crashing process, and
+/// is allowed to write any. Cores may instead be piped to a program, e.g. `systemd-coredump`.
+#[cfg(target_os = "linux")]
+fn cores_are_dumped_into_the_working_dir() -> bool {
+	let core_pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern").unwrap();
+	let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+	assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) }, 0);
+	!core_pattern.starts_with('|') && !core_pattern.contains('/') && limit.rlim_max > 0
+}
+
+/// Waits until the worker with the given pid has forked a job, and returns the pid of the job.
+#[cfg(target_os = "linux")]
+async fn wait_for_job_pid(worker_pid: u32) -> i32 {
+	loop {
+		let tasks = std::fs::read_dir(format!("/proc/{worker_pid}/task")).unwrap();
+		let job_pid = tasks.filter_map(Result::ok).find_map(|task| {
+			let children = std::fs::read_to_string(task.path().join("children")).ok()?;
+			children.split_whitespace().next()?.parse().ok()
+		});
+		if let Some(job_pid) = job_pid {
+			return job_pid
+		}
+		tokio::time::sleep(Duration::from_millis(10)).await;
+	}
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn crashed_job_reports_its_core_dump() {
+	if !cores_are_dumped_into_the_working_dir() {
+		return
+	}
+
+	let (mut worker, handle, _cache_dir) =
+		spawn_prepare_worker_with_args(&["--allow-core-dumps"], SecurityStatus::default()).await;
+	negotiate_protocol_version(&mut worker.stream, PROTOCOL_VERSION).await.unwrap();
+
+	// A long timeout, so that the job is still compiling when it is crashed.
+	let pvf = PvfPrepData::from_code(
+		rococo_runtime::WASM_BINARY.unwrap().to_vec(),
+		ExecutorParams::default(),
+		TEST_PREPARATION_TIMEOUT,
+		PrepareJobKind::Compilation,
+	)
+	.with_core_dumps();
+	std::fs::File::create(worker_dir::prepare_tmp_artifact(worker.worker_dir.path())).unwrap();
+	framed_send(&mut worker.stream, &PrepareWorkerRequest::Single(pvf).encode())
+		.await
+		.unwrap();
+
+	// `SIGABRT` dumps the core by default, and unlike `SIGSEGV` is not handled by the compiler.
+	let job_pid = tokio::time::timeout(Duration::from_secs(10), wait_for_job_pid(handle.id()))
+		.await
+		.expect("the worker should fork a job");
+	assert_eq!(unsafe { libc::kill(job_pid, libc::SIGABRT) }, 0);
+
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	let result = assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::Result { result, .. } => result
+	);
+	let core_path = assert_matches!(
+		result,
+		Err(PrepareError::CoreDumped { core_path, inner })
+			if matches!(*inner, PrepareError::JobAborted { .. }) => core_path
+	);
+	assert!(worker.worker_dir.path().join(core_path).is_file());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn cancelled_job_is_killed_promptly() {
+	let (mut worker, handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	// A large runtime, which takes far longer to compile than the cancellation may take.
+	let pvf = PvfPrepData::from_code(
+		rococo_runtime::WASM_BINARY.unwrap().to_vec(),
+		ExecutorParams::default(),
+		TEST_PREPARATION_TIMEOUT,
+		PrepareJobKind::Compilation,
+	);
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	std::fs::File::create(&tmp_artifact).unwrap();
+	framed_send(&mut worker.stream, &PrepareWorkerRequest::Single(pvf).encode())
+		.await
+		.unwrap();
+	let job_pid = tokio::time::timeout(Duration::from_secs(10), wait_for_job_pid(handle.id()))
+		.await
+		.expect("the worker should fork a job");
+
+	framed_send(&mut worker.stream, &PrepareWorkerRequest::Cancel.encode())
+		.await
+		.unwrap();
+	let message = tokio::time::timeout(Duration::from_secs(2), framed_recv(&mut worker.stream))
+		.await
+		.expect("the cancellation should be answered promptly")
+		.unwrap();
+	assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::Result { result: Err(PrepareError::Cancelled), .. }
+	);
+	// The job was reaped along with the cancellation.
+	assert!(!Path::new(&format!("/proc/{job_pid}")).exists());
+
+	// The worker carries on with the next request.
+	let (success, _artifact) = prepare_and_take_artifact(
+		&mut worker.stream,
+		&tmp_artifact,
+		adder_pvf(PrepareJobKind::Compilation),
+	)
+	.await;
+	assert!(success.stats.artifact_produced);
+}
+
+/// Returns the nice value of the process with the given pid.
+#[cfg(target_os = "linux")]
+fn nice_of(pid: i32) -> Option<i32> {
+	let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+	// The fields after the parenthesized command name start with the third one, the nice value
+	// being the nineteenth.
+	let (_, fields) = stat.rsplit_once(')')?;
+	fields.split_whitespace().nth(16)?.parse().ok()
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn job_runs_with_the_configured_nice_value() {
+	let (mut worker, handle, _cache_dir) = spawn_prepare_worker_with_args(
+		&["--job-nice", "19", "--job-io-priority", "idle"],
+		SecurityStatus::default(),
+	)
+	.await;
+	negotiate_protocol_version(&mut worker.stream, PROTOCOL_VERSION).await.unwrap();
+
+	// A large runtime, so that the job is still compiling when it is looked at.
+	let pvf = PvfPrepData::from_code(
+		rococo_runtime::WASM_BINARY.unwrap().to_vec(),
+		ExecutorParams::default(),
+		TEST_PREPARATION_TIMEOUT,
+		PrepareJobKind::Compilation,
+	);
+	std::fs::File::create(worker_dir::prepare_tmp_artifact(worker.worker_dir.path())).unwrap();
+	framed_send(&mut worker.stream, &PrepareWorkerRequest::Single(pvf).encode())
+		.await
+		.unwrap();
+
+	let job_pid = tokio::time::timeout(Duration::from_secs(10), wait_for_job_pid(handle.id()))
+		.await
+		.expect("the worker should fork a job");
+	// The job sets its priority right after it started, so it may not have done so yet.
+	tokio::time::timeout(Duration::from_secs(10), async {
+		while nice_of(job_pid) != Some(19) {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+	})
+	.await
+	.expect("the job should run with the configured nice value");
+
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::Result { result: Ok(_), .. }
+	);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn sigterm_between_requests_shuts_down_cleanly() {
+	let (mut worker, handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	// Run a job, leaving its artifact behind like a host would if it never picked it up.
+	let tmp_artifact = worker_dir::prepare_tmp_artifact(worker.worker_dir.path());
+	std::fs::File::create(&tmp_artifact).unwrap();
+	let request = PrepareWorkerRequest::Single(adder_pvf(PrepareJobKind::Compilation));
+	framed_send(&mut worker.stream, &request.encode()).await.unwrap();
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::Result { result: Ok(_), .. }
+	);
+	assert!(tmp_artifact.exists());
+
+	assert_eq!(unsafe { libc::kill(handle.id() as i32, libc::SIGTERM) }, 0);
+
+	let status = handle.wait().await.unwrap();
+	assert!(status.success());
+	assert!(!tmp_artifact.exists());
+}
+
+#[tokio::test]
+async fn idle_worker_exits_cleanly() {
+	let (mut worker, handle, _cache_dir) =
+		spawn_prepare_worker_with_args(&["--idle-timeout-secs", "1"], SecurityStatus::default())
+			.await;
+	negotiate_protocol_version(&mut worker.stream, PROTOCOL_VERSION).await.unwrap();
+
+	// No request is sent, so the worker exits on its own once the idle timeout passes.
+	let status = tokio::time::timeout(Duration::from_secs(10), handle.wait())
+		.await
+		.expect("the idle worker should exit")
+		.unwrap();
+	assert!(status.success());
+}
+
+#[tokio::test]
+async fn unwritable_worker_dir_is_reported_on_startup() {
+	use std::os::unix::fs::PermissionsExt;
+
+	// Permissions don't apply to root, so the worker dir can't be made unwritable.
+	if unsafe { libc::geteuid() } == 0 {
+		return
+	}
+
+	let (mut worker, handle, _cache_dir) =
+		spawn_prepare_worker_with_args(&[], SecurityStatus::default()).await;
+
+	let worker_dir = worker.worker_dir.path().to_owned();
+	std::fs::set_permissions(&worker_dir, std::fs::Permissions::from_mode(0o555)).unwrap();
+	let result = negotiate_protocol_version(&mut worker.stream, PROTOCOL_VERSION).await;
+	std::fs::set_permissions(&worker_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+	assert_matches!(
+		result,
+		Err(PrepareError::WorkerDirUnwritable { path, .. }) if path == worker_dir.display().to_string()
+	);
+	let status = handle.wait().await.unwrap();
+	assert_eq!(status.code(), Some(1));
+}
+
+// Needs landlock, which CI machines have.
+#[cfg(all(feature = "ci-only-tests", target_os = "linux"))]
+#[tokio::test]
+async fn worker_dir_is_writable_under_landlock() {
+	let security_status = SecurityStatus { can_enable_landlock: true, ..Default::default() };
+	let (mut worker, _handle, _cache_dir) =
+		spawn_prepare_worker_with_args(&[], security_status).await;
+
+	let result = negotiate_protocol_version(&mut worker.stream, PROTOCOL_VERSION).await;
+
+	assert_matches!(result, Ok(()));
+}
+
+#[tokio::test]
+async fn protocol_version_mismatch_terminates_worker() {
+	let (mut worker, handle, _cache_dir) =
+		spawn_prepare_worker_with_args(&[], SecurityStatus::default()).await;
+
+	let result = negotiate_protocol_version(&mut worker.stream, PROTOCOL_VERSION + 1).await;
+	assert_matches!(
+		result,
+		Err(PrepareError::ProtocolMismatch { host, worker })
+			if host == PROTOCOL_VERSION + 1 && worker == PROTOCOL_VERSION
+	);
+
+	// The worker exits on its own instead of waiting for requests it can't decode.
+	let status = handle.wait().await.unwrap();
+	assert_eq!(status.code(), Some(1));
+}
+
+#[tokio::test]
+async fn oversized_request_is_rejected_before_being_read() {
+	use tokio::io::AsyncWriteExt;
+
+	let (mut worker, handle, _cache_dir) = spawn_prepare_worker(SecurityStatus::default()).await;
+
+	// Only send a length prefix. If the worker tried to allocate a buffer for it, it would abort.
+	let len = usize::MAX / 2;
+	worker.stream.write_all(&len.to_le_bytes()).await.unwrap();
+
+	let message = framed_recv(&mut worker.stream).await.unwrap();
+	assert_matches!(
+		PrepareWorkerMessage::decode(&mut &message[..]).unwrap(),
+		PrepareWorkerMessage::Result {
+			request_id: None,
+			reached_stage: None,
+			result: Err(PrepareError::MessageTooLarge { len: l, .. }),
+		}
+			if l == len as u64
+	);
+
+	// Nothing else can be read from the stream, so the worker exits.
+	let status = handle.wait().await.unwrap();
+	assert_eq!(status.code(), Some(1));
+}
+
+#[test]
+fn prepare_blocking_compiles_in_process() {
+	use polkadot_node_core_pvf_prepare_worker::prepare_blocking;
+
+	let (artifact, stats) = prepare_blocking(adder_pvf(PrepareJobKind::Compilation)).unwrap();
+
+	assert!(!artifact.payload().is_empty());
+	assert!(stats.artifact_produced);
+	assert!(stats.observed_wasm_code_len > 0);
+	assert_eq!(stats.compiled_artifact_size, artifact.as_ref().len() as u64);
+	assert_eq!(stats.code_size, test_parachain_adder::wasm_binary_unwrap().len() as u64);
+}
+
+#[test]
+fn optimization_level_changes_the_artifact() {
+	use polkadot_node_core_pvf_prepare_worker::prepare_blocking;
+	use polkadot_primitives::PvfOptimizationLevel;
+
+	let prepare_with_level = |level| {
+		let pvf = PvfPrepData::from_code(
+			test_parachain_adder::wasm_binary_unwrap().to_vec(),
+			ExecutorParams::from(&[ExecutorParam::OptimizationLevel(level)][..]),
+			TEST_PREPARATION_TIMEOUT,
+			PrepareJobKind::Compilation,
+		);
+		prepare_blocking(pvf).unwrap().0
+	};
+
+	let unoptimized = prepare_with_level(PvfOptimizationLevel::None);
+	let optimized = prepare_with_level(PvfOptimizationLevel::SpeedAndSize);
+
+	assert!(!unoptimized.payload().is_empty());
+	assert!(!optimized.payload().is_empty());
+	assert_ne!(unoptimized.payload(), optimized.payload());
+}
+
+#[test]
+fn hugepage_layout_is_recorded_in_the_artifact_header() {
+	use polkadot_node_core_pvf_common::artifact::{self, ArtifactFlags};
+	use polkadot_node_core_pvf_prepare_worker::prepare_blocking;
+
+	const HUGEPAGE_SIZE: usize = 2 * 1024 * 1024;
+
+	let prepare_with_params = |executor_params: &[ExecutorParam]| {
+		let pvf = PvfPrepData::from_code(
+			test_parachain_adder::wasm_binary_unwrap().to_vec(),
+			ExecutorParams::from(executor_params),
+			TEST_PREPARATION_TIMEOUT,
+			PrepareJobKind::Compilation,
+		);
+		prepare_blocking(pvf).unwrap().0
+	};
+
+	let plain = prepare_with_params(&[]);
+	let padded = prepare_with_params(&[ExecutorParam::HugepageAlignedCode]);
+
+	assert_eq!(plain.flags(), ArtifactFlags { hugepage_layout: false });
+	assert_eq!(padded.flags(), ArtifactFlags { hugepage_layout: true });
+	assert_ne!(artifact::decompress(plain.payload()).unwrap().len() % HUGEPAGE_SIZE, 0);
+	assert_eq!(artifact::decompress(padded.payload()).unwrap().len() % HUGEPAGE_SIZE, 0);
+}
@@ -17,11 +17,12 @@
 //! Test unexpected behaviors of the spawned processes. We test both worker processes (directly
 //! spawned by the host) and job processes (spawned by the workers to securely perform PVF jobs).
 
-use super::TestHost;
+use super::{TestHost, TEST_PREPARATION_TIMEOUT};
 use assert_matches::assert_matches;
 use codec::Encode;
 use polkadot_node_core_pvf::{
 	InvalidCandidate, PossiblyInvalidError, PrepareError, ValidationError,
+	JOB_TIMEOUT_WALL_CLOCK_FACTOR,
 };
 use polkadot_node_primitives::PoV;
 use polkadot_parachain_primitives::primitives::{
@@ -274,11 +275,34 @@ rusty_fork_test! {
 			// Note that we get a more specific error if the job died than if the whole worker died.
 			assert_matches!(
 				result,
-				Err(PrepareError::JobDied{ err, job_pid: _ }) if err == "received signal: SIGKILL"
+				Err(PrepareError::JobKilled { signal, .. }) if signal == SIGNAL_KILL
 			);
 		})
 	}
 
+	// What happens when the forked prepare job stalls without using any CPU time?
+	#[test]
+	fn forked_prepare_job_stalled_during_job() {
+		test_wrapper(|host, sid| async move {
+			let start = std::time::Instant::now();
+			let (result, _) = futures::join!(
+				// Choose a job that would normally take the entire timeout.
+				host.precheck_pvf(rococo_runtime::WASM_BINARY.unwrap(), Default::default()),
+				// Send a stop signal to pause the job, so that its CPU time stops running.
+				async {
+					tokio::time::sleep(Duration::from_secs(1)).await;
+					send_signal_by_sid_and_name(sid, PREPARE_PROCESS_NAME, false, SIGNAL_STOP);
+				}
+			);
+
+			assert_matches!(result, Err(PrepareError::TimedOut));
+			// The worker kills the job on its wall clock timeout, before the host gives up on the
+			// whole worker.
+			assert!(start.elapsed() < TEST_PREPARATION_TIMEOUT * JOB_TIMEOUT_WALL_CLOCK_FACTOR);
+			assert!(find_process_by_sid_and_name(sid, PREPARE_PROCESS_NAME, false).is_none());
+		})
+	}
+
 	// What happens when the forked execute job dies in the middle of its job?
 	#[test]
 	fn forked_execute_job_killed_during_job() {
@@ -16,7 +16,7 @@
 
 //! Tracking/limiting global allocator. Calculates the peak allocation between two checkpoints for
 //! the whole process. Accepts an optional limit and a failure handler which is called if the limit
-//! is overflown.
+//! is overflown, or if the underlying allocator fails to allocate (e.g. due to `RLIMIT_AS`).
 
 use core::{
 	alloc::{GlobalAlloc, Layout},
@@ -197,6 +197,20 @@ unsafe fn fail_allocation(guard: SpinlockGuard<TrackingAllocatorData>) -> *mut u
 	null_mut()
 }
 
+/// Checks the result of the underlying allocator. If the allocation failed, the tracked value is
+/// reverted and the failure handler is called, as the failed allocation would otherwise abort the
+/// process.
+#[inline]
+unsafe fn check_allocation(ptr: *mut u8, alloc: isize) -> *mut u8 {
+	if ptr.is_null() {
+		let mut guard = ALLOCATOR_DATA.lock();
+		guard.current -= alloc;
+		fail_allocation(guard)
+	} else {
+		ptr
+	}
+}
+
 unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
 	// SAFETY:
 	// * The wrapped methods are as safe as the underlying allocator implementation is
@@ -209,7 +223,7 @@ unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
 		{
 			fail_allocation(guard)
 		} else {
-			self.0.alloc(layout)
+			check_allocation(self.0.alloc(layout), layout.size() as isize)
 		}
 	}
 
@@ -221,7 +235,7 @@ unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
 		{
 			fail_allocation(guard)
 		} else {
-			self.0.alloc_zeroed(layout)
+			check_allocation(self.0.alloc_zeroed(layout), layout.size() as isize)
 		}
 	}
 
@@ -234,14 +248,12 @@ unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
 
 	#[inline]
 	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		let alloc = (new_size as isize) - (layout.size() as isize);
 		let guard = ALLOCATOR_DATA.lock();
-		if let Some(guard) = TrackingAllocatorData::track_and_check_limits(
-			guard,
-			(new_size as isize) - (layout.size() as isize),
-		) {
+		if let Some(guard) = TrackingAllocatorData::track_and_check_limits(guard, alloc) {
 			fail_allocation(guard)
 		} else {
-			self.0.realloc(ptr, layout, new_size)
+			check_allocation(self.0.realloc(ptr, layout, new_size), alloc)
 		}
 	}
 }
@@ -34,7 +34,7 @@ pub const DEFAULT_LOGICAL_STACK_MAX: u32 = 65536;
 /// Default maximum number of bytes devoted for the stack during execution of a PVF.
 pub const DEFAULT_NATIVE_STACK_MAX: u32 = 256 * 1024 * 1024;
 
-/// The limit of [`ExecutorParam::MaxMemoryPages`].
+/// The limit of [`ExecutorParam::MaxMemoryPages`] and [`ExecutorParam::MaxDeclaredMemoryPages`].
 pub const MEMORY_PAGES_MAX: u32 = 65536;
 /// The lower bound of [`ExecutorParam::StackLogicalMax`].
 pub const LOGICAL_MAX_LO: u32 = 1024;
@@ -44,6 +44,8 @@ pub const LOGICAL_MAX_HI: u32 = 2 * 65536;
 pub const PRECHECK_MEM_MAX_LO: u64 = 256 * 1024 * 1024;
 /// The upper bound of [`ExecutorParam::PrecheckingMaxMemory`].
 pub const PRECHECK_MEM_MAX_HI: u64 = 16 * 1024 * 1024 * 1024;
+/// The upper bound of [`ExecutorParam::CompilationThreads`].
+pub const COMPILATION_THREADS_MAX: u32 = 16;
 
 // Default PVF timeouts. Must never be changed! Use executor environment parameters to adjust them.
 // See also `PvfPrepKind` and `PvfExecKind` docs.
@@ -109,6 +111,91 @@ pub enum ExecutorParam {
 	/// Enables WASM bulk memory proposal
 	#[codec(index = 7)]
 	WasmExtBulkMemory,
+	/// The level of optimization applied when compiling a PVF.
+	/// When absent, [`PvfOptimizationLevel::SpeedAndSize`] is used.
+	#[codec(index = 8)]
+	OptimizationLevel(PvfOptimizationLevel),
+	/// The number of threads compiling a PVF. The preparation timeout is measured in CPU time of
+	/// all of them, so more threads only cut down on the wall clock time of the preparation.
+	/// A valid value lies within [1, [`COMPILATION_THREADS_MAX`]].
+	/// When absent, a PVF is compiled on a single thread.
+	#[codec(index = 9)]
+	CompilationThreads(u32),
+	/// Maximum number of memory pages (64KiB bytes per page) a PVF may declare for its linear
+	/// memory, checked when preparing it. PVFs declaring more are rejected before compilation.
+	/// A valid value lies within (0, [`MEMORY_PAGES_MAX`]].
+	/// When absent, the declaration is not limited.
+	#[codec(index = 10)]
+	MaxDeclaredMemoryPages(u32),
+	/// PVF preparation timeout of the given kind that grows with the size of the code, so that
+	/// large runtimes get more time than small ones. It is `per_mib_ms` milliseconds per MiB of
+	/// the code as it is sent to the worker, i.e. usually compressed, clamped to
+	/// [`min_ms`, `max_ms`]. Takes precedence over the [`ExecutorParam::PvfPrepTimeout`] of the
+	/// same kind.
+	/// A valid value has a non-zero `per_mib_ms` and `0 < min_ms <= max_ms`.
+	/// When absent, the preparation timeout doesn't depend on the size of the code.
+	#[codec(index = 11)]
+	PvfPrepTimeoutScaling { kind: PvfPrepKind, per_mib_ms: u64, min_ms: u64, max_ms: u64 },
+	/// Pads the compiled code of a PVF to whole hugepages, so that it can be mapped with
+	/// hugepages when executed. This eases the TLB pressure of executing large PVFs.
+	/// When absent, the compiled code is not padded.
+	#[codec(index = 12)]
+	HugepageAlignedCode,
+	/// Maximum size in bytes of the artifact a PVF compiles to, before it is compressed. PVFs
+	/// compiling to larger artifacts fail to prepare instead of filling the disk.
+	/// A valid value is non-zero.
+	/// When absent, the size of the artifact is not limited.
+	#[codec(index = 13)]
+	MaxArtifactSize(u64),
+	/// Whether the NaNs produced by float operations are canonicalized. The bit pattern of such a
+	/// NaN otherwise depends on the machine, so PVFs using float operations are rejected when it
+	/// is disabled. This spares compiling the canonicalization into PVFs that don't use floats.
+	/// Disabling it is incompatible with [`RelaxedSimdPolicy::Deterministic`].
+	/// When absent, NaNs are canonicalized.
+	#[codec(index = 14)]
+	NanCanonicalization(bool),
+	/// Whether PVFs may use the relaxed SIMD instructions, whose results are
+	/// implementation-defined.
+	/// When absent, [`RelaxedSimdPolicy::Reject`] is used.
+	#[codec(index = 15)]
+	RelaxedSimd(RelaxedSimdPolicy),
+	/// Whether out-of-bounds accesses to the linear memory of a PVF are caught by guard pages and
+	/// the signal handler of the executor, instead of by bounds checks compiled into the PVF.
+	/// Either way, such an access traps at the same point. Bounds checks make the PVF slower, but
+	/// don't need any address space reserved for guard pages. Other traps are always raised
+	/// through the signal handler.
+	/// When absent, signals are used.
+	#[codec(index = 16)]
+	SignalsBasedTraps(bool),
+}
+
+/// Whether PVFs may use the relaxed SIMD instructions. Both policies keep the execution
+/// deterministic across validators.
+#[derive(Clone, Copy, Debug, Encode, Decode, PartialEq, Eq, TypeInfo, Serialize, Deserialize)]
+pub enum RelaxedSimdPolicy {
+	/// PVFs using relaxed SIMD are rejected.
+	#[codec(index = 0)]
+	Reject,
+	/// PVFs may use relaxed SIMD, and fixed-width SIMD along with it. Every relaxed instruction
+	/// is compiled to one fixed choice of its allowed results, whatever the machine supports
+	/// natively. Requires NaN canonicalization, see [`ExecutorParam::NanCanonicalization`].
+	#[codec(index = 1)]
+	Deterministic,
+}
+
+/// The level of optimization applied when compiling a PVF. Lower levels compile faster, but
+/// produce slower code.
+#[derive(Clone, Copy, Debug, Encode, Decode, PartialEq, Eq, TypeInfo, Serialize, Deserialize)]
+pub enum PvfOptimizationLevel {
+	/// No optimizations.
+	#[codec(index = 0)]
+	None,
+	/// Optimizations for the speed of the generated code.
+	#[codec(index = 1)]
+	Speed,
+	/// Optimizations for both the speed and the size of the generated code.
+	#[codec(index = 2)]
+	SpeedAndSize,
 }
 
 /// Possible inconsistencies of executor params.
@@ -221,6 +308,17 @@ impl ExecutorParams {
 				PvfPrepTimeout(..) => Some(param),
 				PvfExecTimeout(..) => None,
 				WasmExtBulkMemory => Some(param),
+				OptimizationLevel(..) => Some(param),
+				// The compiled code doesn't depend on how many threads compile it.
+				CompilationThreads(..) => None,
+				MaxDeclaredMemoryPages(..) => Some(param),
+				PvfPrepTimeoutScaling { .. } => Some(param),
+				HugepageAlignedCode => Some(param),
+				MaxArtifactSize(..) => Some(param),
+				NanCanonicalization(..) => Some(param),
+				RelaxedSimd(..) => Some(param),
+				// Bounds checks are compiled into the code.
+				SignalsBasedTraps(..) => Some(param),
 			})
 			.for_each(|p| enc.extend(p.encode()));
 
@@ -239,6 +337,27 @@ impl ExecutorParams {
 		None
 	}
 
+	/// Returns a PVF preparation timeout for code of the given size in bytes, if the timeout of
+	/// the given kind scales with the size of the code.
+	pub fn pvf_prep_timeout_for_code_size(
+		&self,
+		kind: PvfPrepKind,
+		code_size: u64,
+	) -> Option<Duration> {
+		for param in &self.0 {
+			if let ExecutorParam::PvfPrepTimeoutScaling { kind: k, per_mib_ms, min_ms, max_ms } =
+				param
+			{
+				if kind == *k {
+					let scaled = u128::from(*per_mib_ms) * u128::from(code_size) / (1024 * 1024);
+					let timeout = u64::try_from(scaled).unwrap_or(u64::MAX);
+					return Some(Duration::from_millis(timeout.max(*min_ms).min(*max_ms)))
+				}
+			}
+		}
+		None
+	}
+
 	/// Returns a PVF execution timeout, if any
 	pub fn pvf_exec_timeout(&self, kind: PvfExecKind) -> Option<Duration> {
 		for param in &self.0 {
@@ -261,6 +380,46 @@ impl ExecutorParams {
 		None
 	}
 
+	/// Returns the optimization level for compiling PVFs, if any.
+	pub fn optimization_level(&self) -> Option<PvfOptimizationLevel> {
+		for param in &self.0 {
+			if let ExecutorParam::OptimizationLevel(level) = param {
+				return Some(*level)
+			}
+		}
+		None
+	}
+
+	/// Returns the number of threads compiling a PVF, if any.
+	pub fn compilation_threads(&self) -> Option<u32> {
+		for param in &self.0 {
+			if let ExecutorParam::CompilationThreads(threads) = param {
+				return Some(*threads)
+			}
+		}
+		None
+	}
+
+	/// Returns the maximum number of memory pages a PVF may declare, if any.
+	pub fn max_declared_memory_pages(&self) -> Option<u32> {
+		for param in &self.0 {
+			if let ExecutorParam::MaxDeclaredMemoryPages(max_pages) = param {
+				return Some(*max_pages)
+			}
+		}
+		None
+	}
+
+	/// Returns the maximum size of the artifact a PVF may compile to, if any.
+	pub fn max_artifact_size(&self) -> Option<u64> {
+		for param in &self.0 {
+			if let ExecutorParam::MaxArtifactSize(max_size) = param {
+				return Some(*max_size)
+			}
+		}
+		None
+	}
+
 	/// Check params coherence.
 	pub fn check_consistency(&self) -> Result<(), ExecutorParamError> {
 		use ExecutorParam::*;
@@ -304,6 +463,18 @@ impl ExecutorParams {
 					PvfExecKind::Approval => "PvfExecKind::Approval",
 				},
 				WasmExtBulkMemory => "WasmExtBulkMemory",
+				OptimizationLevel(_) => "OptimizationLevel",
+				CompilationThreads(_) => "CompilationThreads",
+				MaxDeclaredMemoryPages(_) => "MaxDeclaredMemoryPages",
+				PvfPrepTimeoutScaling { kind, .. } => match kind {
+					PvfPrepKind::Precheck => "PvfPrepTimeoutScaling::Precheck",
+					PvfPrepKind::Prepare => "PvfPrepTimeoutScaling::Prepare",
+				},
+				HugepageAlignedCode => "HugepageAlignedCode",
+				MaxArtifactSize(_) => "MaxArtifactSize",
+				NanCanonicalization(_) => "NanCanonicalization",
+				RelaxedSimd(_) => "RelaxedSimd",
+				SignalsBasedTraps(_) => "SignalsBasedTraps",
 			};
 
 			match *param {
@@ -338,6 +509,42 @@ impl ExecutorParams {
 				WasmExtBulkMemory => {
 					check!(param_ident, 1);
 				},
+
+				OptimizationLevel(_) => {
+					check!(param_ident, 1);
+				},
+
+				CompilationThreads(val) => {
+					check!(param_ident, val, val == 0 || val > COMPILATION_THREADS_MAX,);
+				},
+
+				MaxDeclaredMemoryPages(val) => {
+					check!(param_ident, val, val == 0 || val > MEMORY_PAGES_MAX,);
+				},
+
+				PvfPrepTimeoutScaling { per_mib_ms, min_ms, max_ms, .. } => {
+					check!(param_ident, max_ms, per_mib_ms == 0 || min_ms == 0 || min_ms > max_ms,);
+				},
+
+				HugepageAlignedCode => {
+					check!(param_ident, 1);
+				},
+
+				MaxArtifactSize(val) => {
+					check!(param_ident, val, val == 0,);
+				},
+
+				NanCanonicalization(val) => {
+					check!(param_ident, val);
+				},
+
+				RelaxedSimd(policy) => {
+					check!(param_ident, policy == RelaxedSimdPolicy::Deterministic);
+				},
+
+				SignalsBasedTraps(val) => {
+					check!(param_ident, val);
+				},
 			}
 		}
 
@@ -371,6 +578,12 @@ impl ExecutorParams {
 			}
 		}
 
+		// The NaNs produced by the relaxed SIMD instructions are only deterministic once
+		// canonicalized.
+		if seen.get("NanCanonicalization") == Some(&0) && seen.get("RelaxedSimd") == Some(&1) {
+			return Err(IncompatibleValues("NanCanonicalization", "RelaxedSimd"))
+		}
+
 		Ok(())
 	}
 }
@@ -408,6 +621,20 @@ fn ensure_prep_hash_changes() {
 			PvfExecTimeout(PvfExecKind::Backing, 0),
 			PvfExecTimeout(PvfExecKind::Approval, 0),
 			WasmExtBulkMemory,
+			OptimizationLevel(PvfOptimizationLevel::None),
+			CompilationThreads(1),
+			MaxDeclaredMemoryPages(1),
+			PvfPrepTimeoutScaling {
+				kind: PvfPrepKind::Prepare,
+				per_mib_ms: 1,
+				min_ms: 1,
+				max_ms: 1,
+			},
+			HugepageAlignedCode,
+			MaxArtifactSize(1),
+			NanCanonicalization(false),
+			RelaxedSimd(RelaxedSimdPolicy::Deterministic),
+			SignalsBasedTraps(false),
 		][..],
 	);
 
@@ -431,8 +658,65 @@ fn ensure_prep_hash_changes() {
 			PvfExecTimeout(_, _) => continue,
 			WasmExtBulkMemory =>
 				(ExecutorParams::default(), ExecutorParams::from(&[WasmExtBulkMemory][..])),
+			OptimizationLevel(_) => (
+				ExecutorParams::from(&[OptimizationLevel(PvfOptimizationLevel::None)][..]),
+				ExecutorParams::from(&[OptimizationLevel(PvfOptimizationLevel::Speed)][..]),
+			),
+			CompilationThreads(_) => continue,
+			MaxDeclaredMemoryPages(_) => (
+				ExecutorParams::from(&[MaxDeclaredMemoryPages(1)][..]),
+				ExecutorParams::from(&[MaxDeclaredMemoryPages(2)][..]),
+			),
+			PvfPrepTimeoutScaling { kind, .. } => (
+				ExecutorParams::from(
+					&[PvfPrepTimeoutScaling { kind: *kind, per_mib_ms: 1, min_ms: 1, max_ms: 1 }][..],
+				),
+				ExecutorParams::from(
+					&[PvfPrepTimeoutScaling { kind: *kind, per_mib_ms: 2, min_ms: 1, max_ms: 1 }][..],
+				),
+			),
+			HugepageAlignedCode =>
+				(ExecutorParams::default(), ExecutorParams::from(&[HugepageAlignedCode][..])),
+			MaxArtifactSize(_) => (
+				ExecutorParams::from(&[MaxArtifactSize(1)][..]),
+				ExecutorParams::from(&[MaxArtifactSize(2)][..]),
+			),
+			NanCanonicalization(_) => (
+				ExecutorParams::from(&[NanCanonicalization(true)][..]),
+				ExecutorParams::from(&[NanCanonicalization(false)][..]),
+			),
+			RelaxedSimd(_) => (
+				ExecutorParams::from(&[RelaxedSimd(RelaxedSimdPolicy::Reject)][..]),
+				ExecutorParams::from(&[RelaxedSimd(RelaxedSimdPolicy::Deterministic)][..]),
+			),
+			SignalsBasedTraps(_) => (
+				ExecutorParams::from(&[SignalsBasedTraps(true)][..]),
+				ExecutorParams::from(&[SignalsBasedTraps(false)][..]),
+			),
 		};
 
 		assert_ne!(ep1.prep_hash(), ep2.prep_hash());
 	}
 }
+
+#[test]
+fn relaxed_simd_requires_nan_canonicalization() {
+	use ExecutorParam::*;
+	let params = |nan_canonicalization| {
+		ExecutorParams::from(
+			&[
+				NanCanonicalization(nan_canonicalization),
+				RelaxedSimd(RelaxedSimdPolicy::Deterministic),
+			][..],
+		)
+	};
+
+	assert!(params(true).check_consistency().is_ok());
+	assert!(matches!(
+		params(false).check_consistency(),
+		Err(ExecutorParamError::IncompatibleValues("NanCanonicalization", "RelaxedSimd"))
+	));
+	assert!(ExecutorParams::from(&[NanCanonicalization(false)][..])
+		.check_consistency()
+		.is_ok());
+}
@@ -66,6 +66,7 @@ pub mod slashing;
 pub use async_backing::AsyncBackingParams;
 pub use executor_params::{
 	ExecutorParam, ExecutorParamError, ExecutorParams, ExecutorParamsHash, ExecutorParamsPrepHash,
+	PvfOptimizationLevel, RelaxedSimdPolicy,
 };
 
 mod metrics;
@@ -17,7 +17,10 @@
 use crate::configuration::*;
 use frame_benchmarking::{benchmarks, BenchmarkError, BenchmarkResult};
 use frame_system::RawOrigin;
-use polkadot_primitives::{ExecutorParam, ExecutorParams, PvfExecKind, PvfPrepKind};
+use polkadot_primitives::{
+	ExecutorParam, ExecutorParams, PvfExecKind, PvfOptimizationLevel, PvfPrepKind,
+	RelaxedSimdPolicy,
+};
 use sp_runtime::traits::One;
 
 benchmarks! {
@@ -45,6 +48,20 @@ benchmarks! {
 		ExecutorParam::PvfPrepTimeout(PvfPrepKind::Prepare, 360_000),
 		ExecutorParam::PvfExecTimeout(PvfExecKind::Backing, 2_000),
 		ExecutorParam::PvfExecTimeout(PvfExecKind::Approval, 12_000),
+		ExecutorParam::OptimizationLevel(PvfOptimizationLevel::SpeedAndSize),
+		ExecutorParam::CompilationThreads(16),
+		ExecutorParam::MaxDeclaredMemoryPages(65536),
+		ExecutorParam::PvfPrepTimeoutScaling {
+			kind: PvfPrepKind::Prepare,
+			per_mib_ms: 60_000,
+			min_ms: 60_000,
+			max_ms: 360_000,
+		},
+		ExecutorParam::HugepageAlignedCode,
+		ExecutorParam::MaxArtifactSize(1024 * 1024 * 1024),
+		ExecutorParam::NanCanonicalization(true),
+		ExecutorParam::RelaxedSimd(RelaxedSimdPolicy::Deterministic),
+		ExecutorParam::SignalsBasedTraps(false),
 	][..]))
 
 	set_config_with_perbill {}: set_on_demand_fee_variability(RawOrigin::Root, Perbill::from_percent(100))
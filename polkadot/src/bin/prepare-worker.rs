@@ -19,6 +19,7 @@
 polkadot_node_core_pvf_common::decl_worker_main!(
 	"prepare-worker",
 	polkadot_node_core_pvf_prepare_worker::worker_entrypoint,
+	args = polkadot_node_core_pvf_prepare_worker::PrepareWorkerArgs,
 	polkadot_cli::NODE_VERSION,
 	env!("SUBSTRATE_CLI_COMMIT_HASH"),
 );
@@ -318,6 +318,10 @@ where
 						wasm_bulk_memory: false,
 						wasm_reference_types: false,
 						wasm_simd: false,
+						wasm_relaxed_simd: false,
+						signals_based_traps: true,
+						optimization_level: sc_executor_wasmtime::OptimizationLevel::SpeedAndSize,
+						hugepage_aligned_code: false,
 					},
 				},
 			)
@@ -38,8 +38,8 @@ mod tests;
 
 pub use runtime::{
 	create_runtime, create_runtime_from_artifact, create_runtime_from_artifact_bytes,
-	prepare_runtime_artifact, Config, DeterministicStackLimit, InstantiationStrategy, Semantics,
-	WasmtimeRuntime,
+	precompile_runtime_blob, prepare_blob_for_compilation, prepare_runtime_artifact, Config,
+	DeterministicStackLimit, InstantiationStrategy, OptimizationLevel, Semantics, WasmtimeRuntime,
 };
 pub use sc_executor_common::{
 	runtime_blob::RuntimeBlob,
@@ -45,6 +45,9 @@ use wasmtime::{AsContext, Engine, Memory};
 
 const MAX_INSTANCE_COUNT: u32 = 64;
 
+/// The size of the hugepages compiled code is padded to, see [`Semantics::hugepage_aligned_code`].
+pub(crate) const HUGEPAGE_SIZE: usize = 2 * 1024 * 1024;
+
 #[derive(Default)]
 pub(crate) struct StoreData {
 	/// This will only be set when we call into the runtime.
@@ -231,7 +234,11 @@ directory = \"{cache_dir}\"
 
 fn common_config(semantics: &Semantics) -> std::result::Result<wasmtime::Config, WasmError> {
 	let mut config = wasmtime::Config::new();
-	config.cranelift_opt_level(wasmtime::OptLevel::SpeedAndSize);
+	config.cranelift_opt_level(match semantics.optimization_level {
+		OptimizationLevel::None => wasmtime::OptLevel::None,
+		OptimizationLevel::Speed => wasmtime::OptLevel::Speed,
+		OptimizationLevel::SpeedAndSize => wasmtime::OptLevel::SpeedAndSize,
+	});
 	config.cranelift_nan_canonicalization(semantics.canonicalize_nans);
 
 	// Since wasmtime 6.0.0 the default for this is `true`, but that heavily regresses
@@ -272,6 +279,8 @@ fn common_config(semantics: &Semantics) -> std::result::Result<wasmtime::Config,
 	// they should be introduced here as well.
 	config.wasm_reference_types(semantics.wasm_reference_types);
 	config.wasm_simd(semantics.wasm_simd);
+	config.wasm_relaxed_simd(semantics.wasm_relaxed_simd);
+	config.relaxed_simd_deterministic(true);
 	config.wasm_bulk_memory(semantics.wasm_bulk_memory);
 	config.wasm_multi_value(semantics.wasm_multi_value);
 	config.wasm_multi_memory(false);
@@ -285,6 +294,19 @@ fn common_config(semantics: &Semantics) -> std::result::Result<wasmtime::Config,
 		InstantiationStrategy::RecreateInstance => (false, false),
 	};
 
+	if !semantics.signals_based_traps {
+		if use_pooling {
+			return Err(WasmError::Other(
+				"pooling instantiation requires signals based traps".to_string(),
+			))
+		}
+
+		// No reservation and no guard pages: every memory access is bounds checked.
+		config.static_memory_maximum_size(0);
+		config.dynamic_memory_guard_size(0);
+		config.guard_before_linear_memory(false);
+	}
+
 	const WASM_PAGE_SIZE: u64 = 65536;
 
 	config.memory_init_cow(use_cow);
@@ -456,6 +478,40 @@ pub struct Semantics {
 
 	/// Enables WASM Fixed-Width SIMD proposal
 	pub wasm_simd: bool,
+
+	/// Enables WASM Relaxed SIMD proposal. Requires [`Self::wasm_simd`].
+	///
+	/// The relaxed instructions are always compiled to their deterministic lowering, so that
+	/// their results don't depend on the host CPU. NaNs they produce are only canonicalized if
+	/// [`Self::canonicalize_nans`] is set though.
+	pub wasm_relaxed_simd: bool,
+
+	/// Whether out-of-bounds memory accesses are caught by guard pages and the resulting
+	/// signals, rather than by bounds checks compiled into the code.
+	///
+	/// Disabling this makes the compiled code slower, but independent of the host's virtual
+	/// memory layout. It is incompatible with the pooling instantiation strategies.
+	pub signals_based_traps: bool,
+
+	/// The level of optimization applied when compiling.
+	pub optimization_level: OptimizationLevel,
+
+	/// Pads the compiled artifact to a whole number of hugepages, see [`HUGEPAGE_SIZE`], so that
+	/// the memory it is loaded into can be backed by hugepages. This eases the TLB pressure of
+	/// executing large runtimes. Only affects [`precompile_runtime_blob`].
+	pub hugepage_aligned_code: bool,
+}
+
+/// The level of optimization Cranelift applies when compiling a runtime.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptimizationLevel {
+	/// No optimizations.
+	None,
+	/// Optimizations for the speed of the generated code.
+	Speed,
+	/// Optimizations for both the speed and the size of the generated code.
+	#[default]
+	SpeedAndSize,
 }
 
 #[derive(Clone)]
@@ -639,7 +695,9 @@ where
 	})
 }
 
-fn prepare_blob_for_compilation(
+/// Transforms the given [`RuntimeBlob`] into the form expected by [`precompile_runtime_blob`],
+/// e.g. by injecting the stack depth metering required by the given semantics.
+pub fn prepare_blob_for_compilation(
 	mut blob: RuntimeBlob,
 	semantics: &Semantics,
 ) -> std::result::Result<RuntimeBlob, WasmError> {
@@ -662,18 +720,32 @@ fn prepare_blob_for_compilation(
 pub fn prepare_runtime_artifact(
 	blob: RuntimeBlob,
 	semantics: &Semantics,
+) -> std::result::Result<Vec<u8>, WasmError> {
+	let blob = prepare_blob_for_compilation(blob, semantics)?;
+	precompile_runtime_blob(blob, semantics)
+}
+
+/// Precompiles a [`RuntimeBlob`] which was already passed through
+/// [`prepare_blob_for_compilation`], returning the serialized result of compilation.
+pub fn precompile_runtime_blob(
+	blob: RuntimeBlob,
+	semantics: &Semantics,
 ) -> std::result::Result<Vec<u8>, WasmError> {
 	let mut semantics = semantics.clone();
 	replace_strategy_if_broken(&mut semantics.instantiation_strategy);
 
-	let blob = prepare_blob_for_compilation(blob, &semantics)?;
-
 	let engine = Engine::new(&common_config(&semantics)?)
 		.map_err(|e| WasmError::Other(format!("cannot create the engine: {:#}", e)))?;
 
-	engine
+	let mut artifact = engine
 		.precompile_module(&blob.serialize())
-		.map_err(|e| WasmError::Other(format!("cannot precompile module: {:#}", e)))
+		.map_err(|e| WasmError::Other(format!("cannot precompile module: {:#}", e)))?;
+	if semantics.hugepage_aligned_code {
+		// Wasmtime can't align the code itself, but it ignores anything past the end of the
+		// serialized module when loading it.
+		artifact.resize(artifact.len().next_multiple_of(HUGEPAGE_SIZE), 0);
+	}
+	Ok(artifact)
 }
 
 fn perform_call(
@@ -146,6 +146,10 @@ impl RuntimeBuilder {
 				wasm_bulk_memory: false,
 				wasm_reference_types: false,
 				wasm_simd: false,
+				wasm_relaxed_simd: false,
+				signals_based_traps: true,
+				optimization_level: crate::OptimizationLevel::SpeedAndSize,
+				hugepage_aligned_code: false,
 			},
 		};
 
@@ -473,6 +477,10 @@ fn test_instances_without_reuse_are_not_leaked() {
 				wasm_bulk_memory: false,
 				wasm_reference_types: false,
 				wasm_simd: false,
+				wasm_relaxed_simd: false,
+				signals_based_traps: true,
+				optimization_level: crate::OptimizationLevel::SpeedAndSize,
+				hugepage_aligned_code: false,
 			},
 		},
 	)
@@ -489,6 +497,93 @@ fn test_instances_without_reuse_are_not_leaked() {
 	}
 }
 
+#[test]
+fn test_hugepage_aligned_code_pads_the_artifact() {
+	let semantics = |hugepage_aligned_code| crate::Semantics {
+		instantiation_strategy: InstantiationStrategy::RecreateInstance,
+		deterministic_stack_limit: None,
+		canonicalize_nans: false,
+		parallel_compilation: false,
+		heap_alloc_strategy: DEFAULT_HEAP_ALLOC_STRATEGY,
+		wasm_multi_value: false,
+		wasm_bulk_memory: false,
+		wasm_reference_types: false,
+		wasm_simd: false,
+		wasm_relaxed_simd: false,
+		signals_based_traps: true,
+		optimization_level: crate::OptimizationLevel::SpeedAndSize,
+		hugepage_aligned_code,
+	};
+	let blob = || RuntimeBlob::uncompress_if_needed(wasm_binary_unwrap()).unwrap();
+
+	let artifact = crate::prepare_runtime_artifact(blob(), &semantics(false)).unwrap();
+	let padded_artifact = crate::prepare_runtime_artifact(blob(), &semantics(true)).unwrap();
+	assert_eq!(padded_artifact.len() % crate::runtime::HUGEPAGE_SIZE, 0);
+	assert_eq!(padded_artifact[..artifact.len()], artifact[..]);
+
+	// The padding doesn't keep the artifact from being loaded.
+	let runtime = unsafe {
+		crate::create_runtime_from_artifact_bytes::<HostFunctions>(
+			&padded_artifact,
+			crate::Config {
+				allow_missing_func_imports: true,
+				cache_path: None,
+				semantics: semantics(true),
+			},
+		)
+	}
+	.unwrap();
+	runtime.new_instance().unwrap().call_export("test_empty_return", &[0]).unwrap();
+}
+
+#[test]
+fn test_out_of_bounds_accesses_trap_without_signals_based_traps() {
+	let semantics = |instantiation_strategy| crate::Semantics {
+		instantiation_strategy,
+		deterministic_stack_limit: None,
+		canonicalize_nans: false,
+		parallel_compilation: false,
+		heap_alloc_strategy: DEFAULT_HEAP_ALLOC_STRATEGY,
+		wasm_multi_value: false,
+		wasm_bulk_memory: false,
+		wasm_reference_types: false,
+		wasm_simd: false,
+		wasm_relaxed_simd: false,
+		signals_based_traps: false,
+		optimization_level: crate::OptimizationLevel::SpeedAndSize,
+		hugepage_aligned_code: false,
+	};
+	let create_runtime = |instantiation_strategy| {
+		let wasm = wat::parse_str(
+			r#"
+			(module
+				(memory (export "memory") 1)
+				(global (export "__heap_base") i32 (i32.const 0))
+				(func (export "main") (param i32 i32) (result i64)
+					i32.const 0x10000
+					i64.load)
+			)
+			"#,
+		)
+		.unwrap();
+		crate::create_runtime::<HostFunctions>(
+			RuntimeBlob::uncompress_if_needed(&wasm).unwrap(),
+			crate::Config {
+				allow_missing_func_imports: true,
+				cache_path: None,
+				semantics: semantics(instantiation_strategy),
+			},
+		)
+	};
+
+	let runtime = create_runtime(InstantiationStrategy::RecreateInstance).unwrap();
+	let mut instance = runtime.new_instance().unwrap();
+	assert!(instance.call_export("main", &[]).is_err());
+
+	// The pooling allocator relies on guard pages.
+	assert!(create_runtime(InstantiationStrategy::Pooling).is_err());
+}
+
 #[test]
 fn test_rustix_version_matches_with_wasmtime() {
 	let metadata = cargo_metadata::MetadataCommand::new().exec().unwrap();